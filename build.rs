@@ -0,0 +1,326 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const SHADER_DIR: &str = "shaders";
+const SHADER_INCLUDE_DIR: &str = "shaders/include";
+const PRECOMPILED_DIR: &str = "shaders/dist";
+/// Hash of the sources each precompiled shader was built from, one `<hash> <name>` per line.
+const PRECOMPILED_HASHES: &str = "shaders/dist/hashes.txt";
+
+/// Set together with a compiler to copy the compiled shaders to `PRECOMPILED_DIR`.
+const UPDATE_PRECOMPILED_VARIABLE: &str = "VOXRT_UPDATE_SHADER_DIST";
+
+const SHADER_EXTENSIONS: &[&str] = &[
+    "vert", "frag", "comp", "rgen", "rchit", "rahit", "rmiss", "rint",
+];
+
+/// Defines passed to every shader.
+const GLOBAL_DEFINES: &[&str] = &["VOXRT=1"];
+
+/// Extra defines for a single shader, keyed by file name.
+const SHADER_DEFINES: &[(&str, &[&str])] = &[];
+
+fn main() {
+    println!("cargo:rerun-if-changed={SHADER_DIR}");
+    println!("cargo:rerun-if-env-changed=GLSLANG_VALIDATOR");
+    println!("cargo:rerun-if-env-changed=VULKAN_SDK");
+    println!("cargo:rerun-if-env-changed={UPDATE_PRECOMPILED_VARIABLE}");
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let compiler = find_compiler();
+
+    if compiler.is_none() {
+        println!(
+            "cargo:warning=glslangValidator not found (set GLSLANG_VALIDATOR or VULKAN_SDK), \
+             embedding precompiled shaders from {PRECOMPILED_DIR}"
+        );
+    }
+
+    let mut sources: Vec<PathBuf> = fs::read_dir(SHADER_DIR)
+        .expect("Cannot read shader directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| SHADER_EXTENSIONS.contains(&ext))
+        })
+        .collect();
+    sources.sort();
+
+    let precompiled_hashes = read_precompiled_hashes();
+    let mut errors = Vec::new();
+    let mut embedded = Vec::new();
+
+    for source in &sources {
+        let name = source.file_name().unwrap().to_str().unwrap().to_string();
+        println!("cargo:rerun-if-changed={}", source.display());
+
+        let hash = source_hash(source, &name);
+        let precompiled = Path::new(PRECOMPILED_DIR).join(format!("{name}.spv"));
+        let is_stale = !precompiled.exists()
+            || precompiled_hashes
+                .iter()
+                .all(|(precompiled_name, precompiled_hash)| {
+                    *precompiled_name != name || *precompiled_hash != hash
+                });
+
+        let spv_path = match &compiler {
+            Some(compiler) => {
+                let output_path = out_dir.join(format!("{name}.spv"));
+                if let Err(mut shader_errors) =
+                    compile_shader(compiler, source, &name, &output_path)
+                {
+                    errors.append(&mut shader_errors);
+                    continue;
+                }
+                if is_stale && env::var_os(UPDATE_PRECOMPILED_VARIABLE).is_none() {
+                    println!(
+                        "cargo:warning={} is out of date, rebuild with \
+                         {UPDATE_PRECOMPILED_VARIABLE}=1 and commit it",
+                        precompiled.display()
+                    );
+                }
+                output_path
+            }
+            // Builds without a compiler must embed the same shaders as builds with one.
+            None if is_stale => {
+                errors.push(format!(
+                    "{}: no compiler available and {} is missing or was built from other \
+                     sources, rebuild it with {UPDATE_PRECOMPILED_VARIABLE}=1 and a compiler",
+                    source.display(),
+                    precompiled.display()
+                ));
+                continue;
+            }
+            None => fs::canonicalize(precompiled).unwrap(),
+        };
+
+        embedded.push((name, spv_path, hash));
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            println!("cargo:warning={error}");
+        }
+        panic!("Shader compilation failed:\n{}", errors.join("\n"));
+    }
+
+    if compiler.is_some() && env::var_os(UPDATE_PRECOMPILED_VARIABLE).is_some() {
+        write_precompiled(&embedded);
+    }
+
+    let mut generated = String::from("pub static EMBEDDED_SHADERS: &[(&str, &[u8])] = &[\n");
+    for (name, spv_path, _) in &embedded {
+        writeln!(
+            generated,
+            "    ({name:?}, include_bytes!({:?})),",
+            spv_path.display().to_string()
+        )
+        .unwrap();
+    }
+    generated.push_str("];\n\n");
+
+    // Shader hot reload recompiles with the exact same defines at runtime.
+    generated.push_str("pub static SHADER_DEFINES: &[(&str, &[&str])] = &[\n");
+    for (name, _, _) in &embedded {
+        writeln!(generated, "    ({name:?}, &{:?}),", shader_defines(name)).unwrap();
+    }
+    generated.push_str("];\n");
+
+    println!(
+        "cargo:rustc-env=VOXRT_SHADER_COMPILER={}",
+        compiler
+            .as_ref()
+            .map(|compiler| compiler.display().to_string())
+            .unwrap_or_default()
+    );
+
+    fs::write(out_dir.join("shaders.rs"), generated).expect("Cannot write embedded shader table");
+}
+
+fn shader_defines(name: &str) -> Vec<&'static str> {
+    let shader_defines = SHADER_DEFINES
+        .iter()
+        .filter(|(shader, _)| *shader == name)
+        .flat_map(|(_, defines)| defines.iter());
+
+    GLOBAL_DEFINES
+        .iter()
+        .chain(shader_defines)
+        .copied()
+        .collect()
+}
+
+/// FNV-1a of everything the compiled shader depends on: its defines, its source and the
+/// files it includes, recursively.
+fn source_hash(source: &Path, name: &str) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    let mut feed = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+
+    for define in shader_defines(name) {
+        feed(define.as_bytes());
+        feed(b"\n");
+    }
+
+    let mut pending = vec![source.to_path_buf()];
+    let mut included = Vec::new();
+    while let Some(path) = pending.pop() {
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Cannot read {}: {err}", path.display()));
+        feed(path.file_name().unwrap().to_str().unwrap().as_bytes());
+        // Line endings depend on the git checkout settings, the compiled shader does not.
+        for line in text.lines() {
+            feed(line.as_bytes());
+            feed(b"\n");
+        }
+
+        for line in text.lines() {
+            let Some(include) = line
+                .trim()
+                .strip_prefix("#include")
+                .and_then(|rest| rest.trim().strip_prefix('"'))
+                .and_then(|rest| rest.strip_suffix('"'))
+            else {
+                continue;
+            };
+            let include = Path::new(SHADER_INCLUDE_DIR).join(include);
+            if !included.contains(&include) {
+                included.push(include.clone());
+                pending.push(include);
+            }
+        }
+    }
+
+    hash
+}
+
+fn read_precompiled_hashes() -> Vec<(String, u64)> {
+    println!("cargo:rerun-if-changed={PRECOMPILED_HASHES}");
+    let Ok(text) = fs::read_to_string(PRECOMPILED_HASHES) else {
+        return Vec::new();
+    };
+
+    text.lines()
+        .filter_map(|line| {
+            let (hash, name) = line.split_once(' ')?;
+            Some((name.to_string(), u64::from_str_radix(hash, 16).ok()?))
+        })
+        .collect()
+}
+
+fn write_precompiled(embedded: &[(String, PathBuf, u64)]) {
+    let mut hashes = String::new();
+    for (name, spv_path, hash) in embedded {
+        let precompiled = Path::new(PRECOMPILED_DIR).join(format!("{name}.spv"));
+        fs::copy(spv_path, &precompiled)
+            .unwrap_or_else(|err| panic!("Cannot write {}: {err}", precompiled.display()));
+        writeln!(hashes, "{hash:016x} {name}").unwrap();
+    }
+    fs::write(PRECOMPILED_HASHES, hashes)
+        .unwrap_or_else(|err| panic!("Cannot write {PRECOMPILED_HASHES}: {err}"));
+}
+
+fn find_compiler() -> Option<PathBuf> {
+    let executable = if cfg!(windows) {
+        "glslangValidator.exe"
+    } else {
+        "glslangValidator"
+    };
+
+    let candidates = [
+        env::var_os("GLSLANG_VALIDATOR").map(PathBuf::from),
+        env::var_os("VULKAN_SDK").map(|sdk| PathBuf::from(sdk).join("Bin").join(executable)),
+        env::var_os("VULKAN_SDK").map(|sdk| PathBuf::from(sdk).join("bin").join(executable)),
+        Some(PathBuf::from(executable)),
+    ];
+
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|candidate| Command::new(candidate).arg("--version").output().is_ok())
+}
+
+fn compile_shader(
+    compiler: &Path,
+    source: &Path,
+    name: &str,
+    output_path: &Path,
+) -> Result<(), Vec<String>> {
+    let mut command = Command::new(compiler);
+    command
+        .args(["--target-env", "vulkan1.2", "-V"])
+        .arg(format!("-I{SHADER_INCLUDE_DIR}"))
+        .arg("-o")
+        .arg(output_path);
+
+    for define in shader_defines(name) {
+        command.arg(format!("-D{define}"));
+    }
+
+    command.arg(source);
+
+    let output = command
+        .output()
+        .unwrap_or_else(|err| panic!("Cannot run {}: {err}", compiler.display()));
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let log = String::from_utf8_lossy(&output.stdout).to_string()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    let errors: Vec<String> = log
+        .lines()
+        .filter_map(|line| format_compiler_error(line, source))
+        .collect();
+
+    if errors.is_empty() {
+        return Err(vec![format!("{}: {}", source.display(), log.trim())]);
+    }
+
+    Err(errors)
+}
+
+/// Turns `ERROR: path:line: message` into `path:line: error: message`.
+fn format_compiler_error(line: &str, source: &Path) -> Option<String> {
+    let (severity, rest) = line
+        .strip_prefix("ERROR: ")
+        .map(|rest| ("error", rest))
+        .or_else(|| line.strip_prefix("WARNING: ").map(|rest| ("warning", rest)))?;
+
+    match split_location(rest) {
+        Some((file, line_number, message)) => {
+            let file = if file.is_empty() {
+                source.display().to_string()
+            } else {
+                file.to_string()
+            };
+            Some(format!("{file}:{line_number}: {severity}: {message}"))
+        }
+        // Summary lines such as "ERROR: 1 compilation errors." carry no location.
+        None if severity == "error" && !rest.contains("compilation errors") => {
+            Some(format!("{}: {severity}: {}", source.display(), rest.trim()))
+        }
+        None => None,
+    }
+}
+
+/// Finds the first `:<line>:` in `file:line: message`, skipping drive letters on Windows.
+fn split_location(text: &str) -> Option<(&str, u32, &str)> {
+    text.match_indices(':').find_map(|(start, _)| {
+        let after = &text[start + 1..];
+        let end = after.find(':')?;
+        let line_number = after[..end].trim().parse().ok()?;
+        Some((text[..start].trim(), line_number, after[end + 1..].trim()))
+    })
+}
@@ -0,0 +1,24 @@
+// Decoding of a palette compressed chunk, the layout of `PaletteVoxels::gpu_data`:
+// index width, palette length, palette two materials per word, then the packed indices.
+// Mirrors `palette::decode_gpu_material`.
+
+#extension GL_EXT_buffer_reference : require
+
+layout(buffer_reference, std430, buffer_reference_align = 4) readonly buffer PaletteChunk {
+    uint words[];
+};
+
+// `index` is the chunk voxel index, x + y * 32 + z * 1024.
+uint paletteChunkMaterial(PaletteChunk chunk, uint index) {
+    uint bits = chunk.words[0];
+    uint paletteLength = chunk.words[1];
+
+    uint paletteIndex = 0;
+    if (bits != 0) {
+        uint bit = index * bits;
+        uint word = chunk.words[2 + (paletteLength + 1) / 2 + bit / 32];
+        paletteIndex = (word >> (bit % 32)) & ((1u << bits) - 1);
+    }
+
+    return (chunk.words[2 + paletteIndex / 2] >> (16 * (paletteIndex % 2))) & 0xFFFF;
+}
@@ -0,0 +1,167 @@
+// Traversal of the sparse 64-tree of `src/world/sparse_tree.rs`, usable from an intersection
+// or a compute shader. Mirrors `SparseVoxelTree::raycast`, keep both in sync.
+
+#extension GL_EXT_buffer_reference : require
+
+#define SPARSE_TREE_MAX_DEPTH 8
+#define SPARSE_TREE_LEAF 1u
+#define SPARSE_TREE_UNIFORM 2u
+
+// `TreeNode`: child mask low and high words, first child, flags.
+layout(buffer_reference, std430, buffer_reference_align = 16) readonly buffer SparseTreeNodes {
+    uvec4 nodes[];
+};
+
+// Two u16 materials per uint, the lower half first.
+layout(buffer_reference, std430, buffer_reference_align = 4) readonly buffer SparseTreeMaterials {
+    uint materials[];
+};
+
+// `SparseTreeGeometry` on the Rust side.
+struct SparseTree {
+    SparseTreeNodes nodes;
+    SparseTreeMaterials materials;
+    ivec3 origin;
+    uint depth;
+};
+
+struct SparseTreeHit {
+    float t;
+    ivec3 voxel;
+    uint face;
+    uint material;
+};
+
+bool sparseTreeHasChild(uvec4 node, uint cell) {
+    uint word = cell < 32 ? node.x : node.y;
+    return (word & (1u << (cell % 32))) != 0;
+}
+
+uint sparseTreeChildIndex(uvec4 node, uint cell) {
+    if (cell < 32) {
+        return node.z + bitCount(node.x & ((1u << cell) - 1));
+    }
+    return node.z + bitCount(node.x) + bitCount(node.y & ((1u << (cell - 32)) - 1));
+}
+
+uint sparseTreeMaterial(SparseTreeMaterials materials, uint index) {
+    return (materials.materials[index / 2] >> (16 * (index % 2))) & 0xFFFF;
+}
+
+ivec3 sparseTreeEntryCell(vec3 origin, vec3 direction, vec3 gridOrigin, float cellSize, float t) {
+    return clamp(ivec3(floor((origin + direction * t - gridOrigin) / cellSize)), ivec3(0), ivec3(3));
+}
+
+bool traceSparseTree(SparseTree tree, vec3 origin, vec3 direction, float tMin, float tMax,
+                     out SparseTreeHit hit) {
+    float size = float(1 << (2 * tree.depth));
+    vec3 rootOrigin = vec3(tree.origin);
+
+    // Slab test against the root, see `voxel_dda::intersect_box`.
+    float near = -1.0 / 0.0;
+    float far = 1.0 / 0.0;
+    int entryAxis = 0;
+    for (int a = 0; a < 3; a++) {
+        if (direction[a] == 0.0) {
+            if (origin[a] < rootOrigin[a] || origin[a] > rootOrigin[a] + size) {
+                return false;
+            }
+            continue;
+        }
+        float t0 = (rootOrigin[a] - origin[a]) / direction[a];
+        float t1 = (rootOrigin[a] + size - origin[a]) / direction[a];
+        if (min(t0, t1) > near) {
+            near = min(t0, t1);
+            entryAxis = a;
+        }
+        far = min(far, max(t0, t1));
+    }
+    float tEnter = max(near, tMin);
+    float tExit = min(far, tMax);
+    if (tEnter > tExit) {
+        return false;
+    }
+
+    ivec3 step = ivec3(sign(direction));
+
+    // One frame per level, see `Frame`.
+    uint stackNode[SPARSE_TREE_MAX_DEPTH];
+    vec3 stackOrigin[SPARSE_TREE_MAX_DEPTH];
+    float stackCellSize[SPARSE_TREE_MAX_DEPTH];
+    ivec3 stackCell[SPARSE_TREE_MAX_DEPTH];
+    float stackT[SPARSE_TREE_MAX_DEPTH];
+    uint stackFace[SPARSE_TREE_MAX_DEPTH];
+    float stackTExit[SPARSE_TREE_MAX_DEPTH];
+
+    int top = 0;
+    stackNode[0] = 0;
+    stackOrigin[0] = rootOrigin;
+    stackCellSize[0] = size / 4.0;
+    stackCell[0] = sparseTreeEntryCell(origin, direction, rootOrigin, size / 4.0, tEnter);
+    stackT[0] = tEnter;
+    stackFace[0] = uint(entryAxis) * 2 + (step[entryAxis] > 0 ? 1 : 0);
+    stackTExit[0] = tExit;
+
+    while (top >= 0) {
+        ivec3 cellPosition = stackCell[top];
+        // Past the node, also reached when a child is done and its parent was moved on.
+        if (stackT[top] > stackTExit[top] || any(lessThan(cellPosition, ivec3(0)))
+            || any(greaterThan(cellPosition, ivec3(3)))) {
+            top--;
+            continue;
+        }
+
+        uvec4 node = tree.nodes.nodes[stackNode[top]];
+        uint cell = uint(cellPosition.x + cellPosition.y * 4 + cellPosition.z * 16);
+        float cellSize = stackCellSize[top];
+
+        vec3 tNext = vec3(1.0 / 0.0);
+        for (int a = 0; a < 3; a++) {
+            if (step[a] != 0) {
+                float boundary = stackOrigin[top][a] + float(cellPosition[a] + (step[a] > 0 ? 1 : 0)) * cellSize;
+                tNext[a] = (boundary - origin[a]) / direction[a];
+            }
+        }
+        int nextAxis = (tNext.x < tNext.y && tNext.x < tNext.z) ? 0 : (tNext.y < tNext.z ? 1 : 2);
+
+        float t = stackT[top];
+        uint face = stackFace[top];
+
+        // Moves this frame past the current cell, before a child is pushed or not.
+        stackT[top] = tNext[nextAxis];
+        stackCell[top][nextAxis] += step[nextAxis];
+        stackFace[top] = uint(nextAxis) * 2 + (step[nextAxis] > 0 ? 1 : 0);
+
+        if (!sparseTreeHasChild(node, cell)) {
+            continue;
+        }
+
+        vec3 childOrigin = stackOrigin[top] + vec3(cellPosition) * cellSize;
+        uint childIndex = sparseTreeChildIndex(node, cell);
+
+        if ((node.w & SPARSE_TREE_LEAF) != 0) {
+            hit = SparseTreeHit(t, ivec3(childOrigin), face, sparseTreeMaterial(tree.materials, childIndex));
+            return true;
+        }
+
+        uvec4 child = tree.nodes.nodes[childIndex];
+        if ((child.w & SPARSE_TREE_UNIFORM) != 0) {
+            vec3 local = clamp(floor(origin + direction * t - childOrigin), vec3(0.0), vec3(cellSize - 1.0));
+            hit = SparseTreeHit(t, ivec3(childOrigin + local), face, child.z);
+            return true;
+        }
+
+        float childCellSize = cellSize / 4.0;
+        float childTExit = min(tNext[nextAxis], stackTExit[top]);
+        top++;
+        stackNode[top] = childIndex;
+        stackOrigin[top] = childOrigin;
+        stackCellSize[top] = childCellSize;
+        stackCell[top] = sparseTreeEntryCell(origin, direction, childOrigin, childCellSize, t);
+        stackT[top] = t;
+        stackFace[top] = face;
+        stackTExit[top] = childTExit;
+    }
+
+    return false;
+}
@@ -0,0 +1,65 @@
+// Per chunk voxel data of the procedural hit group, see `raytracing_voxel_geometry.rs`.
+// Layouts match `vk::AabbPositionsKHR`, `PrimitiveInfo`, `Brick` and `ChunkGeometry`
+// on the Rust side, brick materials come from the palette compressed chunk.
+
+#extension GL_EXT_buffer_reference : require
+
+#include "palette_chunk.glsl"
+
+#define AABB_GRANULARITY_VOXEL 0
+#define AABB_GRANULARITY_BRICK 1
+#define AABB_GRANULARITY_GREEDY 2
+
+#define BRICK_SIZE 8
+
+struct Aabb {
+    float minX, minY, minZ;
+    float maxX, maxY, maxZ;
+};
+
+struct PrimitiveInfo {
+    uvec3 origin;
+    uint data;
+};
+
+struct Brick {
+    uint occupancy[16];
+};
+
+layout(buffer_reference, std430, buffer_reference_align = 4) readonly buffer Aabbs {
+    Aabb aabbs[];
+};
+
+layout(buffer_reference, std430, buffer_reference_align = 16) readonly buffer PrimitiveInfos {
+    PrimitiveInfo primitives[];
+};
+
+layout(buffer_reference, std430, buffer_reference_align = 4) readonly buffer Bricks {
+    Brick bricks[];
+};
+
+struct ChunkGeometry {
+    Aabbs aabbs;
+    PrimitiveInfos primitives;
+    Bricks bricks;
+    PaletteChunk materials;
+    uint granularity;
+};
+
+// Indexed by gl_InstanceCustomIndexEXT.
+layout(set = 0, binding = 2, std430) readonly buffer ChunkGeometries {
+    ChunkGeometry chunkGeometries[];
+};
+
+// Hit attributes of the procedural hit group, the face is the hit kind.
+struct VoxelHitAttributes {
+    uvec3 voxel;
+    uint material;
+};
+
+// axis * 2, plus 1 for the negative side.
+vec3 faceNormal(uint face) {
+    vec3 normal = vec3(0.0);
+    normal[face / 2] = (face % 2 == 0) ? 1.0 : -1.0;
+    return normal;
+}
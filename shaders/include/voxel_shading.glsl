@@ -0,0 +1,25 @@
+// Voxel colors shared by `voxelhit.rchit` and `voxel_mesh.frag`, mirrored on the CPU by
+// `world::cpu_tracer`.
+
+// Indexed by `MaterialId`, same as `MATERIAL_COLORS` in `world/material.rs`.
+const vec3 MATERIAL_COLORS[10] = vec3[](
+    vec3(1.0, 0.0, 1.0),    // AIR, also used for unknown materials
+    vec3(0.5, 0.5, 0.5),    // STONE
+    vec3(0.45, 0.3, 0.2),   // DIRT
+    vec3(0.3, 0.6, 0.2),    // GRASS
+    vec3(0.85, 0.8, 0.55),  // SAND
+    vec3(0.95, 0.95, 1.0),  // SNOW
+    vec3(0.55, 0.5, 0.5),   // GRAVEL
+    vec3(0.15, 0.15, 0.15), // COAL_ORE
+    vec3(0.7, 0.55, 0.45),  // IRON_ORE
+    vec3(0.9, 0.75, 0.2)    // GOLD_ORE
+);
+
+const vec3 SUN_DIRECTION = normalize(vec3(0.4, 1.0, 0.3));
+
+// Albedo of `material` lit by the sun, `normal` in world space.
+vec3 shadeVoxel(uint material, vec3 normal) {
+    material = material < uint(MATERIAL_COLORS.length()) ? material : 0u;
+    float light = 0.25 + 0.75 * max(dot(normal, SUN_DIRECTION), 0.0);
+    return MATERIAL_COLORS[material] * light;
+}
@@ -0,0 +1,16 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "voxel_shading.glsl"
+
+layout(location = 0) in vec3 inNormal;
+layout(location = 1) flat in uint inMaterial;
+layout(location = 2) in float inAmbientOcclusion;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // Fully enclosed corners keep some light, like the ambient term of the sun.
+    float ambientOcclusion = 0.4 + 0.6 * inAmbientOcclusion;
+    outColor = vec4(shadeVoxel(inMaterial, inNormal) * ambientOcclusion, 1.0);
+}
@@ -0,0 +1,29 @@
+#version 450
+
+// `VoxelVertex` in `world/greedy_mesh.rs`.
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in uint inFace;
+layout(location = 2) in uint inMaterial;
+layout(location = 3) in uint inAmbientOcclusion;
+
+// `VoxelMeshPushConstants` in `volcan/raster_voxel_mesh.rs`.
+layout(push_constant) uniform PushConstants {
+    mat4 viewProjection;
+    vec4 chunkOrigin;
+} pushConstants;
+
+layout(location = 0) out vec3 outNormal;
+layout(location = 1) flat out uint outMaterial;
+layout(location = 2) out float outAmbientOcclusion;
+
+void main() {
+    gl_Position = pushConstants.viewProjection * vec4(pushConstants.chunkOrigin.xyz + inPosition, 1.0);
+
+    // axis * 2, plus 1 for the negative side.
+    vec3 normal = vec3(0.0);
+    normal[inFace / 2] = (inFace % 2 == 0) ? 1.0 : -1.0;
+
+    outNormal = normal;
+    outMaterial = inMaterial;
+    outAmbientOcclusion = float(inAmbientOcclusion) / 3.0;
+}
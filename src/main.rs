@@ -1,42 +1,558 @@
 mod unwraped_option;
-#[allow(dead_code)]
 mod volcan;
+mod world;
 
 use std::{
+    collections::HashSet,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use ash::vk::{self};
+use glam::{Affine3A, IVec3, UVec2, Vec3};
+use log::{error, info};
 use unwraped_option::Lazy;
-use volcan::{init::Volcan, pipeline::VolcanPipeline};
+use volcan::{
+    init::Volcan,
+    pipeline::VolcanPipeline,
+    raster_voxel_mesh::VoxelMeshes,
+    raytracing_chunk_streaming::ChunkResidency,
+    raytracing_host_build::HostBlasBuilder,
+    raytracing_sbt::ShaderBindingTable,
+    raytracing_sparse_tree::{SparseTreeBuffers, SparseTreeRaycaster},
+    raytracing_tlas::{InstanceId, Tlas, TlasInstance},
+    raytracing_voxel_model::{VoxelModelBlases, VoxelModelMeshBlases},
+    shader_hot_reload::ShaderHotReload,
+};
 use winit::{
     application::ApplicationHandler,
     dpi::{LogicalSize, Size},
-    event::WindowEvent,
+    event::{ElementState, MouseButton, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
     window::{Window, WindowId},
 };
+use world::{
+    camera::Camera,
+    chunk_aabbs::AabbGranularity,
+    chunk_streaming::{ChunkStreamer, StreamingSettings},
+    cpu_tracer::{CpuTracer, SKY_COLOR},
+    material::MaterialId,
+    sparse_tree::{SparseVoxelTree, TreeHit},
+    terrain::{TerrainGenerator, TerrainSettings},
+    vox::VoxFile,
+    voxel_dda,
+    world_save::{WorldSave, WorldSaveError},
+};
+
+/// Farthest voxel that can be edited, in voxels from the camera.
+const PICK_DISTANCE: f32 = 64.0;
+
+/// Where the models of `--vox` are centered, in front of the starting camera.
+const VOX_ORIGIN: IVec3 = IVec3::new(0, 0, 32);
+
+/// Column of the starting camera, which starts [`SPAWN_HEIGHT`] voxels above the terrain.
+const SPAWN_COLUMN: IVec3 = IVec3::new(0, 0, -5);
+const SPAWN_HEIGHT: f32 = 8.0;
+
+/// Camera speed with WASD, space and left shift, in voxels per second.
+const CAMERA_SPEED: f32 = 16.0;
+/// Camera turn rate with the arrow keys, in radians per second.
+const CAMERA_TURN_SPEED: f32 = 1.5;
+
+/// Turn rate of the `--vox` models with `--vox-spin`, in radians per second.
+const VOX_SPIN_SPEED: f32 = 0.5;
+
+/// What the window presents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RenderMode {
+    /// The streamed world, ray traced (`--raytrace`).
+    Raytrace,
+    /// The streamed world, rasterized from greedy meshes.
+    VoxelMesh,
+    /// The hardcoded triangle of `basic_triangle.vert` (`--test-triangle`).
+    TestTriangle,
+}
+
+impl RenderMode {
+    fn from_args() -> Self {
+        if std::env::args().any(|arg| arg == "--raytrace") {
+            Self::Raytrace
+        } else if std::env::args().any(|arg| arg == "--test-triangle") {
+            Self::TestTriangle
+        } else {
+            Self::VoxelMesh
+        }
+    }
+}
 
 pub struct App {
     window: Option<Arc<Window>>,
     frame_count: u32,
     last_update_time: Instant,
+    last_frame_time: Instant,
 
     volcan: Lazy<Volcan>,
     test_raster_pipeline: Lazy<vk::Pipeline>,
+    voxel_mesh_pipeline: Lazy<vk::Pipeline>,
+    raytracing_pipeline: Lazy<vk::Pipeline>,
+    shader_binding_table: Lazy<ShaderBindingTable>,
+    tlas: Tlas,
+    camera: Camera,
+    /// Camera controls currently pressed.
+    held_keys: HashSet<KeyCode>,
+    render_mode: RenderMode,
+    /// Loads the chunks around the camera, unless drawing the test triangle. Created with the
+    /// device, to build the chunk BLASes on the loading threads.
+    chunk_streamer: Option<ChunkStreamer>,
+    chunk_residency: ChunkResidency,
+    voxel_meshes: VoxelMeshes,
+    /// File given with `--vox` and its name, until its models are placed.
+    vox_file: Option<(String, VoxFile)>,
+    /// Models of the `--vox` file, instanced in the TLAS.
+    voxel_models: Vec<VoxelModelBlases>,
+    /// Same, greedy meshed into triangles with `--vox-triangles`.
+    voxel_model_meshes: Vec<VoxelModelMeshBlases>,
+    /// TLAS instances of the models and their transform around [`VOX_ORIGIN`].
+    vox_instances: Vec<(InstanceId, Affine3A)>,
+    /// Start of the `--vox-spin` animation.
+    vox_spin_start: Option<Instant>,
+    /// Picks the edited voxel on the GPU, on the CPU when `None`.
+    sparse_tree_raycaster: Option<SparseTreeRaycaster>,
+    /// Last cursor position in the window, in pixels.
+    cursor_position: Option<UVec2>,
+    shader_hot_reload: Option<ShaderHotReload>,
+    /// Directory given with `--dump-frame`, cleared once the frame is written.
+    frame_dump_directory: Option<PathBuf>,
 }
 
 impl App {
     fn new() -> Self {
+        let render_mode = RenderMode::from_args();
+
         Self {
             window: None,
             frame_count: 0,
             last_update_time: Instant::now(),
+            last_frame_time: Instant::now(),
             test_raster_pipeline: Lazy::new(),
+            voxel_mesh_pipeline: Lazy::new(),
+            raytracing_pipeline: Lazy::new(),
+            shader_binding_table: Lazy::new(),
+            tlas: Tlas::new(),
+            camera: Camera::default(),
+            held_keys: HashSet::new(),
+            render_mode,
+            chunk_streamer: None,
+            chunk_residency: ChunkResidency::new(),
+            voxel_meshes: VoxelMeshes::new(),
+            vox_file: (render_mode != RenderMode::TestTriangle)
+                .then(load_vox_file)
+                .flatten(),
+            voxel_models: Vec::new(),
+            voxel_model_meshes: Vec::new(),
+            vox_instances: Vec::new(),
+            vox_spin_start: std::env::args()
+                .any(|arg| arg == "--vox-spin")
+                .then(Instant::now),
+            sparse_tree_raycaster: None,
+            cursor_position: None,
+            shader_hot_reload: None,
+            frame_dump_directory: (render_mode == RenderMode::Raytrace)
+                .then(|| argument_value("--dump-frame").map(PathBuf::from))
+                .flatten(),
             volcan: Lazy::new(),
         }
     }
+
+    /// Writes the traced frame as `gpu.ppm` and the CPU reference render of the same world
+    /// and camera as `cpu.ppm`, once every chunk around the camera is resident.
+    fn dump_frame(&mut self) {
+        let Some(chunk_streamer) = self.chunk_streamer.as_ref() else {
+            return;
+        };
+        if !chunk_streamer.is_idle() {
+            return;
+        }
+        let Some(directory) = self.frame_dump_directory.take() else {
+            return;
+        };
+
+        let gpu_image = self.volcan.read_raytracing_output();
+        let cpu_image = CpuTracer::new(&chunk_streamer.world, chunk_streamer.settings.granularity)
+            .render(
+                &self.camera,
+                gpu_image.width,
+                gpu_image.height,
+                std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            );
+
+        // Every ray missing means the trace itself is broken, e.g. its flags or cull mask.
+        if gpu_image.is_uniform(SKY_COLOR) {
+            eprintln!("Frame dump: the traced frame is only sky, no ray hit the scene");
+        }
+
+        let difference = gpu_image.compare(&cpu_image, 2);
+        println!(
+            "Frame dump: {} of {} pixels differ from the CPU render, by up to {}",
+            difference.differing_pixels,
+            gpu_image.width * gpu_image.height,
+            difference.max_channel_difference
+        );
+
+        let written = std::fs::create_dir_all(&directory)
+            .and_then(|()| gpu_image.save_ppm(&directory.join("gpu.ppm")))
+            .and_then(|()| cpu_image.save_ppm(&directory.join("cpu.ppm")));
+        match written {
+            Ok(()) => println!("Frame dumped to {}", directory.display()),
+            Err(error) => eprintln!("Cannot dump the frame to {}: {error}", directory.display()),
+        }
+    }
+
+    /// Instances the models of the `--vox` file in the TLAS, around [`VOX_ORIGIN`].
+    fn add_vox_instances(&mut self) {
+        let Some((name, vox_file)) = self.vox_file.take() else {
+            return;
+        };
+        let granularity = self.chunk_streamer.as_ref().unwrap().settings.granularity;
+        let materials = vox_file.closest_materials();
+        let volcan = &*self.volcan;
+
+        let as_triangles = std::env::args().any(|arg| arg == "--vox-triangles");
+
+        for (index, model) in vox_file.models.iter().enumerate() {
+            let model_world = model.to_world(&materials);
+            // Around VOX_ORIGIN, see `vox_placement`.
+            let transforms = vox_file
+                .instances
+                .iter()
+                .filter(|instance| instance.model == index)
+                .map(|instance| instance.transform(model.size));
+
+            let tlas_instances: Vec<TlasInstance> = if as_triangles {
+                let blases = VoxelModelMeshBlases::new(volcan, &model_world);
+                let tlas_instances = transforms
+                    .flat_map(|transform| blases.instances(transform))
+                    .collect();
+                self.voxel_model_meshes.push(blases);
+                tlas_instances
+            } else {
+                let blases = VoxelModelBlases::new(
+                    volcan,
+                    &format!("{name}_{index}"),
+                    &model_world,
+                    granularity,
+                );
+                let first_slot = self
+                    .chunk_residency
+                    .append_geometries(volcan, blases.geometries());
+                let tlas_instances = transforms
+                    .flat_map(|transform| blases.instances(transform, first_slot))
+                    .collect();
+                self.voxel_models.push(blases);
+                tlas_instances
+            };
+
+            for mut tlas_instance in tlas_instances {
+                let local = tlas_instance.transform;
+                tlas_instance.transform = self.vox_placement() * local;
+                let id = self.tlas.add_instance(tlas_instance);
+                self.vox_instances.push((id, local));
+            }
+        }
+    }
+
+    /// Where the `--vox` instances are placed: at [`VOX_ORIGIN`], turning around it with
+    /// `--vox-spin`.
+    fn vox_placement(&self) -> Affine3A {
+        let angle = self
+            .vox_spin_start
+            .map_or(0.0, |start| start.elapsed().as_secs_f32() * VOX_SPIN_SPEED);
+        Affine3A::from_translation(VOX_ORIGIN.as_vec3()) * Affine3A::from_rotation_y(angle)
+    }
+
+    /// Moves the spinning `--vox` instances, the TLAS is refitted rather than rebuilt.
+    fn spin_vox_instances(&mut self) {
+        if self.vox_spin_start.is_none() {
+            return;
+        }
+        let placement = self.vox_placement();
+        for &(id, local) in &self.vox_instances {
+            self.tlas.update_transform(id, placement * local);
+        }
+    }
+
+    /// Writes the models of the `--vox` file into the world around [`VOX_ORIGIN`], once the
+    /// chunks there are loaded.
+    fn place_vox_models(&mut self) {
+        let Some(chunk_streamer) = self.chunk_streamer.as_mut() else {
+            return;
+        };
+        if !chunk_streamer.is_idle() {
+            return;
+        }
+        let Some((_, vox_file)) = self.vox_file.take() else {
+            return;
+        };
+
+        vox_file.place_in_world(
+            &mut chunk_streamer.world,
+            VOX_ORIGIN,
+            &vox_file.closest_materials(),
+        );
+    }
+
+    /// Voxel under the cursor, at most [`PICK_DISTANCE`] away.
+    fn pick(&self) -> Option<TreeHit> {
+        let world = &self.chunk_streamer.as_ref()?.world;
+        let cursor = self.cursor_position?;
+        let size = self.window.as_ref()?.inner_size();
+        let ray = self
+            .camera
+            .ray(cursor, UVec2::new(size.width, size.height).max(UVec2::ONE));
+
+        let tree = SparseVoxelTree::along_ray(world, &ray, PICK_DISTANCE);
+        let stats = tree.stats();
+        info!(
+            "Picking through {} tree nodes, {:.1}x smaller than the chunks",
+            stats.node_count,
+            stats.compression_ratio()
+        );
+        match self.sparse_tree_raycaster.as_ref() {
+            Some(raycaster) => {
+                let buffers = SparseTreeBuffers::new(&self.volcan, &tree);
+                let hits = raycaster.raycast(&self.volcan, &buffers, &[(ray, 0.0, PICK_DISTANCE)]);
+                buffers.destroy(&self.volcan.device);
+                hits[0]
+            }
+            None => tree.raycast(&ray, 0.0, PICK_DISTANCE),
+        }
+    }
+
+    /// Digs the voxel under the cursor on a left click, places stone against it on a right
+    /// click. The streamer uploads the edited chunks on its next poll.
+    fn edit_voxel(&mut self, button: MouseButton) {
+        if !matches!(button, MouseButton::Left | MouseButton::Right) {
+            return;
+        }
+        let Some(hit) = self.pick() else {
+            info!("Picking: no voxel under the cursor");
+            return;
+        };
+        info!("Picking: voxel {} at distance {:.1}", hit.voxel, hit.t);
+
+        let world = &mut self.chunk_streamer.as_mut().unwrap().world;
+        if button == MouseButton::Left {
+            world.set_voxel(hit.voxel, MaterialId::AIR);
+        } else {
+            let position = hit.voxel + voxel_dda::face_normal(hit.face);
+            world.set_voxel(position, MaterialId::STONE);
+        }
+    }
+
+    /// Rebuilds the pipelines whose shaders were recompiled by the watch mode.
+    fn reload_changed_shaders(&mut self) {
+        let Some(shader_hot_reload) = self.shader_hot_reload.as_mut() else {
+            return;
+        };
+
+        let reloaded = shader_hot_reload.poll();
+        if reloaded.is_empty() {
+            return;
+        }
+
+        // Only the live pipelines built from a reloaded module are rebuilt.
+        let affected: HashSet<vk::Pipeline> = reloaded
+            .iter()
+            .flat_map(|name| self.volcan.shader_library.pipelines_using(name))
+            .collect();
+        let is_affected =
+            |pipeline: Option<vk::Pipeline>| pipeline.is_some_and(|p| affected.contains(&p));
+
+        // The previous frame may still be using the pipelines being replaced.
+        unsafe { self.volcan.device.device_wait_idle() }.expect("Failed to wait for device idle");
+
+        let volcan = &mut *self.volcan;
+
+        // A pipeline that fails to build keeps the previous one, e.g. when the driver rejects
+        // the recompiled shaders.
+        let keep_previous = |volcan: &mut Volcan, pipeline_name: &str, error: vk::Result| {
+            error!(
+                "Cannot rebuild the {pipeline_name} pipeline, keeping the previous one: {error}"
+            );
+            volcan.shader_library.collect_unused();
+        };
+
+        if is_affected(self.test_raster_pipeline.get().copied()) {
+            match VolcanPipeline::create_raster_pipeline(
+                volcan.device.clone(),
+                *volcan.render_pass,
+                *volcan.raster_pipeline_layout,
+                *volcan.pipeline_cache,
+                &mut volcan.shader_library,
+            ) {
+                Ok(raster_pipeline) => {
+                    if let Some(previous) = self.test_raster_pipeline.replace(raster_pipeline) {
+                        unsafe { volcan.device.destroy_pipeline(previous, None) };
+                        volcan.shader_library.release_pipeline(previous);
+                    }
+                }
+                Err(error) => keep_previous(volcan, "raster", error),
+            }
+        }
+
+        if is_affected(self.voxel_mesh_pipeline.get().copied()) {
+            match VolcanPipeline::create_voxel_mesh_pipeline(
+                &volcan.device,
+                *volcan.render_pass,
+                *volcan.voxel_mesh_pipeline_layout,
+                *volcan.pipeline_cache,
+                &mut volcan.shader_library,
+            ) {
+                Ok(voxel_mesh_pipeline) => {
+                    if let Some(previous) = self.voxel_mesh_pipeline.replace(voxel_mesh_pipeline) {
+                        unsafe { volcan.device.destroy_pipeline(previous, None) };
+                        volcan.shader_library.release_pipeline(previous);
+                    }
+                }
+                Err(error) => keep_previous(volcan, "voxel mesh", error),
+            }
+        }
+
+        if is_affected(self.raytracing_pipeline.get().copied()) {
+            match VolcanPipeline::create_raytracing_pipeline(
+                &volcan.instance,
+                &volcan.device,
+                *volcan.raytracing_pipeline_layout,
+                *volcan.pipeline_cache,
+                &mut volcan.shader_library,
+            ) {
+                Ok(raytracing_pipeline) => {
+                    if let Some(previous) = self.raytracing_pipeline.replace(raytracing_pipeline) {
+                        unsafe { volcan.device.destroy_pipeline(previous, None) };
+                        volcan.shader_library.release_pipeline(previous);
+                    }
+
+                    // Shader group handles are specific to a pipeline.
+                    let shader_binding_table =
+                        ShaderBindingTable::new(volcan, raytracing_pipeline, 1, 2);
+                    if let Some(previous) = self.shader_binding_table.replace(shader_binding_table)
+                    {
+                        previous.destroy(&volcan.device);
+                    }
+                }
+                Err(error) => keep_previous(volcan, "raytracing", error),
+            }
+        }
+
+        if is_affected(
+            self.sparse_tree_raycaster
+                .as_ref()
+                .map(|raycaster| raycaster.pipeline),
+        ) {
+            match SparseTreeRaycaster::new(volcan) {
+                Ok(raycaster) => {
+                    if let Some(previous) = self.sparse_tree_raycaster.replace(raycaster) {
+                        previous.destroy(volcan);
+                    }
+                }
+                Err(error) => keep_previous(volcan, "sparse tree raycast", error),
+            }
+        }
+    }
+
+    /// Flies the camera with WASD, space and left shift, turns it with the arrow keys.
+    fn move_camera(&mut self, elapsed: f32) {
+        let axis = |positive: KeyCode, negative: KeyCode| {
+            self.held_keys.contains(&positive) as i32 as f32
+                - self.held_keys.contains(&negative) as i32 as f32
+        };
+
+        let offset = Vec3::new(
+            axis(KeyCode::KeyD, KeyCode::KeyA),
+            axis(KeyCode::Space, KeyCode::ShiftLeft),
+            axis(KeyCode::KeyW, KeyCode::KeyS),
+        );
+        let turn = CAMERA_TURN_SPEED * elapsed;
+
+        self.camera
+            .fly(offset.normalize_or_zero() * CAMERA_SPEED * elapsed);
+        self.camera.turn(
+            axis(KeyCode::ArrowRight, KeyCode::ArrowLeft) * turn,
+            axis(KeyCode::ArrowUp, KeyCode::ArrowDown) * turn,
+        );
+    }
+
+    /// Logs the streaming state once per second, next to the FPS update.
+    fn log_statistics(&self) {
+        let Some(chunk_streamer) = self.chunk_streamer.as_ref() else {
+            return;
+        };
+        info!(
+            "Chunks: {} loaded within {} chunks, {} MiB on the GPU",
+            chunk_streamer.loaded_count(),
+            chunk_streamer.budget_radius(),
+            chunk_streamer.gpu_bytes() >> 20,
+        );
+        match self.render_mode {
+            RenderMode::Raytrace => info!(
+                "Ray tracing: {} resident chunks, {} BLAS builds, {} TLAS instances, {} KiB saved by compaction",
+                self.chunk_residency.resident_count(),
+                self.chunk_residency.building_count(),
+                self.tlas.instance_count(),
+                self.chunk_residency.compacted_bytes() >> 10,
+            ),
+            RenderMode::VoxelMesh => info!(
+                "Voxel meshes: {} resident, {} pending",
+                self.voxel_meshes.resident_count(),
+                self.voxel_meshes.pending_count(),
+            ),
+            RenderMode::TestTriangle => {}
+        }
+    }
+
+    /// Frees the GPU resources owned by the app, then the device.
+    fn destroy(&mut self) {
+        let Some(mut volcan) = self.volcan.take() else {
+            return;
+        };
+        unsafe { volcan.device.device_wait_idle() }.expect("Failed to wait for device idle");
+
+        if let Some(chunk_streamer) = self.chunk_streamer.as_mut() {
+            for blas in chunk_streamer.stop() {
+                blas.destroy(&volcan);
+            }
+        }
+        self.chunk_residency.destroy(&volcan, &mut self.tlas);
+        self.voxel_meshes.destroy(&volcan);
+        for voxel_model in self.voxel_models.drain(..) {
+            voxel_model.destroy(&volcan);
+        }
+        for voxel_model in self.voxel_model_meshes.drain(..) {
+            voxel_model.destroy(&volcan);
+        }
+        self.tlas.destroy(&volcan);
+        if let Some(raycaster) = self.sparse_tree_raycaster.take() {
+            raycaster.destroy(&mut volcan);
+        }
+        if let Some(shader_binding_table) = self.shader_binding_table.take() {
+            shader_binding_table.destroy(&volcan.device);
+        }
+        for pipeline in [
+            self.test_raster_pipeline.take(),
+            self.voxel_mesh_pipeline.take(),
+            self.raytracing_pipeline.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            unsafe { volcan.device.destroy_pipeline(pipeline, None) };
+            volcan.shader_library.release_pipeline(pipeline);
+        }
+
+        volcan.unload();
+    }
 }
 
 impl ApplicationHandler for App {
@@ -51,37 +567,192 @@ impl ApplicationHandler for App {
         let mut volcan = Volcan::new(&window);
         volcan.create_swapchain(1920, 1080);
         volcan.create_swapchain_images();
+        volcan.create_depth_buffer();
         volcan.create_render_pass();
         volcan.create_framebuffers();
         volcan.create_command_pool();
+        volcan.create_pipeline_cache();
+        volcan.create_raster_pipeline_layout();
+        volcan.create_voxel_mesh_pipeline_layout();
 
         volcan.create_fences();
 
         // volcan
-        let raster_pipeline =
-            VolcanPipeline::create_raster_pipeline(volcan.device.clone(), *volcan.render_pass);
+        let raster_pipeline = VolcanPipeline::create_raster_pipeline(
+            volcan.device.clone(),
+            *volcan.render_pass,
+            *volcan.raster_pipeline_layout,
+            *volcan.pipeline_cache,
+            &mut volcan.shader_library,
+        )
+        .expect("Cannot create graphic pipeline");
 
-        let raytracing_pipeline =
-            VolcanPipeline::create_raytracing_pipeline(&volcan.instance, &volcan.device);
-        volcan.unload();
+        let voxel_mesh_pipeline = VolcanPipeline::create_voxel_mesh_pipeline(
+            &volcan.device,
+            *volcan.render_pass,
+            *volcan.voxel_mesh_pipeline_layout,
+            *volcan.pipeline_cache,
+            &mut volcan.shader_library,
+        )
+        .expect("Cannot create voxel mesh pipeline");
+
+        self.test_raster_pipeline.set(raster_pipeline);
+        self.voxel_mesh_pipeline.set(voxel_mesh_pipeline);
+
+        // The other modes also run on devices without ray tracing.
+        if self.render_mode == RenderMode::Raytrace {
+            assert!(
+                volcan.ray_tracing_supported,
+                "--raytrace needs a GPU with ray tracing support"
+            );
+            volcan.create_raytracing_output();
+            volcan.create_raytracing_descriptors();
 
-        std::process::exit(0);
+            let raytracing_pipeline = VolcanPipeline::create_raytracing_pipeline(
+                &volcan.instance,
+                &volcan.device,
+                *volcan.raytracing_pipeline_layout,
+                *volcan.pipeline_cache,
+                &mut volcan.shader_library,
+            )
+            .expect("Failed to create ray tracing pipeline");
+            let shader_binding_table = ShaderBindingTable::new(&volcan, raytracing_pipeline, 1, 2);
+
+            self.raytracing_pipeline.set(raytracing_pipeline);
+            self.shader_binding_table.set(shader_binding_table);
+        }
+
+        if self.render_mode != RenderMode::TestTriangle {
+            // Only the ray tracing mode renders the BLASes built by the loading threads.
+            let host_blas_builder = (self.render_mode == RenderMode::Raytrace)
+                .then(|| volcan.host_blas_builder())
+                .flatten();
+            let chunk_streamer = create_chunk_streamer(host_blas_builder);
+
+            // Above the terrain, looking at the surface over the `--vox` models.
+            let generator = chunk_streamer.generator();
+            let surface = |column: IVec3| generator.surface_height(column.x, column.z) as f32;
+            let spawn = SPAWN_COLUMN
+                .as_vec3()
+                .with_y(surface(SPAWN_COLUMN) + SPAWN_HEIGHT);
+            let target = VOX_ORIGIN.as_vec3().with_y(surface(VOX_ORIGIN));
+            self.camera = Camera::looking_at(spawn, target);
+
+            self.chunk_streamer = Some(chunk_streamer);
+            self.sparse_tree_raycaster = SparseTreeRaycaster::new(&mut volcan)
+                .inspect_err(|error| {
+                    error!("Cannot create the sparse tree raycaster, picking on the CPU: {error}")
+                })
+                .ok();
+        }
+
+        if std::env::args().any(|arg| arg == "--watch-shaders") {
+            self.shader_hot_reload = ShaderHotReload::new();
+        }
 
         self.volcan.set(volcan);
+
+        if self.render_mode == RenderMode::Raytrace {
+            self.add_vox_instances();
+        }
+    }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        self.destroy();
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
         match event {
             WindowEvent::CloseRequested => {
                 println!("The close button was pressed; stopping");
+                if let Some(chunk_streamer) = self.chunk_streamer.as_mut() {
+                    chunk_streamer
+                        .save_all()
+                        .unwrap_or_else(|error| eprintln!("Cannot save the world: {error}"));
+                }
                 event_loop.exit();
             }
 
-            // WindowEvent::Focused(WindowId) => {}
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Some(UVec2::new(position.x as u32, position.y as u32));
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor_position = None;
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button,
+                ..
+            } => self.edit_voxel(button),
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    match event.state {
+                        ElementState::Pressed => self.held_keys.insert(key),
+                        ElementState::Released => self.held_keys.remove(&key),
+                    };
+                }
+            }
+            WindowEvent::Focused(false) => self.held_keys.clear(),
+
             WindowEvent::RedrawRequested => {
                 self.window.as_ref().unwrap().request_redraw();
 
-                self.volcan.test_draw(*self.test_raster_pipeline);
+                self.reload_changed_shaders();
+                let now = Instant::now();
+                self.move_camera(now.duration_since(self.last_frame_time).as_secs_f32());
+                self.last_frame_time = now;
+
+                match self.render_mode {
+                    RenderMode::Raytrace => {
+                        self.spin_vox_instances();
+                        let chunk_streamer = self.chunk_streamer.as_mut().unwrap();
+                        let chunk_residency = &mut self.chunk_residency;
+                        let update = chunk_streamer.poll(self.camera.position);
+
+                        self.volcan.raytrace_draw(
+                            *self.raytracing_pipeline,
+                            &self.shader_binding_table,
+                            &mut self.tlas,
+                            &self.camera,
+                            |volcan, command_buffer, tlas| {
+                                let gpu_bytes =
+                                    chunk_residency.update(volcan, command_buffer, tlas, update);
+                                for (position, bytes) in gpu_bytes {
+                                    chunk_streamer.set_gpu_bytes(position, bytes);
+                                }
+                            },
+                        );
+                        self.dump_frame();
+                    }
+                    RenderMode::VoxelMesh => {
+                        let chunk_streamer = self.chunk_streamer.as_mut().unwrap();
+                        let update = chunk_streamer.poll(self.camera.position);
+                        let camera_chunk = ChunkStreamer::camera_chunk(self.camera.position);
+
+                        self.volcan.raster_draw(
+                            *self.voxel_mesh_pipeline,
+                            &mut self.voxel_meshes,
+                            &self.camera,
+                            |volcan, command_buffer, voxel_meshes| {
+                                let gpu_bytes = voxel_meshes.update(
+                                    volcan,
+                                    command_buffer,
+                                    &chunk_streamer.world,
+                                    camera_chunk,
+                                    update,
+                                );
+                                for (position, bytes) in gpu_bytes {
+                                    chunk_streamer.set_gpu_bytes(position, bytes);
+                                }
+                            },
+                        );
+                        // Uploaded with the edits of the next poll.
+                        self.place_vox_models();
+                    }
+                    RenderMode::TestTriangle => {
+                        self.volcan.test_draw(*self.test_raster_pipeline);
+                    }
+                }
 
                 self.frame_count += 1;
                 let now = Instant::now();
@@ -89,6 +760,7 @@ impl ApplicationHandler for App {
                 if elapsed >= Duration::from_secs(1) {
                     let title_str = format!("VOXRT. FPS: {}", self.frame_count);
                     self.window.as_ref().unwrap().set_title(&title_str);
+                    self.log_statistics();
                     self.frame_count = 0;
                     self.last_update_time = now;
                 }
@@ -98,9 +770,93 @@ impl ApplicationHandler for App {
     }
 }
 
+/// Value following `name` on the command line.
+fn argument_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Model file given with `--vox <file>`, named after the file for the BLAS cache.
+fn load_vox_file() -> Option<(String, VoxFile)> {
+    let path = PathBuf::from(argument_value("--vox")?);
+    match VoxFile::load(&path) {
+        Ok(vox_file) => {
+            println!(
+                "Loaded {} (version {}): {} models, {} instances",
+                path.display(),
+                vox_file.version,
+                vox_file.models.len(),
+                vox_file.instances.len()
+            );
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            Some((name.into_owned(), vox_file))
+        }
+        Err(error) => {
+            eprintln!("Cannot load {}: {error}", path.display());
+            None
+        }
+    }
+}
+
+/// Streams from the save given with `--world <directory>`, created if needed,
+/// or from a freshly generated world.
+fn create_chunk_streamer(host_blas_builder: Option<HostBlasBuilder>) -> ChunkStreamer {
+    let directory = argument_value("--world").map(PathBuf::from);
+
+    let save = directory.map(|directory| {
+        // Only a directory without a save is initialized, creating deletes the regions.
+        let save = match WorldSave::open(&directory) {
+            Err(WorldSaveError::NotAWorldSave(_)) => {
+                println!("Creating a new world in {}", directory.display());
+                WorldSave::create(&directory, TerrainSettings::default())
+            }
+            save => save,
+        };
+        let save = save.expect("Cannot open the world save");
+        if std::env::args().any(|arg| arg == "--upgrade-world") {
+            save.upgrade().expect("Cannot upgrade the world save");
+            println!("Upgraded {} to the current format", directory.display());
+        }
+        Arc::new(save)
+    });
+
+    let settings = save
+        .as_ref()
+        .map_or_else(TerrainSettings::default, |save| *save.settings());
+    let streaming_settings = StreamingSettings {
+        granularity: granularity_argument(),
+        ..StreamingSettings::default()
+    };
+    ChunkStreamer::new(
+        streaming_settings,
+        TerrainGenerator::new(settings),
+        save,
+        host_blas_builder,
+    )
+}
+
+/// Granularity of the chunk BLAS primitives given with `--granularity voxel|brick|greedy`.
+fn granularity_argument() -> AabbGranularity {
+    let default = StreamingSettings::default().granularity;
+    match argument_value("--granularity").as_deref() {
+        None => default,
+        Some("voxel") => AabbGranularity::Voxel,
+        Some("brick") => AabbGranularity::Brick,
+        Some("greedy") => AabbGranularity::Greedy,
+        Some(other) => {
+            eprintln!("Unknown granularity {other}, expected voxel, brick or greedy");
+            default
+        }
+    }
+}
+
 fn main() {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    if std::env::args().any(|arg| arg == "--palette-benchmark") {
+        world::palette::run_palette_benchmark();
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
     let mut app = App::new();
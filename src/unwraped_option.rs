@@ -1,4 +1,8 @@
-use std::{cell::OnceCell, fmt::Debug, ops::Deref};
+use std::{
+    cell::OnceCell,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+};
 
 #[derive(Debug)]
 pub struct UnwrappedOption<T>(pub(crate) Option<T>);
@@ -22,6 +26,20 @@ impl<T> Lazy<T> {
     pub fn set(&self, data: T) {
         _ = self.0.set(data)
     }
+
+    pub fn get(&self) -> Option<&T> {
+        self.0.get()
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        self.0.take()
+    }
+
+    pub fn replace(&mut self, data: T) -> Option<T> {
+        let previous = self.0.take();
+        self.set(data);
+        previous
+    }
 }
 
 impl<T> Deref for Lazy<T> {
@@ -31,3 +49,9 @@ impl<T> Deref for Lazy<T> {
         self.0.get().expect("Lazy value not initialized")
     }
 }
+
+impl<T> DerefMut for Lazy<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.get_mut().expect("Lazy value not initialized")
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{
+    vulkan::{Allocation, AllocationCreateDesc, AllocationScheme, Allocator, AllocatorCreateDesc},
+    AllocationSizes, AllocatorDebugSettings, MemoryLocation,
+};
+use log::warn;
+
+use super::init::Volcan;
+
+/// Device memory suballocator, shared with the buffers and the threads creating them.
+/// Every allocation must be freed before [`Volcan::destroy_allocator`].
+pub type SharedAllocator = Arc<Mutex<Allocator>>;
+
+impl Volcan {
+    pub(super) fn create_allocator(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+    ) -> SharedAllocator {
+        let allocator = Allocator::new(&AllocatorCreateDesc {
+            instance: instance.clone(),
+            device: device.clone(),
+            physical_device,
+            debug_settings: AllocatorDebugSettings::default(),
+            // Always enabled, see `vulkan_12_features` in `Volcan::new`.
+            buffer_device_address: true,
+            allocation_sizes: AllocationSizes::default(),
+        })
+        .expect("Cannot create the memory allocator");
+
+        Arc::new(Mutex::new(allocator))
+    }
+
+    /// Allocates device local memory for `image` and binds it. Images are swapchain sized
+    /// and recreated with it, so they get their own allocation.
+    pub fn allocate_image_memory(&self, image: vk::Image, name: &str) -> Allocation {
+        let requirements = unsafe { self.device.get_image_memory_requirements(image) };
+        let allocation = self
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate(&AllocationCreateDesc {
+                name,
+                requirements,
+                location: MemoryLocation::GpuOnly,
+                linear: false,
+                allocation_scheme: AllocationScheme::DedicatedImage(image),
+            })
+            .expect("Cannot allocate image memory");
+
+        unsafe {
+            self.device
+                .bind_image_memory(image, allocation.memory(), allocation.offset())
+                .expect("Cannot bind image memory");
+        }
+        allocation
+    }
+
+    pub fn free_memory(&self, allocation: Allocation) {
+        self.allocator
+            .lock()
+            .unwrap()
+            .free(allocation)
+            .expect("Cannot free memory");
+    }
+
+    /// Frees the memory blocks, must be called before destroying the device.
+    pub fn destroy_allocator(&mut self) {
+        let Some(allocator) = self.allocator.take() else {
+            return;
+        };
+
+        match Arc::try_unwrap(allocator) {
+            Ok(allocator) => {
+                allocator
+                    .into_inner()
+                    .unwrap()
+                    .report_memory_leaks(log::Level::Warn);
+            }
+            // A buffer or a loading thread outlives the device, its memory is leaked.
+            Err(allocator) => {
+                warn!("Memory allocator still in use on exit, leaking it");
+                std::mem::forget(allocator);
+            }
+        }
+    }
+}
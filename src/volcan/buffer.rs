@@ -0,0 +1,142 @@
+use ash::vk;
+use gpu_allocator::{
+    vulkan::{Allocation, AllocationCreateDesc, AllocationScheme},
+    MemoryLocation,
+};
+
+use super::{allocator::SharedAllocator, init::Volcan};
+
+/// A buffer suballocated from [`Volcan::allocator`].
+#[derive(Debug)]
+pub struct VolcanBuffer {
+    pub buffer: vk::Buffer,
+    pub allocation: Allocation,
+    allocator: SharedAllocator,
+    pub size: vk::DeviceSize,
+    /// Zero unless created with `SHADER_DEVICE_ADDRESS` usage.
+    pub device_address: vk::DeviceAddress,
+}
+
+impl VolcanBuffer {
+    pub fn new(
+        volcan: &Volcan,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> Self {
+        Self::new_on_device(&volcan.allocator, &volcan.device, size, usage, location)
+    }
+
+    /// Same as [`VolcanBuffer::new`], for threads that only hold a clone of the device.
+    pub fn new_on_device(
+        allocator: &SharedAllocator,
+        device: &ash::Device,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> Self {
+        let buffer_info = vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let buffer = unsafe {
+            device
+                .create_buffer(&buffer_info, None)
+                .expect("Cannot create buffer")
+        };
+
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let allocation = allocator
+            .lock()
+            .unwrap()
+            .allocate(&AllocationCreateDesc {
+                name: "VolcanBuffer",
+                requirements,
+                location,
+                linear: true,
+                allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+            })
+            .expect("Cannot allocate memory");
+
+        unsafe {
+            device
+                .bind_buffer_memory(buffer, allocation.memory(), allocation.offset())
+                .expect("Cannot bind buffer memory")
+        };
+
+        let device_address = if usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+            unsafe {
+                device.get_buffer_device_address(
+                    &vk::BufferDeviceAddressInfo::default().buffer(buffer),
+                )
+            }
+        } else {
+            0
+        };
+
+        Self {
+            buffer,
+            allocation,
+            allocator: allocator.clone(),
+            size,
+            device_address,
+        }
+    }
+
+    /// Creates a host visible buffer filled with `data`.
+    pub fn new_with_data<T: Copy>(
+        volcan: &Volcan,
+        usage: vk::BufferUsageFlags,
+        data: &[T],
+    ) -> Self {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        let buffer = Self::new(volcan, size.max(1), usage, MemoryLocation::CpuToGpu);
+
+        buffer.write(0, data);
+        buffer
+    }
+
+    /// Copies `data` at `offset` bytes. The buffer must be host visible, host visible memory
+    /// stays mapped and is coherent.
+    pub fn write<T: Copy>(&self, offset: vk::DeviceSize, data: &[T]) {
+        let size = std::mem::size_of_val(data);
+        if size == 0 {
+            return;
+        }
+        assert!(
+            offset + size as u64 <= self.size,
+            "Write past the end of the buffer"
+        );
+
+        let mapped = self
+            .allocation
+            .mapped_ptr()
+            .expect("Buffer memory is not host visible");
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                (mapped.as_ptr() as *mut u8).add(offset as usize),
+                size,
+            );
+        }
+    }
+
+    /// Copies the buffer content out. The buffer must be host visible.
+    pub fn read(&self) -> Vec<u8> {
+        let mapped = self
+            .allocation
+            .mapped_slice()
+            .expect("Buffer memory is not host visible");
+        mapped[..self.size as usize].to_vec()
+    }
+
+    pub fn destroy(self, device: &ash::Device) {
+        unsafe { device.destroy_buffer(self.buffer, None) };
+        self.allocator
+            .lock()
+            .unwrap()
+            .free(self.allocation)
+            .expect("Cannot free buffer memory");
+    }
+}
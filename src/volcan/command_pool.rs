@@ -4,8 +4,9 @@ use super::init::Volcan;
 
 impl Volcan {
     pub fn create_command_pool(&mut self) {
-        let command_pool_info =
-            vk::CommandPoolCreateInfo::default().queue_family_index(self.queue_index);
+        let command_pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(self.queue_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
 
         let command_pool = unsafe {
             self.device
@@ -24,7 +25,60 @@ impl Volcan {
                 .expect("Cannot allocate command buffer.")
         };
 
+        self.command_pool.set(command_pool);
         self.command_buffers.set(command_buffers);
         println!("Command buffers: {:?}", *self.command_buffers)
     }
+
+    /// Records commands with `record`, submits them and waits for completion.
+    pub fn execute_one_time_commands<R>(&self, record: impl FnOnce(vk::CommandBuffer) -> R) -> R {
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(*self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+
+        let command_buffer = unsafe {
+            self.device
+                .allocate_command_buffers(&alloc_info)
+                .expect("Cannot allocate command buffer.")[0]
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        unsafe {
+            self.device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .expect("Cannot begin command buffer");
+        }
+
+        let result = record(command_buffer);
+
+        let command_buffers = [command_buffer];
+        let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+
+        unsafe {
+            self.device
+                .end_command_buffer(command_buffer)
+                .expect("Cannot end command buffer.");
+
+            let fence = self
+                .device
+                .create_fence(&vk::FenceCreateInfo::default(), None)
+                .expect("Cannot create fence.");
+
+            self.device
+                .queue_submit(self.primary_queue, &[submit_info], fence)
+                .expect("Failed to submit one time command buffer");
+            self.device
+                .wait_for_fences(&[fence], true, u64::MAX)
+                .expect("Failed to wait for fence");
+
+            self.device.destroy_fence(fence, None);
+            self.device
+                .free_command_buffers(*self.command_pool, &command_buffers);
+        }
+
+        result
+    }
 }
@@ -0,0 +1,117 @@
+use ash::vk;
+
+use gpu_allocator::vulkan::Allocation;
+
+use super::init::Volcan;
+
+/// Candidates for the depth attachment, most precise first.
+const DEPTH_FORMATS: [vk::Format; 3] = [
+    vk::Format::D32_SFLOAT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+];
+
+/// Depth attachment of the render pass, sized to the swapchain and shared by the frames
+/// since only one is in flight.
+pub struct DepthBuffer {
+    pub image: vk::Image,
+    pub memory: Allocation,
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+}
+
+impl Volcan {
+    /// Must be called before [`Volcan::create_render_pass`], which uses its format, and again
+    /// after the swapchain is recreated.
+    pub fn create_depth_buffer(&mut self) {
+        let format = DEPTH_FORMATS
+            .into_iter()
+            .find(|&format| {
+                let properties = unsafe {
+                    self.instance
+                        .get_physical_device_format_properties(self.physical_device, format)
+                };
+                properties
+                    .optimal_tiling_features
+                    .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+            })
+            .expect("No supported depth format");
+
+        let extent = *self.swapchain_extents;
+        let image_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        let image = unsafe {
+            self.device
+                .create_image(&image_info, None)
+                .expect("Cannot create depth image")
+        };
+
+        let memory = self.allocate_image_memory(image, "Depth buffer");
+
+        let view_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: depth_aspect_mask(format),
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+
+        let view = unsafe {
+            self.device
+                .create_image_view(&view_info, None)
+                .expect("Failed to create image view")
+        };
+
+        if let Some(previous) = self.depth_buffer.replace(DepthBuffer {
+            image,
+            memory,
+            view,
+            format,
+        }) {
+            self.destroy_depth_buffer(previous);
+        }
+
+        println!("Depth buffer: {:?} {:?}", image, format);
+    }
+
+    fn destroy_depth_buffer(&self, depth_buffer: DepthBuffer) {
+        unsafe {
+            self.device.destroy_image_view(depth_buffer.view, None);
+            self.device.destroy_image(depth_buffer.image, None);
+        }
+        self.free_memory(depth_buffer.memory);
+    }
+
+    pub fn destroy_depth_resources(&mut self) {
+        if let Some(depth_buffer) = self.depth_buffer.take() {
+            self.destroy_depth_buffer(depth_buffer);
+        }
+    }
+}
+
+fn depth_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        _ => vk::ImageAspectFlags::DEPTH,
+    }
+}
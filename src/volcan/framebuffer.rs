@@ -8,7 +8,7 @@ impl Volcan {
             .swapchain_image_views
             .iter()
             .map(|&image_view| {
-                let image_views = [image_view];
+                let image_views = [image_view, self.depth_buffer.view];
 
                 let framebuffer_info = vk::FramebufferCreateInfo::default()
                     .render_pass(*self.render_pass)
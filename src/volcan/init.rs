@@ -8,10 +8,28 @@ use ash::{
 use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use winit::window::Window;
 
-use crate::unwraped_option::{Lazy, UnwrappedOption};
+use crate::{
+    unwraped_option::{Lazy, UnwrappedOption},
+    world::camera::Camera,
+};
+
+use super::{
+    allocator::SharedAllocator, buffer::VolcanBuffer, depth_buffer::DepthBuffer,
+    raster_voxel_mesh::VoxelMeshes, raytracing_output::RaytracingOutput,
+    raytracing_sbt::ShaderBindingTable, raytracing_tlas::Tlas, shader_library::ShaderLibrary,
+};
+
+/// Device extensions needed by the ray tracing path, enabled only when all are supported.
+const RAY_TRACING_EXTENSIONS: [&std::ffi::CStr; 4] = [
+    ash::khr::ray_tracing_pipeline::NAME,
+    ash::khr::ray_tracing_maintenance1::NAME,
+    ash::khr::acceleration_structure::NAME,
+    ash::khr::deferred_host_operations::NAME,
+];
 
 pub struct Volcan {
-    pub(super) entry: Entry,
+    /// Keeps the Vulkan library loaded for as long as the instance lives.
+    _entry: Entry,
     pub(crate) instance: Instance,
     pub(super) physical_device: PhysicalDevice,
 
@@ -20,7 +38,10 @@ pub struct Volcan {
 
     pub(super) queue_index: u32,
     pub(super) primary_queue: vk::Queue,
+    /// Family index and queue of a compute only family, for async acceleration structure builds.
+    pub(super) async_compute_queue: Option<(u32, vk::Queue)>,
     pub(crate) device: ash::Device,
+    pub(crate) allocator: Lazy<SharedAllocator>,
 
     pub(super) swapchain: UnwrappedOption<SwapchainKHR>,
     pub(super) swapchain_extents: Lazy<vk::Extent2D>,
@@ -29,11 +50,40 @@ pub struct Volcan {
     pub(super) swapchain_images: UnwrappedOption<Vec<vk::Image>>,
     pub(super) swapchain_image_views: UnwrappedOption<Vec<vk::ImageView>>,
 
+    pub(super) depth_buffer: Lazy<DepthBuffer>,
     pub(crate) render_pass: Lazy<vk::RenderPass>,
     pub(super) framebuffers: Lazy<Vec<vk::Framebuffer>>,
 
     pub(super) command_buffers: Lazy<Vec<vk::CommandBuffer>>,
 
+    pub(super) command_pool: Lazy<vk::CommandPool>,
+
+    pub(crate) pipeline_cache: Lazy<vk::PipelineCache>,
+    pub(crate) shader_library: ShaderLibrary,
+
+    /// Whether [`RAY_TRACING_EXTENSIONS`] are enabled. The loaders below have no functions
+    /// otherwise.
+    pub(crate) ray_tracing_supported: bool,
+    pub(crate) acceleration_structure_loader: khr::acceleration_structure::Device,
+    pub(crate) deferred_host_operations_loader: khr::deferred_host_operations::Device,
+    pub(crate) ray_tracing_pipeline_loader: khr::ray_tracing_pipeline::Device,
+    /// `accelerationStructureHostCommands`, see `raytracing_host_build`.
+    pub(crate) acceleration_structure_host_commands: bool,
+    pub(crate) acceleration_structure_properties:
+        vk::PhysicalDeviceAccelerationStructurePropertiesKHR<'static>,
+
+    pub(super) raytracing_output: Lazy<RaytracingOutput>,
+    pub(super) raytracing_descriptor_set_layout: Lazy<vk::DescriptorSetLayout>,
+    pub(super) raytracing_descriptor_pool: Lazy<vk::DescriptorPool>,
+    pub(super) raytracing_descriptor_set: Lazy<vk::DescriptorSet>,
+    pub(crate) raytracing_pipeline_layout: Lazy<vk::PipelineLayout>,
+    /// Bound to the chunk geometry binding until a real table is written.
+    pub(super) chunk_geometry_placeholder: Lazy<VolcanBuffer>,
+
+    /// Empty layout of the test triangle pipeline.
+    pub(crate) raster_pipeline_layout: Lazy<vk::PipelineLayout>,
+    pub(crate) voxel_mesh_pipeline_layout: Lazy<vk::PipelineLayout>,
+
     pub(super) img_available_sem: Lazy<vk::Semaphore>,
     pub(super) render_finished_sem: Lazy<vk::Semaphore>,
     pub(super) in_flight_fence: Lazy<vk::Fence>,
@@ -103,8 +153,10 @@ impl Volcan {
             panic!("No Vulkan-compatible GPU found!");
         }
 
+        // Devices able to ray trace come first, then any device that can present.
         let mut selected_device = None;
         let mut selected_queue_index: Option<_> = None;
+        let mut ray_tracing_supported = false;
 
         'outer: for (i, &physical_device) in physical_devices.iter().enumerate() {
             let device_properties =
@@ -118,50 +170,59 @@ impl Volcan {
             // continue;;
             //             }
 
-            let mut ray_tracing_pipeline_properties =
-                vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
-            let mut acceleration_structure_properties =
-                vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+            let available_extensions =
+                Self::get_physical_device_extensions(&instance, physical_device);
 
-            let mut device_properties2 = vk::PhysicalDeviceProperties2::default();
+            let supports_ray_tracing = RAY_TRACING_EXTENSIONS.iter().all(|extension| {
+                available_extensions.contains(&extension.to_str().unwrap().to_string())
+            });
+            println!("  - Ray tracing: {supports_ray_tracing}");
 
-            device_properties2 = device_properties2
-                .push_next(&mut ray_tracing_pipeline_properties)
-                .push_next(&mut acceleration_structure_properties);
+            // The property structs of unsupported extensions must not be chained.
+            if supports_ray_tracing {
+                let mut ray_tracing_pipeline_properties =
+                    vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+                let mut acceleration_structure_properties =
+                    vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
 
-            unsafe {
-                instance.get_physical_device_properties2(physical_device, &mut device_properties2)
-            };
+                let mut device_properties2 = vk::PhysicalDeviceProperties2::default();
 
-            println!(
-                "  - Shader Group Handle Size: {}",
-                ray_tracing_pipeline_properties.shader_group_handle_size
-            );
-            println!(
-                "  - Max Ray Recursion Depth: {}",
-                ray_tracing_pipeline_properties.max_ray_recursion_depth
-            );
-            println!(
-                "  - Max Ray Dispatch Invocation Count: {}",
-                ray_tracing_pipeline_properties.max_ray_dispatch_invocation_count
-            );
+                device_properties2 = device_properties2
+                    .push_next(&mut ray_tracing_pipeline_properties)
+                    .push_next(&mut acceleration_structure_properties);
 
-            // Check Acceleration Structure support
-            println!(
-                "  - Max Instance Count: {}",
-                acceleration_structure_properties.max_instance_count
-            );
-            println!(
-                "  - Max Geometry Count: {}",
-                acceleration_structure_properties.max_geometry_count
-            );
+                unsafe {
+                    instance
+                        .get_physical_device_properties2(physical_device, &mut device_properties2)
+                };
+
+                println!(
+                    "  - Shader Group Handle Size: {}",
+                    ray_tracing_pipeline_properties.shader_group_handle_size
+                );
+                println!(
+                    "  - Max Ray Recursion Depth: {}",
+                    ray_tracing_pipeline_properties.max_ray_recursion_depth
+                );
+                println!(
+                    "  - Max Ray Dispatch Invocation Count: {}",
+                    ray_tracing_pipeline_properties.max_ray_dispatch_invocation_count
+                );
+
+                // Check Acceleration Structure support
+                println!(
+                    "  - Max Instance Count: {}",
+                    acceleration_structure_properties.max_instance_count
+                );
+                println!(
+                    "  - Max Geometry Count: {}",
+                    acceleration_structure_properties.max_geometry_count
+                );
+            }
 
             let device_queue_properties =
                 unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
 
-            let available_extensions =
-                Self::get_physical_device_extensions(&instance, physical_device);
-
             for (j, info) in device_queue_properties.iter().enumerate() {
                 let supports_graphic_and_surface = unsafe {
                     info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
@@ -174,39 +235,38 @@ impl Volcan {
                 println!("{} - {:?}", info.queue_count, info.queue_flags);
 
                 if supports_graphic_and_surface
-                    && available_extensions.contains(&"VK_KHR_ray_tracing_pipeline".to_string())
-                    && available_extensions.contains(
-                        &ash::khr::acceleration_structure::NAME
-                            .to_str()
-                            .unwrap()
-                            .to_string(),
-                    )
+                    && (selected_device.is_none() || supports_ray_tracing)
                 {
-                    // vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::get
                     selected_device = Some(physical_device);
                     selected_queue_index = Some(j as u32);
+                    ray_tracing_supported = supports_ray_tracing;
                     println!(
                         "Selected device : {device_name}, queue {:?}",
                         selected_queue_index
                     );
 
-                    break 'outer;
+                    if supports_ray_tracing {
+                        break 'outer;
+                    }
                 }
             }
         }
 
         let selected_device = selected_device.expect("No suitable GPU found.");
         let selected_queue_index = selected_queue_index.expect("No suitable queue found.");
+        println!("Ray tracing: {ray_tracing_supported}");
 
         /* ------------------------ CREATE DEVICE AND QUEUES ------------------------ */
 
-        let device_extension_names_raw = [
+        let mut device_extension_names_raw = vec![
             ash::khr::swapchain::NAME.as_ptr(),
-            ash::khr::ray_tracing_pipeline::NAME.as_ptr(),
-            ash::khr::ray_tracing_maintenance1::NAME.as_ptr(),
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             ash::khr::portability_subset::NAME.as_ptr(),
         ];
+        if ray_tracing_supported {
+            device_extension_names_raw
+                .extend(RAY_TRACING_EXTENSIONS.map(|extension| extension.as_ptr()));
+        }
         let features = vk::PhysicalDeviceFeatures {
             shader_clip_distance: 1,
             ..Default::default()
@@ -218,10 +278,61 @@ impl Volcan {
             .queue_family_index(selected_queue_index)
             .queue_priorities(&priorities);
 
-        let device_create_info = vk::DeviceCreateInfo::default()
-            .queue_create_infos(std::slice::from_ref(&queue_info))
+        let async_compute_queue_index =
+            unsafe { instance.get_physical_device_queue_family_properties(selected_device) }
+                .iter()
+                .position(|info| {
+                    info.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                        && !info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                })
+                .map(|index| index as u32);
+        println!(
+            "Async compute queue family: {:?}",
+            async_compute_queue_index
+        );
+
+        let mut queue_infos = vec![queue_info];
+        if let Some(index) = async_compute_queue_index {
+            queue_infos.push(
+                vk::DeviceQueueCreateInfo::default()
+                    .queue_family_index(index)
+                    .queue_priorities(&priorities),
+            );
+        }
+
+        let mut vulkan_12_features =
+            vk::PhysicalDeviceVulkan12Features::default().buffer_device_address(true);
+        let mut supported_acceleration_structure_features =
+            vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        if ray_tracing_supported {
+            let mut supported_features2 = vk::PhysicalDeviceFeatures2::default()
+                .push_next(&mut supported_acceleration_structure_features);
+            unsafe {
+                instance.get_physical_device_features2(selected_device, &mut supported_features2)
+            };
+        }
+        let acceleration_structure_host_commands = supported_acceleration_structure_features
+            .acceleration_structure_host_commands
+            == vk::TRUE;
+        println!("Acceleration structure host commands: {acceleration_structure_host_commands}");
+
+        let mut acceleration_structure_features =
+            vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default()
+                .acceleration_structure(true)
+                .acceleration_structure_host_commands(acceleration_structure_host_commands);
+        let mut ray_tracing_pipeline_features =
+            vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default().ray_tracing_pipeline(true);
+
+        let mut device_create_info = vk::DeviceCreateInfo::default()
+            .queue_create_infos(&queue_infos)
             .enabled_extension_names(&device_extension_names_raw)
-            .enabled_features(&features);
+            .enabled_features(&features)
+            .push_next(&mut vulkan_12_features);
+        if ray_tracing_supported {
+            device_create_info = device_create_info
+                .push_next(&mut acceleration_structure_features)
+                .push_next(&mut ray_tracing_pipeline_features);
+        }
 
         let device = unsafe {
             instance
@@ -229,11 +340,35 @@ impl Volcan {
                 .expect("Unable to create device.")
         };
         let present_queue = unsafe { device.get_device_queue(selected_queue_index, 0) };
+        let async_compute_queue = async_compute_queue_index
+            .map(|index| (index, unsafe { device.get_device_queue(index, 0) }));
+
+        let acceleration_structure_loader =
+            khr::acceleration_structure::Device::new(&instance, &device);
+        let deferred_host_operations_loader =
+            khr::deferred_host_operations::Device::new(&instance, &device);
+        let ray_tracing_pipeline_loader =
+            khr::ray_tracing_pipeline::Device::new(&instance, &device);
+
+        let mut acceleration_structure_properties =
+            vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+        if ray_tracing_supported {
+            let mut device_properties2 = vk::PhysicalDeviceProperties2::default()
+                .push_next(&mut acceleration_structure_properties);
+            unsafe {
+                instance.get_physical_device_properties2(selected_device, &mut device_properties2)
+            };
+        }
+        acceleration_structure_properties.p_next = std::ptr::null_mut();
 
         ///////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+        let shader_library = ShaderLibrary::new(device.clone());
+        let allocator: Lazy<SharedAllocator> = Lazy::new();
+        allocator.set(Self::create_allocator(&instance, &device, selected_device));
+
         Self {
-            entry,
+            _entry: entry,
             instance,
             physical_device: selected_device,
 
@@ -242,7 +377,9 @@ impl Volcan {
 
             primary_queue: present_queue,
             queue_index: selected_queue_index,
-            device: device,
+            async_compute_queue,
+            device,
+            allocator,
 
             swapchain: UnwrappedOption(None),
             swapchain_extents: Lazy::new(),
@@ -251,10 +388,33 @@ impl Volcan {
             swapchain_images: UnwrappedOption(None),
             swapchain_image_views: UnwrappedOption(None),
 
+            depth_buffer: Lazy::new(),
             render_pass: Lazy::new(),
             framebuffers: Lazy::new(),
             command_buffers: Lazy::new(),
 
+            command_pool: Lazy::new(),
+
+            pipeline_cache: Lazy::new(),
+            shader_library,
+
+            ray_tracing_supported,
+            acceleration_structure_loader,
+            deferred_host_operations_loader,
+            ray_tracing_pipeline_loader,
+            acceleration_structure_host_commands,
+            acceleration_structure_properties,
+
+            raytracing_output: Lazy::new(),
+            raytracing_descriptor_set_layout: Lazy::new(),
+            raytracing_descriptor_pool: Lazy::new(),
+            raytracing_descriptor_set: Lazy::new(),
+            raytracing_pipeline_layout: Lazy::new(),
+            chunk_geometry_placeholder: Lazy::new(),
+
+            raster_pipeline_layout: Lazy::new(),
+            voxel_mesh_pipeline_layout: Lazy::new(),
+
             img_available_sem: Lazy::new(),
             render_finished_sem: Lazy::new(),
             in_flight_fence: Lazy::new(),
@@ -282,21 +442,6 @@ impl Volcan {
         (surface, surface_loader)
     }
 
-    fn get_instance_extensions(entry: &Entry) {
-        let available_extensions = unsafe {
-            entry
-                .enumerate_instance_extension_properties(None)
-                .expect("Failed to list Vulkan extensions.")
-        };
-
-        println!("Available Vulkan Instance Extensions:");
-        for ext in available_extensions {
-            let ext_name =
-                unsafe { std::ffi::CStr::from_ptr(ext.extension_name.as_ptr()).to_string_lossy() };
-            println!("- {} (spec version: {})", ext_name, ext.spec_version);
-        }
-    }
-
     pub fn create_fences(&mut self) {
         let semaphore_info = vk::SemaphoreCreateInfo::default();
         let fence_info = vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
@@ -334,11 +479,19 @@ impl Volcan {
                     .begin_command_buffer(command_buffer, &begin_info)
                     .expect("Cannot begin command buffer");
             }
-            let clear_values = [vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
+            let clear_values = [
+                vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [0.0, 0.0, 0.0, 1.0],
+                    },
+                },
+                vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: 1.0,
+                        stencil: 0,
+                    },
                 },
-            }];
+            ];
 
             let render_pass_begin_info = vk::RenderPassBeginInfo::default()
                 .render_pass(*self.render_pass)
@@ -372,7 +525,7 @@ impl Volcan {
 
         unsafe {
             self.device
-                .wait_for_fences(&[*self.in_flight_fence], true, std::u64::MAX)
+                .wait_for_fences(&[*self.in_flight_fence], true, u64::MAX)
                 .expect("Failed to wait for fence");
             self.device
                 .reset_fences(&[*self.in_flight_fence])
@@ -383,7 +536,7 @@ impl Volcan {
             self.swapchain_loader
                 .acquire_next_image(
                     *self.swapchain,
-                    std::u64::MAX,
+                    u64::MAX,
                     *self.img_available_sem,
                     vk::Fence::null(),
                 )
@@ -421,6 +574,207 @@ impl Volcan {
         }
     }
 
+    /// Traces the scene into the output image and blits it to the next swapchain image.
+    /// `tlas` is updated in the frame's command buffer before the trace, after
+    /// `record_updates` (e.g. BLAS builds), called once the previous frame has completed.
+    pub fn raytrace_draw(
+        &self,
+        raytracing_pipeline: vk::Pipeline,
+        shader_binding_table: &ShaderBindingTable,
+        tlas: &mut Tlas,
+        camera: &Camera,
+        record_updates: impl FnOnce(&Volcan, vk::CommandBuffer, &mut Tlas),
+    ) {
+        unsafe {
+            self.device
+                .wait_for_fences(&[*self.in_flight_fence], true, u64::MAX)
+                .expect("Failed to wait for fence");
+            self.device
+                .reset_fences(&[*self.in_flight_fence])
+                .expect("Failed to reset fence");
+        }
+
+        let (image_index, _) = unsafe {
+            self.swapchain_loader
+                .acquire_next_image(
+                    *self.swapchain,
+                    u64::MAX,
+                    *self.img_available_sem,
+                    vk::Fence::null(),
+                )
+                .expect("Failed to acquire next image")
+        };
+
+        let command_buffer = self.command_buffers[image_index as usize];
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            self.device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .expect("Cannot begin command buffer");
+        }
+
+        // The fence was waited on, the descriptor set is not in use anymore.
+        record_updates(self, command_buffer, tlas);
+        tlas.update(self, command_buffer);
+        self.write_tlas_descriptor(tlas.handle);
+
+        self.record_raytracing_frame(
+            command_buffer,
+            raytracing_pipeline,
+            shader_binding_table,
+            camera,
+            self.swapchain_images[image_index as usize],
+        );
+
+        unsafe {
+            self.device
+                .end_command_buffer(command_buffer)
+                .expect("Cannot end command buffer.");
+        }
+
+        let wait_semaphores = [*self.img_available_sem];
+        let signal_semaphores = [*self.render_finished_sem];
+        let wait_stages = [vk::PipelineStageFlags::TRANSFER];
+
+        let command_buffers_binding = [command_buffer];
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers_binding)
+            .signal_semaphores(&signal_semaphores);
+
+        unsafe {
+            self.device
+                .queue_submit(self.primary_queue, &[submit_info], *self.in_flight_fence)
+                .expect("Failed to submit draw command buffer");
+        }
+
+        let swapchain_binding = [*self.swapchain];
+        let image_index_binding: [u32; 1] = [image_index];
+        let present_info = vk::PresentInfoKHR::default()
+            .wait_semaphores(&signal_semaphores)
+            .swapchains(&swapchain_binding)
+            .image_indices(&image_index_binding);
+
+        unsafe {
+            self.swapchain_loader
+                .queue_present(self.primary_queue, &present_info)
+                .expect("Failed to present swapchain image");
+        }
+    }
+
+    /// Draws the voxel meshes with depth testing into the next swapchain image.
+    /// `record_updates` (e.g. mesh uploads) is called once the previous frame has completed,
+    /// before the render pass.
+    pub fn raster_draw(
+        &self,
+        voxel_mesh_pipeline: vk::Pipeline,
+        meshes: &mut VoxelMeshes,
+        camera: &Camera,
+        record_updates: impl FnOnce(&Volcan, vk::CommandBuffer, &mut VoxelMeshes),
+    ) {
+        unsafe {
+            self.device
+                .wait_for_fences(&[*self.in_flight_fence], true, u64::MAX)
+                .expect("Failed to wait for fence");
+            self.device
+                .reset_fences(&[*self.in_flight_fence])
+                .expect("Failed to reset fence");
+        }
+
+        let (image_index, _) = unsafe {
+            self.swapchain_loader
+                .acquire_next_image(
+                    *self.swapchain,
+                    u64::MAX,
+                    *self.img_available_sem,
+                    vk::Fence::null(),
+                )
+                .expect("Failed to acquire next image")
+        };
+
+        let command_buffer = self.command_buffers[image_index as usize];
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            self.device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .expect("Cannot begin command buffer");
+        }
+
+        record_updates(self, command_buffer, meshes);
+
+        // Same sky as `raymiss.rmiss`.
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 1.0, 1.0],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+
+        let render_pass_begin_info = vk::RenderPassBeginInfo::default()
+            .render_pass(*self.render_pass)
+            .framebuffer(self.framebuffers[image_index as usize])
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: *self.swapchain_extents,
+            })
+            .clear_values(&clear_values);
+
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                command_buffer,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+        self.record_voxel_meshes(command_buffer, voxel_mesh_pipeline, meshes, camera);
+        unsafe {
+            self.device.cmd_end_render_pass(command_buffer);
+            self.device
+                .end_command_buffer(command_buffer)
+                .expect("Cannot end command buffer.");
+        }
+
+        let wait_semaphores = [*self.img_available_sem];
+        let signal_semaphores = [*self.render_finished_sem];
+        let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+
+        let command_buffers_binding = [command_buffer];
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers_binding)
+            .signal_semaphores(&signal_semaphores);
+
+        unsafe {
+            self.device
+                .queue_submit(self.primary_queue, &[submit_info], *self.in_flight_fence)
+                .expect("Failed to submit draw command buffer");
+        }
+
+        let swapchain_binding = [*self.swapchain];
+        let image_index_binding: [u32; 1] = [image_index];
+        let present_info = vk::PresentInfoKHR::default()
+            .wait_semaphores(&signal_semaphores)
+            .swapchains(&swapchain_binding)
+            .image_indices(&image_index_binding);
+
+        unsafe {
+            self.swapchain_loader
+                .queue_present(self.primary_queue, &present_info)
+                .expect("Failed to present swapchain image");
+        }
+    }
+
     fn get_physical_device_extensions(
         instance: &Instance,
         physical_device: PhysicalDevice,
@@ -444,6 +798,18 @@ impl Volcan {
     }
 
     pub fn unload(&mut self) {
+        self.shader_library.destroy();
+        self.destroy_raytracing_resources();
+        self.destroy_depth_resources();
+        self.destroy_raster_pipeline_layout();
+        self.destroy_voxel_mesh_resources();
+
+        self.save_pipeline_cache();
+        unsafe {
+            self.device
+                .destroy_pipeline_cache(*self.pipeline_cache, None);
+        }
+
         unsafe {
             self.swapchain_loader
                 .destroy_swapchain(*self.swapchain, None);
@@ -452,6 +818,8 @@ impl Volcan {
             unsafe { self.device.destroy_image_view(*image_view, None) };
         }
 
+        self.destroy_allocator();
+
         unsafe { self.surface_loader.destroy_surface(self.surface, None) };
         unsafe {
             self.device.destroy_device(None);
@@ -1,10 +1,30 @@
+pub mod allocator;
+pub mod buffer;
 pub mod command_pool;
+pub mod depth_buffer;
 pub mod device;
 pub mod framebuffer;
 pub mod init;
 pub mod pipeline;
+pub mod pipeline_cache;
+pub mod raster_voxel_mesh;
 pub mod raytracing_accecleration_structure;
+pub mod raytracing_blas_update;
+pub mod raytracing_build_queue;
+pub mod raytracing_chunk_streaming;
+pub mod raytracing_compaction;
+pub mod raytracing_host_build;
+pub mod raytracing_output;
+pub mod raytracing_sbt;
+pub mod raytracing_serialization;
+pub mod raytracing_sparse_tree;
+pub mod raytracing_tlas;
+pub mod raytracing_voxel_geometry;
+pub mod raytracing_voxel_model;
 pub mod render_pass;
+pub mod shader_hot_reload;
+pub mod shader_library;
 pub mod shader_modules;
+pub mod shader_reflection;
 pub mod surface;
 pub mod swapchain;
@@ -1,39 +1,81 @@
-use std::{ffi::CString, sync::Arc};
+use std::ffi::CString;
 
 use ash::vk;
 
-use super::{init::Volcan, shader_modules::VolcanShaderModule};
+use log::{info, warn};
+
+use super::{
+    init::Volcan,
+    raster_voxel_mesh::{
+        VOXEL_MESH_PUSH_CONSTANTS, VOXEL_MESH_VERTEX_ATTRIBUTES, VOXEL_MESH_VERTEX_BINDING,
+    },
+    raytracing_output::{RAYTRACING_DESCRIPTOR_BINDINGS, RAYTRACING_PUSH_CONSTANTS},
+    shader_library::ShaderLibrary,
+    shader_modules::VolcanShaderModule,
+    shader_reflection::ReflectedPipelineLayout,
+};
 
 pub struct VolcanPipeline {
     // _volcan: Arc<Volcan>,
 }
 
-pub struct VolcanPipelineData {}
+/// Shader sources of each pipeline, their reflected layout is checked against the declared one.
+pub const RASTER_PIPELINE_SHADERS: &[&str] = &["basic_triangle.vert", "basic_triangle.frag"];
+pub const VOXEL_MESH_PIPELINE_SHADERS: &[&str] = &["voxel_mesh.vert", "voxel_mesh.frag"];
+pub const RAYTRACING_PIPELINE_SHADERS: &[&str] = &[
+    "raygen.rgen",
+    "raymiss.rmiss",
+    "rayhit.rchit",
+    "intersection.rint",
+    "voxelhit.rchit",
+];
 
 impl VolcanPipeline {
+    /// Reflects the shaders of a pipeline and reports where the declared layout disagrees.
+    pub fn check_layout(
+        pipeline_name: &str,
+        shaders: &[&str],
+        declared_sets: &[&[vk::DescriptorSetLayoutBinding]],
+        declared_push_constants: &[vk::PushConstantRange],
+    ) -> ReflectedPipelineLayout {
+        let reflected_layout = Self::reflect_layout(shaders);
+        for mismatch in reflected_layout.validate(declared_sets, declared_push_constants) {
+            warn!("{pipeline_name} pipeline layout: {mismatch}");
+        }
+
+        reflected_layout
+    }
+
+    /// Layout expected by `shaders` together.
+    pub fn reflect_layout(shaders: &[&str]) -> ReflectedPipelineLayout {
+        let reflections: Vec<_> = shaders
+            .iter()
+            .map(|name| VolcanShaderModule::reflect(name))
+            .collect();
+        ReflectedPipelineLayout::merge(&reflections)
+    }
+
     // pub fn init(volcan: Arc<Volcan>) -> Self {
     //     Self {
     //         _volcan: volcan.clone(),
     //     }
     // }
 
+    /// `pipeline_layout` is [`Volcan::raster_pipeline_layout`]. Fails instead of panicking so
+    /// the shader hot reload can keep the previous pipeline.
     pub fn create_raster_pipeline(
         device: ash::Device,
         render_pass: vk::RenderPass,
-    ) -> vk::Pipeline {
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
-        let pipeline_layout = unsafe {
-            device
-                .create_pipeline_layout(&pipeline_layout_info, None)
-                .expect("Cannot create Pipeline.")
-        };
+        pipeline_layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        shader_library: &mut ShaderLibrary,
+    ) -> Result<vk::Pipeline, vk::Result> {
+        Self::check_layout("Raster", RASTER_PIPELINE_SHADERS, &[], &[]);
 
         /* ------------------------------ SHADER STAGE ------------------------------ */
 
-        let vert_shader_module =
-            VolcanShaderModule::create_shader(&device, "./shaders/dist/basic_triangle.vert.spv");
-        let frag_shader_module =
-            VolcanShaderModule::create_shader(&device, "./shaders/dist/basic_triangle.frag.spv");
+        let vert_shader_module = shader_library.module("basic_triangle.vert")?;
+        let frag_shader_module = shader_library.module("basic_triangle.frag")?;
 
         let entry_point = CString::new("main").unwrap();
 
@@ -41,14 +83,14 @@ impl VolcanPipeline {
             vk::PipelineShaderStageCreateInfo {
                 s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
                 stage: vk::ShaderStageFlags::VERTEX,
-                module: vert_shader_module,
+                module: vert_shader_module.module,
                 p_name: entry_point.as_ptr(),
                 ..Default::default()
             },
             vk::PipelineShaderStageCreateInfo {
                 s_type: vk::StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
                 stage: vk::ShaderStageFlags::FRAGMENT,
-                module: frag_shader_module,
+                module: frag_shader_module.module,
                 p_name: entry_point.as_ptr(),
                 ..Default::default()
             },
@@ -80,7 +122,7 @@ impl VolcanPipeline {
 
         let scissor = vk::Rect2D {
             offset: vk::Offset2D { x: 0, y: 0 },
-            extent: extent,
+            extent,
         };
 
         let viewport_binding = [viewport];
@@ -103,6 +145,11 @@ impl VolcanPipeline {
             .sample_shading_enable(false)
             .rasterization_samples(vk::SampleCountFlags::TYPE_1);
 
+        // The render pass has a depth attachment, the test triangle ignores it.
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(false)
+            .depth_write_enable(false);
+
         let color_blend_attachment = vk::PipelineColorBlendAttachmentState::default()
             .color_write_mask(vk::ColorComponentFlags::RGBA)
             .blend_enable(false);
@@ -119,69 +166,204 @@ impl VolcanPipeline {
             .viewport_state(&viewport_state)
             .rasterization_state(&rasterizer)
             .multisample_state(&multisampling)
+            .depth_stencil_state(&depth_stencil)
             .color_blend_state(&color_blending)
             .layout(pipeline_layout)
             .render_pass(render_pass)
             .subpass(0);
 
-        let graphics_pipeline = unsafe {
-            device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-        }
-        .expect("Cannot create graphic pipeline")
-        .remove(0);
+        let graphics_pipeline =
+            unsafe { device.create_graphics_pipelines(pipeline_cache, &[pipeline_info], None) }
+                .map_err(|(_, error)| error)?
+                .remove(0);
+
+        shader_library.bind_pipeline(graphics_pipeline, &[vert_shader_module, frag_shader_module]);
 
         println!("graphics_pipeline: {:?}", graphics_pipeline);
 
-        return graphics_pipeline;
+        Ok(graphics_pipeline)
     }
 
-    pub fn create_raytracing_pipeline(instance: &ash::Instance, device: &ash::Device) {
+    /// Draws the greedy meshes of `raster_voxel_mesh` with depth testing. `pipeline_layout`
+    /// must only have [`VOXEL_MESH_PUSH_CONSTANTS`], the viewport and scissor are dynamic.
+    pub fn create_voxel_mesh_pipeline(
+        device: &ash::Device,
+        render_pass: vk::RenderPass,
+        pipeline_layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        shader_library: &mut ShaderLibrary,
+    ) -> Result<vk::Pipeline, vk::Result> {
+        Self::check_layout(
+            "Voxel mesh",
+            VOXEL_MESH_PIPELINE_SHADERS,
+            &[],
+            &[VOXEL_MESH_PUSH_CONSTANTS],
+        );
+
+        let entry_point = CString::new("main").unwrap();
+
+        let vert_shader_module = shader_library.module("voxel_mesh.vert")?;
+        let frag_shader_module = shader_library.module("voxel_mesh.frag")?;
+
+        let shader_stages = [
+            ShaderLibrary::stage_info(
+                vert_shader_module,
+                vk::ShaderStageFlags::VERTEX,
+                &entry_point,
+                None,
+            ),
+            ShaderLibrary::stage_info(
+                frag_shader_module,
+                vk::ShaderStageFlags::FRAGMENT,
+                &entry_point,
+                None,
+            ),
+        ];
+
+        let vertex_bindings = [VOXEL_MESH_VERTEX_BINDING];
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&VOXEL_MESH_VERTEX_ATTRIBUTES);
+
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .primitive_restart_enable(false);
+
+        let viewport_state = vk::PipelineViewportStateCreateInfo::default()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
+
+        // The meshes are counter clockwise from outside, `Camera::view_projection` flips y.
+        let rasterizer = vk::PipelineRasterizationStateCreateInfo::default()
+            .depth_clamp_enable(false)
+            .rasterizer_discard_enable(false)
+            .polygon_mode(vk::PolygonMode::FILL)
+            .line_width(1.0)
+            .cull_mode(vk::CullModeFlags::BACK)
+            .front_face(vk::FrontFace::CLOCKWISE)
+            .depth_bias_enable(false);
+
+        let multisampling = vk::PipelineMultisampleStateCreateInfo::default()
+            .sample_shading_enable(false)
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::LESS)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false);
+
+        let color_blend_attachments = [vk::PipelineColorBlendAttachmentState::default()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .blend_enable(false)];
+        let color_blending = vk::PipelineColorBlendStateCreateInfo::default()
+            .logic_op_enable(false)
+            .attachments(&color_blend_attachments);
+
+        let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_info)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterizer)
+            .multisample_state(&multisampling)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&color_blending)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0);
+
+        let voxel_mesh_pipeline =
+            unsafe { device.create_graphics_pipelines(pipeline_cache, &[pipeline_info], None) }
+                .map_err(|(_, error)| error)?
+                .remove(0);
+
+        shader_library.bind_pipeline(
+            voxel_mesh_pipeline,
+            &[vert_shader_module, frag_shader_module],
+        );
+
+        info!("Voxel mesh pipeline: {:?}", voxel_mesh_pipeline);
+
+        Ok(voxel_mesh_pipeline)
+    }
+
+    /// `pipeline_layout` is [`Volcan::raytracing_pipeline_layout`].
+    ///
+    /// Hit group 0 is for triangles, hit group 1 for the voxel chunk AABBs
+    /// (see `raytracing_voxel_geometry::VOXEL_HIT_GROUP_SBT_OFFSET`).
+    pub fn create_raytracing_pipeline(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        pipeline_layout: vk::PipelineLayout,
+        pipeline_cache: vk::PipelineCache,
+        shader_library: &mut ShaderLibrary,
+    ) -> Result<vk::Pipeline, vk::Result> {
         /* ------------------------------ SHADER STAGE ------------------------------ */
 
         let entry_point = CString::new("main").unwrap();
 
-        let raygen_module =
-            VolcanShaderModule::create_shader(&device, "./shaders/dist/raygen.rgen.spv");
-        let raymiss_module =
-            VolcanShaderModule::create_shader(&device, "./shaders/dist/raymiss.rmiss.spv");
-        let rayhit_module =
-            VolcanShaderModule::create_shader(&device, "./shaders/dist/rayhit.rchit.spv");
-        // let intersection_module =
-        // VolcanShaderModule::create_shader(&device, "./shaders/dist/intersection.rint.spv");
-
-        let raygen_stage = vk::PipelineShaderStageCreateInfo::default()
-            .stage(vk::ShaderStageFlags::RAYGEN_KHR)
-            .module(raygen_module)
-            .name(&entry_point);
-
-        let miss_stage = vk::PipelineShaderStageCreateInfo::default()
-            .stage(vk::ShaderStageFlags::MISS_KHR)
-            .module(raymiss_module)
-            .name(&entry_point);
-
-        let chit_stage = vk::PipelineShaderStageCreateInfo::default()
-            .stage(vk::ShaderStageFlags::CLOSEST_HIT_KHR)
-            .module(rayhit_module)
-            .name(&entry_point);
-
-        // let intersection_stage = vk::PipelineShaderStageCreateInfo::default()
-        //     .stage(vk::ShaderStageFlags::INTERSECTION_KHR)
-        //     .module(intersection_stage)
-        //     .name(&entry_point);
+        let raygen_module = shader_library.module("raygen.rgen")?;
+        let raymiss_module = shader_library.module("raymiss.rmiss")?;
+        let rayhit_module = shader_library.module("rayhit.rchit")?;
+        let intersection_module = shader_library.module("intersection.rint")?;
+        let voxelhit_module = shader_library.module("voxelhit.rchit")?;
+
+        let raygen_stage = ShaderLibrary::stage_info(
+            raygen_module,
+            vk::ShaderStageFlags::RAYGEN_KHR,
+            &entry_point,
+            None,
+        );
+
+        let miss_stage = ShaderLibrary::stage_info(
+            raymiss_module,
+            vk::ShaderStageFlags::MISS_KHR,
+            &entry_point,
+            None,
+        );
+
+        let chit_stage = ShaderLibrary::stage_info(
+            rayhit_module,
+            vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            &entry_point,
+            None,
+        );
+
+        let intersection_stage = ShaderLibrary::stage_info(
+            intersection_module,
+            vk::ShaderStageFlags::INTERSECTION_KHR,
+            &entry_point,
+            None,
+        );
+
+        let voxel_chit_stage = ShaderLibrary::stage_info(
+            voxelhit_module,
+            vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            &entry_point,
+            None,
+        );
 
         let shader_stages = [
             raygen_stage,
             miss_stage,
             chit_stage,
-            //intersection_stage
+            intersection_stage,
+            voxel_chit_stage,
         ];
 
-        // let procedural_hit_group = vk::RayTracingShaderGroupCreateInfoKHR::default()
-        //     .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
-        //     .general_shader(vk::SHADER_UNUSED_KHR)
-        //     .closest_hit_shader(2)
-        //     .any_hit_shader(vk::SHADER_UNUSED_KHR)
-        //     .intersection_shader(3);
+        let procedural_hit_group = vk::RayTracingShaderGroupCreateInfoKHR::default()
+            .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
+            .general_shader(vk::SHADER_UNUSED_KHR)
+            .closest_hit_shader(4)
+            .any_hit_shader(vk::SHADER_UNUSED_KHR)
+            .intersection_shader(3);
 
         let raygen_group = vk::RayTracingShaderGroupCreateInfoKHR::default()
             .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
@@ -204,21 +386,16 @@ impl VolcanPipeline {
             .any_hit_shader(vk::SHADER_UNUSED_KHR)
             .intersection_shader(vk::SHADER_UNUSED_KHR);
 
-        let shader_groups = [
-            raygen_group,
-            miss_group,
-            hit_group,
-            //  procedural_hitgroup
-        ];
+        let shader_groups = [raygen_group, miss_group, hit_group, procedural_hit_group];
 
         /* -------------------------------- PIPELINE -------------------------------- */
 
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
-        let pipeline_layout = unsafe {
-            device
-                .create_pipeline_layout(&pipeline_layout_info, None)
-                .expect("Failed to create pipeline layout")
-        };
+        Self::check_layout(
+            "Raytracing",
+            RAYTRACING_PIPELINE_SHADERS,
+            &[&RAYTRACING_DESCRIPTOR_BINDINGS],
+            &[RAYTRACING_PUSH_CONSTANTS],
+        );
 
         let pipeline_info = vk::RayTracingPipelineCreateInfoKHR::default()
             .stages(&shader_stages)
@@ -233,14 +410,47 @@ impl VolcanPipeline {
             ray_tracing_pipeline_loader
                 .create_ray_tracing_pipelines(
                     vk::DeferredOperationKHR::null(),
-                    vk::PipelineCache::null(),
+                    pipeline_cache,
                     &[pipeline_info],
                     None,
                 )
-                .expect("Failed to create ray tracing pipeline")
+                .map_err(|(_, error)| error)?
                 .remove(0)
         };
 
+        shader_library.bind_pipeline(
+            ray_tracing_pipeline,
+            &[
+                raygen_module,
+                raymiss_module,
+                rayhit_module,
+                intersection_module,
+                voxelhit_module,
+            ],
+        );
+
         println!("Raytracing pipeline: {:?}", ray_tracing_pipeline);
+
+        Ok(ray_tracing_pipeline)
+    }
+}
+
+impl Volcan {
+    /// Created once, the test triangle pipeline is rebuilt with it by the shader hot reload.
+    pub fn create_raster_pipeline_layout(&mut self) {
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
+        let pipeline_layout = unsafe {
+            self.device
+                .create_pipeline_layout(&pipeline_layout_info, None)
+                .expect("Cannot create Pipeline.")
+        };
+
+        self.raster_pipeline_layout.set(pipeline_layout);
+    }
+
+    pub fn destroy_raster_pipeline_layout(&mut self) {
+        if let Some(pipeline_layout) = self.raster_pipeline_layout.take() {
+            unsafe { self.device.destroy_pipeline_layout(pipeline_layout, None) };
+        }
     }
 }
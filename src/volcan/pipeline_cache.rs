@@ -0,0 +1,215 @@
+use std::path::PathBuf;
+
+use ash::vk;
+use log::{info, warn};
+
+use super::init::Volcan;
+
+const PIPELINE_CACHE_FILE: &str = "pipeline_cache.bin";
+
+/// Size of `VkPipelineCacheHeaderVersionOne` (header size, version, vendor, device, UUID).
+const PIPELINE_CACHE_HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+impl Volcan {
+    /// Directory where on-disk caches are stored.
+    /// `VOXRT_CACHE_DIR` overrides the platform default.
+    pub fn cache_directory() -> PathBuf {
+        if let Some(dir) = std::env::var_os("VOXRT_CACHE_DIR") {
+            return PathBuf::from(dir);
+        }
+
+        let base = if cfg!(target_os = "windows") {
+            std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+        } else {
+            std::env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        };
+
+        base.unwrap_or_else(std::env::temp_dir).join("voxrt")
+    }
+
+    pub fn create_pipeline_cache(&mut self) {
+        let cache_path = Self::cache_directory().join(PIPELINE_CACHE_FILE);
+        let properties = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+        };
+
+        let initial_data = match std::fs::read(&cache_path) {
+            Ok(data) if is_pipeline_cache_compatible(&data, &properties) => {
+                info!(
+                    "Loaded pipeline cache ({} bytes) from {}",
+                    data.len(),
+                    cache_path.display()
+                );
+                data
+            }
+            Ok(_) => {
+                warn!(
+                    "Pipeline cache {} was created by another driver or device, ignoring it.",
+                    cache_path.display()
+                );
+                Vec::new()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let pipeline_cache_info =
+            vk::PipelineCacheCreateInfo::default().initial_data(&initial_data);
+
+        let pipeline_cache = unsafe {
+            self.device
+                .create_pipeline_cache(&pipeline_cache_info, None)
+                .or_else(|_| {
+                    // Drivers may still reject a blob that passed the header check.
+                    self.device
+                        .create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)
+                })
+                .expect("Cannot create pipeline cache")
+        };
+
+        self.pipeline_cache.set(pipeline_cache);
+        println!("Pipeline cache: {:?}", *self.pipeline_cache);
+    }
+
+    pub fn save_pipeline_cache(&self) {
+        let data = unsafe {
+            match self.device.get_pipeline_cache_data(*self.pipeline_cache) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Cannot read pipeline cache data: {err}");
+                    return;
+                }
+            }
+        };
+
+        let cache_directory = Self::cache_directory();
+        let cache_path = cache_directory.join(PIPELINE_CACHE_FILE);
+
+        // Write to a temporary file first so a crash never leaves a truncated cache behind.
+        let tmp_path = cache_path.with_extension("tmp");
+        let result = std::fs::create_dir_all(&cache_directory)
+            .and_then(|_| std::fs::write(&tmp_path, &data))
+            .and_then(|_| std::fs::rename(&tmp_path, &cache_path));
+
+        match result {
+            Ok(_) => info!(
+                "Saved pipeline cache ({} bytes) to {}",
+                data.len(),
+                cache_path.display()
+            ),
+            Err(err) => warn!(
+                "Cannot save pipeline cache to {}: {err}",
+                cache_path.display()
+            ),
+        }
+    }
+}
+
+/// Whether `data` starts with a pipeline cache header written by the device of `properties`,
+/// the driver would otherwise reject or misuse it.
+fn is_pipeline_cache_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < PIPELINE_CACHE_HEADER_SIZE {
+        return false;
+    }
+
+    // The header is little endian whatever the host.
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    let header_size = read_u32(0) as usize;
+    let header_version = read_u32(4);
+    let vendor_id = read_u32(8);
+    let device_id = read_u32(12);
+    let uuid = &data[16..PIPELINE_CACHE_HEADER_SIZE];
+
+    header_size >= PIPELINE_CACHE_HEADER_SIZE
+        && header_size <= data.len()
+        && header_version == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && vendor_id == properties.vendor_id
+        && device_id == properties.device_id
+        && uuid == properties.pipeline_cache_uuid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2684,
+            pipeline_cache_uuid: std::array::from_fn(|i| i as u8 * 7),
+            ..Default::default()
+        }
+    }
+
+    /// A cache blob as written by the device of `properties`, with some driver data after
+    /// the header.
+    fn cache_data(properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((PIPELINE_CACHE_HEADER_SIZE as u32).to_le_bytes());
+        data.extend((vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes());
+        data.extend(properties.vendor_id.to_le_bytes());
+        data.extend(properties.device_id.to_le_bytes());
+        data.extend(properties.pipeline_cache_uuid);
+        data.extend([0xab; 64]);
+        data
+    }
+
+    #[test]
+    fn accepts_a_matching_header() {
+        let properties = properties();
+        let data = cache_data(&properties);
+        assert!(is_pipeline_cache_compatible(&data, &properties));
+        assert!(is_pipeline_cache_compatible(
+            &data[..PIPELINE_CACHE_HEADER_SIZE],
+            &properties
+        ));
+    }
+
+    #[test]
+    fn rejects_another_vendor_device_or_uuid() {
+        let data = cache_data(&properties());
+
+        let other_vendor = vk::PhysicalDeviceProperties {
+            vendor_id: 0x1002,
+            ..properties()
+        };
+        let other_device = vk::PhysicalDeviceProperties {
+            device_id: 0x2685,
+            ..properties()
+        };
+        let mut other_uuid = properties();
+        other_uuid.pipeline_cache_uuid[15] ^= 1;
+
+        for properties in [other_vendor, other_device, other_uuid] {
+            assert!(!is_pipeline_cache_compatible(&data, &properties));
+        }
+    }
+
+    #[test]
+    fn rejects_a_malformed_header() {
+        let properties = properties();
+        let patched = |offset: usize, value: u32| {
+            let mut data = cache_data(&properties);
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            data
+        };
+
+        // Header size smaller than the header, larger than the data, unknown version.
+        let data_len = cache_data(&properties).len() as u32;
+        for data in [patched(0, 16), patched(0, data_len + 1), patched(4, 2)] {
+            assert!(!is_pipeline_cache_compatible(&data, &properties));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let properties = properties();
+        let data = cache_data(&properties);
+        for len in [0, 4, 16, PIPELINE_CACHE_HEADER_SIZE - 1] {
+            assert!(!is_pipeline_cache_compatible(&data[..len], &properties));
+        }
+    }
+}
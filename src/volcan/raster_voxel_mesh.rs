@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::IVec3;
+use gpu_allocator::MemoryLocation;
+
+use crate::world::{
+    camera::Camera,
+    chunk::Chunk,
+    chunk_streaming::StreamingUpdate,
+    greedy_mesh::{ChunkMesh, VoxelVertex},
+    voxel_world::World,
+};
+
+use super::{buffer::VolcanBuffer, init::Volcan};
+
+/// Chunks meshed by a [`VoxelMeshes::update`], the farther ones wait for the next frames.
+const MESHES_PER_UPDATE: usize = 16;
+
+/// Clip planes of the raster camera, in voxels.
+pub const NEAR_PLANE: f32 = 0.1;
+pub const FAR_PLANE: f32 = 2048.0;
+
+/// Push constants of `voxel_mesh.vert`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Pod, Zeroable)]
+pub struct VoxelMeshPushConstants {
+    pub view_projection: [[f32; 4]; 4],
+    /// World position of the chunk, `w` is unused.
+    pub chunk_origin: [f32; 4],
+}
+
+pub const VOXEL_MESH_PUSH_CONSTANTS: vk::PushConstantRange = vk::PushConstantRange {
+    stage_flags: vk::ShaderStageFlags::VERTEX,
+    offset: 0,
+    size: std::mem::size_of::<VoxelMeshPushConstants>() as u32,
+};
+
+/// Vertex input of `voxel_mesh.vert`, one [`VoxelVertex`] per vertex.
+pub const VOXEL_MESH_VERTEX_BINDING: vk::VertexInputBindingDescription =
+    vk::VertexInputBindingDescription {
+        binding: 0,
+        stride: std::mem::size_of::<VoxelVertex>() as u32,
+        input_rate: vk::VertexInputRate::VERTEX,
+    };
+
+pub const VOXEL_MESH_VERTEX_ATTRIBUTES: [vk::VertexInputAttributeDescription; 4] = [
+    vk::VertexInputAttributeDescription {
+        location: 0,
+        binding: 0,
+        format: vk::Format::R32G32B32_SFLOAT,
+        offset: std::mem::offset_of!(VoxelVertex, position) as u32,
+    },
+    vk::VertexInputAttributeDescription {
+        location: 1,
+        binding: 0,
+        format: vk::Format::R32_UINT,
+        offset: std::mem::offset_of!(VoxelVertex, face) as u32,
+    },
+    vk::VertexInputAttributeDescription {
+        location: 2,
+        binding: 0,
+        format: vk::Format::R32_UINT,
+        offset: std::mem::offset_of!(VoxelVertex, material) as u32,
+    },
+    vk::VertexInputAttributeDescription {
+        location: 3,
+        binding: 0,
+        format: vk::Format::R32_UINT,
+        offset: std::mem::offset_of!(VoxelVertex, ambient_occlusion) as u32,
+    },
+];
+
+/// Device local vertex and index buffers of a [`ChunkMesh`].
+pub struct ChunkMeshBuffers {
+    pub vertices: VolcanBuffer,
+    pub indices: VolcanBuffer,
+    pub index_count: u32,
+}
+
+impl ChunkMeshBuffers {
+    /// Records the copies from the returned staging buffer, which must live until they have
+    /// executed. [`record_mesh_upload_barrier`] must follow the uploads.
+    pub fn upload(
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        mesh: &ChunkMesh,
+    ) -> (Self, VolcanBuffer) {
+        let vertices_size = std::mem::size_of_val(mesh.vertices.as_slice()) as vk::DeviceSize;
+        let indices_size = std::mem::size_of_val(mesh.indices.as_slice()) as vk::DeviceSize;
+
+        let staging = VolcanBuffer::new(
+            volcan,
+            (vertices_size + indices_size).max(1),
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+        );
+        staging.write(0, &mesh.vertices);
+        staging.write(vertices_size, &mesh.indices);
+
+        let copy = |offset: vk::DeviceSize, size: vk::DeviceSize, usage| {
+            let buffer = VolcanBuffer::new(
+                volcan,
+                size.max(1),
+                usage | vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::GpuOnly,
+            );
+            if size > 0 {
+                let region = vk::BufferCopy::default()
+                    .src_offset(offset)
+                    .dst_offset(0)
+                    .size(size);
+                unsafe {
+                    volcan.device.cmd_copy_buffer(
+                        command_buffer,
+                        staging.buffer,
+                        buffer.buffer,
+                        &[region],
+                    );
+                }
+            }
+            buffer
+        };
+
+        let buffers = Self {
+            vertices: copy(0, vertices_size, vk::BufferUsageFlags::VERTEX_BUFFER),
+            indices: copy(
+                vertices_size,
+                indices_size,
+                vk::BufferUsageFlags::INDEX_BUFFER,
+            ),
+            index_count: mesh.indices.len() as u32,
+        };
+        (buffers, staging)
+    }
+
+    pub fn memory_bytes(&self) -> vk::DeviceSize {
+        self.vertices.size + self.indices.size
+    }
+
+    pub fn destroy(self, device: &ash::Device) {
+        self.vertices.destroy(device);
+        self.indices.destroy(device);
+    }
+}
+
+/// Makes the copies of [`ChunkMeshBuffers::upload`] visible to the vertex input.
+pub fn record_mesh_upload_barrier(device: &ash::Device, command_buffer: vk::CommandBuffer) {
+    let memory_barrier = vk::MemoryBarrier::default()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ);
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::VERTEX_INPUT,
+            vk::DependencyFlags::empty(),
+            &[memory_barrier],
+            &[],
+            &[],
+        );
+    }
+}
+
+/// Raster counterpart of [`super::raytracing_chunk_streaming::ChunkResidency`]: the meshes of
+/// the streamed chunks. Chunks are meshed against their neighbours, so the neighbours of a
+/// changed chunk are meshed again too.
+///
+/// Updates are recorded into the frame command buffer and assume the previous frame has
+/// completed, which [`Volcan::raster_draw`] waits for before calling back.
+pub struct VoxelMeshes {
+    chunks: HashMap<IVec3, ChunkMeshBuffers>,
+    /// Chunks to mesh, nearest to the camera first.
+    pending: HashSet<IVec3>,
+    /// Used by the frame in flight, destroyed at the next update.
+    retired_buffers: Vec<VolcanBuffer>,
+    retired_meshes: Vec<ChunkMeshBuffers>,
+}
+
+impl Default for VoxelMeshes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VoxelMeshes {
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            pending: HashSet::new(),
+            retired_buffers: Vec::new(),
+            retired_meshes: Vec::new(),
+        }
+    }
+
+    pub fn resident_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Meshes up to [`MESHES_PER_UPDATE`] changed chunks of `world` around `camera_chunk` and
+    /// records their uploads. Returns the GPU memory of the chunks that changed, zero for the
+    /// removed ones, for [`crate::world::chunk_streaming::ChunkStreamer::set_gpu_bytes`].
+    pub fn update(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        world: &World,
+        camera_chunk: IVec3,
+        update: StreamingUpdate,
+    ) -> Vec<(IVec3, u64)> {
+        self.free_completed(volcan);
+
+        let mut gpu_bytes = Vec::new();
+        for &position in &update.remove {
+            self.pending.remove(&position);
+            if let Some(mesh) = self.chunks.remove(&position) {
+                self.retired_meshes.push(mesh);
+                gpu_bytes.push((position, 0));
+            }
+        }
+
+        let changed = update
+            .remove
+            .iter()
+            .chain(update.upload.iter().map(|(position, _)| position));
+        for &position in changed {
+            for neighbour in std::iter::once(position).chain(neighbours(position)) {
+                if world.chunk(neighbour).is_some() || self.chunks.contains_key(&neighbour) {
+                    self.pending.insert(neighbour);
+                }
+            }
+        }
+
+        let mut batch: Vec<IVec3> = self.pending.iter().copied().collect();
+        batch.sort_by_key(|position| (*position - camera_chunk).length_squared());
+        batch.truncate(MESHES_PER_UPDATE);
+
+        let mut uploaded = false;
+        for position in batch {
+            self.pending.remove(&position);
+            let mesh = ChunkMesh::from_world(world, position);
+
+            let previous = self.chunks.remove(&position);
+            let was_resident = previous.is_some();
+            self.retired_meshes.extend(previous);
+
+            if mesh.is_empty() {
+                if was_resident {
+                    gpu_bytes.push((position, 0));
+                }
+                continue;
+            }
+
+            let (buffers, staging) = ChunkMeshBuffers::upload(volcan, command_buffer, &mesh);
+            self.retired_buffers.push(staging);
+            gpu_bytes.push((position, buffers.memory_bytes()));
+            self.chunks.insert(position, buffers);
+            uploaded = true;
+        }
+
+        if uploaded {
+            record_mesh_upload_barrier(&volcan.device, command_buffer);
+        }
+
+        gpu_bytes
+    }
+
+    fn free_completed(&mut self, volcan: &Volcan) {
+        for buffer in self.retired_buffers.drain(..) {
+            buffer.destroy(&volcan.device);
+        }
+        for mesh in self.retired_meshes.drain(..) {
+            mesh.destroy(&volcan.device);
+        }
+    }
+
+    /// The device must be idle.
+    pub fn destroy(&mut self, volcan: &Volcan) {
+        self.retired_meshes
+            .extend(self.chunks.drain().map(|(_, mesh)| mesh));
+        self.pending.clear();
+        self.free_completed(volcan);
+    }
+}
+
+fn neighbours(position: IVec3) -> impl Iterator<Item = IVec3> {
+    [
+        IVec3::X,
+        IVec3::NEG_X,
+        IVec3::Y,
+        IVec3::NEG_Y,
+        IVec3::Z,
+        IVec3::NEG_Z,
+    ]
+    .into_iter()
+    .map(move |offset| position + offset)
+}
+
+impl Volcan {
+    /// Creates the pipeline layout of the voxel mesh pipeline, see
+    /// [`super::pipeline::VolcanPipeline::create_voxel_mesh_pipeline`].
+    pub fn create_voxel_mesh_pipeline_layout(&mut self) {
+        let push_constant_ranges = [VOXEL_MESH_PUSH_CONSTANTS];
+        let pipeline_layout_info =
+            vk::PipelineLayoutCreateInfo::default().push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe {
+            self.device
+                .create_pipeline_layout(&pipeline_layout_info, None)
+                .expect("Failed to create pipeline layout")
+        };
+
+        self.voxel_mesh_pipeline_layout.set(pipeline_layout);
+    }
+
+    /// Records the draws of the resident chunk meshes, inside the render pass.
+    pub fn record_voxel_meshes(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: vk::Pipeline,
+        meshes: &VoxelMeshes,
+        camera: &Camera,
+    ) {
+        let extent = *self.swapchain_extents;
+        let aspect_ratio = extent.width as f32 / extent.height as f32;
+        let view_projection = camera.view_projection(aspect_ratio, NEAR_PLANE, FAR_PLANE);
+
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+        let scissor = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        };
+
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline,
+            );
+            self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+            self.device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+        }
+
+        for (&position, mesh) in &meshes.chunks {
+            let push_constants = VoxelMeshPushConstants {
+                view_projection: view_projection.to_cols_array_2d(),
+                chunk_origin: Chunk::origin(position).as_vec3().extend(0.0).to_array(),
+            };
+
+            unsafe {
+                self.device.cmd_push_constants(
+                    command_buffer,
+                    *self.voxel_mesh_pipeline_layout,
+                    VOXEL_MESH_PUSH_CONSTANTS.stage_flags,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+                self.device.cmd_bind_vertex_buffers(
+                    command_buffer,
+                    0,
+                    &[mesh.vertices.buffer],
+                    &[0],
+                );
+                self.device.cmd_bind_index_buffer(
+                    command_buffer,
+                    mesh.indices.buffer,
+                    0,
+                    vk::IndexType::UINT32,
+                );
+                self.device
+                    .cmd_draw_indexed(command_buffer, mesh.index_count, 1, 0, 0, 0);
+            }
+        }
+    }
+
+    pub fn destroy_voxel_mesh_resources(&mut self) {
+        if let Some(pipeline_layout) = self.voxel_mesh_pipeline_layout.take() {
+            unsafe { self.device.destroy_pipeline_layout(pipeline_layout, None) };
+        }
+    }
+}
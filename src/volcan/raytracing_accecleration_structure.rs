@@ -1,94 +1,135 @@
 use ash::vk;
+use gpu_allocator::MemoryLocation;
 
-struct VolcanAccelKhr {}
-
-struct VolcanBLASKhr {}
-
-impl VolcanBLASKhr {
-    pub fn create_buffer(
-        instance: &ash::Instance,
-        device: &ash::Device,
-        physical_device: vk::PhysicalDevice,
-        size: vk::DeviceSize,
-        usage: vk::BufferUsageFlags,
-        properties: vk::MemoryPropertyFlags,
-    ) -> Result<(vk::Buffer, vk::DeviceMemory), vk::Result> {
-        let buffer_info = vk::BufferCreateInfo::default()
-            .size(size)
-            .usage(usage)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        let buffer = unsafe {
-            device
-                .create_buffer(&buffer_info, None)
-                .expect("Cannot create buffer")
-        };
+use super::{buffer::VolcanBuffer, init::Volcan};
 
-        // Get memory requirements for the buffer.
-        let mem_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+/// Triangle geometry read from buffers created with
+/// `ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR | SHADER_DEVICE_ADDRESS` usage.
+#[derive(Debug, Clone, Copy)]
+pub struct TriangleGeometry {
+    pub vertex_address: vk::DeviceAddress,
+    pub vertex_format: vk::Format,
+    pub vertex_stride: vk::DeviceSize,
+    /// Highest vertex index referenced by the indices.
+    pub max_vertex: u32,
+    /// Ignored when `index_type` is `NONE_KHR`.
+    pub index_address: vk::DeviceAddress,
+    pub index_type: vk::IndexType,
+    pub triangle_count: u32,
+    /// Optional `VkTransformMatrixKHR` applied to the vertices at build time.
+    pub transform_address: Option<vk::DeviceAddress>,
+}
 
-        // Find a suitable memory type.
-        let mem_type_index = Self::find_memory_type(
-            instance,
-            physical_device,
-            mem_requirements.memory_type_bits,
-            properties,
-        )
-        .expect("Cannot find memory index");
+impl TriangleGeometry {
+    /// Uploads `vertices` (positions first, in `vertex_format`) and `indices`. The buffers
+    /// must outlive the builds reading them.
+    pub fn upload_indexed<V: Copy>(
+        volcan: &Volcan,
+        vertices: &[V],
+        vertex_format: vk::Format,
+        indices: &[u32],
+    ) -> (Self, [VolcanBuffer; 2]) {
+        let usage = vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
 
-        // Allocate memory for the buffer.
-        let alloc_info = vk::MemoryAllocateInfo::default()
-            .allocation_size(mem_requirements.size)
-            .memory_type_index(mem_type_index);
+        let vertex_buffer = VolcanBuffer::new_with_data(volcan, usage, vertices);
+        let index_buffer = VolcanBuffer::new_with_data(volcan, usage, indices);
 
-        let buffer_memory = unsafe {
-            device
-                .allocate_memory(&alloc_info, None)
-                .expect("Cannot allocate memory")
+        let triangles = Self {
+            vertex_address: vertex_buffer.device_address,
+            vertex_format,
+            vertex_stride: std::mem::size_of::<V>() as vk::DeviceSize,
+            max_vertex: vertices.len().saturating_sub(1) as u32,
+            index_address: index_buffer.device_address,
+            index_type: vk::IndexType::UINT32,
+            triangle_count: (indices.len() / 3) as u32,
+            transform_address: None,
         };
 
-        // Bind the buffer with the allocated memory.
-        unsafe {
-            device
-                .bind_buffer_memory(buffer, buffer_memory, 0)
-                .expect("Cannot bind buffer memory")
-        };
+        (triangles, [vertex_buffer, index_buffer])
+    }
 
-        Ok((buffer, buffer_memory))
-    }
-
-    fn find_memory_type(
-        instance: &ash::Instance,
-        physical_device: vk::PhysicalDevice,
-        type_filter: u32,
-        properties: vk::MemoryPropertyFlags,
-    ) -> Result<u32, vk::Result> {
-        let mem_properties =
-            unsafe { instance.get_physical_device_memory_properties(physical_device) };
-        for (i, memory_type) in mem_properties.memory_types.iter().enumerate() {
-            if (type_filter & (1 << i)) != 0
-                && (memory_type.property_flags & properties) == properties
-            {
-                return Ok(i as u32);
-            }
-        }
-        Err(vk::Result::ERROR_FEATURE_NOT_PRESENT)
+    fn to_vk(self) -> vk::AccelerationStructureGeometryKHR<'static> {
+        let triangles_data = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+            .vertex_format(self.vertex_format)
+            .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.vertex_address,
+            })
+            .vertex_stride(self.vertex_stride)
+            .max_vertex(self.max_vertex)
+            .index_type(self.index_type)
+            .index_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.index_address,
+            })
+            .transform_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.transform_address.unwrap_or(0),
+            });
+
+        vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                triangles: triangles_data,
+            })
+            .flags(vk::GeometryFlagsKHR::OPAQUE)
     }
+}
+
+/// Bottom level acceleration structure.
+pub struct Blas {
+    pub handle: vk::AccelerationStructureKHR,
+    pub device_address: vk::DeviceAddress,
+    pub buffer: VolcanBuffer,
+    pub primitive_count: u32,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+
+    /// Geometry and scratch buffers read by the recorded build.
+    /// Must outlive the command buffer execution, see [`Blas::free_build_resources`].
+    build_resources: Vec<VolcanBuffer>,
+
+    /// Kept for the lifetime of BLASes built with `ALLOW_UPDATE`, see `raytracing_blas_update`.
+    pub(super) update_resources: Option<BlasUpdateResources>,
+    pub refit_count: u32,
+}
 
-    pub fn create_blas(
-        instance: &ash::Instance,
-        device: &ash::Device,
-        physical_device: vk::PhysicalDevice,
-    ) {
-        let aabb_buffer_address = 0u64;
-        let aabb_count = 0u32;
+pub(super) struct BlasUpdateResources {
+    /// AABB buffer rewritten in place by refits.
+    pub(super) geometry: Option<VolcanBuffer>,
+    pub(super) scratch_buffer: VolcanBuffer,
+    pub(super) scratch_address: vk::DeviceAddress,
+}
+
+impl Blas {
+    /// Records the build of a BLAS made of one procedural primitive per AABB
+    /// (e.g. one per voxel brick) into `command_buffer`.
+    pub fn build_aabbs(
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        aabbs: &[vk::AabbPositionsKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Self {
+        Self::build(
+            volcan,
+            command_buffer,
+            Self::prepare_aabbs(volcan, aabbs, flags),
+        )
+    }
 
-        let acceleration_structure_loader =
-            ash::khr::acceleration_structure::Device::new(&instance, &device);
+    /// Uploads `aabbs` and creates the BLAS they will be built into, without recording anything.
+    pub(super) fn prepare_aabbs(
+        volcan: &Volcan,
+        aabbs: &[vk::AabbPositionsKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> PreparedBlas {
+        let aabb_buffer = VolcanBuffer::new_with_data(
+            volcan,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            aabbs,
+        );
 
         let aabb_data = vk::AccelerationStructureGeometryAabbsDataKHR::default()
             .data(vk::DeviceOrHostAddressConstKHR {
-                device_address: aabb_buffer_address,
+                device_address: aabb_buffer.device_address,
             })
             .stride(std::mem::size_of::<vk::AabbPositionsKHR>() as u64);
 
@@ -97,64 +138,366 @@ impl VolcanBLASKhr {
             .geometry(vk::AccelerationStructureGeometryDataKHR { aabbs: aabb_data })
             .flags(vk::GeometryFlagsKHR::OPAQUE);
 
-        let blas_build_range_info = vk::AccelerationStructureBuildRangeInfoKHR {
-            primitive_count: aabb_count,
-            primitive_offset: 0,
-            first_vertex: 0,
-            transform_offset: 0,
-        };
+        let mut prepared = Self::prepare(volcan, blas_geometry, aabbs.len() as u32, flags);
+        prepared.aabb_buffer = Some(aabb_buffer);
+        prepared
+    }
 
+    pub(super) fn prepare_triangles(
+        volcan: &Volcan,
+        triangles: TriangleGeometry,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> PreparedBlas {
+        Self::prepare(volcan, triangles.to_vk(), triangles.triangle_count, flags)
+    }
+
+    fn prepare(
+        volcan: &Volcan,
+        blas_geometry: vk::AccelerationStructureGeometryKHR<'static>,
+        primitive_count: u32,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> PreparedBlas {
         let blas_geometry_binding = [blas_geometry];
         let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
             .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .flags(flags)
             .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
             .geometries(&blas_geometry_binding);
 
         let mut accecleration_build_size_info =
             vk::AccelerationStructureBuildSizesInfoKHR::default();
 
-        let size_info = unsafe {
-            acceleration_structure_loader.get_acceleration_structure_build_sizes(
-                vk::AccelerationStructureBuildTypeKHR::DEVICE,
-                &build_geometry_info,
-                &[blas_build_range_info.primitive_count],
-                &mut accecleration_build_size_info,
-            )
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &build_geometry_info,
+                    &[primitive_count],
+                    &mut accecleration_build_size_info,
+                )
         };
 
-        let blas_usage = vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
-            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
-        let blas_properties = vk::MemoryPropertyFlags::DEVICE_LOCAL;
-
-        let blas_buffer = Self::create_buffer(
-            instance,
-            device,
-            physical_device,
+        let (handle, buffer) = create_acceleration_structure(
+            volcan,
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
             accecleration_build_size_info.acceleration_structure_size,
-            blas_usage,
-            blas_properties,
-        )
-        .unwrap();
-
-        let blas_create_info = vk::AccelerationStructureCreateInfoKHR::default()
-            .buffer(blas_buffer.0)
-            .offset(0)
-            .size(accecleration_build_size_info.acceleration_structure_size)
-            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL);
-
-        let blas = unsafe {
-            acceleration_structure_loader
-                .create_acceleration_structure(&blas_create_info, None)
-                .expect("Failed to create BLAS")
+        );
+
+        // Updatable BLASes keep their scratch buffer around for the refits.
+        let scratch_size = if flags.contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE) {
+            accecleration_build_size_info
+                .build_scratch_size
+                .max(accecleration_build_size_info.update_scratch_size)
+        } else {
+            accecleration_build_size_info.build_scratch_size
         };
 
-        // let build_cmd = unsafe {
-        //     device.cmd_build_acceleration_structures_khr(
-        //         command_buffer,
-        //         &[build_geometry_info],
-        //         &[&[blas_build_range_info]],
-        //     )
-        // };
+        PreparedBlas {
+            blas: Self {
+                handle,
+                device_address: acceleration_structure_device_address(volcan, handle),
+                buffer,
+                primitive_count,
+                flags,
+                build_resources: Vec::new(),
+                update_resources: None,
+                refit_count: 0,
+            },
+            geometry: blas_geometry,
+            aabb_buffer: None,
+            scratch_size,
+        }
+    }
+
+    fn build(volcan: &Volcan, command_buffer: vk::CommandBuffer, prepared: PreparedBlas) -> Self {
+        let (scratch_buffer, scratch_address) =
+            create_scratch_buffer(volcan, prepared.scratch_size);
+
+        record_blas_builds(volcan, command_buffer, &[(&prepared, scratch_address)]);
+        acceleration_structure_build_barrier(&volcan.device, command_buffer);
+
+        prepared.finish(Some((scratch_buffer, scratch_address)))
+    }
+
+    /// Records a copy of this BLAS into a new one of `compacted_size` bytes.
+    /// Requires a build with `ALLOW_COMPACTION` and the size from a compacted size query.
+    pub fn compacted_copy(
+        &self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        compacted_size: vk::DeviceSize,
+    ) -> Self {
+        let (handle, buffer) = create_acceleration_structure(
+            volcan,
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+            compacted_size,
+        );
+
+        let copy_info = vk::CopyAccelerationStructureInfoKHR::default()
+            .src(self.handle)
+            .dst(handle)
+            .mode(vk::CopyAccelerationStructureModeKHR::COMPACT);
+
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .cmd_copy_acceleration_structure(command_buffer, &copy_info);
+        }
+
+        acceleration_structure_build_barrier(&volcan.device, command_buffer);
+
+        // A compacted copy cannot be refitted without the update resources.
+        Self::from_copy(volcan, handle, buffer, self.primitive_count, self.flags)
+    }
+
+    /// Wraps an acceleration structure filled by a copy (e.g. deserialized from disk).
+    /// It has no update resources, so it cannot be refitted.
+    pub(super) fn from_copy(
+        volcan: &Volcan,
+        handle: vk::AccelerationStructureKHR,
+        buffer: VolcanBuffer,
+        primitive_count: u32,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Self {
+        Self::from_parts(
+            handle,
+            acceleration_structure_device_address(volcan, handle),
+            buffer,
+            primitive_count,
+            flags,
+        )
+    }
+
+    /// Wraps an acceleration structure that is already built, without update resources.
+    pub(super) fn from_parts(
+        handle: vk::AccelerationStructureKHR,
+        device_address: vk::DeviceAddress,
+        buffer: VolcanBuffer,
+        primitive_count: u32,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Self {
+        Self {
+            handle,
+            device_address,
+            buffer,
+            primitive_count,
+            flags: flags & !vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+            build_resources: Vec::new(),
+            update_resources: None,
+            refit_count: 0,
+        }
+    }
+
+    /// Frees the geometry and scratch buffers once the build has completed on the GPU.
+    pub fn free_build_resources(&mut self, device: &ash::Device) {
+        for buffer in self.build_resources.drain(..) {
+            buffer.destroy(device);
+        }
+    }
+
+    pub fn destroy(mut self, volcan: &Volcan) {
+        self.free_build_resources(&volcan.device);
+        if let Some(update_resources) = self.update_resources.take() {
+            if let Some(geometry) = update_resources.geometry {
+                geometry.destroy(&volcan.device);
+            }
+            update_resources.scratch_buffer.destroy(&volcan.device);
+        }
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .destroy_acceleration_structure(self.handle, None);
+        }
+        self.buffer.destroy(&volcan.device);
+    }
+}
+
+/// A BLAS whose acceleration structure exists but whose build is not recorded yet.
+/// Lets several builds share a single `cmd_build_acceleration_structures` call and scratch buffer.
+pub(super) struct PreparedBlas {
+    blas: Blas,
+    geometry: vk::AccelerationStructureGeometryKHR<'static>,
+    aabb_buffer: Option<VolcanBuffer>,
+    pub(super) scratch_size: vk::DeviceSize,
+}
+
+impl PreparedBlas {
+    /// Updatable BLASes need a scratch buffer of their own, reused by later refits.
+    pub(super) fn needs_own_scratch(&self) -> bool {
+        self.blas
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+    }
+
+    /// `scratch` is the buffer the build was recorded with, when not shared with other builds.
+    pub(super) fn finish(self, scratch: Option<(VolcanBuffer, vk::DeviceAddress)>) -> Blas {
+        let mut blas = self.blas;
+
+        if blas
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+        {
+            let (scratch_buffer, scratch_address) =
+                scratch.expect("Updatable BLASes need their own scratch buffer");
+            blas.update_resources = Some(BlasUpdateResources {
+                geometry: self.aabb_buffer,
+                scratch_buffer,
+                scratch_address,
+            });
+        } else {
+            blas.build_resources.extend(self.aabb_buffer);
+            blas.build_resources
+                .extend(scratch.map(|(scratch_buffer, _)| scratch_buffer));
+        }
+
+        blas
+    }
+
+    /// The BLAS buffer, for queue family ownership transfers.
+    pub(super) fn buffer(&self) -> vk::Buffer {
+        self.blas.buffer.buffer
+    }
+}
+
+/// Records the builds of `builds` with a single call, each one using the scratch memory at
+/// the given address. No barrier is recorded.
+pub(super) fn record_blas_builds(
+    volcan: &Volcan,
+    command_buffer: vk::CommandBuffer,
+    builds: &[(&PreparedBlas, vk::DeviceAddress)],
+) {
+    if builds.is_empty() {
+        return;
+    }
+
+    let build_geometry_infos: Vec<_> = builds
+        .iter()
+        .map(|(prepared, scratch_address)| {
+            vk::AccelerationStructureBuildGeometryInfoKHR::default()
+                .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                .flags(prepared.blas.flags)
+                .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+                .geometries(std::slice::from_ref(&prepared.geometry))
+                .dst_acceleration_structure(prepared.blas.handle)
+                .scratch_data(vk::DeviceOrHostAddressKHR {
+                    device_address: *scratch_address,
+                })
+        })
+        .collect();
+
+    let build_range_infos: Vec<_> = builds
+        .iter()
+        .map(|(prepared, _)| {
+            [vk::AccelerationStructureBuildRangeInfoKHR {
+                primitive_count: prepared.blas.primitive_count,
+                primitive_offset: 0,
+                first_vertex: 0,
+                transform_offset: 0,
+            }]
+        })
+        .collect();
+    let build_range_info_refs: Vec<&[_]> = build_range_infos.iter().map(|r| &r[..]).collect();
+
+    unsafe {
+        volcan
+            .acceleration_structure_loader
+            .cmd_build_acceleration_structures(
+                command_buffer,
+                &build_geometry_infos,
+                &build_range_info_refs,
+            );
+    }
+}
+
+/// Creates an acceleration structure backed by its own device local buffer.
+pub fn create_acceleration_structure(
+    volcan: &Volcan,
+    ty: vk::AccelerationStructureTypeKHR,
+    size: vk::DeviceSize,
+) -> (vk::AccelerationStructureKHR, VolcanBuffer) {
+    let buffer = VolcanBuffer::new(
+        volcan,
+        size,
+        vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+        MemoryLocation::GpuOnly,
+    );
+
+    let create_info = vk::AccelerationStructureCreateInfoKHR::default()
+        .buffer(buffer.buffer)
+        .offset(0)
+        .size(size)
+        .ty(ty);
+
+    let handle = unsafe {
+        volcan
+            .acceleration_structure_loader
+            .create_acceleration_structure(&create_info, None)
+            .expect("Failed to create acceleration structure")
+    };
+
+    (handle, buffer)
+}
+
+/// Creates a scratch buffer and returns it with its address rounded up to
+/// `minAccelerationStructureScratchOffsetAlignment`.
+pub fn create_scratch_buffer(
+    volcan: &Volcan,
+    size: vk::DeviceSize,
+) -> (VolcanBuffer, vk::DeviceAddress) {
+    let alignment = volcan
+        .acceleration_structure_properties
+        .min_acceleration_structure_scratch_offset_alignment
+        .max(1) as vk::DeviceSize;
+
+    let scratch_buffer = VolcanBuffer::new(
+        volcan,
+        size + alignment - 1,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+        MemoryLocation::GpuOnly,
+    );
+
+    let scratch_address = scratch_buffer.device_address.next_multiple_of(alignment);
+    (scratch_buffer, scratch_address)
+}
+
+pub fn acceleration_structure_device_address(
+    volcan: &Volcan,
+    handle: vk::AccelerationStructureKHR,
+) -> vk::DeviceAddress {
+    let address_info =
+        vk::AccelerationStructureDeviceAddressInfoKHR::default().acceleration_structure(handle);
+
+    unsafe {
+        volcan
+            .acceleration_structure_loader
+            .get_acceleration_structure_device_address(&address_info)
+    }
+}
+
+/// Makes acceleration structure writes visible to later builds and traces.
+pub fn acceleration_structure_build_barrier(
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
+) {
+    let memory_barrier = vk::MemoryBarrier::default()
+        .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+        .dst_access_mask(
+            vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+        );
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR
+                | vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::DependencyFlags::empty(),
+            &[memory_barrier],
+            &[],
+            &[],
+        );
     }
 }
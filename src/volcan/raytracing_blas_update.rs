@@ -0,0 +1,130 @@
+use ash::vk;
+
+use super::{
+    init::Volcan,
+    raytracing_accecleration_structure::{acceleration_structure_build_barrier, Blas},
+};
+
+/// When to stop refitting an edited BLAS and rebuild it instead.
+#[derive(Debug, Clone, Copy)]
+pub struct BlasUpdatePolicy {
+    /// Refits degrade trace performance as boxes drift from the original hierarchy.
+    pub max_refits: u32,
+}
+
+impl Default for BlasUpdatePolicy {
+    fn default() -> Self {
+        Self { max_refits: 16 }
+    }
+}
+
+pub enum BlasUpdate {
+    /// Updated in place, the handle and device address are unchanged.
+    Refit,
+    /// Replaced by a new BLAS. `previous` must be destroyed once the GPU is done with it,
+    /// and TLAS instances retargeted to the new device address.
+    Rebuild { previous: Box<Blas> },
+}
+
+impl Blas {
+    pub fn can_refit(&self, primitive_count: u32, policy: &BlasUpdatePolicy) -> bool {
+        self.flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+            && self
+                .update_resources
+                .as_ref()
+                .is_some_and(|resources| resources.geometry.is_some())
+            && self.primitive_count == primitive_count
+            && self.refit_count < policy.max_refits
+    }
+
+    /// Records an update of the AABBs of an edited voxel chunk: a refit when only the extents
+    /// changed, a full rebuild when the primitive count changed or after too many refits.
+    pub fn update_aabbs(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        aabbs: &[vk::AabbPositionsKHR],
+        policy: &BlasUpdatePolicy,
+    ) -> BlasUpdate {
+        if self.can_refit(aabbs.len() as u32, policy) {
+            self.refit_aabbs(volcan, command_buffer, aabbs);
+            return BlasUpdate::Refit;
+        }
+
+        let rebuilt = Blas::build_aabbs(volcan, command_buffer, aabbs, self.flags);
+        BlasUpdate::Rebuild {
+            previous: Box::new(std::mem::replace(self, rebuilt)),
+        }
+    }
+
+    /// Records a `BuildAccelerationStructureModeKHR::UPDATE` of this BLAS with new AABB extents.
+    /// The AABB count must match the one of the last build.
+    pub fn refit_aabbs(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        aabbs: &[vk::AabbPositionsKHR],
+    ) {
+        assert_eq!(
+            aabbs.len() as u32,
+            self.primitive_count,
+            "A refit cannot change the primitive count"
+        );
+
+        let update_resources = self
+            .update_resources
+            .as_ref()
+            .expect("BLAS was not built with ALLOW_UPDATE");
+        let aabb_buffer = update_resources
+            .geometry
+            .as_ref()
+            .expect("Only AABB BLASes can be refitted");
+
+        // The previous frame's fence was waited on, the GPU no longer reads the old extents.
+        aabb_buffer.write(0, aabbs);
+
+        let aabb_data = vk::AccelerationStructureGeometryAabbsDataKHR::default()
+            .data(vk::DeviceOrHostAddressConstKHR {
+                device_address: aabb_buffer.device_address,
+            })
+            .stride(std::mem::size_of::<vk::AabbPositionsKHR>() as u64);
+
+        let blas_geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::AABBS)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { aabbs: aabb_data })
+            .flags(vk::GeometryFlagsKHR::OPAQUE);
+
+        let blas_geometry_binding = [blas_geometry];
+        let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(self.flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+            .src_acceleration_structure(self.handle)
+            .dst_acceleration_structure(self.handle)
+            .geometries(&blas_geometry_binding)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: update_resources.scratch_address,
+            });
+
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR {
+            primitive_count: self.primitive_count,
+            primitive_offset: 0,
+            first_vertex: 0,
+            transform_offset: 0,
+        };
+
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .cmd_build_acceleration_structures(
+                    command_buffer,
+                    &[build_geometry_info],
+                    &[&[build_range_info]],
+                );
+        }
+
+        acceleration_structure_build_barrier(&volcan.device, command_buffer);
+        self.refit_count += 1;
+    }
+}
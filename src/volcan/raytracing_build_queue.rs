@@ -0,0 +1,507 @@
+use ash::vk;
+use log::info;
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    raytracing_accecleration_structure::{
+        acceleration_structure_build_barrier, create_scratch_buffer, record_blas_builds, Blas,
+        PreparedBlas, TriangleGeometry,
+    },
+    raytracing_tlas::Tlas,
+};
+
+/// Scratch memory shared by the builds of one batch, larger builds get a batch of their own.
+pub const DEFAULT_SCRATCH_BUDGET: vk::DeviceSize = 64 * 1024 * 1024;
+
+/// Identifies a queued build in the results of [`AccelerationStructureBuildQueue::record`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BuildId(pub u32);
+
+enum BuildInput {
+    Aabbs(Vec<vk::AabbPositionsKHR>),
+    Triangles(TriangleGeometry),
+}
+
+struct PendingBuild {
+    id: BuildId,
+    input: BuildInput,
+    flags: vk::BuildAccelerationStructureFlagsKHR,
+}
+
+/// (index in the prepared builds, offset in the shared scratch buffer)
+type Batch = Vec<(usize, vk::DeviceSize)>;
+
+/// BLAS builds submitted to the async compute queue.
+struct AsyncBuild {
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    scratch_buffer: Option<VolcanBuffer>,
+    results: Vec<(BuildId, Blas)>,
+}
+
+/// Collects the BLAS builds of a frame (e.g. the chunks loaded since the last one) and records
+/// them in as few `cmd_build_acceleration_structures` calls as the scratch budget allows,
+/// instead of one call, one barrier and one scratch buffer per BLAS.
+///
+/// Builds of a batch run concurrently on the GPU, each one in its own slice of a shared scratch
+/// buffer. Batches are separated by a barrier since they reuse the same scratch memory.
+pub struct AccelerationStructureBuildQueue {
+    pending: Vec<PendingBuild>,
+    next_id: u32,
+    pub scratch_budget: vk::DeviceSize,
+
+    /// Reused across frames, grown when a batch needs more.
+    scratch_buffer: Option<(VolcanBuffer, vk::DeviceAddress)>,
+    /// Buffers replaced while the previous frame could still use them.
+    retired: Vec<VolcanBuffer>,
+
+    async_command_pool: Option<vk::CommandPool>,
+    async_builds: Vec<AsyncBuild>,
+}
+
+impl AccelerationStructureBuildQueue {
+    pub fn new(scratch_budget: vk::DeviceSize) -> Self {
+        Self {
+            pending: Vec::new(),
+            next_id: 0,
+            scratch_budget,
+            scratch_buffer: None,
+            retired: Vec::new(),
+            async_command_pool: None,
+            async_builds: Vec::new(),
+        }
+    }
+
+    pub fn push_aabbs(
+        &mut self,
+        aabbs: &[vk::AabbPositionsKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> BuildId {
+        self.push(BuildInput::Aabbs(aabbs.to_vec()), flags)
+    }
+
+    /// The triangle buffers must stay alive until the recorded builds have completed.
+    pub fn push_triangles(
+        &mut self,
+        triangles: TriangleGeometry,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> BuildId {
+        self.push(BuildInput::Triangles(triangles), flags)
+    }
+
+    fn push(
+        &mut self,
+        input: BuildInput,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> BuildId {
+        let id = BuildId(self.next_id);
+        self.next_id += 1;
+        self.pending.push(PendingBuild { id, input, flags });
+        id
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Records every pending BLAS build into `command_buffer`, then the update of `tlas`
+    /// so that it sees the new BLASes. Returns the BLASes, whose build resources must be freed
+    /// once the command buffer has completed.
+    pub fn record(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        tlas: Option<&mut Tlas>,
+    ) -> Vec<(BuildId, Blas)> {
+        // The previous frame's fence was waited on, the GPU no longer uses them.
+        for buffer in self.retired.drain(..) {
+            buffer.destroy(&volcan.device);
+        }
+
+        let prepared = self.prepare_pending(volcan);
+        let (batches, scratch_size) = self.split_batches(volcan, &prepared);
+
+        let current_size = self
+            .scratch_buffer
+            .as_ref()
+            .map_or(0, |(buffer, _)| buffer.size);
+        if scratch_size > 0 && current_size < scratch_size {
+            if let Some((previous, _)) = self.scratch_buffer.take() {
+                self.retired.push(previous);
+            }
+            self.scratch_buffer = Some(create_scratch_buffer(volcan, scratch_size));
+        }
+        let scratch_address = self
+            .scratch_buffer
+            .as_ref()
+            .map_or(0, |(_, address)| *address);
+
+        let own_scratch =
+            record_batches(volcan, command_buffer, &prepared, &batches, scratch_address);
+
+        if !prepared.is_empty() {
+            info!(
+                "Recorded {} BLAS builds in {} batches, {} bytes of shared scratch",
+                prepared.len(),
+                batches.len(),
+                scratch_size
+            );
+        }
+
+        if let Some(tlas) = tlas {
+            tlas.update(volcan, command_buffer);
+        }
+
+        finish_builds(prepared, own_scratch)
+    }
+
+    /// Records the pending BLAS builds on the async compute queue and submits them, so that
+    /// large uploads do not stall the frame. Returns `false`, leaving the builds pending,
+    /// when the device has no dedicated compute queue family.
+    ///
+    /// The BLASes are handed out by [`AccelerationStructureBuildQueue::poll_async`].
+    pub fn submit_async(&mut self, volcan: &Volcan) -> bool {
+        let Some((compute_queue_index, compute_queue)) = volcan.async_compute_queue else {
+            return false;
+        };
+
+        if self.pending.is_empty() {
+            return true;
+        }
+
+        let command_pool = *self.async_command_pool.get_or_insert_with(|| {
+            let command_pool_info = vk::CommandPoolCreateInfo::default()
+                .queue_family_index(compute_queue_index)
+                .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+
+            unsafe {
+                volcan
+                    .device
+                    .create_command_pool(&command_pool_info, None)
+                    .expect("Cannot create async compute command pool.")
+            }
+        });
+
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+
+        let command_buffer = unsafe {
+            volcan
+                .device
+                .allocate_command_buffers(&alloc_info)
+                .expect("Cannot allocate command buffer.")[0]
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        unsafe {
+            volcan
+                .device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .expect("Cannot begin command buffer");
+        }
+
+        let prepared = self.prepare_pending(volcan);
+        let (batches, scratch_size) = self.split_batches(volcan, &prepared);
+        let scratch = (scratch_size > 0).then(|| create_scratch_buffer(volcan, scratch_size));
+        let scratch_address = scratch.as_ref().map_or(0, |(_, address)| *address);
+
+        let own_scratch =
+            record_batches(volcan, command_buffer, &prepared, &batches, scratch_address);
+
+        // Release the BLAS buffers to the graphics queue family, acquired in `poll_async`.
+        let release_barriers: Vec<_> = prepared
+            .iter()
+            .map(|(_, prepared)| {
+                ownership_transfer_barrier(
+                    prepared.buffer(),
+                    compute_queue_index,
+                    volcan.queue_index,
+                )
+                .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+            })
+            .collect();
+
+        unsafe {
+            volcan.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &release_barriers,
+                &[],
+            );
+
+            volcan
+                .device
+                .end_command_buffer(command_buffer)
+                .expect("Cannot end command buffer.");
+        }
+
+        let fence = unsafe {
+            volcan
+                .device
+                .create_fence(&vk::FenceCreateInfo::default(), None)
+                .expect("Cannot create fence.")
+        };
+
+        let command_buffers = [command_buffer];
+        let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+
+        unsafe {
+            volcan
+                .device
+                .queue_submit(compute_queue, &[submit_info], fence)
+                .expect("Failed to submit async acceleration structure builds");
+        }
+
+        info!(
+            "Submitted {} BLAS builds in {} batches to the async compute queue",
+            prepared.len(),
+            batches.len()
+        );
+
+        self.async_builds.push(AsyncBuild {
+            command_buffer,
+            fence,
+            scratch_buffer: scratch.map(|(buffer, _)| buffer),
+            results: finish_builds(prepared, own_scratch),
+        });
+
+        true
+    }
+
+    /// Returns the BLASes of the completed async builds, after recording into `command_buffer`
+    /// (of the graphics queue) the ownership acquire they need before being traced or
+    /// referenced by a TLAS build.
+    pub fn poll_async(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+    ) -> Vec<(BuildId, Blas)> {
+        let Some((compute_queue_index, _)) = volcan.async_compute_queue else {
+            return Vec::new();
+        };
+
+        let mut completed = Vec::new();
+        let mut index = 0;
+        while index < self.async_builds.len() {
+            let signaled = unsafe {
+                volcan
+                    .device
+                    .get_fence_status(self.async_builds[index].fence)
+                    .unwrap_or(false)
+            };
+
+            if signaled {
+                let mut build = self.async_builds.swap_remove(index);
+                self.destroy_async_build(volcan, &mut build);
+                completed.extend(build.results);
+            } else {
+                index += 1;
+            }
+        }
+
+        if completed.is_empty() {
+            return completed;
+        }
+
+        // Scratch and AABB buffers kept for refits are rewritten before use,
+        // only the BLAS contents need the transfer.
+        let acquire_barriers: Vec<_> = completed
+            .iter()
+            .map(|(_, blas)| {
+                ownership_transfer_barrier(
+                    blas.buffer.buffer,
+                    compute_queue_index,
+                    volcan.queue_index,
+                )
+                .dst_access_mask(
+                    vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                        | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+                )
+            })
+            .collect();
+
+        unsafe {
+            volcan.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR
+                    | vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::DependencyFlags::empty(),
+                &[],
+                &acquire_barriers,
+                &[],
+            );
+        }
+
+        completed
+    }
+
+    pub fn async_in_flight_count(&self) -> usize {
+        self.async_builds.len()
+    }
+
+    fn destroy_async_build(&self, volcan: &Volcan, build: &mut AsyncBuild) {
+        unsafe {
+            volcan.device.destroy_fence(build.fence, None);
+            if let Some(command_pool) = self.async_command_pool {
+                volcan
+                    .device
+                    .free_command_buffers(command_pool, &[build.command_buffer]);
+            }
+        }
+        if let Some(scratch_buffer) = build.scratch_buffer.take() {
+            scratch_buffer.destroy(&volcan.device);
+        }
+    }
+
+    fn prepare_pending(&mut self, volcan: &Volcan) -> Vec<(BuildId, PreparedBlas)> {
+        self.pending
+            .drain(..)
+            .map(|build| {
+                let prepared = match build.input {
+                    BuildInput::Aabbs(aabbs) => Blas::prepare_aabbs(volcan, &aabbs, build.flags),
+                    BuildInput::Triangles(triangles) => {
+                        Blas::prepare_triangles(volcan, triangles, build.flags)
+                    }
+                };
+                (build.id, prepared)
+            })
+            .collect()
+    }
+
+    /// Splits the builds in batches whose shared scratch fits in the budget.
+    /// Returns the batches and the scratch size needed by the largest one.
+    fn split_batches(
+        &self,
+        volcan: &Volcan,
+        prepared: &[(BuildId, PreparedBlas)],
+    ) -> (Vec<Batch>, vk::DeviceSize) {
+        let alignment = volcan
+            .acceleration_structure_properties
+            .min_acceleration_structure_scratch_offset_alignment
+            .max(1) as vk::DeviceSize;
+
+        let mut batches = Vec::new();
+        let mut batch = Batch::new();
+        let mut batch_scratch_size = 0;
+        let mut max_scratch_size = 0;
+
+        for (index, (_, prepared)) in prepared.iter().enumerate() {
+            if prepared.needs_own_scratch() {
+                batch.push((index, 0));
+                continue;
+            }
+
+            let scratch_size = prepared.scratch_size.next_multiple_of(alignment);
+            if batch_scratch_size > 0 && batch_scratch_size + scratch_size > self.scratch_budget {
+                batches.push(std::mem::take(&mut batch));
+                batch_scratch_size = 0;
+            }
+
+            batch.push((index, batch_scratch_size));
+            batch_scratch_size += scratch_size;
+            max_scratch_size = max_scratch_size.max(batch_scratch_size);
+        }
+
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+
+        (batches, max_scratch_size)
+    }
+
+    /// Waits for the async builds, then destroys everything the queue still owns.
+    pub fn destroy(&mut self, volcan: &Volcan) {
+        for mut build in std::mem::take(&mut self.async_builds) {
+            unsafe {
+                volcan
+                    .device
+                    .wait_for_fences(&[build.fence], true, u64::MAX)
+                    .expect("Failed to wait for fence");
+            }
+            self.destroy_async_build(volcan, &mut build);
+            for (_, blas) in build.results {
+                blas.destroy(volcan);
+            }
+        }
+
+        for buffer in self.retired.drain(..) {
+            buffer.destroy(&volcan.device);
+        }
+        if let Some((scratch_buffer, _)) = self.scratch_buffer.take() {
+            scratch_buffer.destroy(&volcan.device);
+        }
+        if let Some(command_pool) = self.async_command_pool.take() {
+            unsafe { volcan.device.destroy_command_pool(command_pool, None) };
+        }
+    }
+}
+
+/// Records one build call per batch, followed by a barrier. Updatable BLASes get their own
+/// scratch buffer, returned at their index.
+fn record_batches(
+    volcan: &Volcan,
+    command_buffer: vk::CommandBuffer,
+    prepared: &[(BuildId, PreparedBlas)],
+    batches: &[Batch],
+    scratch_address: vk::DeviceAddress,
+) -> Vec<Option<(VolcanBuffer, vk::DeviceAddress)>> {
+    let own_scratch: Vec<_> = prepared
+        .iter()
+        .map(|(_, prepared)| {
+            prepared
+                .needs_own_scratch()
+                .then(|| create_scratch_buffer(volcan, prepared.scratch_size))
+        })
+        .collect();
+
+    for batch in batches {
+        let builds: Vec<_> = batch
+            .iter()
+            .map(|&(index, offset)| {
+                let address = match &own_scratch[index] {
+                    Some((_, own_address)) => *own_address,
+                    None => scratch_address + offset,
+                };
+                (&prepared[index].1, address)
+            })
+            .collect();
+
+        record_blas_builds(volcan, command_buffer, &builds);
+        acceleration_structure_build_barrier(&volcan.device, command_buffer);
+    }
+
+    own_scratch
+}
+
+fn finish_builds(
+    prepared: Vec<(BuildId, PreparedBlas)>,
+    own_scratch: Vec<Option<(VolcanBuffer, vk::DeviceAddress)>>,
+) -> Vec<(BuildId, Blas)> {
+    prepared
+        .into_iter()
+        .zip(own_scratch)
+        .map(|((id, prepared), scratch)| (id, prepared.finish(scratch)))
+        .collect()
+}
+
+fn ownership_transfer_barrier(
+    buffer: vk::Buffer,
+    src_queue_family_index: u32,
+    dst_queue_family_index: u32,
+) -> vk::BufferMemoryBarrier<'static> {
+    vk::BufferMemoryBarrier::default()
+        .src_queue_family_index(src_queue_family_index)
+        .dst_queue_family_index(dst_queue_family_index)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)
+}
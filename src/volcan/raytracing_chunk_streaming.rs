@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use glam::{Affine3A, IVec3};
+use gpu_allocator::MemoryLocation;
+use log::warn;
+
+use crate::world::{chunk::Chunk, chunk_aabbs::ChunkAabbs, chunk_streaming::StreamingUpdate};
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    raytracing_accecleration_structure::Blas,
+    raytracing_blas_update::{BlasUpdate, BlasUpdatePolicy},
+    raytracing_build_queue::{AccelerationStructureBuildQueue, BuildId, DEFAULT_SCRATCH_BUDGET},
+    raytracing_compaction::BlasCompactor,
+    raytracing_host_build::HostBlasBuilder,
+    raytracing_tlas::{InstanceId, Tlas, TlasInstance},
+    raytracing_voxel_geometry::{
+        record_upload_barrier, ChunkGeometry, ChunkGeometryBuffers, VOXEL_HIT_GROUP_SBT_OFFSET,
+    },
+};
+
+/// First capacity of the chunk geometry table, doubled when full.
+const INITIAL_TABLE_CAPACITY: u32 = 256;
+
+/// Loaded chunks are usually left as they are, their BLASes are compacted a frame later.
+const LOADED_CHUNK_FLAGS: vk::BuildAccelerationStructureFlagsKHR =
+    vk::BuildAccelerationStructureFlagsKHR::from_raw(
+        vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE.as_raw()
+            | vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION.as_raw(),
+    );
+
+/// A chunk edited once is likely to be edited again, its BLAS is rebuilt to be refitted
+/// by the next edits.
+const EDITED_CHUNK_FLAGS: vk::BuildAccelerationStructureFlagsKHR =
+    vk::BuildAccelerationStructureFlagsKHR::from_raw(
+        vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_BUILD.as_raw()
+            | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE.as_raw(),
+    );
+
+/// Builds the BLAS of a loaded chunk on a loading thread, see
+/// [`crate::world::chunk_streaming::ChunkStreamer::new`]. `None` when the host build failed,
+/// the chunk is then built through the build queue.
+pub fn build_loaded_chunk_blas(
+    builder: &HostBlasBuilder,
+    chunk_aabbs: &ChunkAabbs,
+) -> Option<Blas> {
+    builder
+        .build_aabbs(&chunk_aabbs.aabbs, LOADED_CHUNK_FLAGS)
+        .inspect_err(|err| warn!("Host chunk BLAS build failed, building it on the device: {err}"))
+        .ok()
+}
+
+struct ResidentChunk {
+    geometry: ChunkGeometryBuffers,
+    blas: Blas,
+    instance: InstanceId,
+    /// Index in the geometry table, the custom index of the instance.
+    slot: u32,
+}
+
+/// GPU side of the chunk streaming (see [`crate::world::chunk_streaming`]): the geometry, BLAS
+/// and TLAS instance of every resident chunk, and the geometry table they index.
+///
+/// Updates are recorded into the frame command buffer and assume the previous frame has
+/// completed, which [`Volcan::raytrace_draw`] waits for before calling back.
+pub struct ChunkResidency {
+    chunks: HashMap<IVec3, ResidentChunk>,
+    build_queue: AccelerationStructureBuildQueue,
+    /// Created on the first update, it needs the device.
+    compactor: Option<BlasCompactor>,
+    /// Loads building on the async compute queue.
+    async_builds: HashMap<BuildId, (IVec3, ChunkGeometryBuffers)>,
+    /// Build of each loading chunk whose result is still wanted.
+    latest_async_builds: HashMap<IVec3, BuildId>,
+
+    /// Mirrors the GPU table, to copy it over when it grows.
+    table: Vec<ChunkGeometry>,
+    table_buffer: Option<VolcanBuffer>,
+    free_slots: Vec<u32>,
+
+    blas_update_policy: BlasUpdatePolicy,
+
+    /// Used by the frame in flight, destroyed at the next update.
+    retired_buffers: Vec<VolcanBuffer>,
+    retired_geometry: Vec<ChunkGeometryBuffers>,
+    retired_blases: Vec<Blas>,
+    /// Chunks built by the frame in flight, their build resources are freed at the next update.
+    built: Vec<IVec3>,
+}
+
+impl Default for ChunkResidency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkResidency {
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            build_queue: AccelerationStructureBuildQueue::new(DEFAULT_SCRATCH_BUDGET),
+            compactor: None,
+            async_builds: HashMap::new(),
+            latest_async_builds: HashMap::new(),
+            table: Vec::new(),
+            table_buffer: None,
+            free_slots: Vec::new(),
+            blas_update_policy: BlasUpdatePolicy::default(),
+            retired_buffers: Vec::new(),
+            retired_geometry: Vec::new(),
+            retired_blases: Vec::new(),
+            built: Vec::new(),
+        }
+    }
+
+    pub fn resident_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Builds recorded in a frame or running on the async compute queue.
+    pub fn building_count(&self) -> usize {
+        self.build_queue.pending_count() + self.build_queue.async_in_flight_count()
+    }
+
+    /// GPU memory given back by compacting the BLASes of loaded chunks.
+    pub fn compacted_bytes(&self) -> vk::DeviceSize {
+        self.compactor
+            .as_ref()
+            .map_or(0, BlasCompactor::total_saved_bytes)
+    }
+
+    /// Records the uploads and BLAS builds of `update` and updates the TLAS instances, before
+    /// the TLAS update of the frame. Returns the GPU memory of the chunks that changed, zero
+    /// for the removed ones, for [`crate::world::chunk_streaming::ChunkStreamer::set_gpu_bytes`].
+    ///
+    /// Loaded chunks built on the host are resident right away. Otherwise, on devices with an
+    /// async compute queue, they are built there and become resident in a later update. Edits are
+    /// always applied in the frame.
+    pub fn update(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        tlas: &mut Tlas,
+        update: StreamingUpdate,
+    ) -> Vec<(IVec3, u64)> {
+        self.free_completed(volcan);
+
+        // Before any new query is recorded, the pending ones were written by completed frames.
+        let mut gpu_bytes = self.compact(volcan, command_buffer, tlas);
+        for &position in &update.remove {
+            // Its async build is dropped once done.
+            self.latest_async_builds.remove(&position);
+            if self.remove(tlas, position) {
+                gpu_bytes.push((position, 0));
+            }
+        }
+
+        for (build_id, blas) in self.build_queue.poll_async(volcan, command_buffer) {
+            let (position, geometry) = self.async_builds.remove(&build_id).unwrap();
+            if self.latest_async_builds.get(&position) == Some(&build_id) {
+                self.latest_async_builds.remove(&position);
+                let bytes = self.insert(volcan, command_buffer, tlas, position, geometry, blas);
+                gpu_bytes.push((position, bytes));
+            } else {
+                // Unloaded or loaded again while it was building.
+                self.retired_geometry.push(geometry);
+                self.retired_blases.push(blas);
+            }
+        }
+
+        let mut host_blases: HashMap<IVec3, Blas> = update.host_blases.into_iter().collect();
+        if update.upload.is_empty() {
+            self.retired_blases.extend(host_blases.into_values());
+            return gpu_bytes;
+        }
+
+        let (edits, loads): (Vec<_>, Vec<_>) = update
+            .upload
+            .iter()
+            .partition(|(position, _)| self.chunks.contains_key(position));
+
+        let mut pending: HashMap<BuildId, (IVec3, ChunkGeometryBuffers)> = HashMap::new();
+        for (position, chunk_aabbs) in loads {
+            let geometry = self.upload_geometry(volcan, command_buffer, chunk_aabbs);
+            if let Some(blas) = host_blases.remove(position) {
+                // Its async build, if any, is dropped once done.
+                self.latest_async_builds.remove(position);
+                let bytes = self.insert(volcan, command_buffer, tlas, *position, geometry, blas);
+                gpu_bytes.push((*position, bytes));
+                continue;
+            }
+            let build_id = self
+                .build_queue
+                .push_aabbs(&chunk_aabbs.aabbs, LOADED_CHUNK_FLAGS);
+            pending.insert(build_id, (*position, geometry));
+        }
+        // Built for chunks unloaded before they were uploaded.
+        self.retired_blases.extend(host_blases.into_values());
+
+        if self.build_queue.submit_async(volcan) {
+            for (build_id, (position, geometry)) in pending.drain() {
+                self.latest_async_builds.insert(position, build_id);
+                self.async_builds.insert(build_id, (position, geometry));
+            }
+        }
+
+        for (position, chunk_aabbs) in edits {
+            let geometry = self.upload_geometry(volcan, command_buffer, chunk_aabbs);
+
+            let chunk = &self.chunks[position];
+            if chunk
+                .blas
+                .flags
+                .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+            {
+                let bytes = self.update_edited(
+                    volcan,
+                    command_buffer,
+                    tlas,
+                    *position,
+                    geometry,
+                    &chunk_aabbs.aabbs,
+                );
+                gpu_bytes.push((*position, bytes));
+            } else {
+                // First edit, rebuilt from scratch.
+                self.remove(tlas, *position);
+                let build_id = self
+                    .build_queue
+                    .push_aabbs(&chunk_aabbs.aabbs, EDITED_CHUNK_FLAGS);
+                pending.insert(build_id, (*position, geometry));
+            }
+        }
+        record_upload_barrier(&volcan.device, command_buffer);
+
+        for (build_id, blas) in self.build_queue.record(volcan, command_buffer, None) {
+            let (position, geometry) = pending.remove(&build_id).unwrap();
+            let bytes = self.insert(volcan, command_buffer, tlas, position, geometry, blas);
+            gpu_bytes.push((position, bytes));
+        }
+
+        gpu_bytes
+    }
+
+    /// Records the upload of the geometry read by the intersection shader.
+    fn upload_geometry(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        chunk_aabbs: &ChunkAabbs,
+    ) -> ChunkGeometryBuffers {
+        let (geometry, staging) = ChunkGeometryBuffers::upload(volcan, command_buffer, chunk_aabbs);
+        self.retired_buffers.push(staging);
+        geometry
+    }
+
+    /// Makes a chunk whose BLAS was just built resident. Returns its GPU memory.
+    fn insert(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        tlas: &mut Tlas,
+        position: IVec3,
+        geometry: ChunkGeometryBuffers,
+        blas: Blas,
+    ) -> u64 {
+        if blas
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION)
+        {
+            self.compactor
+                .get_or_insert_with(|| BlasCompactor::new(volcan))
+                .request(volcan, command_buffer, &blas);
+        }
+
+        let slot = self.allocate_slot(volcan);
+        self.set_table_entry(slot, geometry.geometry());
+
+        let transform = Affine3A::from_translation(Chunk::origin(position).as_vec3());
+        let instance = tlas.add_instance(TlasInstance {
+            custom_index: slot,
+            sbt_offset: VOXEL_HIT_GROUP_SBT_OFFSET,
+            ..TlasInstance::new(blas.device_address, transform)
+        });
+
+        let bytes = geometry.memory_bytes() + blas.buffer.size;
+        self.built.push(position);
+        self.chunks.insert(
+            position,
+            ResidentChunk {
+                geometry,
+                blas,
+                instance,
+                slot,
+            },
+        );
+        bytes
+    }
+
+    /// Swaps the BLASes whose compacted size is known for compacted copies. Returns the new GPU
+    /// memory of the compacted chunks.
+    fn compact(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        tlas: &mut Tlas,
+    ) -> Vec<(IVec3, u64)> {
+        let Some(compactor) = self.compactor.as_mut() else {
+            return Vec::new();
+        };
+        // Also destroys the originals swapped out last frame, so it runs without pending queries.
+        let compacted = compactor.compact(
+            volcan,
+            command_buffer,
+            self.chunks.values_mut().map(|chunk| &mut chunk.blas),
+        );
+        if compacted.is_empty() {
+            return Vec::new();
+        }
+        for stats in &compacted {
+            tlas.retarget_blas(stats.original_address, stats.compacted_address);
+        }
+
+        self.chunks
+            .iter()
+            .filter(|(_, chunk)| {
+                compacted
+                    .iter()
+                    .any(|stats| stats.compacted_address == chunk.blas.device_address)
+            })
+            .map(|(&position, chunk)| {
+                (
+                    position,
+                    chunk.geometry.memory_bytes() + chunk.blas.buffer.size,
+                )
+            })
+            .collect()
+    }
+
+    /// Swaps in the geometry of an edited chunk whose BLAS allows updates, and refits the BLAS
+    /// or rebuilds it as [`BlasUpdatePolicy`] decides. Returns the GPU memory of the chunk.
+    fn update_edited(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        tlas: &mut Tlas,
+        position: IVec3,
+        geometry: ChunkGeometryBuffers,
+        aabbs: &[vk::AabbPositionsKHR],
+    ) -> u64 {
+        let chunk = self.chunks.get_mut(&position).unwrap();
+        let previous_geometry = std::mem::replace(&mut chunk.geometry, geometry);
+        self.retired_geometry.push(previous_geometry);
+
+        match chunk
+            .blas
+            .update_aabbs(volcan, command_buffer, aabbs, &self.blas_update_policy)
+        {
+            BlasUpdate::Refit => tlas.mark_blas_refitted(),
+            BlasUpdate::Rebuild { previous } => {
+                let instance = *tlas.instance(chunk.instance).unwrap();
+                tlas.update_instance(
+                    chunk.instance,
+                    TlasInstance {
+                        blas_address: chunk.blas.device_address,
+                        ..instance
+                    },
+                );
+                self.retired_blases.push(*previous);
+                self.built.push(position);
+            }
+        }
+
+        let (slot, geometry, bytes) = (
+            chunk.slot,
+            chunk.geometry.geometry(),
+            chunk.geometry.memory_bytes() + chunk.blas.buffer.size,
+        );
+        self.set_table_entry(slot, geometry);
+        bytes
+    }
+
+    fn free_completed(&mut self, volcan: &Volcan) {
+        for buffer in self.retired_buffers.drain(..) {
+            buffer.destroy(&volcan.device);
+        }
+        for geometry in self.retired_geometry.drain(..) {
+            geometry.destroy(&volcan.device);
+        }
+        for blas in self.retired_blases.drain(..) {
+            blas.destroy(volcan);
+        }
+        for position in self.built.drain(..) {
+            if let Some(chunk) = self.chunks.get_mut(&position) {
+                chunk.blas.free_build_resources(&volcan.device);
+            }
+        }
+    }
+
+    /// Returns whether the chunk was resident. Its resources are destroyed at the next update.
+    fn remove(&mut self, tlas: &mut Tlas, position: IVec3) -> bool {
+        let Some(chunk) = self.chunks.remove(&position) else {
+            return false;
+        };
+
+        tlas.remove_instance(chunk.instance);
+        if let Some(compactor) = self.compactor.as_mut() {
+            compactor.cancel(chunk.blas.handle);
+        }
+        // Nothing references the slot anymore, the stale entry is overwritten when reused.
+        self.free_slots.push(chunk.slot);
+        self.retired_geometry.push(chunk.geometry);
+        self.retired_blases.push(chunk.blas);
+        true
+    }
+
+    /// Appends the geometry of other instances to the table, e.g. the chunks of a
+    /// [`super::raytracing_voxel_model::VoxelModelBlases`], at consecutive slots that stay
+    /// theirs. Returns the first slot.
+    pub fn append_geometries(
+        &mut self,
+        volcan: &Volcan,
+        geometries: impl IntoIterator<Item = ChunkGeometry>,
+    ) -> u32 {
+        let first_slot = self.table.len() as u32;
+        for geometry in geometries {
+            let slot = self.push_slot(volcan);
+            self.set_table_entry(slot, geometry);
+        }
+        first_slot
+    }
+
+    fn allocate_slot(&mut self, volcan: &Volcan) -> u32 {
+        match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => self.push_slot(volcan),
+        }
+    }
+
+    /// Grows the table by one slot.
+    fn push_slot(&mut self, volcan: &Volcan) -> u32 {
+        let slot = self.table.len() as u32;
+        self.table.push(ChunkGeometry::default());
+
+        let capacity = self.table_buffer.as_ref().map_or(0, |buffer| {
+            buffer.size as usize / size_of::<ChunkGeometry>()
+        });
+        if self.table.len() > capacity {
+            let capacity = (capacity * 2).max(INITIAL_TABLE_CAPACITY as usize);
+            let buffer = VolcanBuffer::new(
+                volcan,
+                (capacity * size_of::<ChunkGeometry>()) as vk::DeviceSize,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::CpuToGpu,
+            );
+            buffer.write(0, &self.table);
+            volcan.write_chunk_geometry_descriptor(buffer.buffer);
+
+            if let Some(previous) = self.table_buffer.replace(buffer) {
+                self.retired_buffers.push(previous);
+            }
+        }
+        slot
+    }
+
+    fn set_table_entry(&mut self, slot: u32, geometry: ChunkGeometry) {
+        self.table[slot as usize] = geometry;
+        let offset = slot as vk::DeviceSize * size_of::<ChunkGeometry>() as vk::DeviceSize;
+        self.table_buffer
+            .as_ref()
+            .unwrap()
+            .write(offset, &[geometry]);
+    }
+
+    /// The device must be idle.
+    pub fn destroy(&mut self, volcan: &Volcan, tlas: &mut Tlas) {
+        let positions: Vec<IVec3> = self.chunks.keys().copied().collect();
+        for position in positions {
+            self.remove(tlas, position);
+        }
+        self.built.clear();
+        self.free_completed(volcan);
+
+        if let Some(buffer) = self.table_buffer.take() {
+            buffer.destroy(&volcan.device);
+        }
+        if let Some(mut compactor) = self.compactor.take() {
+            compactor.destroy(volcan);
+        }
+        self.build_queue.destroy(volcan);
+        self.latest_async_builds.clear();
+        for (_, (_, geometry)) in self.async_builds.drain() {
+            geometry.destroy(&volcan.device);
+        }
+    }
+}
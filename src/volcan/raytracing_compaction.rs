@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use log::{debug, warn};
+
+use super::{init::Volcan, raytracing_accecleration_structure::Blas};
+
+const MAX_PENDING_COMPACTIONS: u32 = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionStats {
+    pub original_handle: vk::AccelerationStructureKHR,
+    pub original_size: vk::DeviceSize,
+    pub compacted_size: vk::DeviceSize,
+    /// TLAS instances referencing `original_address` must be updated to `compacted_address`.
+    pub original_address: vk::DeviceAddress,
+    pub compacted_address: vk::DeviceAddress,
+}
+
+impl CompactionStats {
+    pub fn saved_bytes(&self) -> vk::DeviceSize {
+        self.original_size.saturating_sub(self.compacted_size)
+    }
+}
+
+/// Opt-in compaction of BLASes built with `ALLOW_COMPACTION`.
+///
+/// 1. [`BlasCompactor::request`] right after the build queries the compacted size.
+/// 2. [`BlasCompactor::compact`], in a later frame, copies the BLASes whose size is known into
+///    right-sized buffers and swaps them in place.
+/// 3. The originals are destroyed on the following `compact` call, once the previous frame's
+///    fence guarantees the GPU no longer reads them.
+pub struct BlasCompactor {
+    query_pool: vk::QueryPool,
+    free_queries: Vec<u32>,
+    pending: HashMap<vk::AccelerationStructureKHR, u32>,
+    retired: Vec<Blas>,
+    saved_bytes: vk::DeviceSize,
+}
+
+impl BlasCompactor {
+    pub fn new(volcan: &Volcan) -> Self {
+        let query_pool_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+            .query_count(MAX_PENDING_COMPACTIONS);
+
+        let query_pool = unsafe {
+            volcan
+                .device
+                .create_query_pool(&query_pool_info, None)
+                .expect("Cannot create compaction query pool")
+        };
+
+        Self {
+            query_pool,
+            free_queries: (0..MAX_PENDING_COMPACTIONS).rev().collect(),
+            pending: HashMap::new(),
+            retired: Vec::new(),
+            saved_bytes: 0,
+        }
+    }
+
+    /// Records a compacted size query for `blas`, after its build in the same command buffer.
+    pub fn request(&mut self, volcan: &Volcan, command_buffer: vk::CommandBuffer, blas: &Blas) {
+        if !blas
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION)
+        {
+            warn!("BLAS {:?} was not built with ALLOW_COMPACTION", blas.handle);
+            return;
+        }
+
+        if self.pending.contains_key(&blas.handle) {
+            return;
+        }
+
+        let Some(query) = self.free_queries.pop() else {
+            warn!(
+                "Too many pending BLAS compactions, skipping {:?}",
+                blas.handle
+            );
+            return;
+        };
+
+        unsafe {
+            volcan
+                .device
+                .cmd_reset_query_pool(command_buffer, self.query_pool, query, 1);
+            volcan
+                .acceleration_structure_loader
+                .cmd_write_acceleration_structures_properties(
+                    command_buffer,
+                    &[blas.handle],
+                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                    self.query_pool,
+                    query,
+                );
+        }
+
+        self.pending.insert(blas.handle, query);
+    }
+
+    fn compacted_size(&self, volcan: &Volcan, query: u32) -> Option<vk::DeviceSize> {
+        // [value, availability]
+        let mut result = [[0u64; 2]];
+
+        unsafe {
+            volcan.device.get_query_pool_results(
+                self.query_pool,
+                query,
+                &mut result,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+            )
+        }
+        .ok()?;
+
+        let [size, available] = result[0];
+        (available != 0 && size != 0).then_some(size)
+    }
+
+    /// Replaces every BLAS of `blases` whose compacted size is known by a compacted copy.
+    /// Returns the statistics of the compactions recorded by this call.
+    ///
+    /// Must be called every frame, even without pending queries, so the originals swapped out
+    /// by the previous call are destroyed.
+    pub fn compact<'a>(
+        &mut self,
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        blases: impl IntoIterator<Item = &'a mut Blas>,
+    ) -> Vec<CompactionStats> {
+        for blas in self.retired.drain(..) {
+            blas.destroy(volcan);
+        }
+
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        let mut compacted = Vec::new();
+
+        for blas in blases {
+            let Some(&query) = self.pending.get(&blas.handle) else {
+                continue;
+            };
+            let Some(compacted_size) = self.compacted_size(volcan, query) else {
+                continue;
+            };
+
+            self.pending.remove(&blas.handle);
+            self.free_queries.push(query);
+
+            let original_size = blas.buffer.size;
+            if compacted_size >= original_size {
+                continue;
+            }
+
+            let compacted_blas = blas.compacted_copy(volcan, command_buffer, compacted_size);
+            let original = std::mem::replace(blas, compacted_blas);
+
+            compacted.push(CompactionStats {
+                original_handle: original.handle,
+                original_size,
+                compacted_size,
+                original_address: original.device_address,
+                compacted_address: blas.device_address,
+            });
+            self.retired.push(original);
+        }
+
+        for stats in &compacted {
+            debug!(
+                "Compacted BLAS {:?}: {} -> {} bytes ({} saved)",
+                stats.original_handle,
+                stats.original_size,
+                stats.compacted_size,
+                stats.saved_bytes()
+            );
+        }
+
+        self.saved_bytes += compacted
+            .iter()
+            .map(CompactionStats::saved_bytes)
+            .sum::<vk::DeviceSize>();
+        compacted
+    }
+
+    pub fn total_saved_bytes(&self) -> vk::DeviceSize {
+        self.saved_bytes
+    }
+
+    /// Forgets a pending query, e.g. when its BLAS is destroyed before being compacted.
+    pub fn cancel(&mut self, handle: vk::AccelerationStructureKHR) {
+        if let Some(query) = self.pending.remove(&handle) {
+            self.free_queries.push(query);
+        }
+    }
+
+    pub fn destroy(&mut self, volcan: &Volcan) {
+        for blas in self.retired.drain(..) {
+            blas.destroy(volcan);
+        }
+
+        unsafe { volcan.device.destroy_query_pool(self.query_pool, None) };
+    }
+}
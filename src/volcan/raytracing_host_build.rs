@@ -0,0 +1,225 @@
+use std::ffi::c_void;
+
+use ash::{khr, prelude::VkResult, vk};
+use gpu_allocator::MemoryLocation;
+use log::warn;
+
+use super::{
+    allocator::SharedAllocator, buffer::VolcanBuffer, init::Volcan,
+    raytracing_accecleration_structure::Blas,
+};
+
+/// Builds BLASes on the CPU with `accelerationStructureHostCommands`, so that chunk loading
+/// threads do not need the graphics queue. Each build is a deferred operation joined by up to
+/// `worker_threads` threads.
+///
+/// Cheap to clone, every loading thread keeps its own copy.
+#[derive(Clone)]
+pub struct HostBlasBuilder {
+    allocator: SharedAllocator,
+    device: ash::Device,
+    acceleration_structure_loader: khr::acceleration_structure::Device,
+    deferred_host_operations_loader: khr::deferred_host_operations::Device,
+    pub worker_threads: usize,
+}
+
+impl Volcan {
+    /// `None` when the device cannot build acceleration structures on the host,
+    /// builds must then go through [`Blas::build_aabbs`] or the build queue.
+    pub fn host_blas_builder(&self) -> Option<HostBlasBuilder> {
+        if !self.acceleration_structure_host_commands {
+            return None;
+        }
+
+        Some(HostBlasBuilder {
+            allocator: (*self.allocator).clone(),
+            device: self.device.clone(),
+            acceleration_structure_loader: self.acceleration_structure_loader.clone(),
+            deferred_host_operations_loader: self.deferred_host_operations_loader.clone(),
+            worker_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        })
+    }
+}
+
+impl HostBlasBuilder {
+    /// Builds a BLAS over `aabbs` on the calling thread and its helpers.
+    /// The result lives in host visible memory and is usable by the GPU once returned.
+    pub fn build_aabbs(
+        &self,
+        aabbs: &[vk::AabbPositionsKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> VkResult<Blas> {
+        // Host builds have no update resources, refits go through a device build.
+        let flags = flags & !vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE;
+
+        let aabb_data = vk::AccelerationStructureGeometryAabbsDataKHR::default()
+            .data(vk::DeviceOrHostAddressConstKHR {
+                host_address: aabbs.as_ptr() as *const c_void,
+            })
+            .stride(std::mem::size_of::<vk::AabbPositionsKHR>() as u64);
+
+        let blas_geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::AABBS)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { aabbs: aabb_data })
+            .flags(vk::GeometryFlagsKHR::OPAQUE);
+
+        let blas_geometry_binding = [blas_geometry];
+        let mut build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(&blas_geometry_binding);
+
+        let primitive_count = aabbs.len() as u32;
+        let mut build_size_info = vk::AccelerationStructureBuildSizesInfoKHR::default();
+
+        unsafe {
+            self.acceleration_structure_loader
+                .get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::HOST,
+                    &build_geometry_info,
+                    &[primitive_count],
+                    &mut build_size_info,
+                )
+        };
+
+        // Host commands can only write acceleration structures backed by host visible memory.
+        let buffer = VolcanBuffer::new_on_device(
+            &self.allocator,
+            &self.device,
+            build_size_info.acceleration_structure_size,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::CpuToGpu,
+        );
+
+        let create_info = vk::AccelerationStructureCreateInfoKHR::default()
+            .buffer(buffer.buffer)
+            .offset(0)
+            .size(build_size_info.acceleration_structure_size)
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL);
+
+        let handle = match unsafe {
+            self.acceleration_structure_loader
+                .create_acceleration_structure(&create_info, None)
+        } {
+            Ok(handle) => handle,
+            Err(err) => {
+                buffer.destroy(&self.device);
+                return Err(err);
+            }
+        };
+
+        let mut scratch = vec![0u8; build_size_info.build_scratch_size as usize];
+
+        build_geometry_info = build_geometry_info
+            .dst_acceleration_structure(handle)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                host_address: scratch.as_mut_ptr() as *mut c_void,
+            });
+
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR {
+            primitive_count,
+            primitive_offset: 0,
+            first_vertex: 0,
+            transform_offset: 0,
+        };
+
+        let result = self.run_deferred(|operation| unsafe {
+            self.acceleration_structure_loader
+                .build_acceleration_structures(
+                    operation,
+                    &[build_geometry_info],
+                    &[&[build_range_info]],
+                )
+        });
+
+        if let Err(err) = result {
+            unsafe {
+                self.acceleration_structure_loader
+                    .destroy_acceleration_structure(handle, None);
+            }
+            buffer.destroy(&self.device);
+            return Err(err);
+        }
+
+        let device_address = unsafe {
+            self.acceleration_structure_loader
+                .get_acceleration_structure_device_address(
+                    &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                        .acceleration_structure(handle),
+                )
+        };
+
+        Ok(Blas::from_parts(
+            handle,
+            device_address,
+            buffer,
+            primitive_count,
+            flags,
+        ))
+    }
+
+    /// Starts `start` as a deferred operation and joins it from up to `worker_threads` threads.
+    fn run_deferred(
+        &self,
+        start: impl FnOnce(vk::DeferredOperationKHR) -> VkResult<()>,
+    ) -> VkResult<()> {
+        let operation = unsafe {
+            self.deferred_host_operations_loader
+                .create_deferred_operation(None)?
+        };
+
+        let result = match start(operation) {
+            Err(vk::Result::OPERATION_DEFERRED_KHR) => {
+                let concurrency = unsafe {
+                    self.deferred_host_operations_loader
+                        .get_deferred_operation_max_concurrency(operation)
+                };
+                let threads = (concurrency as usize).clamp(1, self.worker_threads.max(1));
+
+                std::thread::scope(|scope| {
+                    for _ in 1..threads {
+                        scope.spawn(|| self.join_deferred(operation));
+                    }
+                    self.join_deferred(operation);
+                });
+
+                unsafe {
+                    self.deferred_host_operations_loader
+                        .get_deferred_operation_result(operation)
+                }
+            }
+            // The driver completed the operation in the call.
+            Ok(()) | Err(vk::Result::OPERATION_NOT_DEFERRED_KHR) => Ok(()),
+            Err(err) => Err(err),
+        };
+
+        unsafe {
+            self.deferred_host_operations_loader
+                .destroy_deferred_operation(operation, None);
+        }
+
+        result
+    }
+
+    fn join_deferred(&self, operation: vk::DeferredOperationKHR) {
+        loop {
+            let result = unsafe {
+                self.deferred_host_operations_loader
+                    .deferred_operation_join(operation)
+            };
+
+            match result {
+                // Done, or no more work for this thread.
+                Ok(()) | Err(vk::Result::THREAD_DONE_KHR) => return,
+                // Other threads hold the remaining work, it may be split again.
+                Err(vk::Result::THREAD_IDLE_KHR) => std::thread::yield_now(),
+                Err(err) => {
+                    warn!("Deferred acceleration structure build failed: {err}");
+                    return;
+                }
+            }
+        }
+    }
+}
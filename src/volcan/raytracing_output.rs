@@ -0,0 +1,498 @@
+use ash::vk;
+
+use glam::UVec2;
+use gpu_allocator::{vulkan::Allocation, MemoryLocation};
+
+use crate::world::{
+    camera::{Camera, CameraPushConstants},
+    cpu_tracer::RgbaImage,
+};
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    pipeline::{VolcanPipeline, RAYTRACING_PIPELINE_SHADERS},
+    raytracing_voxel_geometry::ChunkGeometry,
+};
+
+/// Format written by `raygen.rgen` (`rgba8`), blitted to the swapchain format.
+pub const RAYTRACING_OUTPUT_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// Set 0 of the ray tracing pipeline: the TLAS, the output image and the chunk geometry table.
+/// The set is created from the reflected shaders, this declaration is checked against them.
+pub const RAYTRACING_DESCRIPTOR_BINDINGS: [vk::DescriptorSetLayoutBinding<'static>; 3] = [
+    vk::DescriptorSetLayoutBinding {
+        binding: 0,
+        descriptor_type: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+        descriptor_count: 1,
+        stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+        p_immutable_samplers: std::ptr::null(),
+        _marker: std::marker::PhantomData,
+    },
+    vk::DescriptorSetLayoutBinding {
+        binding: 1,
+        descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+        descriptor_count: 1,
+        stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+        p_immutable_samplers: std::ptr::null(),
+        _marker: std::marker::PhantomData,
+    },
+    vk::DescriptorSetLayoutBinding {
+        binding: 2,
+        descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+        descriptor_count: 1,
+        stage_flags: vk::ShaderStageFlags::from_raw(
+            vk::ShaderStageFlags::INTERSECTION_KHR.as_raw()
+                | vk::ShaderStageFlags::CLOSEST_HIT_KHR.as_raw(),
+        ),
+        p_immutable_samplers: std::ptr::null(),
+        _marker: std::marker::PhantomData,
+    },
+];
+
+/// The camera of `raygen.rgen`.
+pub const RAYTRACING_PUSH_CONSTANTS: vk::PushConstantRange = vk::PushConstantRange {
+    stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+    offset: 0,
+    size: std::mem::size_of::<CameraPushConstants>() as u32,
+};
+
+/// Storage image the ray tracing pipeline writes to, sized to the swapchain.
+pub struct RaytracingOutput {
+    pub image: vk::Image,
+    pub memory: Allocation,
+    pub view: vk::ImageView,
+    pub extent: vk::Extent2D,
+}
+
+impl Volcan {
+    /// Creates the output image, must be called again after the swapchain is recreated.
+    pub fn create_raytracing_output(&mut self) {
+        let extent = *self.swapchain_extents;
+
+        let image_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(RAYTRACING_OUTPUT_FORMAT)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        let image = unsafe {
+            self.device
+                .create_image(&image_info, None)
+                .expect("Cannot create ray tracing output image")
+        };
+
+        let memory = self.allocate_image_memory(image, "Ray tracing output");
+
+        let view_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(RAYTRACING_OUTPUT_FORMAT)
+            .subresource_range(color_subresource_range());
+
+        let view = unsafe {
+            self.device
+                .create_image_view(&view_info, None)
+                .expect("Failed to create image view")
+        };
+
+        if let Some(previous) = self.raytracing_output.replace(RaytracingOutput {
+            image,
+            memory,
+            view,
+            extent,
+        }) {
+            self.destroy_raytracing_output(previous);
+        }
+
+        if self.raytracing_descriptor_set.get().is_some() {
+            self.write_output_descriptor();
+        }
+
+        println!("Raytracing output: {:?} {:?}", image, extent);
+    }
+
+    fn destroy_raytracing_output(&self, output: RaytracingOutput) {
+        unsafe {
+            self.device.destroy_image_view(output.view, None);
+            self.device.destroy_image(output.image, None);
+        }
+        self.free_memory(output.memory);
+    }
+
+    /// Creates the descriptor set of [`RAYTRACING_DESCRIPTOR_BINDINGS`], as reflected from the
+    /// ray tracing shaders, and the pipeline layout of the ray tracing pipeline. The TLAS binding is written every frame by
+    /// [`Volcan::raytrace_draw`], the chunk geometry binding points at an empty table until
+    /// [`Volcan::write_chunk_geometry_descriptor`] is called.
+    pub fn create_raytracing_descriptors(&mut self) {
+        let bindings = VolcanPipeline::reflect_layout(RAYTRACING_PIPELINE_SHADERS)
+            .descriptor_set_layout_bindings()
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
+
+        let descriptor_set_layout = unsafe {
+            self.device
+                .create_descriptor_set_layout(&layout_info, None)
+                .expect("Cannot create descriptor set layout")
+        };
+
+        let pool_sizes: Vec<_> = bindings
+            .iter()
+            .map(|binding| vk::DescriptorPoolSize {
+                ty: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+            })
+            .collect();
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+
+        let descriptor_pool = unsafe {
+            self.device
+                .create_descriptor_pool(&pool_info, None)
+                .expect("Cannot create descriptor pool")
+        };
+
+        let set_layouts = [descriptor_set_layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+
+        let descriptor_set = unsafe {
+            self.device
+                .allocate_descriptor_sets(&alloc_info)
+                .expect("Cannot allocate descriptor set")[0]
+        };
+
+        let push_constant_ranges = [RAYTRACING_PUSH_CONSTANTS];
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe {
+            self.device
+                .create_pipeline_layout(&pipeline_layout_info, None)
+                .expect("Failed to create pipeline layout")
+        };
+
+        self.raytracing_descriptor_set_layout
+            .set(descriptor_set_layout);
+        self.raytracing_descriptor_pool.set(descriptor_pool);
+        self.raytracing_descriptor_set.set(descriptor_set);
+        self.raytracing_pipeline_layout.set(pipeline_layout);
+
+        if self.raytracing_output.get().is_some() {
+            self.write_output_descriptor();
+        }
+
+        // Every binding must be valid when the set is bound, even with no chunk in the TLAS.
+        let placeholder = VolcanBuffer::new_with_data(
+            self,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            &[ChunkGeometry::default()],
+        );
+        self.write_chunk_geometry_descriptor(placeholder.buffer);
+        self.chunk_geometry_placeholder.set(placeholder);
+    }
+
+    fn write_output_descriptor(&self) {
+        let image_infos = [vk::DescriptorImageInfo::default()
+            .image_view(self.raytracing_output.view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(*self.raytracing_descriptor_set)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .image_info(&image_infos);
+
+        unsafe { self.device.update_descriptor_sets(&[write], &[]) };
+    }
+
+    /// Points the TLAS binding at `tlas`. The set must not be in use by a pending frame.
+    pub fn write_tlas_descriptor(&self, tlas: vk::AccelerationStructureKHR) {
+        let acceleration_structures = [tlas];
+        let mut acceleration_structure_write =
+            vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                .acceleration_structures(&acceleration_structures);
+
+        let mut write = vk::WriteDescriptorSet::default()
+            .dst_set(*self.raytracing_descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+            .push_next(&mut acceleration_structure_write);
+        write.descriptor_count = 1;
+
+        unsafe { self.device.update_descriptor_sets(&[write], &[]) };
+    }
+
+    /// Records the transitions, trace and blit that present the ray traced image
+    /// on `swapchain_image`.
+    pub fn record_raytracing_frame(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: vk::Pipeline,
+        shader_binding_table: &super::raytracing_sbt::ShaderBindingTable,
+        camera: &Camera,
+        swapchain_image: vk::Image,
+    ) {
+        let output = &*self.raytracing_output;
+        let push_constants =
+            camera.push_constants(UVec2::new(output.extent.width, output.extent.height));
+
+        // The previous content is overwritten, the old layout does not matter.
+        image_barrier(
+            &self.device,
+            command_buffer,
+            output.image,
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            (vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE),
+            (
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            ),
+        );
+
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                *self.raytracing_pipeline_layout,
+                0,
+                &[*self.raytracing_descriptor_set],
+                &[],
+            );
+            self.device.cmd_push_constants(
+                command_buffer,
+                *self.raytracing_pipeline_layout,
+                RAYTRACING_PUSH_CONSTANTS.stage_flags,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+            self.ray_tracing_pipeline_loader.cmd_trace_rays(
+                command_buffer,
+                &shader_binding_table.raygen_region,
+                &shader_binding_table.miss_region,
+                &shader_binding_table.hit_region,
+                &shader_binding_table.callable_region,
+                output.extent.width,
+                output.extent.height,
+                1,
+            );
+        }
+
+        image_barrier(
+            &self.device,
+            command_buffer,
+            output.image,
+            (
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ),
+            (
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::TRANSFER_READ,
+            ),
+            (
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::PipelineStageFlags::TRANSFER,
+            ),
+        );
+
+        // Waits on the image available semaphore at the transfer stage.
+        image_barrier(
+            &self.device,
+            command_buffer,
+            swapchain_image,
+            (
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            ),
+            (vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE),
+            (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+            ),
+        );
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let extent_offset = vk::Offset3D {
+            x: output.extent.width as i32,
+            y: output.extent.height as i32,
+            z: 1,
+        };
+        // A blit rather than a copy, it converts to the swapchain format (e.g. BGRA).
+        let blit = vk::ImageBlit {
+            src_subresource: subresource,
+            src_offsets: [vk::Offset3D::default(), extent_offset],
+            dst_subresource: subresource,
+            dst_offsets: [vk::Offset3D::default(), extent_offset],
+        };
+
+        unsafe {
+            self.device.cmd_blit_image(
+                command_buffer,
+                output.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                swapchain_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                vk::Filter::NEAREST,
+            );
+        }
+
+        image_barrier(
+            &self.device,
+            command_buffer,
+            swapchain_image,
+            (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            ),
+            (vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty()),
+            (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            ),
+        );
+    }
+
+    /// Copies the last traced image out, to compare with
+    /// [`crate::world::cpu_tracer::CpuTracer::render`]. Waits for the frame in flight.
+    pub fn read_raytracing_output(&self) -> RgbaImage {
+        unsafe {
+            self.device
+                .wait_for_fences(&[*self.in_flight_fence], true, u64::MAX)
+                .expect("Failed to wait for fence");
+        }
+
+        let output = &*self.raytracing_output;
+        let size =
+            output.extent.width as vk::DeviceSize * output.extent.height as vk::DeviceSize * 4;
+        let readback = VolcanBuffer::new(
+            self,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuToCpu,
+        );
+
+        self.execute_one_time_commands(|command_buffer| {
+            // Left as a transfer source by `record_raytracing_frame`.
+            let region = vk::BufferImageCopy::default()
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(vk::Extent3D {
+                    width: output.extent.width,
+                    height: output.extent.height,
+                    depth: 1,
+                });
+
+            unsafe {
+                self.device.cmd_copy_image_to_buffer(
+                    command_buffer,
+                    output.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    readback.buffer,
+                    &[region],
+                );
+            }
+        });
+
+        let pixels = readback.read();
+        readback.destroy(&self.device);
+        RgbaImage {
+            width: output.extent.width,
+            height: output.extent.height,
+            pixels,
+        }
+    }
+
+    pub fn destroy_raytracing_resources(&mut self) {
+        if let Some(output) = self.raytracing_output.take() {
+            self.destroy_raytracing_output(output);
+        }
+        if let Some(placeholder) = self.chunk_geometry_placeholder.take() {
+            placeholder.destroy(&self.device);
+        }
+
+        unsafe {
+            if let Some(pipeline_layout) = self.raytracing_pipeline_layout.take() {
+                self.device.destroy_pipeline_layout(pipeline_layout, None);
+            }
+            if let Some(descriptor_pool) = self.raytracing_descriptor_pool.take() {
+                self.device.destroy_descriptor_pool(descriptor_pool, None);
+            }
+            if let Some(descriptor_set_layout) = self.raytracing_descriptor_set_layout.take() {
+                self.device
+                    .destroy_descriptor_set_layout(descriptor_set_layout, None);
+            }
+        }
+    }
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// Records a layout transition of a single mip, single layer color image.
+fn image_barrier(
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    (old_layout, new_layout): (vk::ImageLayout, vk::ImageLayout),
+    (src_access_mask, dst_access_mask): (vk::AccessFlags, vk::AccessFlags),
+    (src_stage_mask, dst_stage_mask): (vk::PipelineStageFlags, vk::PipelineStageFlags),
+) {
+    let barrier = vk::ImageMemoryBarrier::default()
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(color_subresource_range());
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            src_stage_mask,
+            dst_stage_mask,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier],
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+
+use super::{buffer::VolcanBuffer, init::Volcan};
+
+/// Shader binding table of a ray tracing pipeline whose groups are ordered
+/// raygen, misses, then hit groups (see `VolcanPipeline::create_raytracing_pipeline`).
+pub struct ShaderBindingTable {
+    pub buffer: VolcanBuffer,
+    pub raygen_region: vk::StridedDeviceAddressRegionKHR,
+    pub miss_region: vk::StridedDeviceAddressRegionKHR,
+    pub hit_region: vk::StridedDeviceAddressRegionKHR,
+    pub callable_region: vk::StridedDeviceAddressRegionKHR,
+}
+
+impl ShaderBindingTable {
+    pub fn new(volcan: &Volcan, pipeline: vk::Pipeline, miss_count: u32, hit_count: u32) -> Self {
+        let mut ray_tracing_properties =
+            vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut device_properties2 =
+            vk::PhysicalDeviceProperties2::default().push_next(&mut ray_tracing_properties);
+        unsafe {
+            volcan
+                .instance
+                .get_physical_device_properties2(volcan.physical_device, &mut device_properties2)
+        };
+
+        let handle_size = ray_tracing_properties.shader_group_handle_size as vk::DeviceSize;
+        let handle_stride = handle_size
+            .next_multiple_of(ray_tracing_properties.shader_group_handle_alignment as u64);
+        let base_alignment = ray_tracing_properties.shader_group_base_alignment as vk::DeviceSize;
+
+        let group_count = 1 + miss_count + hit_count;
+        let handles = unsafe {
+            volcan
+                .ray_tracing_pipeline_loader
+                .get_ray_tracing_shader_group_handles(
+                    pipeline,
+                    0,
+                    group_count,
+                    (group_count as vk::DeviceSize * handle_size) as usize,
+                )
+                .expect("Cannot get shader group handles")
+        };
+
+        // The raygen region stride must equal its size.
+        let raygen_size = handle_stride.next_multiple_of(base_alignment);
+        let miss_size =
+            (miss_count as vk::DeviceSize * handle_stride).next_multiple_of(base_alignment);
+        let hit_size =
+            (hit_count as vk::DeviceSize * handle_stride).next_multiple_of(base_alignment);
+
+        let miss_offset = raygen_size;
+        let hit_offset = miss_offset + miss_size;
+
+        let mut table = vec![0u8; (hit_offset + hit_size) as usize];
+        let region_offsets = std::iter::once(0)
+            .chain((0..miss_count).map(|i| miss_offset + i as u64 * handle_stride))
+            .chain((0..hit_count).map(|i| hit_offset + i as u64 * handle_stride));
+
+        for (handle, offset) in handles
+            .chunks_exact(handle_size as usize)
+            .zip(region_offsets)
+        {
+            table[offset as usize..][..handle_size as usize].copy_from_slice(handle);
+        }
+
+        // Over-allocate so the start can be aligned to `shaderGroupBaseAlignment`.
+        let mut padded_table = vec![0u8; table.len() + base_alignment as usize];
+        let buffer = VolcanBuffer::new(
+            volcan,
+            padded_table.len() as vk::DeviceSize,
+            vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::CpuToGpu,
+        );
+
+        let base_address = buffer.device_address.next_multiple_of(base_alignment);
+        let padding = (base_address - buffer.device_address) as usize;
+        padded_table[padding..][..table.len()].copy_from_slice(&table);
+        buffer.write(0, &padded_table);
+
+        let region = |offset: vk::DeviceSize, stride: vk::DeviceSize, size: vk::DeviceSize| {
+            vk::StridedDeviceAddressRegionKHR {
+                device_address: if size == 0 { 0 } else { base_address + offset },
+                stride,
+                size,
+            }
+        };
+
+        Self {
+            raygen_region: region(0, raygen_size, raygen_size),
+            miss_region: region(miss_offset, handle_stride, miss_size),
+            hit_region: region(hit_offset, handle_stride, hit_size),
+            callable_region: vk::StridedDeviceAddressRegionKHR::default(),
+            buffer,
+        }
+    }
+
+    pub fn destroy(self, device: &ash::Device) {
+        self.buffer.destroy(device);
+    }
+}
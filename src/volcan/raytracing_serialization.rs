@@ -0,0 +1,407 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+use log::{info, warn};
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    raytracing_accecleration_structure::{
+        acceleration_structure_build_barrier, create_acceleration_structure, Blas,
+    },
+};
+
+const BLAS_FILE_MAGIC: &[u8; 4] = b"VXAS";
+const BLAS_FILE_VERSION: u32 = 1;
+
+/// magic, version, driver UUID, geometry hash, primitive count, build flags, blob size.
+const BLAS_FILE_HEADER_SIZE: usize = 4 + 4 + vk::UUID_SIZE + 8 + 4 + 4 + 8;
+
+/// The serialized blob starts with the driver and compatibility UUIDs, the serialized size,
+/// then the size the acceleration structure needs once deserialized.
+const SERIALIZED_VERSION_SIZE: usize = 2 * vk::UUID_SIZE;
+const SERIALIZED_DESERIALIZED_SIZE_OFFSET: usize = SERIALIZED_VERSION_SIZE + 8;
+
+/// Serialization copies need 256 byte aligned blob addresses.
+const SERIALIZED_BLOB_ALIGNMENT: vk::DeviceSize = 256;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+struct BlasFileHeader {
+    driver_uuid: [u8; vk::UUID_SIZE],
+    geometry_hash: u64,
+    primitive_count: u32,
+    flags: vk::BuildAccelerationStructureFlagsKHR,
+    blob_size: u64,
+}
+
+impl BlasFileHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BLAS_FILE_HEADER_SIZE);
+        bytes.extend_from_slice(BLAS_FILE_MAGIC);
+        bytes.extend_from_slice(&BLAS_FILE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.driver_uuid);
+        bytes.extend_from_slice(&self.geometry_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.primitive_count.to_le_bytes());
+        bytes.extend_from_slice(&self.flags.as_raw().to_le_bytes());
+        bytes.extend_from_slice(&self.blob_size.to_le_bytes());
+        bytes
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < BLAS_FILE_HEADER_SIZE || &data[0..4] != BLAS_FILE_MAGIC {
+            return None;
+        }
+
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        if read_u32(4) != BLAS_FILE_VERSION {
+            return None;
+        }
+
+        let uuid_end = 8 + vk::UUID_SIZE;
+        Some(Self {
+            driver_uuid: data[8..uuid_end].try_into().unwrap(),
+            geometry_hash: read_u64(uuid_end),
+            primitive_count: read_u32(uuid_end + 8),
+            flags: vk::BuildAccelerationStructureFlagsKHR::from_raw(read_u32(uuid_end + 12)),
+            blob_size: read_u64(uuid_end + 16),
+        })
+    }
+}
+
+impl Volcan {
+    /// Identifies the driver build, serialized acceleration structures are only valid for it.
+    pub fn driver_uuid(&self) -> [u8; vk::UUID_SIZE] {
+        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+        let mut device_properties2 =
+            vk::PhysicalDeviceProperties2::default().push_next(&mut id_properties);
+
+        unsafe {
+            self.instance
+                .get_physical_device_properties2(self.physical_device, &mut device_properties2)
+        };
+
+        id_properties.driver_uuid
+    }
+
+    /// Where [`Blas::load_or_build_aabbs`] stores the BLAS called `name`.
+    pub fn blas_cache_path(name: &str) -> PathBuf {
+        Self::cache_directory()
+            .join("blas")
+            .join(format!("{name}.vxas"))
+    }
+}
+
+/// Hash of the AABBs a BLAS was built from, to detect stale files. FNV-1a, so that saved files
+/// stay valid across Rust releases.
+pub fn aabbs_hash(aabbs: &[vk::AabbPositionsKHR]) -> u64 {
+    aabbs
+        .iter()
+        .flat_map(|aabb| {
+            [
+                aabb.min_x, aabb.min_y, aabb.min_z, aabb.max_x, aabb.max_y, aabb.max_z,
+            ]
+        })
+        .flat_map(f32::to_le_bytes)
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Creates a buffer holding a serialized blob of `size` bytes at the returned offset, whose
+/// device address is aligned as the serialization copies require.
+fn create_blob_buffer(
+    volcan: &Volcan,
+    size: vk::DeviceSize,
+    location: MemoryLocation,
+) -> (VolcanBuffer, vk::DeviceSize) {
+    let buffer = VolcanBuffer::new(
+        volcan,
+        size + SERIALIZED_BLOB_ALIGNMENT - 1,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+        location,
+    );
+
+    let offset = buffer
+        .device_address
+        .next_multiple_of(SERIALIZED_BLOB_ALIGNMENT)
+        - buffer.device_address;
+    (buffer, offset)
+}
+
+impl Blas {
+    /// Size of the blob written by a `SERIALIZE` copy of this BLAS.
+    fn serialization_size(&self, volcan: &Volcan) -> vk::DeviceSize {
+        let query_pool_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::ACCELERATION_STRUCTURE_SERIALIZATION_SIZE_KHR)
+            .query_count(1);
+
+        let query_pool = unsafe {
+            volcan
+                .device
+                .create_query_pool(&query_pool_info, None)
+                .expect("Cannot create serialization size query pool")
+        };
+
+        volcan.execute_one_time_commands(|command_buffer| unsafe {
+            volcan
+                .device
+                .cmd_reset_query_pool(command_buffer, query_pool, 0, 1);
+            volcan
+                .acceleration_structure_loader
+                .cmd_write_acceleration_structures_properties(
+                    command_buffer,
+                    &[self.handle],
+                    vk::QueryType::ACCELERATION_STRUCTURE_SERIALIZATION_SIZE_KHR,
+                    query_pool,
+                    0,
+                );
+        });
+
+        let mut size = [0u64];
+        unsafe {
+            volcan
+                .device
+                .get_query_pool_results(
+                    query_pool,
+                    0,
+                    &mut size,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+                )
+                .expect("Cannot read serialization size query");
+            volcan.device.destroy_query_pool(query_pool, None);
+        }
+
+        size[0]
+    }
+
+    /// Serializes this BLAS to `path`. Waits for the GPU, meant for loading screens and exit.
+    pub fn save(&self, volcan: &Volcan, path: &Path, geometry_hash: u64) -> io::Result<()> {
+        let blob_size = self.serialization_size(volcan);
+
+        let (blob_buffer, blob_offset) =
+            create_blob_buffer(volcan, blob_size, MemoryLocation::GpuToCpu);
+
+        volcan.execute_one_time_commands(|command_buffer| {
+            let copy_info = vk::CopyAccelerationStructureToMemoryInfoKHR::default()
+                .src(self.handle)
+                .dst(vk::DeviceOrHostAddressKHR {
+                    device_address: blob_buffer.device_address + blob_offset,
+                })
+                .mode(vk::CopyAccelerationStructureModeKHR::SERIALIZE);
+
+            let memory_barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+
+            unsafe {
+                volcan
+                    .acceleration_structure_loader
+                    .cmd_copy_acceleration_structure_to_memory(command_buffer, &copy_info);
+                volcan.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[memory_barrier],
+                    &[],
+                    &[],
+                );
+            }
+        });
+
+        let blob = blob_buffer.read();
+        let blob = &blob[blob_offset as usize..(blob_offset + blob_size) as usize];
+        blob_buffer.destroy(&volcan.device);
+
+        let header = BlasFileHeader {
+            driver_uuid: volcan.driver_uuid(),
+            geometry_hash,
+            primitive_count: self.primitive_count,
+            flags: self.flags,
+            blob_size,
+        };
+
+        let mut data = header.to_bytes();
+        data.extend_from_slice(blob);
+
+        // Write to a temporary file first so a crash never leaves a truncated blob behind.
+        let tmp_path = path.with_extension("tmp");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&tmp_path, &data)?;
+        std::fs::rename(&tmp_path, path)?;
+
+        info!("Saved BLAS ({} bytes) to {}", data.len(), path.display());
+        Ok(())
+    }
+
+    /// Deserializes a BLAS saved by [`Blas::save`]. Returns `None` when the file is missing,
+    /// stale (other geometry) or was written by another driver.
+    pub fn load(volcan: &Volcan, path: &Path, geometry_hash: u64) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+
+        let Some(header) = BlasFileHeader::parse(&data) else {
+            warn!("{} is not a BLAS file, ignoring it.", path.display());
+            return None;
+        };
+
+        let blob = &data[BLAS_FILE_HEADER_SIZE..];
+        if blob.len() as u64 != header.blob_size
+            || blob.len() < SERIALIZED_DESERIALIZED_SIZE_OFFSET + 8
+        {
+            warn!("BLAS file {} is truncated, ignoring it.", path.display());
+            return None;
+        }
+
+        if header.geometry_hash != geometry_hash {
+            info!("BLAS file {} is stale, ignoring it.", path.display());
+            return None;
+        }
+
+        if header.driver_uuid != volcan.driver_uuid() {
+            warn!(
+                "BLAS file {} was created by another driver, ignoring it.",
+                path.display()
+            );
+            return None;
+        }
+
+        let version_data: &[u8; SERIALIZED_VERSION_SIZE] =
+            blob[..SERIALIZED_VERSION_SIZE].try_into().unwrap();
+        let compatibility = unsafe {
+            volcan
+                .acceleration_structure_loader
+                .get_device_acceleration_structure_compatibility(
+                    &vk::AccelerationStructureVersionInfoKHR::default().version_data(version_data),
+                )
+        };
+
+        if compatibility != vk::AccelerationStructureCompatibilityKHR::COMPATIBLE {
+            warn!(
+                "BLAS file {} is incompatible with this device, ignoring it.",
+                path.display()
+            );
+            return None;
+        }
+
+        let deserialized_size = u64::from_ne_bytes(
+            blob[SERIALIZED_DESERIALIZED_SIZE_OFFSET..SERIALIZED_DESERIALIZED_SIZE_OFFSET + 8]
+                .try_into()
+                .unwrap(),
+        );
+
+        let (blob_buffer, blob_offset) =
+            create_blob_buffer(volcan, header.blob_size, MemoryLocation::CpuToGpu);
+        blob_buffer.write(blob_offset, blob);
+
+        let (handle, buffer) = create_acceleration_structure(
+            volcan,
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+            deserialized_size,
+        );
+
+        volcan.execute_one_time_commands(|command_buffer| {
+            let copy_info = vk::CopyMemoryToAccelerationStructureInfoKHR::default()
+                .src(vk::DeviceOrHostAddressConstKHR {
+                    device_address: blob_buffer.device_address + blob_offset,
+                })
+                .dst(handle)
+                .mode(vk::CopyAccelerationStructureModeKHR::DESERIALIZE);
+
+            unsafe {
+                volcan
+                    .acceleration_structure_loader
+                    .cmd_copy_memory_to_acceleration_structure(command_buffer, &copy_info);
+            }
+
+            acceleration_structure_build_barrier(&volcan.device, command_buffer);
+        });
+
+        blob_buffer.destroy(&volcan.device);
+
+        info!(
+            "Loaded BLAS ({} primitives) from {}",
+            header.primitive_count,
+            path.display()
+        );
+
+        Some(Self::from_copy(
+            volcan,
+            handle,
+            buffer,
+            header.primitive_count,
+            header.flags,
+        ))
+    }
+
+    /// Loads the BLAS called `name` from the cache directory, or builds it from `aabbs`
+    /// and saves it for the next launch. Builds run on the host when the device supports it.
+    pub fn load_or_build_aabbs(
+        volcan: &Volcan,
+        name: &str,
+        aabbs: &[vk::AabbPositionsKHR],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Self {
+        let path = Volcan::blas_cache_path(name);
+        let geometry_hash = aabbs_hash(aabbs);
+
+        if let Some(blas) = Self::load(volcan, &path, geometry_hash) {
+            return blas;
+        }
+
+        let host_blas = volcan.host_blas_builder().and_then(|builder| {
+            match builder.build_aabbs(aabbs, flags) {
+                Ok(blas) => Some(blas),
+                Err(err) => {
+                    warn!("Host BLAS build failed, building {name} on the device: {err}");
+                    None
+                }
+            }
+        });
+        let blas = host_blas.unwrap_or_else(|| {
+            let mut blas = volcan.execute_one_time_commands(|command_buffer| {
+                Self::build_aabbs(volcan, command_buffer, aabbs, flags)
+            });
+            blas.free_build_resources(&volcan.device);
+            blas
+        });
+
+        if let Err(err) = blas.save(volcan, &path, geometry_hash) {
+            warn!("Cannot save BLAS to {}: {err}", path.display());
+        }
+
+        blas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aabbs_hash_is_stable() {
+        let unit = vk::AabbPositionsKHR {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 1.0,
+            max_y: 1.0,
+            max_z: 1.0,
+        };
+
+        // Saved files are matched against these values.
+        assert_eq!(aabbs_hash(&[]), FNV_OFFSET_BASIS);
+        assert_eq!(aabbs_hash(&[unit]), 0x42a1_8453_c511_f358);
+    }
+}
@@ -0,0 +1,307 @@
+use std::ffi::CString;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::IVec3;
+
+use crate::world::{
+    material::MaterialId,
+    sparse_tree::{SparseVoxelTree, TreeHit},
+    voxel_dda::Ray,
+};
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    pipeline::VolcanPipeline,
+    shader_library::{ShaderLibrary, SpecializationConstants},
+    shader_modules::VolcanShaderModule,
+};
+
+/// Shaders of [`SparseTreeRaycaster`].
+pub const SPARSE_TREE_RAYCAST_SHADERS: &[&str] = &["sparse_tree_raycast.comp"];
+
+/// Specialization constant of the `local_size_x` of `sparse_tree_raycast.comp`.
+const SPARSE_TREE_RAYCAST_GROUP_SIZE_ID: u32 = 0;
+
+pub const SPARSE_TREE_RAYCAST_PUSH_CONSTANTS: vk::PushConstantRange = vk::PushConstantRange {
+    stage_flags: vk::ShaderStageFlags::COMPUTE,
+    offset: 0,
+    size: std::mem::size_of::<SparseTreeRaycastPushConstants>() as u32,
+};
+
+/// Matches `SparseTree` in `shaders/include/sparse_tree.glsl`, to put in a uniform or storage
+/// buffer read by the shader calling `traceSparseTree`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct SparseTreeGeometry {
+    pub nodes: vk::DeviceAddress,
+    pub materials: vk::DeviceAddress,
+    pub origin: [i32; 3],
+    pub depth: u32,
+}
+
+/// GPU copy of a [`SparseVoxelTree`], read through device addresses.
+pub struct SparseTreeBuffers {
+    pub nodes: VolcanBuffer,
+    pub materials: VolcanBuffer,
+    pub origin: [i32; 3],
+    pub depth: u32,
+}
+
+impl SparseTreeBuffers {
+    pub fn new(volcan: &Volcan, tree: &SparseVoxelTree) -> Self {
+        let usage =
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        let (nodes, materials) = tree.gpu_data();
+
+        Self {
+            nodes: VolcanBuffer::new_with_data(volcan, usage, nodes),
+            materials: VolcanBuffer::new_with_data(volcan, usage, &materials),
+            origin: tree.origin.to_array(),
+            depth: tree.depth,
+        }
+    }
+
+    pub fn geometry(&self) -> SparseTreeGeometry {
+        SparseTreeGeometry {
+            nodes: self.nodes.device_address,
+            materials: self.materials.device_address,
+            origin: self.origin,
+            depth: self.depth,
+        }
+    }
+
+    pub fn destroy(self, device: &ash::Device) {
+        self.nodes.destroy(device);
+        self.materials.destroy(device);
+    }
+}
+
+/// Matches `SparseTreeRay` in `sparse_tree_raycast.comp`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Pod, Zeroable)]
+struct SparseTreeRay {
+    origin: [f32; 3],
+    t_min: f32,
+    direction: [f32; 3],
+    t_max: f32,
+}
+
+/// Matches `SparseTreeRayHit` in `sparse_tree_raycast.comp`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Pod, Zeroable)]
+struct SparseTreeRayHit {
+    voxel: [i32; 3],
+    t: f32,
+    face: u32,
+    material: u32,
+    hit: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable)]
+struct SparseTreeRaycastPushConstants {
+    tree: SparseTreeGeometry,
+    rays: vk::DeviceAddress,
+    hits: vk::DeviceAddress,
+    ray_count: u32,
+    _padding: u32,
+}
+
+/// Casts batches of rays through [`SparseTreeBuffers`] on the GPU, the counterpart of
+/// [`SparseVoxelTree::raycast`].
+pub struct SparseTreeRaycaster {
+    pub pipeline_layout: vk::PipelineLayout,
+    pub pipeline: vk::Pipeline,
+    /// Invocations per workgroup, specialized to one subgroup of the device.
+    group_size: u32,
+}
+
+impl Volcan {
+    /// Invocations per subgroup, at most the workgroup width of compute shaders.
+    fn compute_subgroup_size(&self) -> u32 {
+        let mut subgroup_properties = vk::PhysicalDeviceSubgroupProperties::default();
+        let mut device_properties2 =
+            vk::PhysicalDeviceProperties2::default().push_next(&mut subgroup_properties);
+
+        unsafe {
+            self.instance
+                .get_physical_device_properties2(self.physical_device, &mut device_properties2)
+        };
+
+        let max_width = device_properties2
+            .properties
+            .limits
+            .max_compute_work_group_size[0];
+        subgroup_properties.subgroup_size.clamp(1, max_width.max(1))
+    }
+}
+
+impl SparseTreeRaycaster {
+    pub fn new(volcan: &mut Volcan) -> Result<Self, vk::Result> {
+        VolcanPipeline::check_layout(
+            "Sparse tree raycast",
+            SPARSE_TREE_RAYCAST_SHADERS,
+            &[],
+            &[SPARSE_TREE_RAYCAST_PUSH_CONSTANTS],
+        );
+
+        let push_constant_ranges = [SPARSE_TREE_RAYCAST_PUSH_CONSTANTS];
+        let pipeline_layout_info =
+            vk::PipelineLayoutCreateInfo::default().push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe {
+            volcan
+                .device
+                .create_pipeline_layout(&pipeline_layout_info, None)?
+        };
+
+        let module = match volcan.shader_library.module(SPARSE_TREE_RAYCAST_SHADERS[0]) {
+            Ok(module) => module,
+            Err(error) => {
+                unsafe { volcan.device.destroy_pipeline_layout(pipeline_layout, None) };
+                return Err(error);
+            }
+        };
+
+        let reflection = VolcanShaderModule::reflect(SPARSE_TREE_RAYCAST_SHADERS[0]);
+        let shader_entry_point = &reflection.entry_points[0];
+        // A single subgroup per workgroup, the reflected size is the largest one the shader
+        // was written for.
+        let declared_size = shader_entry_point.workgroup_size.map_or(1, |size| size[0]);
+        let group_size = volcan.compute_subgroup_size().min(declared_size);
+        let specialization =
+            SpecializationConstants::new().with(SPARSE_TREE_RAYCAST_GROUP_SIZE_ID, group_size);
+        let specialization_info = specialization.info();
+        let entry_point = CString::new(shader_entry_point.name.as_str()).unwrap();
+        let pipeline_info = vk::ComputePipelineCreateInfo::default()
+            .stage(ShaderLibrary::stage_info(
+                module,
+                vk::ShaderStageFlags::COMPUTE,
+                &entry_point,
+                Some(&specialization_info),
+            ))
+            .layout(pipeline_layout);
+
+        let pipeline = unsafe {
+            volcan
+                .device
+                .create_compute_pipelines(*volcan.pipeline_cache, &[pipeline_info], None)
+        };
+        let pipeline = match pipeline {
+            Ok(pipelines) => pipelines[0],
+            Err((_, error)) => {
+                volcan.shader_library.collect_unused();
+                unsafe { volcan.device.destroy_pipeline_layout(pipeline_layout, None) };
+                return Err(error);
+            }
+        };
+        volcan.shader_library.bind_pipeline(pipeline, &[module]);
+
+        Ok(Self {
+            pipeline_layout,
+            pipeline,
+            group_size,
+        })
+    }
+
+    /// Closest hit of each world space ray between its `t_min` and `t_max`. Waits for the GPU.
+    pub fn raycast(
+        &self,
+        volcan: &Volcan,
+        tree: &SparseTreeBuffers,
+        rays: &[(Ray, f32, f32)],
+    ) -> Vec<Option<TreeHit>> {
+        if rays.is_empty() {
+            return Vec::new();
+        }
+
+        let usage =
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        let gpu_rays: Vec<SparseTreeRay> = rays
+            .iter()
+            .map(|(ray, t_min, t_max)| SparseTreeRay {
+                origin: ray.origin.to_array(),
+                t_min: *t_min,
+                direction: ray.direction.to_array(),
+                t_max: *t_max,
+            })
+            .collect();
+        let ray_buffer = VolcanBuffer::new_with_data(volcan, usage, &gpu_rays);
+        let hit_buffer = VolcanBuffer::new_with_data(
+            volcan,
+            usage,
+            &vec![SparseTreeRayHit::default(); rays.len()],
+        );
+
+        let push_constants = SparseTreeRaycastPushConstants {
+            tree: tree.geometry(),
+            rays: ray_buffer.device_address,
+            hits: hit_buffer.device_address,
+            ray_count: rays.len() as u32,
+            _padding: 0,
+        };
+
+        volcan.execute_one_time_commands(|command_buffer| unsafe {
+            volcan.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            volcan.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                SPARSE_TREE_RAYCAST_PUSH_CONSTANTS.stage_flags,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+            volcan.device.cmd_dispatch(
+                command_buffer,
+                (rays.len() as u32).div_ceil(self.group_size),
+                1,
+                1,
+            );
+
+            // Makes the hits visible to the host read below.
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            volcan.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        });
+
+        let hit_bytes = hit_buffer.read();
+        ray_buffer.destroy(&volcan.device);
+        hit_buffer.destroy(&volcan.device);
+
+        bytemuck::cast_slice::<u8, SparseTreeRayHit>(&hit_bytes)
+            .iter()
+            .map(|hit| {
+                (hit.hit != 0).then(|| TreeHit {
+                    t: hit.t,
+                    voxel: IVec3::from_array(hit.voxel),
+                    face: hit.face,
+                    material: MaterialId(hit.material as u16),
+                })
+            })
+            .collect()
+    }
+
+    pub fn destroy(&self, volcan: &mut Volcan) {
+        unsafe {
+            volcan.device.destroy_pipeline(self.pipeline, None);
+            volcan
+                .device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+        }
+        volcan.shader_library.release_pipeline(self.pipeline);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+
+use ash::vk;
+use glam::Affine3A;
+use gpu_allocator::MemoryLocation;
+
+use super::{
+    buffer::VolcanBuffer,
+    init::Volcan,
+    raytracing_accecleration_structure::{
+        acceleration_structure_build_barrier, acceleration_structure_device_address,
+        create_acceleration_structure, create_scratch_buffer,
+    },
+};
+
+/// Refitting degrades the TLAS quality, force a full rebuild from time to time.
+const MAX_REFITS_BEFORE_REBUILD: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstanceId(u32);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlasInstance {
+    /// Device address of the referenced BLAS.
+    pub blas_address: vk::DeviceAddress,
+    pub transform: Affine3A,
+    /// `gl_InstanceCustomIndexEXT`, 24 bits.
+    pub custom_index: u32,
+    pub mask: u8,
+    /// Hit group offset in the shader binding table, 24 bits.
+    pub sbt_offset: u32,
+    pub flags: vk::GeometryInstanceFlagsKHR,
+}
+
+impl TlasInstance {
+    pub fn new(blas_address: vk::DeviceAddress, transform: Affine3A) -> Self {
+        Self {
+            blas_address,
+            transform,
+            custom_index: 0,
+            mask: 0xFF,
+            sbt_offset: 0,
+            flags: vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE,
+        }
+    }
+
+    fn to_vk(self) -> vk::AccelerationStructureInstanceKHR {
+        vk::AccelerationStructureInstanceKHR {
+            transform: affine_to_transform_matrix(&self.transform),
+            instance_custom_index_and_mask: vk::Packed24_8::new(self.custom_index, self.mask),
+            instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                self.sbt_offset,
+                self.flags.as_raw() as u8,
+            ),
+            acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                device_handle: self.blas_address,
+            },
+        }
+    }
+}
+
+/// Row-major 3x4 matrix expected by `VkTransformMatrixKHR`.
+pub fn affine_to_transform_matrix(transform: &Affine3A) -> vk::TransformMatrixKHR {
+    let m = transform.matrix3;
+    let t = transform.translation;
+
+    vk::TransformMatrixKHR {
+        matrix: [
+            m.x_axis.x, m.y_axis.x, m.z_axis.x, t.x, //
+            m.x_axis.y, m.y_axis.y, m.z_axis.y, t.y, //
+            m.x_axis.z, m.y_axis.z, m.z_axis.z, t.z,
+        ],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlasUpdate {
+    None,
+    /// Only transforms changed, the existing TLAS is updated in place.
+    Refit,
+    Rebuild,
+}
+
+/// Top level acceleration structure over a set of BLAS instances.
+///
+/// Changes are only recorded on the next [`Tlas::update`], which is expected to be called once
+/// per frame after the previous frame's fence was waited on.
+#[derive(Default)]
+pub struct Tlas {
+    pub handle: vk::AccelerationStructureKHR,
+    pub device_address: vk::DeviceAddress,
+
+    instances: BTreeMap<InstanceId, TlasInstance>,
+    next_instance_id: u32,
+
+    buffer: Option<VolcanBuffer>,
+    instance_buffer: Option<VolcanBuffer>,
+    scratch_buffer: Option<(VolcanBuffer, vk::DeviceAddress)>,
+    /// Resources replaced during the last update, possibly still read by the previous frame.
+    retired: Vec<(vk::AccelerationStructureKHR, VolcanBuffer)>,
+
+    built_instance_count: Option<usize>,
+    instances_changed: bool,
+    transforms_changed: bool,
+    refit_count: u32,
+}
+
+impl Tlas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_instance(&mut self, instance: TlasInstance) -> InstanceId {
+        let id = InstanceId(self.next_instance_id);
+        self.next_instance_id += 1;
+
+        self.instances.insert(id, instance);
+        self.instances_changed = true;
+        id
+    }
+
+    pub fn remove_instance(&mut self, id: InstanceId) -> Option<TlasInstance> {
+        let removed = self.instances.remove(&id);
+        self.instances_changed |= removed.is_some();
+        removed
+    }
+
+    pub fn update_transform(&mut self, id: InstanceId, transform: Affine3A) {
+        if let Some(instance) = self.instances.get_mut(&id) {
+            instance.transform = transform;
+            self.transforms_changed = true;
+        }
+    }
+
+    /// Changing anything but the transform (BLAS, mask, SBT offset...) requires a rebuild.
+    pub fn update_instance(&mut self, id: InstanceId, instance: TlasInstance) {
+        if let Some(existing) = self.instances.get_mut(&id) {
+            *existing = instance;
+            self.instances_changed = true;
+        }
+    }
+
+    /// A refitted BLAS keeps its address but its bounds changed, the TLAS needs a refit too.
+    pub fn mark_blas_refitted(&mut self) {
+        self.transforms_changed = true;
+    }
+
+    /// Points every instance of the BLAS at `old_address` to `new_address`,
+    /// e.g. after the BLAS was compacted.
+    pub fn retarget_blas(
+        &mut self,
+        old_address: vk::DeviceAddress,
+        new_address: vk::DeviceAddress,
+    ) {
+        for instance in self.instances.values_mut() {
+            if instance.blas_address == old_address {
+                instance.blas_address = new_address;
+                self.instances_changed = true;
+            }
+        }
+    }
+
+    pub fn instance(&self, id: InstanceId) -> Option<&TlasInstance> {
+        self.instances.get(&id)
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn pending_update(&self) -> TlasUpdate {
+        match self.built_instance_count {
+            None => TlasUpdate::Rebuild,
+            Some(_) if self.instances_changed => TlasUpdate::Rebuild,
+            Some(_) if !self.transforms_changed => TlasUpdate::None,
+            Some(count) if count != self.instances.len() => TlasUpdate::Rebuild,
+            Some(_) if self.refit_count >= MAX_REFITS_BEFORE_REBUILD => TlasUpdate::Rebuild,
+            Some(_) => TlasUpdate::Refit,
+        }
+    }
+
+    /// Records a rebuild or a refit of the TLAS if instances changed since the last call.
+    pub fn update(&mut self, volcan: &Volcan, command_buffer: vk::CommandBuffer) -> TlasUpdate {
+        for (handle, buffer) in self.retired.drain(..) {
+            unsafe {
+                volcan
+                    .acceleration_structure_loader
+                    .destroy_acceleration_structure(handle, None);
+            }
+            buffer.destroy(&volcan.device);
+        }
+
+        let update = self.pending_update();
+        if update == TlasUpdate::None {
+            return update;
+        }
+
+        let instances: Vec<vk::AccelerationStructureInstanceKHR> = self
+            .instances
+            .values()
+            .map(|instance| instance.to_vk())
+            .collect();
+
+        self.upload_instances(volcan, &instances);
+
+        let instances_data = vk::AccelerationStructureGeometryInstancesDataKHR::default()
+            .array_of_pointers(false)
+            .data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.instance_buffer.as_ref().unwrap().device_address,
+            });
+
+        let tlas_geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                instances: instances_data,
+            });
+
+        let tlas_geometry_binding = [tlas_geometry];
+        let mut build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(
+                vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                    | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+            )
+            .geometries(&tlas_geometry_binding);
+
+        let instance_count = instances.len() as u32;
+        let mut build_size_info = vk::AccelerationStructureBuildSizesInfoKHR::default();
+
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &build_geometry_info,
+                    &[instance_count],
+                    &mut build_size_info,
+                )
+        };
+
+        if update == TlasUpdate::Rebuild {
+            let fits = self
+                .buffer
+                .as_ref()
+                .is_some_and(|buffer| buffer.size >= build_size_info.acceleration_structure_size);
+
+            if !fits {
+                let (handle, buffer) = create_acceleration_structure(
+                    volcan,
+                    vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+                    build_size_info.acceleration_structure_size,
+                );
+
+                if let Some(previous_buffer) = self.buffer.replace(buffer) {
+                    self.retired.push((self.handle, previous_buffer));
+                }
+                self.handle = handle;
+                self.device_address = acceleration_structure_device_address(volcan, handle);
+            }
+
+            build_geometry_info = build_geometry_info
+                .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+                .dst_acceleration_structure(self.handle);
+        } else {
+            build_geometry_info = build_geometry_info
+                .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+                .src_acceleration_structure(self.handle)
+                .dst_acceleration_structure(self.handle);
+        }
+
+        let scratch_size = build_size_info
+            .build_scratch_size
+            .max(build_size_info.update_scratch_size);
+        let scratch_fits = self
+            .scratch_buffer
+            .as_ref()
+            .is_some_and(|(buffer, address)| {
+                buffer.device_address + buffer.size >= address + scratch_size
+            });
+
+        if !scratch_fits {
+            if let Some((previous, _)) = self.scratch_buffer.take() {
+                self.retired
+                    .push((vk::AccelerationStructureKHR::null(), previous));
+            }
+            self.scratch_buffer = Some(create_scratch_buffer(volcan, scratch_size));
+        }
+
+        build_geometry_info = build_geometry_info.scratch_data(vk::DeviceOrHostAddressKHR {
+            device_address: self.scratch_buffer.as_ref().unwrap().1,
+        });
+
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR {
+            primitive_count: instance_count,
+            primitive_offset: 0,
+            first_vertex: 0,
+            transform_offset: 0,
+        };
+
+        unsafe {
+            volcan
+                .acceleration_structure_loader
+                .cmd_build_acceleration_structures(
+                    command_buffer,
+                    &[build_geometry_info],
+                    &[&[build_range_info]],
+                );
+        }
+
+        acceleration_structure_build_barrier(&volcan.device, command_buffer);
+
+        match update {
+            TlasUpdate::Refit => self.refit_count += 1,
+            _ => self.refit_count = 0,
+        }
+        self.built_instance_count = Some(instances.len());
+        self.instances_changed = false;
+        self.transforms_changed = false;
+
+        update
+    }
+
+    fn upload_instances(
+        &mut self,
+        volcan: &Volcan,
+        instances: &[vk::AccelerationStructureInstanceKHR],
+    ) {
+        let size = std::mem::size_of_val(instances).max(1) as vk::DeviceSize;
+
+        let fits = self
+            .instance_buffer
+            .as_ref()
+            .is_some_and(|buffer| buffer.size >= size);
+
+        if !fits {
+            // Grow geometrically so adding instances one by one does not reallocate every frame.
+            let capacity = size.max(self.instance_buffer.as_ref().map_or(0, |b| b.size * 2));
+            let buffer = VolcanBuffer::new(
+                volcan,
+                capacity,
+                vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                    | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                MemoryLocation::CpuToGpu,
+            );
+
+            if let Some(previous) = self.instance_buffer.replace(buffer) {
+                self.retired
+                    .push((vk::AccelerationStructureKHR::null(), previous));
+            }
+        }
+
+        self.instance_buffer.as_ref().unwrap().write(0, instances);
+    }
+
+    pub fn destroy(&mut self, volcan: &Volcan) {
+        let mut buffers: Vec<_> = self.retired.drain(..).collect();
+        if let Some(buffer) = self.buffer.take() {
+            buffers.push((self.handle, buffer));
+        }
+
+        for (handle, buffer) in buffers {
+            unsafe {
+                volcan
+                    .acceleration_structure_loader
+                    .destroy_acceleration_structure(handle, None);
+            }
+            buffer.destroy(&volcan.device);
+        }
+
+        for buffer in [
+            self.instance_buffer.take(),
+            self.scratch_buffer.take().map(|(buffer, _)| buffer),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            buffer.destroy(&volcan.device);
+        }
+
+        self.handle = vk::AccelerationStructureKHR::null();
+        self.device_address = 0;
+        self.built_instance_count = None;
+    }
+}
@@ -0,0 +1,230 @@
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use gpu_allocator::MemoryLocation;
+
+use crate::world::chunk_aabbs::{AabbGranularity, ChunkAabbs};
+
+use super::{buffer::VolcanBuffer, init::Volcan};
+
+/// Hit group of the voxel chunks in the shader binding table, to use as
+/// [`super::raytracing_tlas::TlasInstance::sbt_offset`] of chunk instances.
+pub const VOXEL_HIT_GROUP_SBT_OFFSET: u32 = 1;
+
+/// Entry of the chunk geometry table bound at set 0 binding 2, indexed by the instance
+/// custom index. Matches `ChunkGeometry` in `shaders/include/voxel_geometry.glsl`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct ChunkGeometry {
+    pub aabbs: vk::DeviceAddress,
+    pub primitives: vk::DeviceAddress,
+    /// Zero unless the granularity is [`AabbGranularity::Brick`].
+    pub bricks: vk::DeviceAddress,
+    /// Palette compressed voxels of the chunk, zero when `bricks` is.
+    pub materials: vk::DeviceAddress,
+    pub granularity: u32,
+    pub _padding: u32,
+}
+
+/// Index of the granularity in the shaders, `AABB_GRANULARITY_*`.
+pub fn granularity_index(granularity: AabbGranularity) -> u32 {
+    match granularity {
+        AabbGranularity::Voxel => 0,
+        AabbGranularity::Brick => 1,
+        AabbGranularity::Greedy => 2,
+    }
+}
+
+/// GPU copy of a [`ChunkAabbs`], read by `intersection.rint` through device addresses.
+pub struct ChunkGeometryBuffers {
+    /// Can also be the input of the chunk BLAS build.
+    pub aabbs: VolcanBuffer,
+    pub primitives: VolcanBuffer,
+    pub bricks: Option<VolcanBuffer>,
+    /// Palette compressed voxels, read for the brick materials.
+    pub materials: Option<VolcanBuffer>,
+    pub granularity: AabbGranularity,
+}
+
+impl ChunkGeometryBuffers {
+    pub fn new(volcan: &Volcan, chunk_aabbs: &ChunkAabbs) -> Self {
+        let usage =
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+
+        let aabbs = VolcanBuffer::new_with_data(
+            volcan,
+            usage | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+            &chunk_aabbs.aabbs,
+        );
+        let primitives = VolcanBuffer::new_with_data(volcan, usage, &chunk_aabbs.primitives);
+        let bricks = (!chunk_aabbs.bricks.is_empty())
+            .then(|| VolcanBuffer::new_with_data(volcan, usage, &chunk_aabbs.bricks));
+        let materials = (!chunk_aabbs.materials.is_empty())
+            .then(|| VolcanBuffer::new_with_data(volcan, usage, &chunk_aabbs.materials));
+
+        Self {
+            aabbs,
+            primitives,
+            bricks,
+            materials,
+            granularity: chunk_aabbs.granularity,
+        }
+    }
+
+    /// Device local version of [`ChunkGeometryBuffers::new`]: the data goes through a staging
+    /// buffer whose copies are recorded into `command_buffer`. The returned staging buffer must
+    /// live until it has executed, and [`record_upload_barrier`] must follow the uploads.
+    pub fn upload(
+        volcan: &Volcan,
+        command_buffer: vk::CommandBuffer,
+        chunk_aabbs: &ChunkAabbs,
+    ) -> (Self, VolcanBuffer) {
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER
+            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+            | vk::BufferUsageFlags::TRANSFER_DST;
+        let sizes = [
+            std::mem::size_of_val(chunk_aabbs.aabbs.as_slice()),
+            std::mem::size_of_val(chunk_aabbs.primitives.as_slice()),
+            std::mem::size_of_val(chunk_aabbs.bricks.as_slice()),
+            std::mem::size_of_val(chunk_aabbs.materials.as_slice()),
+        ];
+
+        let staging = VolcanBuffer::new(
+            volcan,
+            (sizes.iter().sum::<usize>() as vk::DeviceSize).max(1),
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+        );
+        let offsets = [
+            0,
+            sizes[0],
+            sizes[0] + sizes[1],
+            sizes[0] + sizes[1] + sizes[2],
+        ]
+        .map(|offset| offset as vk::DeviceSize);
+        staging.write(offsets[0], &chunk_aabbs.aabbs);
+        staging.write(offsets[1], &chunk_aabbs.primitives);
+        staging.write(offsets[2], &chunk_aabbs.bricks);
+        staging.write(offsets[3], &chunk_aabbs.materials);
+
+        let copy = |index: usize, extra_usage: vk::BufferUsageFlags| {
+            let size = sizes[index] as vk::DeviceSize;
+            let buffer = VolcanBuffer::new(
+                volcan,
+                size.max(1),
+                usage | extra_usage,
+                MemoryLocation::GpuOnly,
+            );
+            if size > 0 {
+                let region = vk::BufferCopy::default()
+                    .src_offset(offsets[index])
+                    .dst_offset(0)
+                    .size(size);
+                unsafe {
+                    volcan.device.cmd_copy_buffer(
+                        command_buffer,
+                        staging.buffer,
+                        buffer.buffer,
+                        &[region],
+                    );
+                }
+            }
+            buffer
+        };
+
+        let buffers = Self {
+            aabbs: copy(
+                0,
+                vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+            ),
+            primitives: copy(1, vk::BufferUsageFlags::empty()),
+            bricks: (sizes[2] > 0).then(|| copy(2, vk::BufferUsageFlags::empty())),
+            materials: (sizes[3] > 0).then(|| copy(3, vk::BufferUsageFlags::empty())),
+            granularity: chunk_aabbs.granularity,
+        };
+        (buffers, staging)
+    }
+
+    /// Device memory of the buffers.
+    pub fn memory_bytes(&self) -> vk::DeviceSize {
+        [
+            Some(&self.aabbs),
+            Some(&self.primitives),
+            self.bricks.as_ref(),
+            self.materials.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|buffer| buffer.size)
+        .sum()
+    }
+
+    pub fn geometry(&self) -> ChunkGeometry {
+        ChunkGeometry {
+            aabbs: self.aabbs.device_address,
+            primitives: self.primitives.device_address,
+            bricks: self
+                .bricks
+                .as_ref()
+                .map_or(0, |bricks| bricks.device_address),
+            materials: self
+                .materials
+                .as_ref()
+                .map_or(0, |materials| materials.device_address),
+            granularity: granularity_index(self.granularity),
+            _padding: 0,
+        }
+    }
+
+    pub fn destroy(self, device: &ash::Device) {
+        self.aabbs.destroy(device);
+        self.primitives.destroy(device);
+        if let Some(bricks) = self.bricks {
+            bricks.destroy(device);
+        }
+        if let Some(materials) = self.materials {
+            materials.destroy(device);
+        }
+    }
+}
+
+/// Makes the copies of [`ChunkGeometryBuffers::upload`] visible to the BLAS builds and the
+/// ray tracing shaders.
+pub fn record_upload_barrier(device: &ash::Device, command_buffer: vk::CommandBuffer) {
+    let memory_barrier = vk::MemoryBarrier::default()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR,
+        );
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR
+                | vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::DependencyFlags::empty(),
+            &[memory_barrier],
+            &[],
+            &[],
+        );
+    }
+}
+
+impl Volcan {
+    /// Points the chunk geometry binding at a table of [`ChunkGeometry`].
+    /// The set must not be in use by a pending frame.
+    pub fn write_chunk_geometry_descriptor(&self, table: vk::Buffer) {
+        let buffer_infos = [vk::DescriptorBufferInfo::default()
+            .buffer(table)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(*self.raytracing_descriptor_set)
+            .dst_binding(2)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&buffer_infos);
+
+        unsafe { self.device.update_descriptor_sets(&[write], &[]) };
+    }
+}
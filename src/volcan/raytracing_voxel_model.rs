@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use glam::{Affine3A, IVec3};
+use log::debug;
+
+use crate::world::{
+    chunk::Chunk,
+    chunk_aabbs::{AabbGranularity, ChunkAabbs},
+    greedy_mesh::ChunkMesh,
+    voxel_world::World,
+};
+
+use super::{
+    init::Volcan,
+    raytracing_accecleration_structure::{Blas, TriangleGeometry},
+    raytracing_build_queue::{AccelerationStructureBuildQueue, DEFAULT_SCRATCH_BUDGET},
+    raytracing_tlas::TlasInstance,
+    raytracing_voxel_geometry::{ChunkGeometry, ChunkGeometryBuffers, VOXEL_HIT_GROUP_SBT_OFFSET},
+};
+
+pub struct VoxelModelChunk {
+    pub geometry: ChunkGeometryBuffers,
+    pub blas: Blas,
+    /// Chunk origin in model space.
+    pub origin: IVec3,
+}
+
+/// A voxel model (e.g. an imported `.vox` model, see [`crate::world::vox`]) as one BLAS per
+/// chunk, in model space. Every placement of the model is a set of TLAS instances sharing them.
+pub struct VoxelModelBlases {
+    pub chunks: Vec<VoxelModelChunk>,
+}
+
+impl VoxelModelBlases {
+    /// Loads the chunk BLASes of the model called `name` from the BLAS cache, building and
+    /// caching the missing ones. Waits for the GPU, meant for loading.
+    pub fn new(volcan: &Volcan, name: &str, model: &World, granularity: AabbGranularity) -> Self {
+        let chunks = model
+            .chunks()
+            .map(|(position, chunk)| {
+                let chunk_aabbs = ChunkAabbs::new(chunk, granularity);
+                let blas_name = format!("{name}_{}_{}_{}", position.x, position.y, position.z);
+                VoxelModelChunk {
+                    geometry: ChunkGeometryBuffers::new(volcan, &chunk_aabbs),
+                    blas: Blas::load_or_build_aabbs(
+                        volcan,
+                        &blas_name,
+                        &chunk_aabbs.aabbs,
+                        vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+                    ),
+                    origin: Chunk::origin(position),
+                }
+            })
+            .collect();
+
+        Self { chunks }
+    }
+
+    /// Entries to append to the chunk geometry table, in chunk order.
+    pub fn geometries(&self) -> impl Iterator<Item = ChunkGeometry> + '_ {
+        self.chunks.iter().map(|chunk| chunk.geometry.geometry())
+    }
+
+    /// Instances placing the model with `transform`, e.g. [`crate::world::vox::VoxInstance::transform`].
+    /// Chunk `i` uses the custom index `first_custom_index + i`, where [`Self::geometries`]
+    /// was written in the geometry table.
+    pub fn instances(&self, transform: Affine3A, first_custom_index: u32) -> Vec<TlasInstance> {
+        self.chunks
+            .iter()
+            .zip(first_custom_index..)
+            .map(|(chunk, custom_index)| TlasInstance {
+                custom_index,
+                sbt_offset: VOXEL_HIT_GROUP_SBT_OFFSET,
+                ..TlasInstance::new(
+                    chunk.blas.device_address,
+                    transform * Affine3A::from_translation(chunk.origin.as_vec3()),
+                )
+            })
+            .collect()
+    }
+
+    pub fn destroy(self, volcan: &Volcan) {
+        for chunk in self.chunks {
+            chunk.geometry.destroy(&volcan.device);
+            chunk.blas.destroy(volcan);
+        }
+    }
+}
+
+/// A voxel model as one triangle BLAS per greedy meshed chunk, in model space, hit through
+/// the triangle hit group instead of `intersection.rint`.
+pub struct VoxelModelMeshBlases {
+    /// BLAS and origin in model space of each non-empty chunk mesh.
+    pub chunks: Vec<(Blas, IVec3)>,
+}
+
+impl VoxelModelMeshBlases {
+    /// Meshes the chunks of `model` and builds their BLASes. Waits for the GPU, meant for loading.
+    pub fn new(volcan: &Volcan, model: &World) -> Self {
+        let meshes: Vec<(ChunkMesh, IVec3)> = model
+            .chunks()
+            .map(|(position, _)| (ChunkMesh::from_world(model, position), position))
+            .filter(|(mesh, _)| !mesh.is_empty())
+            .collect();
+        debug!(
+            "Meshed a voxel model in {} quads",
+            meshes
+                .iter()
+                .map(|(mesh, _)| mesh.quad_count())
+                .sum::<usize>()
+        );
+
+        // One queue so that the chunk builds share their scratch memory.
+        let mut build_queue = AccelerationStructureBuildQueue::new(DEFAULT_SCRATCH_BUDGET);
+        let mut origins = HashMap::with_capacity(meshes.len());
+        let mut mesh_buffers = Vec::with_capacity(meshes.len() * 2);
+        for (mesh, position) in &meshes {
+            let (triangles, buffers) = TriangleGeometry::upload_indexed(
+                volcan,
+                &mesh.vertices,
+                vk::Format::R32G32B32_SFLOAT,
+                &mesh.indices,
+            );
+            let build_id = build_queue.push_triangles(
+                triangles,
+                vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+            );
+            origins.insert(build_id, Chunk::origin(*position));
+            mesh_buffers.extend(buffers);
+        }
+
+        let mut built = Vec::new();
+        volcan.execute_one_time_commands(|command_buffer| {
+            built = build_queue.record(volcan, command_buffer, None);
+        });
+        build_queue.destroy(volcan);
+        for buffer in mesh_buffers {
+            buffer.destroy(&volcan.device);
+        }
+
+        let chunks = built
+            .into_iter()
+            .map(|(build_id, mut blas)| {
+                blas.free_build_resources(&volcan.device);
+                (blas, origins[&build_id])
+            })
+            .collect();
+
+        Self { chunks }
+    }
+
+    /// Instances placing the model with `transform`, see [`VoxelModelBlases::instances`].
+    pub fn instances(&self, transform: Affine3A) -> Vec<TlasInstance> {
+        self.chunks
+            .iter()
+            .map(|(blas, origin)| {
+                TlasInstance::new(
+                    blas.device_address,
+                    transform * Affine3A::from_translation(origin.as_vec3()),
+                )
+            })
+            .collect()
+    }
+
+    pub fn destroy(self, volcan: &Volcan) {
+        for (blas, _) in self.chunks {
+            blas.destroy(volcan);
+        }
+    }
+}
@@ -14,21 +14,55 @@ impl Volcan {
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
 
+        // Only used within the frame, the previous content is cleared.
+        let depth_attachment = vk::AttachmentDescription::default()
+            .format(self.depth_buffer.format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
         let color_attachment_ref = vk::AttachmentReference {
             attachment: 0,
             layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
         };
+        let depth_attachment_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
 
         let color_attachments_ref = [color_attachment_ref];
         let subpass = vk::SubpassDescription::default()
             .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .color_attachments(&color_attachments_ref);
+            .color_attachments(&color_attachments_ref)
+            .depth_stencil_attachment(&depth_attachment_ref);
+
+        // The swapchain image is acquired for the color output stage, and the depth buffer is
+        // shared with the previous frame.
+        let fragment_stages = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        let dependency = vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(fragment_stages)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(fragment_stages)
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            );
 
-        let color_attachments = [color_attachment];
+        let attachments = [color_attachment, depth_attachment];
         let subpasses = [subpass];
+        let dependencies = [dependency];
         let render_pass_info = vk::RenderPassCreateInfo::default()
-            .attachments(&color_attachments)
-            .subpasses(&subpasses);
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
 
         let render_pass = unsafe {
             self.device
@@ -36,7 +70,7 @@ impl Volcan {
                 .expect("Unable to create RenderPass")
         };
 
-        _ = self.render_pass.set(render_pass);
+        self.render_pass.set(render_pass);
         println!("Render Pass: {:?}", *self.render_pass);
     }
 }
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{error, info, warn};
+
+use super::shader_modules::VolcanShaderModule;
+
+const SHADER_SOURCE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
+const SHADER_INCLUDE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/include");
+const SHADER_COMPILER: &str = env!("VOXRT_SHADER_COMPILER");
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Development watch mode: recompiles shader sources edited under `shaders/` while running.
+///
+/// Successfully compiled SPIR-V is registered with [`VolcanShaderModule::override_spirv`],
+/// so rebuilding a pipeline picks it up. On a compile error nothing is registered and the
+/// pipeline built from the previous code keeps running.
+pub struct ShaderHotReload {
+    compiler: PathBuf,
+    modified_times: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+impl ShaderHotReload {
+    pub fn new() -> Option<Self> {
+        if SHADER_COMPILER.is_empty() {
+            warn!("Shader watch mode needs glslangValidator at build time, disabling it.");
+            return None;
+        }
+
+        let mut hot_reload = Self {
+            compiler: PathBuf::from(SHADER_COMPILER),
+            modified_times: HashMap::new(),
+            last_poll: Instant::now(),
+        };
+
+        // Record the initial state so only later edits trigger a reload.
+        hot_reload.changed_files();
+        info!("Watching {} for shader changes", SHADER_SOURCE_DIR);
+
+        Some(hot_reload)
+    }
+
+    /// Returns the names (e.g. `raygen.rgen`) of the shaders that were successfully recompiled
+    /// since the last call. Meant to be called once per frame.
+    pub fn poll(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let changed_files = self.changed_files();
+        if changed_files.is_empty() {
+            return Vec::new();
+        }
+
+        // An edited include may be used by any shader, so everything is recompiled.
+        let include_changed = changed_files
+            .iter()
+            .any(|path| path.starts_with(SHADER_INCLUDE_DIR));
+
+        let to_compile: Vec<String> = if include_changed {
+            VolcanShaderModule::embedded_shader_names()
+                .map(str::to_string)
+                .collect()
+        } else {
+            changed_files
+                .iter()
+                .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+                .filter(|name| VolcanShaderModule::embedded_spirv(name).is_some())
+                .collect()
+        };
+
+        let mut reloaded = Vec::new();
+        for name in to_compile {
+            match self.compile(&name) {
+                Ok(spirv) => {
+                    info!("Recompiled shader {name}");
+                    VolcanShaderModule::override_spirv(&name, spirv);
+                    reloaded.push(name);
+                }
+                Err(log) => error!("Cannot recompile {name}, keeping the previous version:\n{log}"),
+            }
+        }
+
+        reloaded
+    }
+
+    fn changed_files(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+
+        for directory in [SHADER_SOURCE_DIR, SHADER_INCLUDE_DIR] {
+            let Ok(entries) = std::fs::read_dir(directory) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+                    continue;
+                };
+                if !path.is_file() {
+                    continue;
+                }
+
+                if self.modified_times.insert(path.clone(), modified) != Some(modified) {
+                    changed.push(path);
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn compile(&self, name: &str) -> Result<Vec<u8>, String> {
+        let source = Path::new(SHADER_SOURCE_DIR).join(name);
+        let output_path = std::env::temp_dir().join(format!("voxrt_{name}.spv"));
+
+        let mut command = Command::new(&self.compiler);
+        command
+            .args(["--target-env", "vulkan1.2", "-V"])
+            .arg(format!("-I{SHADER_INCLUDE_DIR}"))
+            .arg("-o")
+            .arg(&output_path);
+
+        for define in VolcanShaderModule::shader_defines(name) {
+            command.arg(format!("-D{define}"));
+        }
+
+        let output = command
+            .arg(&source)
+            .output()
+            .map_err(|err| format!("Cannot run {}: {err}", self.compiler.display()))?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stdout).to_string()
+                + &String::from_utf8_lossy(&output.stderr));
+        }
+
+        std::fs::read(&output_path).map_err(|err| err.to_string())
+    }
+}
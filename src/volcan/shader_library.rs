@@ -0,0 +1,200 @@
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap, HashSet,
+    },
+    ffi::CStr,
+    hash::{Hash, Hasher},
+};
+
+use ash::vk;
+use bytemuck::NoUninit;
+use log::info;
+
+use super::shader_modules::VolcanShaderModule;
+
+/// A shader module owned by the [`ShaderLibrary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaderModuleRef {
+    pub module: vk::ShaderModule,
+    pub content_hash: u64,
+}
+
+struct LibraryModule {
+    /// Every shader file with this SPIR-V.
+    names: HashSet<String>,
+    module: vk::ShaderModule,
+    pipelines: HashSet<vk::Pipeline>,
+}
+
+/// Deduplicates shader modules by SPIR-V content and destroys them once no pipeline uses them.
+///
+/// Modules handed out by [`ShaderLibrary::module`] must be tied to the pipeline built from them
+/// with [`ShaderLibrary::bind_pipeline`], otherwise the next [`ShaderLibrary::collect_unused`]
+/// destroys them.
+pub struct ShaderLibrary {
+    device: ash::Device,
+    modules: HashMap<u64, LibraryModule>,
+}
+
+impl ShaderLibrary {
+    pub fn new(device: ash::Device) -> Self {
+        Self {
+            device,
+            modules: HashMap::new(),
+        }
+    }
+
+    pub fn module(&mut self, name: &str) -> Result<ShaderModuleRef, vk::Result> {
+        let spirv = VolcanShaderModule::spirv(name);
+
+        let mut hasher = DefaultHasher::new();
+        spirv.hash(&mut hasher);
+        let content_hash = hasher.finish();
+
+        let library_module = match self.modules.entry(content_hash) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(LibraryModule {
+                names: HashSet::new(),
+                module: VolcanShaderModule::create_shader_from_spirv(&self.device, &spirv)?,
+                pipelines: HashSet::new(),
+            }),
+        };
+        library_module.names.insert(name.to_string());
+
+        Ok(ShaderModuleRef {
+            module: library_module.module,
+            content_hash,
+        })
+    }
+
+    pub fn bind_pipeline(&mut self, pipeline: vk::Pipeline, modules: &[ShaderModuleRef]) {
+        for module in modules {
+            if let Some(library_module) = self.modules.get_mut(&module.content_hash) {
+                library_module.pipelines.insert(pipeline);
+            }
+        }
+    }
+
+    /// Forgets `pipeline` and destroys the modules it was the last user of.
+    /// The pipeline must no longer be in use by the GPU.
+    pub fn release_pipeline(&mut self, pipeline: vk::Pipeline) {
+        for library_module in self.modules.values_mut() {
+            library_module.pipelines.remove(&pipeline);
+        }
+
+        self.collect_unused();
+    }
+
+    pub fn collect_unused(&mut self) {
+        let device = &self.device;
+        self.modules.retain(|_, library_module| {
+            if !library_module.pipelines.is_empty() {
+                return true;
+            }
+
+            let mut names: Vec<&str> = library_module.names.iter().map(String::as_str).collect();
+            names.sort_unstable();
+            info!("Destroying unused shader module {}", names.join(", "));
+            unsafe { device.destroy_shader_module(library_module.module, None) };
+            false
+        });
+    }
+
+    pub fn pipelines_using(&self, name: &str) -> Vec<vk::Pipeline> {
+        self.modules
+            .values()
+            .filter(|library_module| library_module.names.contains(name))
+            .flat_map(|library_module| library_module.pipelines.iter().copied())
+            .collect()
+    }
+
+    pub fn stage_info<'a>(
+        module: ShaderModuleRef,
+        stage: vk::ShaderStageFlags,
+        entry_point: &'a CStr,
+        specialization: Option<&'a vk::SpecializationInfo<'a>>,
+    ) -> vk::PipelineShaderStageCreateInfo<'a> {
+        let stage_info = vk::PipelineShaderStageCreateInfo::default()
+            .stage(stage)
+            .module(module.module)
+            .name(entry_point);
+
+        match specialization {
+            Some(specialization) => stage_info.specialization_info(specialization),
+            None => stage_info,
+        }
+    }
+
+    pub fn destroy(&mut self) {
+        for (_, library_module) in self.modules.drain() {
+            unsafe {
+                self.device
+                    .destroy_shader_module(library_module.module, None)
+            };
+        }
+    }
+}
+
+/// Values for `layout(constant_id = N) const` and `local_size_x_id = N` declarations, set per
+/// pipeline stage with [`ShaderLibrary::stage_info`].
+#[derive(Debug, Default, Clone)]
+pub struct SpecializationConstants {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `value` is a `u32`, `i32` or `f32`, booleans are a [`vk::Bool32`].
+    pub fn with<T: NoUninit>(mut self, constant_id: u32, value: T) -> Self {
+        let bytes = bytemuck::bytes_of(&value);
+        self.entries.push(vk::SpecializationMapEntry {
+            constant_id,
+            offset: self.data.len() as u32,
+            size: bytes.len(),
+        });
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn info(&self) -> vk::SpecializationInfo<'_> {
+        vk::SpecializationInfo::default()
+            .map_entries(&self.entries)
+            .data(&self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specialization_constants_are_packed_in_order() {
+        let constants = SpecializationConstants::new()
+            .with(0, 64u32)
+            .with(2, 0.5f32)
+            .with(1, vk::TRUE)
+            .with(3, -1i32);
+        let info = constants.info();
+
+        let entries: Vec<_> = constants
+            .entries
+            .iter()
+            .map(|entry| (entry.constant_id, entry.offset, entry.size))
+            .collect();
+        assert_eq!(entries, [(0, 0, 4), (2, 4, 4), (1, 8, 4), (3, 12, 4)]);
+        assert_eq!(info.map_entry_count, 4);
+        assert_eq!(info.data_size, 16);
+
+        let words: Vec<u32> = constants
+            .data
+            .chunks_exact(4)
+            .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, [64, 0.5f32.to_bits(), vk::TRUE, u32::MAX]);
+    }
+}
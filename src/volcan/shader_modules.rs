@@ -1,36 +1,87 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, RwLock},
+};
 
 use ash::{
     util::read_spv,
     vk::{self},
 };
-use log::error;
+
+use super::shader_reflection::ShaderReflection;
+
+mod embedded {
+    include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+}
+
+/// SPIR-V recompiled at runtime by the shader hot reload, shadowing the embedded one.
+static RELOADED_SHADERS: RwLock<Option<HashMap<String, Arc<[u8]>>>> = RwLock::new(None);
 
 pub struct VolcanShaderModule {}
 
 impl VolcanShaderModule {
-    pub fn create_shader(device: &ash::Device, file_path: &str) -> vk::ShaderModule {
-        let shader_source = match file_path {
-            // "shaders/compute_old.wgsl" => {
-            //     include_bytes!("../../shaders/dist/basic_triangle.vert.spv")
-            // }
-            // "shaders/compute.wgsl" => include_str!("../shaders/compute.wgsl"),
-            // "shaders/render.wgsl" => include_str!("../shaders/render.wgsl"),
-            _ => &{
-                error!("{} will NOT be integrated into the binary.", file_path);
-                std::fs::read(file_path).expect("Failed to read shader file")
-            },
-        };
-
-        let shader_source_cursor = &mut Cursor::new(shader_source);
+    /// SPIR-V compiled by `build.rs` for a shader source such as `raygen.rgen`.
+    pub fn embedded_spirv(name: &str) -> Option<&'static [u8]> {
+        embedded::EMBEDDED_SHADERS
+            .iter()
+            .find(|(shader_name, _)| *shader_name == name)
+            .map(|(_, spirv)| *spirv)
+    }
+
+    pub fn shader_defines(name: &str) -> &'static [&'static str] {
+        embedded::SHADER_DEFINES
+            .iter()
+            .find(|(shader_name, _)| *shader_name == name)
+            .map(|(_, defines)| *defines)
+            .unwrap_or(&[])
+    }
+
+    pub fn embedded_shader_names() -> impl Iterator<Item = &'static str> {
+        embedded::EMBEDDED_SHADERS.iter().map(|(name, _)| *name)
+    }
+
+    /// Makes later `create_shader` calls for `name` use `spirv` instead of the embedded code.
+    pub fn override_spirv(name: &str, spirv: Vec<u8>) {
+        RELOADED_SHADERS
+            .write()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(name.to_string(), spirv.into());
+    }
+
+    /// Current SPIR-V of a shader: the hot reloaded version if any, the embedded one otherwise.
+    pub fn spirv(name: &str) -> Cow<'static, [u8]> {
+        let reloaded = RELOADED_SHADERS
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|shaders| shaders.get(name).cloned());
+
+        match reloaded {
+            Some(shader_source) => Cow::Owned(shader_source.to_vec()),
+            None => Cow::Borrowed(
+                Self::embedded_spirv(name)
+                    .unwrap_or_else(|| panic!("{} is not an embedded shader", name)),
+            ),
+        }
+    }
+
+    pub fn reflect(name: &str) -> ShaderReflection {
+        ShaderReflection::from_bytes(&Self::spirv(name))
+            .unwrap_or_else(|err| panic!("Cannot reflect {}: {}", name, err))
+    }
+
+    pub fn create_shader_from_spirv(
+        device: &ash::Device,
+        spirv: &[u8],
+    ) -> Result<vk::ShaderModule, vk::Result> {
+        let shader_source_cursor = &mut Cursor::new(spirv);
         let code = read_spv(shader_source_cursor).expect("Error during SPV parsing");
 
         let create_info = vk::ShaderModuleCreateInfo::default().code(&code);
 
-        unsafe {
-            device
-                .create_shader_module(&create_info, None)
-                .expect("Cannot create shader module")
-        }
+        unsafe { device.create_shader_module(&create_info, None) }
     }
 }
@@ -0,0 +1,1251 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Cursor,
+};
+
+use ash::{util::read_spv, vk};
+
+/* --------------------------------- SPIR-V --------------------------------- */
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const SPIRV_HEADER_WORDS: usize = 5;
+
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_VOID: u32 = 19;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_TYPE_FORWARD_POINTER: u32 = 39;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+const STORAGE_CLASS_CALLABLE_DATA: u32 = 5328;
+const STORAGE_CLASS_INCOMING_CALLABLE_DATA: u32 = 5329;
+const STORAGE_CLASS_RAY_PAYLOAD: u32 = 5338;
+const STORAGE_CLASS_INCOMING_RAY_PAYLOAD: u32 = 5342;
+const STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER: u32 = 5349;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Debug)]
+pub enum ReflectionError {
+    InvalidSpirv(String),
+    UnknownExecutionModel(u32),
+}
+
+impl fmt::Display for ReflectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSpirv(reason) => write!(f, "invalid SPIR-V: {reason}"),
+            Self::UnknownExecutionModel(model) => write!(f, "unknown execution model {model}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryPoint {
+    pub name: String,
+    pub stage: vk::ShaderStageFlags,
+    /// `local_size_x/y/z` of compute shaders.
+    pub workgroup_size: Option<[u32; 3]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// Array length, 0 for runtime-sized arrays.
+    pub count: u32,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadStorage {
+    RayPayload,
+    IncomingRayPayload,
+    CallableData,
+    IncomingCallableData,
+}
+
+#[derive(Debug, Clone)]
+pub struct RayPayload {
+    pub location: u32,
+    pub storage: PayloadStorage,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PushConstantBlock {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// What a shader module expects from the pipeline it is used in.
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub entry_points: Vec<EntryPoint>,
+    pub descriptor_bindings: Vec<DescriptorBinding>,
+    pub push_constants: Option<PushConstantBlock>,
+    pub ray_payloads: Vec<RayPayload>,
+}
+
+enum SpirvType {
+    Scalar {
+        width: u32,
+    },
+    Vector {
+        component: u32,
+        count: u32,
+    },
+    Matrix {
+        column: u32,
+        count: u32,
+    },
+    Image {
+        dim: u32,
+        sampled: u32,
+    },
+    Sampler,
+    SampledImage,
+    AccelerationStructure,
+    Array {
+        element: u32,
+        length_id: u32,
+    },
+    RuntimeArray {
+        element: u32,
+    },
+    Struct {
+        members: Vec<u32>,
+    },
+    Pointer {
+        storage_class: u32,
+        pointee: u32,
+    },
+    /// Booleans, functions and other types never found in interface blocks.
+    Other,
+}
+
+#[derive(Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    array_stride: Option<u32>,
+    block: bool,
+    buffer_block: bool,
+}
+
+#[derive(Default)]
+struct MemberDecorations {
+    offset: Option<u32>,
+    matrix_stride: Option<u32>,
+}
+
+#[derive(Default)]
+struct SpirvModule {
+    names: HashMap<u32, String>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), MemberDecorations>,
+    /// (result id, result type, storage class)
+    variables: Vec<(u32, u32, u32)>,
+    /// (execution model, entry point id, name)
+    entry_points: Vec<(u32, u32, String)>,
+    local_sizes: HashMap<u32, [u32; 3]>,
+}
+
+impl ShaderReflection {
+    pub fn from_bytes(spirv: &[u8]) -> Result<Self, ReflectionError> {
+        let words = read_spv(&mut Cursor::new(spirv))
+            .map_err(|err| ReflectionError::InvalidSpirv(err.to_string()))?;
+        Self::from_words(&words)
+    }
+
+    pub fn from_words(words: &[u32]) -> Result<Self, ReflectionError> {
+        let module = SpirvModule::parse(words)?;
+
+        let entry_points = module
+            .entry_points
+            .iter()
+            .map(|(model, id, name)| {
+                Ok(EntryPoint {
+                    name: name.clone(),
+                    stage: execution_model_stage(*model)?,
+                    workgroup_size: module.local_sizes.get(id).copied(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut descriptor_bindings = Vec::new();
+        let mut push_constants = None;
+        let mut ray_payloads = Vec::new();
+
+        for &(id, type_id, storage_class) in &module.variables {
+            let decorations = module.decorations.get(&id);
+            let name = module.names.get(&id).cloned();
+            let pointee = match module.types.get(&type_id) {
+                Some(SpirvType::Pointer { pointee, .. }) => *pointee,
+                _ => continue,
+            };
+
+            match storage_class {
+                STORAGE_CLASS_UNIFORM_CONSTANT
+                | STORAGE_CLASS_UNIFORM
+                | STORAGE_CLASS_STORAGE_BUFFER => {
+                    let (Some(set), Some(binding)) = (
+                        decorations.and_then(|d| d.set),
+                        decorations.and_then(|d| d.binding),
+                    ) else {
+                        continue;
+                    };
+
+                    let (element, count) = module.array_element(pointee);
+                    let Some(descriptor_type) = module.descriptor_type(element, storage_class)
+                    else {
+                        continue;
+                    };
+
+                    descriptor_bindings.push(DescriptorBinding {
+                        set,
+                        binding,
+                        descriptor_type,
+                        count,
+                        name,
+                    });
+                }
+                STORAGE_CLASS_PUSH_CONSTANT => {
+                    let (offset, end) = module.struct_extent(pointee);
+                    push_constants = Some(PushConstantBlock {
+                        offset,
+                        size: end - offset,
+                    });
+                }
+                STORAGE_CLASS_RAY_PAYLOAD
+                | STORAGE_CLASS_INCOMING_RAY_PAYLOAD
+                | STORAGE_CLASS_CALLABLE_DATA
+                | STORAGE_CLASS_INCOMING_CALLABLE_DATA => {
+                    let storage = match storage_class {
+                        STORAGE_CLASS_RAY_PAYLOAD => PayloadStorage::RayPayload,
+                        STORAGE_CLASS_INCOMING_RAY_PAYLOAD => PayloadStorage::IncomingRayPayload,
+                        STORAGE_CLASS_CALLABLE_DATA => PayloadStorage::CallableData,
+                        _ => PayloadStorage::IncomingCallableData,
+                    };
+
+                    ray_payloads.push(RayPayload {
+                        location: decorations.and_then(|d| d.location).unwrap_or(0),
+                        storage,
+                        name,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        descriptor_bindings.sort_by_key(|binding| (binding.set, binding.binding));
+        ray_payloads.sort_by_key(|payload| payload.location);
+
+        Ok(Self {
+            entry_points,
+            descriptor_bindings,
+            push_constants,
+            ray_payloads,
+        })
+    }
+
+    pub fn stages(&self) -> vk::ShaderStageFlags {
+        self.entry_points
+            .iter()
+            .fold(vk::ShaderStageFlags::empty(), |stages, entry_point| {
+                stages | entry_point.stage
+            })
+    }
+}
+
+fn execution_model_stage(model: u32) -> Result<vk::ShaderStageFlags, ReflectionError> {
+    Ok(match model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        5313 => vk::ShaderStageFlags::RAYGEN_KHR,
+        5314 => vk::ShaderStageFlags::INTERSECTION_KHR,
+        5315 => vk::ShaderStageFlags::ANY_HIT_KHR,
+        5316 => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        5317 => vk::ShaderStageFlags::MISS_KHR,
+        5318 => vk::ShaderStageFlags::CALLABLE_KHR,
+        _ => return Err(ReflectionError::UnknownExecutionModel(model)),
+    })
+}
+
+fn literal_string(words: &[u32]) -> (String, usize) {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|&byte| byte != 0)
+        .collect();
+    let word_count = bytes.len() / 4 + 1;
+
+    (String::from_utf8_lossy(&bytes).into_owned(), word_count)
+}
+
+impl SpirvModule {
+    fn parse(words: &[u32]) -> Result<Self, ReflectionError> {
+        if words.len() < SPIRV_HEADER_WORDS || words[0] != SPIRV_MAGIC {
+            return Err(ReflectionError::InvalidSpirv("bad header".into()));
+        }
+
+        let mut module = Self::default();
+        let mut cursor = SPIRV_HEADER_WORDS;
+
+        while cursor < words.len() {
+            let word_count = (words[cursor] >> 16) as usize;
+            let opcode = words[cursor] & 0xFFFF;
+
+            if word_count == 0 || cursor + word_count > words.len() {
+                return Err(ReflectionError::InvalidSpirv(format!(
+                    "truncated instruction at word {cursor}"
+                )));
+            }
+
+            let operands = &words[cursor + 1..cursor + word_count];
+            module.parse_instruction(opcode, operands)?;
+            cursor += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, opcode: u32, operands: &[u32]) -> Result<(), ReflectionError> {
+        match (opcode, operands) {
+            (OP_NAME, [id, name @ ..]) => {
+                self.names.insert(*id, literal_string(name).0);
+            }
+            (OP_ENTRY_POINT, [model, id, name @ ..]) => {
+                self.entry_points
+                    .push((*model, *id, literal_string(name).0));
+            }
+            (OP_EXECUTION_MODE, [id, EXECUTION_MODE_LOCAL_SIZE, x, y, z, ..]) => {
+                self.local_sizes.insert(*id, [*x, *y, *z]);
+            }
+            (OP_TYPE_INT | OP_TYPE_FLOAT, [id, width, ..]) => {
+                self.declare_type(*id, SpirvType::Scalar { width: *width })?;
+            }
+            (OP_TYPE_VECTOR, [id, component, count]) => {
+                let (component, count) = (*component, *count);
+                self.declare_type(*id, SpirvType::Vector { component, count })?;
+            }
+            (OP_TYPE_MATRIX, [id, column, count]) => {
+                let (column, count) = (*column, *count);
+                self.declare_type(*id, SpirvType::Matrix { column, count })?;
+            }
+            (OP_TYPE_IMAGE, [id, _, dim, _, _, _, sampled, ..]) => {
+                let (dim, sampled) = (*dim, *sampled);
+                self.declare_type(*id, SpirvType::Image { dim, sampled })?;
+            }
+            (OP_TYPE_SAMPLER, [id]) => {
+                self.declare_type(*id, SpirvType::Sampler)?;
+            }
+            (OP_TYPE_SAMPLED_IMAGE, [id, _]) => {
+                self.declare_type(*id, SpirvType::SampledImage)?;
+            }
+            (OP_TYPE_ACCELERATION_STRUCTURE, [id]) => {
+                self.declare_type(*id, SpirvType::AccelerationStructure)?;
+            }
+            (OP_TYPE_ARRAY, [id, element, length_id]) => {
+                let (element, length_id) = (*element, *length_id);
+                self.declare_type(*id, SpirvType::Array { element, length_id })?;
+            }
+            (OP_TYPE_RUNTIME_ARRAY, [id, element]) => {
+                let element = *element;
+                self.declare_type(*id, SpirvType::RuntimeArray { element })?;
+            }
+            (OP_TYPE_STRUCT, [id, members @ ..]) => {
+                let members = members.to_vec();
+                self.declare_type(*id, SpirvType::Struct { members })?;
+            }
+            (OP_TYPE_POINTER, [id, storage_class, pointee]) => {
+                let (storage_class, pointee) = (*storage_class, *pointee);
+                self.declare_type(
+                    *id,
+                    SpirvType::Pointer {
+                        storage_class,
+                        pointee,
+                    },
+                )?;
+            }
+            (OP_TYPE_FORWARD_POINTER, [id, storage_class]) => {
+                let storage_class = *storage_class;
+                self.declare_type(
+                    *id,
+                    SpirvType::Pointer {
+                        storage_class,
+                        pointee: 0,
+                    },
+                )?;
+            }
+            (OP_TYPE_VOID..=OP_TYPE_FORWARD_POINTER, [id, ..]) => {
+                self.declare_type(*id, SpirvType::Other)?;
+            }
+            (OP_CONSTANT, [_, id, value, ..]) => {
+                self.constants.insert(*id, *value);
+            }
+            (OP_VARIABLE, [type_id, id, storage_class, ..]) => {
+                self.variables.push((*id, *type_id, *storage_class));
+            }
+            (OP_DECORATE, [id, decoration, arguments @ ..]) => {
+                let decorations = self.decorations.entry(*id).or_default();
+                let argument = arguments.first().copied();
+                match *decoration {
+                    DECORATION_BLOCK => decorations.block = true,
+                    DECORATION_BUFFER_BLOCK => decorations.buffer_block = true,
+                    DECORATION_ARRAY_STRIDE => decorations.array_stride = argument,
+                    DECORATION_LOCATION => decorations.location = argument,
+                    DECORATION_BINDING => decorations.binding = argument,
+                    DECORATION_DESCRIPTOR_SET => decorations.set = argument,
+                    _ => {}
+                }
+            }
+            (OP_MEMBER_DECORATE, [id, member, decoration, argument, ..]) => {
+                let decorations = self.member_decorations.entry((*id, *member)).or_default();
+                match *decoration {
+                    DECORATION_OFFSET => decorations.offset = Some(*argument),
+                    DECORATION_MATRIX_STRIDE => decorations.matrix_stride = Some(*argument),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Types are declared before their use, pointers to blocks not declared yet go through
+    /// `OpTypeForwardPointer`. Rejecting redeclarations and unknown members keeps the sizes
+    /// below from recursing forever on malformed modules.
+    fn declare_type(&mut self, id: u32, spirv_type: SpirvType) -> Result<(), ReflectionError> {
+        let members = match &spirv_type {
+            SpirvType::Vector { component, .. } => std::slice::from_ref(component),
+            SpirvType::Matrix { column, .. } => std::slice::from_ref(column),
+            SpirvType::Array { element, .. } | SpirvType::RuntimeArray { element } => {
+                std::slice::from_ref(element)
+            }
+            SpirvType::Struct { members } => members.as_slice(),
+            _ => &[],
+        };
+
+        let forward_pointer = matches!(
+            (self.types.get(&id), &spirv_type),
+            (
+                Some(SpirvType::Pointer { pointee: 0, .. }),
+                SpirvType::Pointer { .. }
+            )
+        );
+        if self.types.contains_key(&id) && !forward_pointer {
+            return Err(ReflectionError::InvalidSpirv(format!(
+                "type %{id} declared twice"
+            )));
+        }
+        if let Some(member) = members
+            .iter()
+            .find(|member| !self.types.contains_key(member))
+        {
+            return Err(ReflectionError::InvalidSpirv(format!(
+                "type %{id} uses undeclared type %{member}"
+            )));
+        }
+
+        self.types.insert(id, spirv_type);
+        Ok(())
+    }
+
+    /// Unwraps one level of array, returning the element type and descriptor count.
+    fn array_element(&self, type_id: u32) -> (u32, u32) {
+        match self.types.get(&type_id) {
+            Some(SpirvType::Array { element, length_id }) => (
+                *element,
+                self.constants.get(length_id).copied().unwrap_or(1),
+            ),
+            Some(SpirvType::RuntimeArray { element }) => (*element, 0),
+            _ => (type_id, 1),
+        }
+    }
+
+    fn descriptor_type(&self, type_id: u32, storage_class: u32) -> Option<vk::DescriptorType> {
+        let descriptor_type = match (self.types.get(&type_id)?, storage_class) {
+            (SpirvType::AccelerationStructure, _) => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            (SpirvType::Sampler, _) => vk::DescriptorType::SAMPLER,
+            (SpirvType::SampledImage, _) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (SpirvType::Image { dim, .. }, _) if *dim == DIM_SUBPASS_DATA => {
+                vk::DescriptorType::INPUT_ATTACHMENT
+            }
+            (SpirvType::Image { dim, sampled }, _) => match (*dim == DIM_BUFFER, *sampled == 2) {
+                (true, true) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (true, false) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (false, true) => vk::DescriptorType::STORAGE_IMAGE,
+                (false, false) => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            (SpirvType::Struct { .. }, STORAGE_CLASS_STORAGE_BUFFER) => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            (SpirvType::Struct { .. }, STORAGE_CLASS_UNIFORM) => {
+                let decorations = self.decorations.get(&type_id);
+                if decorations.is_some_and(|d| d.buffer_block) {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::UNIFORM_BUFFER
+                }
+            }
+            _ => return None,
+        };
+
+        Some(descriptor_type)
+    }
+
+    /// Byte range `(first member offset, end of last member)` covered by a block.
+    fn struct_extent(&self, type_id: u32) -> (u32, u32) {
+        let Some(SpirvType::Struct { members }) = self.types.get(&type_id) else {
+            return (0, self.type_size(type_id, None));
+        };
+
+        let mut start = u32::MAX;
+        let mut end = 0;
+        for (index, &member) in members.iter().enumerate() {
+            let decorations = self.member_decorations.get(&(type_id, index as u32));
+            let offset = decorations.and_then(|d| d.offset).unwrap_or(end);
+            let size = self.type_size(member, decorations.and_then(|d| d.matrix_stride));
+            start = start.min(offset);
+            end = end.max(offset.saturating_add(size));
+        }
+
+        (start.min(end), end)
+    }
+
+    fn type_size(&self, type_id: u32, matrix_stride: Option<u32>) -> u32 {
+        match self.types.get(&type_id) {
+            Some(SpirvType::Scalar { width }) => width / 8,
+            Some(SpirvType::Vector { component, count }) => {
+                self.type_size(*component, None).saturating_mul(*count)
+            }
+            Some(SpirvType::Matrix { column, count }) => matrix_stride
+                .unwrap_or_else(|| self.type_size(*column, None))
+                .saturating_mul(*count),
+            Some(SpirvType::Array { element, length_id }) => {
+                let length = self.constants.get(length_id).copied().unwrap_or(1);
+                let stride = self
+                    .decorations
+                    .get(&type_id)
+                    .and_then(|d| d.array_stride)
+                    .unwrap_or_else(|| self.type_size(*element, matrix_stride));
+                stride.saturating_mul(length)
+            }
+            Some(SpirvType::Struct { .. }) => self.struct_extent(type_id).1,
+            // `buffer_reference` blocks are passed around as 64 bit device addresses.
+            Some(SpirvType::Pointer {
+                storage_class: STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER,
+                ..
+            }) => 8,
+            _ => 0,
+        }
+    }
+}
+
+/* ----------------------------- PIPELINE LAYOUT ---------------------------- */
+
+#[derive(Debug, Clone)]
+pub struct LayoutBinding {
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    pub name: Option<String>,
+}
+
+/// Pipeline layout merged from the reflection of every stage of a pipeline.
+#[derive(Debug, Default)]
+pub struct ReflectedPipelineLayout {
+    /// Keyed by `(set, binding)`.
+    pub bindings: BTreeMap<(u32, u32), LayoutBinding>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+    /// Stages that disagree on the type of a shared binding.
+    pub conflicts: Vec<LayoutMismatch>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LayoutMismatch {
+    StageConflict {
+        set: u32,
+        binding: u32,
+        first: vk::DescriptorType,
+        second: vk::DescriptorType,
+    },
+    MissingBinding {
+        set: u32,
+        binding: u32,
+        name: Option<String>,
+        descriptor_type: vk::DescriptorType,
+        stages: vk::ShaderStageFlags,
+    },
+    DescriptorTypeMismatch {
+        set: u32,
+        binding: u32,
+        declared: vk::DescriptorType,
+        reflected: vk::DescriptorType,
+    },
+    DescriptorCountTooSmall {
+        set: u32,
+        binding: u32,
+        declared: u32,
+        reflected: u32,
+    },
+    MissingStageFlags {
+        set: u32,
+        binding: u32,
+        declared: vk::ShaderStageFlags,
+        required: vk::ShaderStageFlags,
+    },
+    UnusedBinding {
+        set: u32,
+        binding: u32,
+    },
+    PushConstantsNotCovered {
+        stages: vk::ShaderStageFlags,
+        offset: u32,
+        size: u32,
+    },
+    UnmatchedRayPayload {
+        location: u32,
+        stages: vk::ShaderStageFlags,
+        name: Option<String>,
+    },
+}
+
+impl fmt::Display for LayoutMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StageConflict {
+                set,
+                binding,
+                first,
+                second,
+            } => write!(
+                f,
+                "set {set} binding {binding} is {first:?} in one stage and {second:?} in another"
+            ),
+            Self::MissingBinding {
+                set,
+                binding,
+                name,
+                descriptor_type,
+                stages,
+            } => write!(
+                f,
+                "set {set} binding {binding} ({}: {descriptor_type:?}, {stages:?}) is used by the \
+                 shaders but missing from the pipeline layout",
+                name.as_deref().unwrap_or("<unnamed>")
+            ),
+            Self::DescriptorTypeMismatch {
+                set,
+                binding,
+                declared,
+                reflected,
+            } => write!(
+                f,
+                "set {set} binding {binding} is declared as {declared:?} but the shaders use {reflected:?}"
+            ),
+            Self::DescriptorCountTooSmall {
+                set,
+                binding,
+                declared,
+                reflected,
+            } => write!(
+                f,
+                "set {set} binding {binding} declares {declared} descriptors but the shaders use {reflected}"
+            ),
+            Self::MissingStageFlags {
+                set,
+                binding,
+                declared,
+                required,
+            } => write!(
+                f,
+                "set {set} binding {binding} is visible to {declared:?} but used by {required:?}"
+            ),
+            Self::UnusedBinding { set, binding } => {
+                write!(f, "set {set} binding {binding} is declared but unused by the shaders")
+            }
+            Self::PushConstantsNotCovered {
+                stages,
+                offset,
+                size,
+            } => write!(
+                f,
+                "push constants [{offset}, {}) of {stages:?} are not covered by the pipeline layout",
+                offset + size
+            ),
+            Self::UnmatchedRayPayload {
+                location,
+                stages,
+                name,
+            } => write!(
+                f,
+                "{stages:?} reads the ray payload {} at location {location} but no stage traces with one",
+                name.as_deref().unwrap_or("<unnamed>")
+            ),
+        }
+    }
+}
+
+impl ReflectedPipelineLayout {
+    pub fn merge<'a>(stages: impl IntoIterator<Item = &'a ShaderReflection>) -> Self {
+        let mut layout = Self::default();
+        let mut push_constants: Vec<(u32, u32, vk::ShaderStageFlags)> = Vec::new();
+        let mut outgoing_payloads = Vec::new();
+        let mut incoming_payloads: Vec<(u32, vk::ShaderStageFlags, Option<String>)> = Vec::new();
+
+        for reflection in stages {
+            let stage = reflection.stages();
+
+            for payload in &reflection.ray_payloads {
+                match payload.storage {
+                    PayloadStorage::RayPayload => outgoing_payloads.push(payload.location),
+                    PayloadStorage::IncomingRayPayload => {
+                        incoming_payloads.push((payload.location, stage, payload.name.clone()))
+                    }
+                    _ => {}
+                }
+            }
+
+            for descriptor in &reflection.descriptor_bindings {
+                let key = (descriptor.set, descriptor.binding);
+                match layout.bindings.get_mut(&key) {
+                    Some(existing) => {
+                        if existing.descriptor_type != descriptor.descriptor_type {
+                            layout.conflicts.push(LayoutMismatch::StageConflict {
+                                set: descriptor.set,
+                                binding: descriptor.binding,
+                                first: existing.descriptor_type,
+                                second: descriptor.descriptor_type,
+                            });
+                        }
+                        existing.stages |= stage;
+                        existing.count = existing.count.max(descriptor.count);
+                    }
+                    None => {
+                        layout.bindings.insert(
+                            key,
+                            LayoutBinding {
+                                descriptor_type: descriptor.descriptor_type,
+                                count: descriptor.count,
+                                stages: stage,
+                                name: descriptor.name.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+
+            if let Some(block) = reflection.push_constants {
+                // Stages sharing the exact same block share one range.
+                match push_constants
+                    .iter_mut()
+                    .find(|(offset, size, _)| *offset == block.offset && *size == block.size)
+                {
+                    Some((_, _, stages)) => *stages |= stage,
+                    None => push_constants.push((block.offset, block.size, stage)),
+                }
+            }
+        }
+
+        // Only meaningful when the stage calling traceRayEXT is part of the merge.
+        if !outgoing_payloads.is_empty() {
+            for (location, stages, name) in incoming_payloads {
+                if !outgoing_payloads.contains(&location) {
+                    layout.conflicts.push(LayoutMismatch::UnmatchedRayPayload {
+                        location,
+                        stages,
+                        name,
+                    });
+                }
+            }
+        }
+
+        layout.push_constant_ranges = push_constants
+            .into_iter()
+            .map(|(offset, size, stage_flags)| vk::PushConstantRange {
+                stage_flags,
+                offset,
+                size,
+            })
+            .collect();
+
+        layout
+    }
+
+    /// Descriptor set layout bindings, indexed by set number.
+    pub fn descriptor_set_layout_bindings(
+        &self,
+    ) -> Vec<Vec<vk::DescriptorSetLayoutBinding<'static>>> {
+        let set_count = self
+            .bindings
+            .keys()
+            .map(|(set, _)| set + 1)
+            .max()
+            .unwrap_or(0);
+
+        let mut sets = vec![Vec::new(); set_count as usize];
+        for (&(set, binding), layout_binding) in &self.bindings {
+            sets[set as usize].push(
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(binding)
+                    .descriptor_type(layout_binding.descriptor_type)
+                    .descriptor_count(layout_binding.count.max(1))
+                    .stage_flags(layout_binding.stages),
+            );
+        }
+
+        sets
+    }
+
+    /// Compares the reflected layout with a manually declared one.
+    /// `declared_sets[n]` holds the bindings of set `n`.
+    pub fn validate(
+        &self,
+        declared_sets: &[&[vk::DescriptorSetLayoutBinding]],
+        declared_push_constants: &[vk::PushConstantRange],
+    ) -> Vec<LayoutMismatch> {
+        let mut mismatches = self.conflicts.clone();
+
+        for (&(set, binding), reflected) in &self.bindings {
+            let declared = declared_sets
+                .get(set as usize)
+                .and_then(|bindings| bindings.iter().find(|b| b.binding == binding));
+
+            let Some(declared) = declared else {
+                mismatches.push(LayoutMismatch::MissingBinding {
+                    set,
+                    binding,
+                    name: reflected.name.clone(),
+                    descriptor_type: reflected.descriptor_type,
+                    stages: reflected.stages,
+                });
+                continue;
+            };
+
+            if declared.descriptor_type != reflected.descriptor_type {
+                mismatches.push(LayoutMismatch::DescriptorTypeMismatch {
+                    set,
+                    binding,
+                    declared: declared.descriptor_type,
+                    reflected: reflected.descriptor_type,
+                });
+            }
+
+            if declared.descriptor_count < reflected.count {
+                mismatches.push(LayoutMismatch::DescriptorCountTooSmall {
+                    set,
+                    binding,
+                    declared: declared.descriptor_count,
+                    reflected: reflected.count,
+                });
+            }
+
+            if !declared.stage_flags.contains(reflected.stages) {
+                mismatches.push(LayoutMismatch::MissingStageFlags {
+                    set,
+                    binding,
+                    declared: declared.stage_flags,
+                    required: reflected.stages,
+                });
+            }
+        }
+
+        for (set, bindings) in declared_sets.iter().enumerate() {
+            for declared in bindings.iter() {
+                if !self.bindings.contains_key(&(set as u32, declared.binding)) {
+                    mismatches.push(LayoutMismatch::UnusedBinding {
+                        set: set as u32,
+                        binding: declared.binding,
+                    });
+                }
+            }
+        }
+
+        for range in &self.push_constant_ranges {
+            let covered = declared_push_constants.iter().any(|declared| {
+                declared.stage_flags.contains(range.stage_flags)
+                    && declared.offset <= range.offset
+                    && declared.offset + declared.size >= range.offset + range.size
+            });
+
+            if !covered {
+                mismatches.push(LayoutMismatch::PushConstantsNotCovered {
+                    stages: range.stage_flags,
+                    offset: range.offset,
+                    size: range.size,
+                });
+            }
+        }
+
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::volcan::{
+        pipeline::{
+            RASTER_PIPELINE_SHADERS, RAYTRACING_PIPELINE_SHADERS, VOXEL_MESH_PIPELINE_SHADERS,
+        },
+        raster_voxel_mesh::VOXEL_MESH_PUSH_CONSTANTS,
+        raytracing_output::{RAYTRACING_DESCRIPTOR_BINDINGS, RAYTRACING_PUSH_CONSTANTS},
+        raytracing_sparse_tree::{SPARSE_TREE_RAYCAST_PUSH_CONSTANTS, SPARSE_TREE_RAYCAST_SHADERS},
+    };
+
+    /// SPIR-V committed in `shaders/dist`, what builds without a shader compiler embed.
+    fn dist_spirv(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("shaders/dist")
+            .join(format!("{name}.spv"));
+        fs::read(&path).unwrap_or_else(|err| panic!("Cannot read {}: {}", path.display(), err))
+    }
+
+    fn reflect(name: &str) -> ShaderReflection {
+        ShaderReflection::from_bytes(&dist_spirv(name))
+            .unwrap_or_else(|err| panic!("Cannot reflect {name}: {err}"))
+    }
+
+    fn merged_layout(shaders: &[&str]) -> ReflectedPipelineLayout {
+        let reflections: Vec<_> = shaders.iter().map(|name| reflect(name)).collect();
+        ReflectedPipelineLayout::merge(&reflections)
+    }
+
+    /// Builds a module from `(opcode, operands)` instructions.
+    fn module(instructions: &[(u32, &[u32])]) -> Vec<u32> {
+        let mut words = vec![SPIRV_MAGIC, 0x0001_0500, 0, 64, 0];
+        for (opcode, operands) in instructions {
+            words.push(((operands.len() as u32 + 1) << 16) | opcode);
+            words.extend_from_slice(operands);
+        }
+        words
+    }
+
+    #[test]
+    fn entry_points() {
+        let expected = [
+            ("basic_triangle.vert", vk::ShaderStageFlags::VERTEX),
+            ("basic_triangle.frag", vk::ShaderStageFlags::FRAGMENT),
+            ("voxel_mesh.vert", vk::ShaderStageFlags::VERTEX),
+            ("voxel_mesh.frag", vk::ShaderStageFlags::FRAGMENT),
+            ("raygen.rgen", vk::ShaderStageFlags::RAYGEN_KHR),
+            ("raymiss.rmiss", vk::ShaderStageFlags::MISS_KHR),
+            ("rayhit.rchit", vk::ShaderStageFlags::CLOSEST_HIT_KHR),
+            ("voxelhit.rchit", vk::ShaderStageFlags::CLOSEST_HIT_KHR),
+            ("intersection.rint", vk::ShaderStageFlags::INTERSECTION_KHR),
+            ("sparse_tree_raycast.comp", vk::ShaderStageFlags::COMPUTE),
+        ];
+
+        for (name, stage) in expected {
+            let reflection = reflect(name);
+            assert_eq!(reflection.entry_points.len(), 1, "{name}");
+
+            let entry_point = &reflection.entry_points[0];
+            assert_eq!(entry_point.name, "main", "{name}");
+            assert_eq!(entry_point.stage, stage, "{name}");
+
+            let workgroup_size = (stage == vk::ShaderStageFlags::COMPUTE).then_some([64, 1, 1]);
+            assert_eq!(entry_point.workgroup_size, workgroup_size, "{name}");
+        }
+    }
+
+    #[test]
+    fn ray_tracing_descriptor_bindings() {
+        let raygen = reflect("raygen.rgen");
+        let bindings: Vec<_> = raygen
+            .descriptor_bindings
+            .iter()
+            .map(|binding| {
+                (
+                    binding.set,
+                    binding.binding,
+                    binding.descriptor_type,
+                    binding.count,
+                    binding.name.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            bindings,
+            [
+                (
+                    0,
+                    0,
+                    vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                    1,
+                    Some("topLevelAS")
+                ),
+                (
+                    0,
+                    1,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    1,
+                    Some("outputImage")
+                ),
+            ]
+        );
+
+        let layout = merged_layout(RAYTRACING_PIPELINE_SHADERS);
+        assert!(layout.conflicts.is_empty(), "{:?}", layout.conflicts);
+
+        let stages: Vec<_> = layout
+            .bindings
+            .iter()
+            .map(|(&key, binding)| (key, binding.descriptor_type, binding.stages))
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                (
+                    (0, 0),
+                    vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                    vk::ShaderStageFlags::RAYGEN_KHR
+                ),
+                (
+                    (0, 1),
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    vk::ShaderStageFlags::RAYGEN_KHR
+                ),
+                (
+                    (0, 2),
+                    vk::DescriptorType::STORAGE_BUFFER,
+                    vk::ShaderStageFlags::INTERSECTION_KHR | vk::ShaderStageFlags::CLOSEST_HIT_KHR
+                ),
+            ]
+        );
+
+        // The descriptor set is created from the reflected bindings.
+        let sets = layout.descriptor_set_layout_bindings();
+        assert_eq!(sets.len(), 1);
+        let key = |binding: &vk::DescriptorSetLayoutBinding| {
+            (
+                binding.binding,
+                binding.descriptor_type,
+                binding.descriptor_count,
+                binding.stage_flags,
+            )
+        };
+        assert_eq!(
+            sets[0].iter().map(key).collect::<Vec<_>>(),
+            RAYTRACING_DESCRIPTOR_BINDINGS
+                .iter()
+                .map(key)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn push_constant_ranges() {
+        let expected = [
+            ("raygen.rgen", Some(64)),
+            ("voxel_mesh.vert", Some(80)),
+            ("sparse_tree_raycast.comp", Some(52)),
+            ("basic_triangle.vert", None),
+            ("voxel_mesh.frag", None),
+            ("raymiss.rmiss", None),
+            ("voxelhit.rchit", None),
+            ("intersection.rint", None),
+        ];
+
+        for (name, size) in expected {
+            let block = reflect(name).push_constants;
+            assert_eq!(block.map(|block| block.offset), size.map(|_| 0), "{name}");
+            assert_eq!(block.map(|block| block.size), size, "{name}");
+        }
+
+        let ranges = |shaders| -> Vec<_> {
+            merged_layout(shaders)
+                .push_constant_ranges
+                .iter()
+                .map(|range| (range.stage_flags, range.offset, range.size))
+                .collect()
+        };
+        assert_eq!(
+            ranges(RAYTRACING_PIPELINE_SHADERS),
+            [(vk::ShaderStageFlags::RAYGEN_KHR, 0, 64)]
+        );
+        assert_eq!(
+            ranges(VOXEL_MESH_PIPELINE_SHADERS),
+            [(vk::ShaderStageFlags::VERTEX, 0, 80)]
+        );
+        assert_eq!(ranges(RASTER_PIPELINE_SHADERS), []);
+    }
+
+    #[test]
+    fn declared_layouts_match_the_shaders() {
+        fn check(
+            shaders: &[&str],
+            declared_sets: &[&[vk::DescriptorSetLayoutBinding]],
+            declared_push_constants: &[vk::PushConstantRange],
+        ) {
+            let mismatches =
+                merged_layout(shaders).validate(declared_sets, declared_push_constants);
+            assert!(mismatches.is_empty(), "{shaders:?}: {mismatches:?}");
+        }
+
+        check(RASTER_PIPELINE_SHADERS, &[], &[]);
+        check(
+            VOXEL_MESH_PIPELINE_SHADERS,
+            &[],
+            &[VOXEL_MESH_PUSH_CONSTANTS],
+        );
+        check(
+            RAYTRACING_PIPELINE_SHADERS,
+            &[&RAYTRACING_DESCRIPTOR_BINDINGS],
+            &[RAYTRACING_PUSH_CONSTANTS],
+        );
+        check(
+            SPARSE_TREE_RAYCAST_SHADERS,
+            &[],
+            &[SPARSE_TREE_RAYCAST_PUSH_CONSTANTS],
+        );
+    }
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        let spirv = dist_spirv("raygen.rgen");
+
+        let mut bad_magic = spirv.clone();
+        bad_magic[0] ^= 0xFF;
+
+        let invalid: [&[u8]; 5] = [
+            &[],
+            &spirv[..3],
+            // Not a whole number of words.
+            &spirv[..spirv.len() - 1],
+            // Header only, cut short.
+            &spirv[..16],
+            &bad_magic,
+        ];
+        for bytes in invalid {
+            assert!(
+                ShaderReflection::from_bytes(bytes).is_err(),
+                "{} bytes",
+                bytes.len()
+            );
+        }
+
+        let mut zero_word_count = module(&[]);
+        zero_word_count.push(OP_NAME);
+        assert!(ShaderReflection::from_words(&zero_word_count).is_err());
+    }
+
+    #[test]
+    fn truncated_modules_are_errors() {
+        let words = read_spv(&mut Cursor::new(dist_spirv("raygen.rgen"))).unwrap();
+
+        let mut instruction_ends = vec![SPIRV_HEADER_WORDS];
+        while let Some(&cursor) = instruction_ends.last().filter(|&&end| end < words.len()) {
+            instruction_ends.push(cursor + (words[cursor] >> 16) as usize);
+        }
+
+        // Cut inside an instruction is an error, cut between instructions is a valid
+        // module missing its end.
+        for length in 0..words.len() {
+            let result = ShaderReflection::from_words(&words[..length]);
+            assert_eq!(
+                result.is_ok(),
+                instruction_ends.contains(&length),
+                "{length} words"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_types_are_errors() {
+        // A struct containing itself would recurse forever when computing block sizes.
+        let recursive_struct = module(&[(OP_TYPE_STRUCT, &[1, 1])]);
+        assert!(ShaderReflection::from_words(&recursive_struct).is_err());
+
+        let redeclared = module(&[
+            (OP_TYPE_INT, &[1, 32, 0]),
+            (OP_TYPE_STRUCT, &[2, 1]),
+            (OP_TYPE_STRUCT, &[1, 2]),
+        ]);
+        assert!(ShaderReflection::from_words(&redeclared).is_err());
+
+        // An out of range offset saturates instead of overflowing.
+        let huge_offset = module(&[
+            (OP_TYPE_INT, &[1, 32, 0]),
+            (OP_TYPE_STRUCT, &[2, 1]),
+            (OP_MEMBER_DECORATE, &[2, 0, DECORATION_OFFSET, u32::MAX]),
+            (OP_TYPE_POINTER, &[3, STORAGE_CLASS_PUSH_CONSTANT, 2]),
+            (OP_VARIABLE, &[3, 4, STORAGE_CLASS_PUSH_CONSTANT]),
+        ]);
+        let reflection = ShaderReflection::from_words(&huge_offset).unwrap();
+        assert_eq!(reflection.push_constants.unwrap().size, 0);
+    }
+
+    #[test]
+    fn buffer_references_are_device_addresses() {
+        // layout(buffer_reference) buffer Node { Node next; uint value; };
+        // layout(push_constant) uniform PushConstants { Node node; uint count; };
+        let words = module(&[
+            (OP_TYPE_INT, &[1, 32, 0]),
+            (
+                OP_TYPE_FORWARD_POINTER,
+                &[2, STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER],
+            ),
+            (OP_TYPE_STRUCT, &[3, 2, 1]),
+            (OP_MEMBER_DECORATE, &[3, 0, DECORATION_OFFSET, 0]),
+            (OP_MEMBER_DECORATE, &[3, 1, DECORATION_OFFSET, 8]),
+            (
+                OP_TYPE_POINTER,
+                &[2, STORAGE_CLASS_PHYSICAL_STORAGE_BUFFER, 3],
+            ),
+            (OP_TYPE_STRUCT, &[4, 2, 1]),
+            (OP_MEMBER_DECORATE, &[4, 0, DECORATION_OFFSET, 0]),
+            (OP_MEMBER_DECORATE, &[4, 1, DECORATION_OFFSET, 8]),
+            (OP_TYPE_POINTER, &[5, STORAGE_CLASS_PUSH_CONSTANT, 4]),
+            (OP_VARIABLE, &[5, 6, STORAGE_CLASS_PUSH_CONSTANT]),
+        ]);
+
+        let reflection = ShaderReflection::from_words(&words).unwrap();
+        assert_eq!(reflection.push_constants.unwrap().size, 12);
+    }
+}
@@ -4,10 +4,6 @@ use crate::unwraped_option::UnwrappedOption;
 
 use super::init::Volcan;
 
-struct VolcanSwapchain {
-    //TODO: Move all swapchain_* variables from Volcan here
-}
-
 impl Volcan {
     pub fn create_swapchain(&mut self, window_width: u32, window_height: u32) {
         let capabilities = unsafe {
@@ -74,7 +70,8 @@ impl Volcan {
             .image_color_space(surface_format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            // TRANSFER_DST for the blit of the ray tracing output.
+            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST)
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .pre_transform(capabilities.current_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
@@ -0,0 +1,115 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, UVec2, Vec3};
+
+use super::voxel_dda::Ray;
+
+/// Pinhole camera shared by `raygen.rgen` and the CPU tracer. Y is up, a zero yaw
+/// looks towards +Z.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    /// Rotation around +Y, in radians.
+    pub yaw: f32,
+    /// Rotation above the horizon, in radians.
+    pub pitch: f32,
+    /// Vertical field of view, in radians.
+    pub vertical_fov: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(0.0, 0.0, -5.0),
+            yaw: 0.0,
+            pitch: 0.0,
+            vertical_fov: 90f32.to_radians(),
+        }
+    }
+}
+
+/// Push constants of `raygen.rgen`, vec4 aligned.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Pod, Zeroable)]
+pub struct CameraPushConstants {
+    pub position: [f32; 4],
+    /// Scaled so that the image edges are at `forward ± right ± up`.
+    pub right: [f32; 4],
+    pub up: [f32; 4],
+    pub forward: [f32; 4],
+}
+
+impl Camera {
+    pub fn looking_at(position: Vec3, target: Vec3) -> Self {
+        let direction = (target - position).normalize();
+        Self {
+            position,
+            yaw: direction.x.atan2(direction.z),
+            pitch: direction.y.asin(),
+            ..Self::default()
+        }
+    }
+
+    /// Moves by `offset` in the camera frame: x to the right, y up and z forward, both
+    /// horizontal.
+    pub fn fly(&mut self, offset: Vec3) {
+        let forward = Vec3::new(self.yaw.sin(), 0.0, self.yaw.cos());
+        let right = Vec3::Y.cross(forward);
+        self.position += right * offset.x + Vec3::Y * offset.y + forward * offset.z;
+    }
+
+    /// Turns right by `yaw` and up by `pitch` radians, the pitch stops short of vertical.
+    pub fn turn(&mut self, yaw: f32, pitch: f32) {
+        const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+        self.yaw = (self.yaw + yaw).rem_euclid(std::f32::consts::TAU);
+        self.pitch = (self.pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        Vec3::new(
+            self.yaw.sin() * self.pitch.cos(),
+            self.pitch.sin(),
+            self.yaw.cos() * self.pitch.cos(),
+        )
+    }
+
+    /// `(right, up, forward)`, right and up scaled to the image edges.
+    fn basis(&self, aspect_ratio: f32) -> (Vec3, Vec3, Vec3) {
+        let forward = self.forward();
+        let right = Vec3::Y.cross(forward).normalize_or(Vec3::X);
+        let up = forward.cross(right);
+
+        let half_height = (self.vertical_fov * 0.5).tan();
+        (
+            right * half_height * aspect_ratio,
+            up * half_height,
+            forward,
+        )
+    }
+
+    /// Ray through the center of `pixel`, the top left pixel is `(0, 0)`.
+    pub fn ray(&self, pixel: UVec2, resolution: UVec2) -> Ray {
+        let (right, up, forward) = self.basis(resolution.x as f32 / resolution.y as f32);
+
+        let ndc = (pixel.as_vec2() + 0.5) / resolution.as_vec2() * 2.0 - 1.0;
+        let direction = (forward + right * ndc.x - up * ndc.y).normalize();
+        Ray::new(self.position, direction)
+    }
+
+    /// World to clip space of the raster pipeline, framing the same image as [`Camera::ray`]:
+    /// y points down and the depth goes from 0 at `near` to 1 at `far`.
+    pub fn view_projection(&self, aspect_ratio: f32, near: f32, far: f32) -> Mat4 {
+        let view = Mat4::look_to_lh(self.position, self.forward(), Vec3::Y);
+        let projection = Mat4::perspective_lh(self.vertical_fov, aspect_ratio, near, far);
+        Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0)) * projection * view
+    }
+
+    pub fn push_constants(&self, resolution: UVec2) -> CameraPushConstants {
+        let (right, up, forward) = self.basis(resolution.x as f32 / resolution.y as f32);
+        CameraPushConstants {
+            position: self.position.extend(0.0).to_array(),
+            right: right.extend(0.0).to_array(),
+            up: up.extend(0.0).to_array(),
+            forward: forward.extend(0.0).to_array(),
+        }
+    }
+}
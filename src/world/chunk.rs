@@ -0,0 +1,156 @@
+use glam::{IVec3, UVec3};
+
+use super::{material::MaterialId, palette::PaletteVoxels};
+
+/// Edge length of a chunk, in voxels.
+pub const CHUNK_SIZE: u32 = 32;
+pub const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// A cube of `CHUNK_SIZE`³ voxels, stored x-major then y then z, palette compressed
+/// (see [`PaletteVoxels`]).
+#[derive(Clone)]
+pub struct Chunk {
+    voxels: PaletteVoxels,
+    solid_count: u32,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compares the voxels, not how they are stored.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.solid_count == other.solid_count && self.voxels().eq(other.voxels())
+    }
+}
+
+impl Eq for Chunk {}
+
+impl Chunk {
+    /// A chunk full of air.
+    pub fn new() -> Self {
+        Self {
+            voxels: PaletteVoxels::filled(MaterialId::AIR),
+            solid_count: 0,
+        }
+    }
+
+    /// A chunk filled with `material(local_position)`.
+    pub fn from_fn(mut material: impl FnMut(UVec3) -> MaterialId) -> Self {
+        let voxels = PaletteVoxels::from_voxels(
+            (0..CHUNK_VOLUME).map(|index| material(Self::position(index))),
+        );
+        let solid_count = CHUNK_VOLUME as u32 - voxels.count(MaterialId::AIR);
+        Self {
+            voxels,
+            solid_count,
+        }
+    }
+
+    pub fn index(local: UVec3) -> usize {
+        debug_assert!(
+            local.max_element() < CHUNK_SIZE,
+            "{local} is outside the chunk"
+        );
+        (local.x + local.y * CHUNK_SIZE + local.z * CHUNK_SIZE * CHUNK_SIZE) as usize
+    }
+
+    pub fn position(index: usize) -> UVec3 {
+        let index = index as u32;
+        UVec3::new(
+            index % CHUNK_SIZE,
+            (index / CHUNK_SIZE) % CHUNK_SIZE,
+            index / (CHUNK_SIZE * CHUNK_SIZE),
+        )
+    }
+
+    /// World position of the voxel at local `(0, 0, 0)` of the chunk at `chunk_position`.
+    pub fn origin(chunk_position: IVec3) -> IVec3 {
+        chunk_position * CHUNK_SIZE as i32
+    }
+
+    pub fn get(&self, local: UVec3) -> MaterialId {
+        self.voxels.get(Self::index(local))
+    }
+
+    /// Returns the previous material.
+    pub fn set(&mut self, local: UVec3, material: MaterialId) -> MaterialId {
+        let previous = self.voxels.set(Self::index(local), material);
+
+        match (previous.is_solid(), material.is_solid()) {
+            (false, true) => self.solid_count += 1,
+            (true, false) => self.solid_count -= 1,
+            _ => {}
+        }
+
+        previous
+    }
+
+    /// Every voxel, in index order.
+    pub fn voxels(&self) -> impl Iterator<Item = MaterialId> + '_ {
+        (0..CHUNK_VOLUME).map(|index| self.voxels.get(index))
+    }
+
+    pub fn voxels_storage(&self) -> &PaletteVoxels {
+        &self.voxels
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.voxels.memory_bytes()
+    }
+
+    pub fn solid_count(&self) -> u32 {
+        self.solid_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.solid_count == 0
+    }
+
+    /// Local positions and materials of the solid voxels.
+    pub fn solid_voxels(&self) -> impl Iterator<Item = (UVec3, MaterialId)> + '_ {
+        self.voxels()
+            .enumerate()
+            .filter(|(_, material)| material.is_solid())
+            .map(|(index, material)| (Self::position(index), material))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_and_position_round_trip() {
+        for index in [0, 1, 31, 32, 1023, 1024, CHUNK_VOLUME - 1] {
+            assert_eq!(Chunk::index(Chunk::position(index)), index);
+        }
+        assert_eq!(
+            Chunk::position(CHUNK_VOLUME - 1),
+            UVec3::splat(CHUNK_SIZE - 1)
+        );
+        assert_eq!(Chunk::origin(IVec3::new(-1, 0, 2)), IVec3::new(-32, 0, 64));
+    }
+
+    #[test]
+    fn set_tracks_the_solid_voxels() {
+        let mut chunk = Chunk::new();
+        assert!(chunk.is_empty());
+
+        let local = UVec3::new(3, 31, 0);
+        assert_eq!(chunk.set(local, MaterialId::STONE), MaterialId::AIR);
+        assert_eq!(chunk.set(local, MaterialId::DIRT), MaterialId::STONE);
+        assert_eq!(chunk.solid_count(), 1);
+        assert_eq!(
+            chunk.solid_voxels().collect::<Vec<_>>(),
+            [(local, MaterialId::DIRT)]
+        );
+
+        assert_eq!(chunk.set(local, MaterialId::AIR), MaterialId::DIRT);
+        assert!(chunk.is_empty());
+        assert!(chunk == Chunk::new());
+    }
+}
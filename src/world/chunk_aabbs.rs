@@ -0,0 +1,234 @@
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::UVec3;
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE, CHUNK_VOLUME},
+    material::MaterialId,
+};
+
+/// Edge length of a brick, in voxels.
+pub const BRICK_SIZE: u32 = 8;
+pub const BRICK_VOLUME: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+pub const BRICKS_PER_AXIS: u32 = CHUNK_SIZE / BRICK_SIZE;
+
+/// How a chunk is split into BLAS primitives. Fewer, larger boxes make a smaller BLAS
+/// but more work in the intersection shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AabbGranularity {
+    /// One box per solid voxel, the intersection shader has nothing to march.
+    Voxel,
+    /// One box per non-empty 8³ brick, tight around its solid voxels.
+    /// The intersection shader marches the brick occupancy.
+    Brick,
+    /// Solid voxels of the same material merged into larger boxes.
+    Greedy,
+}
+
+/// Per-primitive data for the intersection shader, indexed by `gl_PrimitiveID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct PrimitiveInfo {
+    /// Chunk local voxel position of the box, or of the brick for `Brick`.
+    pub origin: [u32; 3],
+    /// Material of the box, or brick index for `Brick`.
+    pub data: u32,
+}
+
+/// Occupancy of an 8³ brick, x-major then y then z. The materials are read from the
+/// palette compressed chunk, see [`ChunkAabbs::materials`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct Brick {
+    /// One bit per voxel.
+    pub occupancy: [u32; BRICK_VOLUME / 32],
+}
+
+impl Brick {
+    pub fn index(local: UVec3) -> usize {
+        (local.x + local.y * BRICK_SIZE + local.z * BRICK_SIZE * BRICK_SIZE) as usize
+    }
+
+    pub fn is_solid(&self, local: UVec3) -> bool {
+        let index = Self::index(local);
+        self.occupancy[index / 32] & (1 << (index % 32)) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AabbStats {
+    pub solid_voxels: u32,
+    pub primitive_count: u32,
+    /// Voxels covered by the boxes, solid or not.
+    pub covered_voxels: u32,
+}
+
+impl AabbStats {
+    /// Fraction of the box volume that is solid, 1.0 means rays never miss inside a box.
+    pub fn fill_ratio(&self) -> f32 {
+        if self.covered_voxels == 0 {
+            return 1.0;
+        }
+        self.solid_voxels as f32 / self.covered_voxels as f32
+    }
+}
+
+/// BLAS input of a chunk, in chunk local voxel units.
+pub struct ChunkAabbs {
+    pub granularity: AabbGranularity,
+    pub aabbs: Vec<vk::AabbPositionsKHR>,
+    /// Same length and order as `aabbs`.
+    pub primitives: Vec<PrimitiveInfo>,
+    /// Only filled for [`AabbGranularity::Brick`].
+    pub bricks: Vec<Brick>,
+    /// Voxels of the chunk as [`super::palette::PaletteVoxels::gpu_data`], only filled for
+    /// [`AabbGranularity::Brick`].
+    pub materials: Vec<u32>,
+    pub stats: AabbStats,
+}
+
+impl ChunkAabbs {
+    pub fn new(chunk: &Chunk, granularity: AabbGranularity) -> Self {
+        let mut chunk_aabbs = Self {
+            granularity,
+            aabbs: Vec::new(),
+            primitives: Vec::new(),
+            bricks: Vec::new(),
+            materials: Vec::new(),
+            stats: AabbStats::default(),
+        };
+
+        match granularity {
+            AabbGranularity::Voxel => {
+                for (local, material) in chunk.solid_voxels() {
+                    chunk_aabbs.push(local, local + UVec3::ONE, material.0 as u32);
+                }
+            }
+            AabbGranularity::Brick => {
+                chunk_aabbs.push_bricks(chunk);
+                chunk_aabbs.materials = chunk.voxels_storage().gpu_data();
+            }
+            AabbGranularity::Greedy => chunk_aabbs.push_greedy_boxes(chunk),
+        }
+
+        chunk_aabbs.stats.solid_voxels = chunk.solid_count();
+        chunk_aabbs
+    }
+
+    pub fn primitive_count(&self) -> u32 {
+        self.aabbs.len() as u32
+    }
+
+    fn push(&mut self, min: UVec3, max: UVec3, data: u32) {
+        self.push_with_origin(min, max, min, data);
+    }
+
+    fn push_with_origin(&mut self, min: UVec3, max: UVec3, origin: UVec3, data: u32) {
+        let (min_f, max_f) = (min.as_vec3(), max.as_vec3());
+        self.aabbs.push(vk::AabbPositionsKHR {
+            min_x: min_f.x,
+            min_y: min_f.y,
+            min_z: min_f.z,
+            max_x: max_f.x,
+            max_y: max_f.y,
+            max_z: max_f.z,
+        });
+        self.primitives.push(PrimitiveInfo {
+            origin: origin.to_array(),
+            data,
+        });
+
+        self.stats.primitive_count += 1;
+        self.stats.covered_voxels += (max - min).element_product();
+    }
+
+    fn push_bricks(&mut self, chunk: &Chunk) {
+        for brick_z in 0..BRICKS_PER_AXIS {
+            for brick_y in 0..BRICKS_PER_AXIS {
+                for brick_x in 0..BRICKS_PER_AXIS {
+                    let brick_origin = UVec3::new(brick_x, brick_y, brick_z) * BRICK_SIZE;
+
+                    let mut brick = Brick::zeroed();
+                    let mut min = UVec3::splat(BRICK_SIZE);
+                    let mut max = UVec3::ZERO;
+
+                    for index in 0..BRICK_VOLUME {
+                        let local = UVec3::new(
+                            index as u32 % BRICK_SIZE,
+                            (index as u32 / BRICK_SIZE) % BRICK_SIZE,
+                            index as u32 / (BRICK_SIZE * BRICK_SIZE),
+                        );
+                        let material = chunk.get(brick_origin + local);
+                        if material.is_air() {
+                            continue;
+                        }
+
+                        brick.occupancy[index / 32] |= 1 << (index % 32);
+                        min = min.min(local);
+                        max = max.max(local + UVec3::ONE);
+                    }
+
+                    if min.cmpge(max).any() {
+                        continue;
+                    }
+
+                    let brick_index = self.bricks.len() as u32;
+                    self.bricks.push(brick);
+                    self.push_with_origin(
+                        brick_origin + min,
+                        brick_origin + max,
+                        brick_origin,
+                        brick_index,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Grows boxes along x, then y, then z over unvisited voxels of the same material.
+    fn push_greedy_boxes(&mut self, chunk: &Chunk) {
+        let mut visited = vec![false; CHUNK_VOLUME];
+        let matches = |visited: &[bool], position: UVec3, material: MaterialId| {
+            !visited[Chunk::index(position)] && chunk.get(position) == material
+        };
+
+        for index in 0..visited.len() {
+            let material = chunk.get(Chunk::position(index));
+            if visited[index] || material.is_air() {
+                continue;
+            }
+
+            let min = Chunk::position(index);
+            let mut max = min + UVec3::ONE;
+
+            while max.x < CHUNK_SIZE && matches(&visited, UVec3::new(max.x, min.y, min.z), material)
+            {
+                max.x += 1;
+            }
+
+            while max.y < CHUNK_SIZE
+                && (min.x..max.x).all(|x| matches(&visited, UVec3::new(x, max.y, min.z), material))
+            {
+                max.y += 1;
+            }
+
+            while max.z < CHUNK_SIZE
+                && (min.y..max.y).all(|y| {
+                    (min.x..max.x).all(|x| matches(&visited, UVec3::new(x, y, max.z), material))
+                })
+            {
+                max.z += 1;
+            }
+
+            for z in min.z..max.z {
+                for y in min.y..max.y {
+                    for x in min.x..max.x {
+                        visited[Chunk::index(UVec3::new(x, y, z))] = true;
+                    }
+                }
+            }
+
+            self.push(min, max, material.0 as u32);
+        }
+    }
+}
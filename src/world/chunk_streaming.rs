@@ -0,0 +1,591 @@
+//! Keeps the chunks around the camera loaded. Chunks are read from a [`WorldSave`] or
+//! generated on worker threads, turned into [`ChunkAabbs`] there too, and handed to the
+//! renderer by [`ChunkStreamer::poll`]. Far chunks are unloaded, saved first if edited.
+//! With a [`HostBlasBuilder`], the workers also build the BLAS of each loaded chunk.
+//!
+//! Two hysteresis keep the streaming from thrashing:
+//! - chunks load within `load_radius` but only unload past `unload_radius`,
+//! - over the GPU memory budget the farthest chunks are evicted down to the low watermark
+//!   and the load radius shrinks. It grows back only once the next ring of chunks is expected
+//!   to fit under the low watermark.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use glam::{IVec3, Vec3};
+use log::{error, info, trace};
+
+use crate::volcan::{
+    raytracing_accecleration_structure::Blas, raytracing_chunk_streaming::build_loaded_chunk_blas,
+    raytracing_host_build::HostBlasBuilder,
+};
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE},
+    chunk_aabbs::{AabbGranularity, ChunkAabbs},
+    terrain::TerrainGenerator,
+    voxel_world::World,
+    world_save::{WorldSave, WorldSaveError},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingSettings {
+    /// Horizontal load distance around the camera chunk, in chunks.
+    pub load_radius: u32,
+    /// Loaded chunks farther than this are unloaded, at least `load_radius`.
+    pub unload_radius: u32,
+    /// Loaded chunks above and below the camera chunk.
+    pub vertical_radius: u32,
+    pub worker_threads: usize,
+    /// Chunks requested from the workers at once, so that the nearest ones stay first
+    /// in line when the camera moves.
+    pub max_in_flight: usize,
+    pub granularity: AabbGranularity,
+    /// GPU memory of the resident chunks, see [`ChunkStreamer::set_gpu_bytes`].
+    pub gpu_budget: u64,
+    /// Evictions go down to this fraction of the budget.
+    pub low_watermark: f32,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            load_radius: 8,
+            unload_radius: 10,
+            vertical_radius: 3,
+            worker_threads: 4,
+            max_in_flight: 64,
+            granularity: AabbGranularity::Brick,
+            gpu_budget: 512 * 1024 * 1024,
+            low_watermark: 0.8,
+        }
+    }
+}
+
+/// Changes for the renderer since the last [`ChunkStreamer::poll`].
+#[derive(Default)]
+pub struct StreamingUpdate {
+    /// Loaded or edited chunks, replacing the previous geometry of the same position.
+    pub upload: Vec<(IVec3, ChunkAabbs)>,
+    /// Chunks to drop from the GPU, unloaded or emptied by edits.
+    pub remove: Vec<IVec3>,
+    /// BLASes built by the workers for loads of `upload`. The ones whose chunk is not in
+    /// `upload` anymore must be destroyed.
+    pub host_blases: Vec<(IVec3, Blas)>,
+}
+
+struct LoadedChunk {
+    position: IVec3,
+    chunk: Chunk,
+    /// `None` for empty chunks.
+    aabbs: Option<ChunkAabbs>,
+    blas: Option<Blas>,
+}
+
+pub struct ChunkStreamer {
+    pub settings: StreamingSettings,
+    /// The loaded chunks, edit them through [`World::set_voxel`].
+    pub world: World,
+    save: Option<Arc<WorldSave>>,
+    generator: Arc<TerrainGenerator>,
+
+    /// Loaded positions, empty chunks included.
+    loaded: HashSet<IVec3>,
+    in_flight: HashSet<IVec3>,
+    /// Loaded chunks with edits not written to the save yet.
+    unsaved: HashSet<IVec3>,
+    /// GPU memory of the resident chunks, reported by the renderer.
+    gpu_bytes: HashMap<IVec3, u64>,
+    /// Horizontal radius still allowed by the GPU budget.
+    budget_radius: u32,
+
+    requests: Option<Sender<IVec3>>,
+    results: Receiver<LoadedChunk>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ChunkStreamer {
+    /// Chunks missing from `save` are generated with `generator`, which should use the settings
+    /// of the save. With `host_blas_builder`, loaded chunks come with their BLAS.
+    pub fn new(
+        settings: StreamingSettings,
+        generator: TerrainGenerator,
+        save: Option<Arc<WorldSave>>,
+        host_blas_builder: Option<HostBlasBuilder>,
+    ) -> Self {
+        let (request_sender, request_receiver) = mpsc::channel::<IVec3>();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+        let generator = Arc::new(generator);
+
+        let workers = (0..settings.worker_threads.max(1))
+            .map(|_| {
+                let requests = request_receiver.clone();
+                let results = result_sender.clone();
+                let generator = generator.clone();
+                let save = save.clone();
+                let host_blas_builder = host_blas_builder.clone();
+                let granularity = settings.granularity;
+
+                std::thread::spawn(move || loop {
+                    // The lock is released before loading, the other workers keep receiving.
+                    let Ok(position) = requests.lock().unwrap().recv() else {
+                        return;
+                    };
+                    let chunk = load_or_generate(save.as_deref(), &generator, position);
+                    let aabbs = (!chunk.is_empty()).then(|| ChunkAabbs::new(&chunk, granularity));
+                    if let Some(aabbs) = &aabbs {
+                        trace!(
+                            "Chunk {position}: {} primitives, {:.0}% solid",
+                            aabbs.primitive_count(),
+                            aabbs.stats.fill_ratio() * 100.0
+                        );
+                    }
+                    let blas = host_blas_builder
+                        .as_ref()
+                        .zip(aabbs.as_ref())
+                        .and_then(|(builder, aabbs)| build_loaded_chunk_blas(builder, aabbs));
+                    let loaded = LoadedChunk {
+                        position,
+                        chunk,
+                        aabbs,
+                        blas,
+                    };
+                    if results.send(loaded).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            budget_radius: settings.load_radius,
+            settings,
+            world: World::new(),
+            save,
+            generator,
+            loaded: HashSet::new(),
+            in_flight: HashSet::new(),
+            unsaved: HashSet::new(),
+            gpu_bytes: HashMap::new(),
+            requests: Some(request_sender),
+            results: result_receiver,
+            workers,
+        }
+    }
+
+    /// Generates the chunks missing from the save.
+    pub fn generator(&self) -> &TerrainGenerator {
+        &self.generator
+    }
+
+    pub fn camera_chunk(camera_position: Vec3) -> IVec3 {
+        (camera_position / CHUNK_SIZE as f32).floor().as_ivec3()
+    }
+
+    /// Horizontal distance in chunks, `None` past the vertical radius.
+    fn horizontal_distance(&self, center: IVec3, position: IVec3) -> Option<f32> {
+        let offset = position - center;
+        (offset.y.unsigned_abs() <= self.settings.vertical_radius)
+            .then(|| (offset.x as f32).hypot(offset.z as f32))
+    }
+
+    fn positions_within(&self, center: IVec3, radius: u32) -> impl Iterator<Item = IVec3> + '_ {
+        let horizontal = radius as i32;
+        let vertical = self.settings.vertical_radius as i32;
+        (-horizontal..=horizontal).flat_map(move |x| {
+            (-vertical..=vertical).flat_map(move |y| {
+                (-horizontal..=horizontal).filter_map(move |z| {
+                    let position = center + IVec3::new(x, y, z);
+                    self.horizontal_distance(center, position)
+                        .filter(|&distance| distance <= radius as f32)
+                        .map(|_| position)
+                })
+            })
+        })
+    }
+
+    /// Records the GPU memory of a resident chunk, zero once it left the GPU.
+    pub fn set_gpu_bytes(&mut self, position: IVec3, bytes: u64) {
+        if bytes == 0 {
+            self.gpu_bytes.remove(&position);
+        } else {
+            self.gpu_bytes.insert(position, bytes);
+        }
+    }
+
+    pub fn gpu_bytes(&self) -> u64 {
+        self.gpu_bytes.values().sum()
+    }
+
+    pub fn loaded_count(&self) -> usize {
+        self.loaded.len()
+    }
+
+    pub fn budget_radius(&self) -> u32 {
+        self.budget_radius
+    }
+
+    /// No load in flight: after a [`ChunkStreamer::poll`], every chunk within the radius is
+    /// loaded and its upload was returned.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// Collects the finished loads and the edits, unloads the far chunks and requests the
+    /// missing near ones, nearest first.
+    pub fn poll(&mut self, camera_position: Vec3) -> StreamingUpdate {
+        let center = Self::camera_chunk(camera_position);
+        let mut update = StreamingUpdate::default();
+
+        // Edits since the last poll, loads below are not dirty.
+        for position in self.world.take_dirty_chunks() {
+            // Edits outside of the loaded chunks would replace the whole chunk once loaded.
+            if !self.loaded.contains(&position) {
+                self.world.remove_chunk(position);
+                continue;
+            }
+            self.unsaved.insert(position);
+            match self.world.chunk(position) {
+                Some(chunk) => update
+                    .upload
+                    .push((position, ChunkAabbs::new(chunk, self.settings.granularity))),
+                None => update.remove.push(position),
+            }
+        }
+
+        let unload_radius = self.settings.unload_radius.max(self.settings.load_radius) as f32;
+        while let Ok(loaded) = self.results.try_recv() {
+            self.in_flight.remove(&loaded.position);
+            if let Some(blas) = loaded.blas {
+                update.host_blases.push((loaded.position, blas));
+            }
+            // The camera moved away while it was loading.
+            let wanted = self
+                .horizontal_distance(center, loaded.position)
+                .is_some_and(|distance| distance <= unload_radius);
+            if !wanted {
+                continue;
+            }
+
+            self.loaded.insert(loaded.position);
+            self.world.insert_chunk(loaded.position, loaded.chunk);
+            if let Some(aabbs) = loaded.aabbs {
+                update.upload.push((loaded.position, aabbs));
+            }
+        }
+        self.world.take_dirty_chunks();
+
+        let far: Vec<IVec3> = self
+            .loaded
+            .iter()
+            .copied()
+            .filter(|&position| {
+                self.horizontal_distance(center, position)
+                    .is_none_or(|distance| distance > unload_radius)
+            })
+            .collect();
+        self.unload(&far, &mut update);
+
+        self.enforce_gpu_budget(center, &mut update);
+        self.request_missing(center);
+
+        update
+    }
+
+    fn unload(&mut self, positions: &[IVec3], update: &mut StreamingUpdate) {
+        if positions.is_empty() {
+            return;
+        }
+
+        let unsaved: Vec<IVec3> = positions
+            .iter()
+            .copied()
+            .filter(|position| self.unsaved.remove(position))
+            .collect();
+        if let Some(save) = &self.save {
+            if let Err(error) = save.save_chunks(&self.world, &unsaved) {
+                error!("Cannot save unloaded chunks: {error}");
+            }
+        }
+
+        for &position in positions {
+            self.loaded.remove(&position);
+            if self.world.remove_chunk(position).is_some() {
+                update.remove.push(position);
+            }
+            // Edits of a chunk uploaded in this same poll.
+            update.upload.retain(|(uploaded, _)| *uploaded != position);
+        }
+        self.world.take_dirty_chunks();
+    }
+
+    /// Evicts the farthest chunks when over budget, and only lets the radius grow back once
+    /// the next ring fits under the low watermark.
+    fn enforce_gpu_budget(&mut self, center: IVec3, update: &mut StreamingUpdate) {
+        let budget = self.settings.gpu_budget;
+        let low_watermark = (budget as f64 * self.settings.low_watermark as f64) as u64;
+        let mut used = self.gpu_bytes();
+
+        if used > budget {
+            let mut resident: Vec<(f32, IVec3)> = self
+                .gpu_bytes
+                .keys()
+                .map(|&position| {
+                    let distance = self.horizontal_distance(center, position);
+                    (distance.unwrap_or(f32::INFINITY), position)
+                })
+                .collect();
+            resident.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut evicted = Vec::new();
+            let mut nearest_evicted = f32::INFINITY;
+            for (distance, position) in resident {
+                if used <= low_watermark {
+                    break;
+                }
+                used -= self.gpu_bytes.remove(&position).unwrap_or(0);
+                evicted.push(position);
+                nearest_evicted = distance;
+            }
+
+            // Evicted chunks must not be requested again.
+            let radius = (nearest_evicted.ceil() as u32).saturating_sub(1);
+            self.budget_radius = self.budget_radius.min(radius);
+            info!(
+                "Over the chunk GPU budget, evicted {} chunks, load radius {}",
+                evicted.len(),
+                self.budget_radius
+            );
+
+            self.unload(&evicted, update);
+            return;
+        }
+
+        if self.budget_radius >= self.settings.load_radius || self.gpu_bytes.is_empty() {
+            return;
+        }
+
+        // Average memory per loaded position, empty chunks included.
+        let resident_loaded = self
+            .loaded
+            .iter()
+            .filter(|position| {
+                self.horizontal_distance(center, **position)
+                    .is_some_and(|distance| distance <= self.budget_radius as f32)
+            })
+            .count()
+            .max(1);
+        let bytes_per_position = used as f64 / resident_loaded as f64;
+        let next_ring = self
+            .positions_within(center, self.budget_radius + 1)
+            .count()
+            - self.positions_within(center, self.budget_radius).count();
+
+        if used as f64 + bytes_per_position * next_ring as f64 <= low_watermark as f64 {
+            self.budget_radius += 1;
+        }
+    }
+
+    fn request_missing(&mut self, center: IVec3) {
+        let Some(requests) = &self.requests else {
+            return;
+        };
+        let radius = self.settings.load_radius.min(self.budget_radius);
+        let free_slots = self
+            .settings
+            .max_in_flight
+            .saturating_sub(self.in_flight.len());
+        if free_slots == 0 {
+            return;
+        }
+
+        let mut missing: Vec<IVec3> = self
+            .positions_within(center, radius)
+            .filter(|position| {
+                !self.loaded.contains(position) && !self.in_flight.contains(position)
+            })
+            .collect();
+        missing.sort_by_key(|position| (*position - center).length_squared());
+
+        for position in missing.into_iter().take(free_slots) {
+            if requests.send(position).is_err() {
+                return;
+            }
+            self.in_flight.insert(position);
+        }
+    }
+
+    /// Writes the edited chunks still loaded, e.g. before exiting.
+    pub fn save_all(&mut self) -> Result<(), WorldSaveError> {
+        let Some(save) = &self.save else {
+            return Ok(());
+        };
+        let unsaved: Vec<IVec3> = self.unsaved.drain().collect();
+        save.save_chunks(&self.world, &unsaved)
+    }
+
+    /// Stops the workers. Returns the BLASes of the loads not polled yet, to destroy.
+    pub fn stop(&mut self) -> Vec<Blas> {
+        // Closing the request channel stops the workers after their current chunk.
+        self.requests = None;
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+        self.results
+            .try_iter()
+            .filter_map(|loaded| loaded.blas)
+            .collect()
+    }
+}
+
+impl Drop for ChunkStreamer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn load_or_generate(
+    save: Option<&WorldSave>,
+    generator: &TerrainGenerator,
+    position: IVec3,
+) -> Chunk {
+    match save.map(|save| save.load_chunk(position)) {
+        Some(Ok(Some(chunk))) => chunk,
+        Some(Err(error)) => {
+            error!("Cannot load chunk {position}, generating it: {error}");
+            generator.generate_chunk(position)
+        }
+        _ => generator.generate_chunk(position),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::world::terrain::TerrainSettings;
+
+    fn streamer(settings: StreamingSettings) -> ChunkStreamer {
+        let generator = TerrainGenerator::new(TerrainSettings::default());
+        ChunkStreamer::new(settings, generator, None, None)
+    }
+
+    /// Polls at `camera_chunk` until every chunk within the radius is loaded.
+    fn poll_until_idle(streamer: &mut ChunkStreamer, camera_chunk: IVec3) {
+        let camera_position = (camera_chunk * CHUNK_SIZE as i32).as_vec3() + 0.5;
+        let deadline = Instant::now() + Duration::from_secs(60);
+        loop {
+            streamer.poll(camera_position);
+            if streamer.is_idle() {
+                return;
+            }
+            assert!(Instant::now() < deadline, "chunks still loading");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn loaded(streamer: &ChunkStreamer) -> Vec<IVec3> {
+        let mut loaded: Vec<IVec3> = streamer.loaded.iter().copied().collect();
+        loaded.sort_by_key(|position| position.to_array());
+        loaded
+    }
+
+    #[test]
+    fn unloads_only_past_the_unload_radius() {
+        let mut streamer = streamer(StreamingSettings {
+            load_radius: 1,
+            unload_radius: 2,
+            vertical_radius: 0,
+            worker_threads: 2,
+            ..StreamingSettings::default()
+        });
+
+        poll_until_idle(&mut streamer, IVec3::ZERO);
+        assert_eq!(
+            loaded(&streamer),
+            [
+                IVec3::new(-1, 0, 0),
+                IVec3::new(0, 0, -1),
+                IVec3::new(0, 0, 0),
+                IVec3::new(0, 0, 1),
+                IVec3::new(1, 0, 0),
+            ]
+        );
+
+        // Two chunks away, the previous center is within the unload radius and stays loaded.
+        poll_until_idle(&mut streamer, IVec3::new(2, 0, 0));
+        assert_eq!(
+            loaded(&streamer),
+            [
+                IVec3::new(0, 0, 0),
+                IVec3::new(1, 0, 0),
+                IVec3::new(2, 0, -1),
+                IVec3::new(2, 0, 0),
+                IVec3::new(2, 0, 1),
+                IVec3::new(3, 0, 0),
+            ]
+        );
+        assert!(streamer.world.chunk(IVec3::new(-1, 0, 0)).is_none());
+        assert!(streamer.world.chunk(IVec3::ZERO).is_some());
+
+        // Back within the load radius of the first center, nothing is unloaded.
+        poll_until_idle(&mut streamer, IVec3::new(1, 0, 0));
+        assert!(loaded(&streamer).contains(&IVec3::new(3, 0, 0)));
+        assert!(loaded(&streamer).contains(&IVec3::new(2, 0, 1)));
+    }
+
+    #[test]
+    fn budget_radius_shrinks_over_budget_and_grows_back_once_the_next_ring_fits() {
+        let mut streamer = streamer(StreamingSettings {
+            load_radius: 2,
+            unload_radius: 2,
+            vertical_radius: 0,
+            worker_threads: 1,
+            gpu_budget: 100,
+            low_watermark: 0.8,
+            ..StreamingSettings::default()
+        });
+
+        // 13 resident chunks of 10 bytes, over the budget of 100.
+        let resident: Vec<IVec3> = streamer.positions_within(IVec3::ZERO, 2).collect();
+        assert_eq!(resident.len(), 13);
+        for &position in &resident {
+            streamer.loaded.insert(position);
+            streamer.set_gpu_bytes(position, 10);
+        }
+
+        let mut update = StreamingUpdate::default();
+        streamer.enforce_gpu_budget(IVec3::ZERO, &mut update);
+
+        // The farthest chunks are evicted down to the low watermark of 80 bytes: the 4 chunks
+        // 2 away and one of the diagonal ones, so only the radius 1 is kept.
+        assert_eq!(streamer.gpu_bytes(), 80);
+        assert_eq!(streamer.loaded_count(), 8);
+        assert_eq!(streamer.budget_radius(), 1);
+        for position in [
+            IVec3::new(2, 0, 0),
+            IVec3::new(-2, 0, 0),
+            IVec3::new(0, 0, 2),
+            IVec3::new(0, 0, -2),
+        ] {
+            assert!(!streamer.loaded.contains(&position), "{position}");
+        }
+
+        // Under budget, but the next ring of 8 chunks at 16 bytes each would not fit.
+        streamer.enforce_gpu_budget(IVec3::ZERO, &mut update);
+        assert_eq!(streamer.budget_radius(), 1);
+
+        // It fits under a larger budget.
+        streamer.settings.gpu_budget = 1000;
+        streamer.enforce_gpu_budget(IVec3::ZERO, &mut update);
+        assert_eq!(streamer.budget_radius(), 2);
+    }
+}
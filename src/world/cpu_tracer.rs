@@ -0,0 +1,434 @@
+//! Reference tracer for the ray tracing pipeline: same chunk AABBs, same intersection code
+//! (see [`super::voxel_dda`]), same camera, same shading. Runs anywhere, so terrain, DDA,
+//! materials and camera changes can be checked against golden images without a GPU.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use glam::{IVec3, UVec2, Vec3};
+
+use super::{
+    camera::Camera,
+    chunk::{Chunk, CHUNK_SIZE},
+    chunk_aabbs::{AabbGranularity, ChunkAabbs},
+    voxel_dda::{self, Ray, VoxelHit},
+    voxel_world::World,
+};
+
+/// Distance range of the rays of `raygen.rgen`.
+pub const RAY_T_MIN: f32 = 0.001;
+pub const RAY_T_MAX: f32 = 10000.0;
+
+/// Color of `raymiss.rmiss`.
+pub const SKY_COLOR: Vec3 = Vec3::new(0.0, 0.0, 1.0);
+
+/// Light direction of `shaders/include/voxel_shading.glsl`, not normalized.
+const SUN_DIRECTION: Vec3 = Vec3::new(0.4, 1.0, 0.3);
+
+/// RGBA8 image, rows from top to bottom. Same layout as the ray tracing output image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Result of [`RgbaImage::compare`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageDifference {
+    /// Pixels with at least one channel differing by more than the tolerance.
+    pub differing_pixels: u32,
+    pub max_channel_difference: u8,
+}
+
+impl RgbaImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    /// Channel by channel comparison. Images of different sizes differ everywhere.
+    pub fn compare(&self, other: &RgbaImage, tolerance: u8) -> ImageDifference {
+        if (self.width, self.height) != (other.width, other.height) {
+            return ImageDifference {
+                differing_pixels: self.width.max(other.width) * self.height.max(other.height),
+                max_channel_difference: u8::MAX,
+            };
+        }
+
+        let mut difference = ImageDifference::default();
+        for (a, b) in self
+            .pixels
+            .chunks_exact(4)
+            .zip(other.pixels.chunks_exact(4))
+        {
+            let max = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+
+            difference.max_channel_difference = difference.max_channel_difference.max(max);
+            if max > tolerance {
+                difference.differing_pixels += 1;
+            }
+        }
+        difference
+    }
+
+    /// Every pixel is `color`, alpha aside. A traced frame of only [`SKY_COLOR`] means no ray
+    /// hit anything.
+    pub fn is_uniform(&self, color: Vec3) -> bool {
+        let rgb = color.to_array().map(to_unorm8);
+        self.pixels.chunks_exact(4).all(|pixel| pixel[..3] == rgb)
+    }
+
+    /// Binary PPM, the alpha channel is dropped.
+    pub fn save_ppm(&self, path: &Path) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in self.pixels.chunks_exact(4) {
+            file.write_all(&pixel[..3])?;
+        }
+        file.flush()
+    }
+}
+
+/// `UNORM` conversion done by `imageStore` on the `rgba8` output.
+fn to_unorm8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// The world as the GPU sees it: one set of AABBs per chunk, placed at the chunk origin.
+pub struct CpuTracer {
+    chunks: HashMap<IVec3, ChunkAabbs>,
+}
+
+impl CpuTracer {
+    pub fn new(world: &World, granularity: AabbGranularity) -> Self {
+        Self {
+            chunks: world
+                .chunks()
+                .map(|(position, chunk)| (position, ChunkAabbs::new(chunk, granularity)))
+                .collect(),
+        }
+    }
+
+    /// Closest hit in world space, with the position of the hit chunk. Like the TLAS, chunks
+    /// are visited front to back and the search stops once the next one is farther than the
+    /// closest hit.
+    pub fn trace(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(IVec3, VoxelHit)> {
+        let chunk_extent = Vec3::splat(CHUNK_SIZE as f32);
+
+        let mut candidates: Vec<(f32, IVec3)> = self
+            .chunks
+            .keys()
+            .filter_map(|&position| {
+                let origin = Chunk::origin(position).as_vec3();
+                voxel_dda::intersect_box(ray, origin, origin + chunk_extent, t_min, t_max)
+                    .map(|(t_enter, _, _)| (t_enter, position))
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut closest: Option<(IVec3, VoxelHit)> = None;
+        for (t_enter, position) in candidates {
+            let t_max = closest.map_or(t_max, |(_, hit)| hit.t);
+            if t_enter > t_max {
+                break;
+            }
+
+            // Object space of the chunk instance, distances are unchanged by the translation.
+            let local_ray = Ray::new(
+                ray.origin - Chunk::origin(position).as_vec3(),
+                ray.direction,
+            );
+            if let Some(hit) =
+                voxel_dda::trace_chunk(&self.chunks[&position], &local_ray, t_min, t_max)
+            {
+                closest = Some((position, hit));
+            }
+        }
+
+        closest
+    }
+
+    /// Color of the closest hit shader, or of the miss shader.
+    pub fn shade(hit: Option<&VoxelHit>) -> Vec3 {
+        let Some(hit) = hit else {
+            return SKY_COLOR;
+        };
+
+        // Chunk instances are only translated, the object space normal is the world one.
+        let normal = hit.normal().as_vec3();
+        let light = 0.25 + 0.75 * normal.dot(SUN_DIRECTION.normalize()).max(0.0);
+        hit.material.color() * light
+    }
+
+    pub fn render_pixel(&self, camera: &Camera, pixel: UVec2, resolution: UVec2) -> [u8; 4] {
+        let ray = camera.ray(pixel, resolution);
+        let hit = self.trace(&ray, RAY_T_MIN, RAY_T_MAX);
+        let color = Self::shade(hit.as_ref().map(|(_, hit)| hit));
+
+        [
+            to_unorm8(color.x),
+            to_unorm8(color.y),
+            to_unorm8(color.z),
+            u8::MAX,
+        ]
+    }
+
+    /// Renders the image `raygen.rgen` would produce, rows split across `threads` threads.
+    pub fn render(&self, camera: &Camera, width: u32, height: u32, threads: usize) -> RgbaImage {
+        let mut image = RgbaImage::new(width, height);
+        let resolution = UVec2::new(width, height);
+        let row_size = (width * 4) as usize;
+        let rows_per_thread = (height as usize).div_ceil(threads.max(1)).max(1);
+
+        std::thread::scope(|scope| {
+            for (band_index, band) in image
+                .pixels
+                .chunks_mut(row_size * rows_per_thread)
+                .enumerate()
+            {
+                scope.spawn(move || {
+                    for (row_index, row) in band.chunks_exact_mut(row_size).enumerate() {
+                        let y = (band_index * rows_per_thread + row_index) as u32;
+                        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                            let color =
+                                self.render_pixel(camera, UVec2::new(x as u32, y), resolution);
+                            pixel.copy_from_slice(&color);
+                        }
+                    }
+                });
+            }
+        });
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        path::PathBuf,
+    };
+
+    use glam::UVec3;
+
+    use super::*;
+    use crate::world::{
+        material::MaterialId,
+        terrain::{TerrainGenerator, TerrainSettings},
+    };
+
+    /// Set to rewrite the golden images instead of comparing with them.
+    const UPDATE_GOLDEN_VARIABLE: &str = "VOXRT_UPDATE_GOLDEN";
+
+    fn pixel(image: &RgbaImage, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((x + y * image.width) * 4) as usize;
+        image.pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    /// Reads a binary PPM written by [`RgbaImage::save_ppm`], alpha is set to 255.
+    fn load_ppm(path: &Path) -> io::Result<RgbaImage> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(fs::File::open(path)?);
+
+        // Header fields are whitespace separated, comments are not supported.
+        let mut fields = Vec::new();
+        while fields.len() < 4 {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(invalid("Truncated PPM header"));
+            }
+            fields.extend(line.split_whitespace().map(str::to_string));
+        }
+
+        if fields[0] != "P6" || fields[3] != "255" {
+            return Err(invalid("Only 8 bit binary PPM files are supported"));
+        }
+        let width: u32 = fields[1]
+            .parse()
+            .map_err(|_| invalid("Invalid PPM width"))?;
+        let height: u32 = fields[2]
+            .parse()
+            .map_err(|_| invalid("Invalid PPM height"))?;
+
+        let mut rgb = vec![0; (width * height * 3) as usize];
+        reader.read_exact(&mut rgb)?;
+
+        let mut image = RgbaImage::new(width, height);
+        for (rgba, rgb) in image.pixels.chunks_exact_mut(4).zip(rgb.chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+            rgba[3] = u8::MAX;
+        }
+        Ok(image)
+    }
+
+    fn golden_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/cpu_tracer")
+            .join(format!("{name}.ppm"))
+    }
+
+    /// Only rounding differences are allowed, e.g. from `sin` and `cos` on other platforms.
+    fn assert_matches_golden(name: &str, image: &RgbaImage) {
+        let path = golden_path(name);
+        if std::env::var_os(UPDATE_GOLDEN_VARIABLE).is_some() {
+            image.save_ppm(&path).unwrap();
+            return;
+        }
+
+        let golden = load_ppm(&path).unwrap();
+        let difference = image.compare(&golden, 2);
+        if difference.differing_pixels > image.width * image.height / 200 {
+            let actual = std::env::temp_dir().join(format!("{name}.actual.ppm"));
+            image.save_ppm(&actual).unwrap();
+            panic!(
+                "{name} differs from {}: {difference:?}, rendered to {}",
+                path.display(),
+                actual.display()
+            );
+        }
+    }
+
+    /// Stone floor with a grass pillar and a gold voxel, across negative chunk coordinates.
+    fn voxel_scene() -> World {
+        let mut world = World::new();
+        for z in -6..6 {
+            for x in -6..6 {
+                world.set_voxel(IVec3::new(x, -1, z), MaterialId::STONE);
+            }
+        }
+        for y in 0..4 {
+            world.set_voxel(IVec3::new(-2, y, 1), MaterialId::GRASS);
+        }
+        world.set_voxel(IVec3::new(2, 0, -1), MaterialId::GOLD_ORE);
+        world
+    }
+
+    fn voxel_scene_camera() -> Camera {
+        Camera::looking_at(Vec3::new(7.5, 6.5, -9.5), Vec3::new(0.0, 0.0, 0.0))
+    }
+
+    fn terrain_scene() -> World {
+        let generator = TerrainGenerator::new(TerrainSettings {
+            seed: 7,
+            ..TerrainSettings::default()
+        });
+        let mut positions = Vec::new();
+        for z in -1..=1 {
+            for y in 0..=1 {
+                for x in -1..=1 {
+                    positions.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+
+        let mut world = World::new();
+        for (position, chunk) in positions
+            .iter()
+            .zip(generator.generate_chunks(&positions, 4))
+        {
+            world.insert_chunk(*position, chunk);
+        }
+        world
+    }
+
+    fn terrain_scene_camera() -> Camera {
+        Camera::looking_at(Vec3::new(-40.0, 70.0, -40.0), Vec3::new(8.0, 30.0, 8.0))
+    }
+
+    #[test]
+    fn voxel_scene_matches_golden() {
+        let tracer = CpuTracer::new(&voxel_scene(), AabbGranularity::Brick);
+        let image = tracer.render(&voxel_scene_camera(), 48, 32, 4);
+        assert_matches_golden("voxels", &image);
+    }
+
+    #[test]
+    fn terrain_matches_golden() {
+        let tracer = CpuTracer::new(&terrain_scene(), AabbGranularity::Brick);
+        let image = tracer.render(&terrain_scene_camera(), 64, 40, 4);
+        assert_matches_golden("terrain", &image);
+    }
+
+    #[test]
+    fn granularities_render_the_same_image() {
+        let world = voxel_scene();
+        let camera = voxel_scene_camera();
+        let reference = CpuTracer::new(&world, AabbGranularity::Brick).render(&camera, 48, 32, 2);
+
+        for granularity in [AabbGranularity::Voxel, AabbGranularity::Greedy] {
+            let image = CpuTracer::new(&world, granularity).render(&camera, 48, 32, 2);
+            assert_eq!(image.compare(&reference, 0), ImageDifference::default());
+        }
+    }
+
+    #[test]
+    fn shades_hits_and_misses() {
+        let world = voxel_scene();
+        let tracer = CpuTracer::new(&world, AabbGranularity::Brick);
+
+        // Straight down onto the top face of the gold voxel.
+        let ray = Ray::new(Vec3::new(2.5, 5.0, -0.5), Vec3::NEG_Y);
+        let (chunk, hit) = tracer.trace(&ray, RAY_T_MIN, RAY_T_MAX).unwrap();
+        assert_eq!(chunk, IVec3::new(0, 0, -1));
+        assert_eq!(hit.material, MaterialId::GOLD_ORE);
+        assert_eq!(hit.voxel, UVec3::new(2, 0, 31));
+        assert!((hit.t - 4.0).abs() < 1e-4);
+
+        let light = 0.25 + 0.75 * SUN_DIRECTION.normalize().y;
+        let color = CpuTracer::shade(Some(&hit));
+        assert!(color.abs_diff_eq(MaterialId::GOLD_ORE.color() * light, 1e-6));
+
+        let up = Ray::new(Vec3::new(2.5, 5.0, -0.5), Vec3::Y);
+        assert!(tracer.trace(&up, RAY_T_MIN, RAY_T_MAX).is_none());
+        assert_eq!(CpuTracer::shade(None), SKY_COLOR);
+    }
+
+    #[test]
+    fn detects_frames_of_only_sky() {
+        let tracer = CpuTracer::new(&voxel_scene(), AabbGranularity::Brick);
+        let image = tracer.render(&voxel_scene_camera(), 48, 32, 2);
+        assert!(!image.is_uniform(SKY_COLOR));
+
+        let empty = World::new();
+        let sky =
+            CpuTracer::new(&empty, AabbGranularity::Brick).render(&voxel_scene_camera(), 8, 4, 1);
+        assert!(sky.is_uniform(SKY_COLOR));
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let mut image = RgbaImage::new(3, 2);
+        for (index, channel) in image.pixels.iter_mut().enumerate() {
+            *channel = if index % 4 == 3 { 255 } else { index as u8 * 9 };
+        }
+
+        let path = std::env::temp_dir().join(format!("voxrt-ppm-{}.ppm", std::process::id()));
+        image.save_ppm(&path).unwrap();
+        let loaded = load_ppm(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, image);
+        assert_eq!(pixel(&image, 2, 1), [180, 189, 198, 255]);
+
+        let mut other = image.clone();
+        other.pixels[0] += 3;
+        assert_eq!(
+            image.compare(&other, 2),
+            ImageDifference {
+                differing_pixels: 1,
+                max_channel_difference: 3,
+            }
+        );
+        assert_eq!(image.compare(&other, 3).differing_pixels, 0);
+        assert_eq!(image.compare(&RgbaImage::new(2, 2), 0).differing_pixels, 6);
+    }
+}
@@ -0,0 +1,397 @@
+//! Greedy meshing of chunks for the raster pipeline. The visible faces of each slice are
+//! merged into quads when they share the material and the ambient occlusion of their corners,
+//! so the occlusion still interpolates exactly across the merged quads.
+
+use bytemuck::{Pod, Zeroable};
+use glam::IVec3;
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE},
+    material::MaterialId,
+    voxel_dda::face_normal,
+    voxel_world::World,
+};
+
+/// Vertex of a [`ChunkMesh`], the inputs of `voxel_mesh.vert`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Pod, Zeroable)]
+pub struct VoxelVertex {
+    /// Chunk local, in voxels.
+    pub position: [f32; 3],
+    /// Face of the quad, see [`face_normal`].
+    pub face: u32,
+    pub material: u32,
+    /// Ambient occlusion of the corner, from 0 (enclosed) to 3 (open).
+    pub ambient_occlusion: u32,
+}
+
+/// Triangle list of a chunk, in chunk local voxel units. Front faces are counter clockwise
+/// seen from outside the voxels.
+#[derive(Debug, Default, Clone)]
+pub struct ChunkMesh {
+    pub vertices: Vec<VoxelVertex>,
+    pub indices: Vec<u32>,
+}
+
+/// What must match for two faces of a slice to be merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FaceKey {
+    material: MaterialId,
+    /// In the order of [`QUAD_CORNERS`].
+    ambient_occlusion: [u8; 4],
+}
+
+/// Corners of a face along its two tangent axes, counter clockwise around the positive normal.
+const QUAD_CORNERS: [(i32, i32); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
+
+const PADDED_SIZE: i32 = CHUNK_SIZE as i32 + 2;
+
+/// Solid voxels of a chunk and of the one voxel border around it, which culls the faces
+/// against the neighbour chunks and darkens their corners.
+struct Occupancy {
+    solid: Vec<bool>,
+}
+
+impl Occupancy {
+    fn new(chunk: &Chunk, border: impl Fn(IVec3) -> bool) -> Self {
+        let mut solid = vec![false; (PADDED_SIZE * PADDED_SIZE * PADDED_SIZE) as usize];
+        let size = CHUNK_SIZE as i32;
+
+        for z in -1..=size {
+            for y in -1..=size {
+                for x in -1..=size {
+                    let local = IVec3::new(x, y, z);
+                    let inside =
+                        local.cmpge(IVec3::ZERO).all() && local.cmplt(IVec3::splat(size)).all();
+                    solid[Self::index(local)] = if inside {
+                        chunk.get(local.as_uvec3()).is_solid()
+                    } else {
+                        border(local)
+                    };
+                }
+            }
+        }
+
+        Self { solid }
+    }
+
+    fn index(local: IVec3) -> usize {
+        let padded = local + IVec3::ONE;
+        (padded.x + padded.y * PADDED_SIZE + padded.z * PADDED_SIZE * PADDED_SIZE) as usize
+    }
+
+    fn is_solid(&self, local: IVec3) -> bool {
+        self.solid[Self::index(local)]
+    }
+}
+
+impl ChunkMesh {
+    /// Meshes the chunk at `chunk_position` with the faces against the loaded neighbour chunks
+    /// culled. Remesh the neighbours of an edited chunk too, their border faces depend on it.
+    pub fn from_world(world: &World, chunk_position: IVec3) -> Self {
+        let Some(chunk) = world.chunk(chunk_position) else {
+            return Self::default();
+        };
+
+        let origin = Chunk::origin(chunk_position);
+        let occupancy = Occupancy::new(chunk, |local| world.get_voxel(origin + local).is_solid());
+        Self::build(chunk, &occupancy)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn quad_count(&self) -> usize {
+        self.indices.len() / 6
+    }
+
+    fn build(chunk: &Chunk, occupancy: &Occupancy) -> Self {
+        let mut mesh = Self::default();
+        if chunk.is_empty() {
+            return mesh;
+        }
+
+        let materials: Vec<MaterialId> = chunk.voxels().collect();
+        let size = CHUNK_SIZE as i32;
+        let mut mask: Vec<Option<FaceKey>> = vec![None; (size * size) as usize];
+
+        for face in 0..6 {
+            let axis = (face / 2) as usize;
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let normal = face_normal(face);
+
+            for slice in 0..size {
+                for j in 0..size {
+                    for i in 0..size {
+                        let mut local = IVec3::ZERO;
+                        local[axis] = slice;
+                        local[u] = i;
+                        local[v] = j;
+
+                        let material = materials[Chunk::index(local.as_uvec3())];
+                        let front = local + normal;
+                        mask[(i + j * size) as usize] =
+                            (material.is_solid() && !occupancy.is_solid(front)).then(|| FaceKey {
+                                material,
+                                ambient_occlusion: ambient_occlusion(occupancy, front, u, v),
+                            });
+                    }
+                }
+
+                mesh.merge_slice(&mut mask, face, slice);
+            }
+        }
+
+        mesh
+    }
+
+    /// Grows quads along the first tangent axis, then the second, and clears their faces
+    /// from `mask`.
+    fn merge_slice(&mut self, mask: &mut [Option<FaceKey>], face: u32, slice: i32) {
+        let size = CHUNK_SIZE as i32;
+        let at = |i: i32, j: i32| (i + j * size) as usize;
+
+        for j in 0..size {
+            let mut i = 0;
+            while i < size {
+                let Some(key) = mask[at(i, j)] else {
+                    i += 1;
+                    continue;
+                };
+
+                let mut width = 1;
+                while i + width < size && mask[at(i + width, j)] == Some(key) {
+                    width += 1;
+                }
+
+                let mut height = 1;
+                while j + height < size
+                    && (i..i + width).all(|x| mask[at(x, j + height)] == Some(key))
+                {
+                    height += 1;
+                }
+
+                for y in j..j + height {
+                    for x in i..i + width {
+                        mask[at(x, y)] = None;
+                    }
+                }
+
+                self.push_quad(face, slice, (i, j), (width, height), key);
+                i += width;
+            }
+        }
+    }
+
+    fn push_quad(
+        &mut self,
+        face: u32,
+        slice: i32,
+        (i, j): (i32, i32),
+        (width, height): (i32, i32),
+        key: FaceKey,
+    ) {
+        let axis = (face / 2) as usize;
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let positive = face.is_multiple_of(2);
+
+        let first = self.vertices.len() as u32;
+        for ((corner_u, corner_v), ambient_occlusion) in
+            QUAD_CORNERS.into_iter().zip(key.ambient_occlusion)
+        {
+            let mut position = IVec3::ZERO;
+            position[axis] = slice + positive as i32;
+            position[u] = i + corner_u * width;
+            position[v] = j + corner_v * height;
+
+            self.vertices.push(VoxelVertex {
+                position: position.as_vec3().to_array(),
+                face,
+                material: key.material.0 as u32,
+                ambient_occlusion: ambient_occlusion as u32,
+            });
+        }
+
+        // Split along the darker diagonal, otherwise the occlusion of a single corner
+        // bleeds along the diagonal and looks anisotropic.
+        let ao = key.ambient_occlusion;
+        let corners = if ao[0] + ao[2] > ao[1] + ao[3] {
+            [1, 2, 3, 1, 3, 0]
+        } else {
+            [0, 1, 2, 0, 2, 3]
+        };
+
+        // The corners turn around the positive normal, reversed for the negative faces.
+        let triangles = corners.chunks(3).flat_map(|triangle| {
+            if positive {
+                [triangle[0], triangle[1], triangle[2]]
+            } else {
+                [triangle[0], triangle[2], triangle[1]]
+            }
+        });
+        self.indices.extend(triangles.map(|corner| first + corner));
+    }
+}
+
+/// Occlusion of each corner of a face from the voxels in front of it: both sides solid is
+/// fully occluded, otherwise each solid side and the solid diagonal darken the corner.
+fn ambient_occlusion(occupancy: &Occupancy, front: IVec3, u: usize, v: usize) -> [u8; 4] {
+    QUAD_CORNERS.map(|(corner_u, corner_v)| {
+        let mut side_u = IVec3::ZERO;
+        side_u[u] = corner_u * 2 - 1;
+        let mut side_v = IVec3::ZERO;
+        side_v[v] = corner_v * 2 - 1;
+
+        let solid_u = occupancy.is_solid(front + side_u);
+        let solid_v = occupancy.is_solid(front + side_v);
+        if solid_u && solid_v {
+            return 0;
+        }
+        let solid_corner = occupancy.is_solid(front + side_u + side_v);
+        3 - solid_u as u8 - solid_v as u8 - solid_corner as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{UVec3, Vec3};
+
+    use super::*;
+
+    fn world(chunks: impl IntoIterator<Item = (IVec3, Chunk)>) -> World {
+        let mut world = World::new();
+        for (position, chunk) in chunks {
+            world.insert_chunk(position, chunk);
+        }
+        world
+    }
+
+    /// One voxel thick floor at `y = 0`.
+    fn slab(material: impl Fn(UVec3) -> MaterialId) -> Chunk {
+        Chunk::from_fn(|local| {
+            if local.y == 0 {
+                material(local)
+            } else {
+                MaterialId::AIR
+            }
+        })
+    }
+
+    fn quads(mesh: &ChunkMesh) -> impl Iterator<Item = &[VoxelVertex]> {
+        mesh.vertices.chunks(4)
+    }
+
+    fn quads_per_face(mesh: &ChunkMesh) -> [usize; 6] {
+        let mut counts = [0; 6];
+        for quad in quads(mesh) {
+            counts[quad[0].face as usize] += 1;
+        }
+        counts
+    }
+
+    /// Extent of a quad along each axis, zero along its normal.
+    fn size(quad: &[VoxelVertex]) -> Vec3 {
+        let positions = quad.iter().map(|vertex| Vec3::from(vertex.position));
+        let min = positions.clone().fold(Vec3::MAX, Vec3::min);
+        let max = positions.fold(Vec3::MIN, Vec3::max);
+        max - min
+    }
+
+    #[test]
+    fn single_voxel_gives_six_unit_quads() {
+        let mut chunk = Chunk::new();
+        chunk.set(UVec3::new(5, 6, 7), MaterialId::STONE);
+        let mesh = ChunkMesh::from_world(&world([(IVec3::ZERO, chunk)]), IVec3::ZERO);
+
+        assert_eq!(mesh.quad_count(), 6);
+        assert_eq!(quads_per_face(&mesh), [1; 6]);
+        for quad in quads(&mesh) {
+            let normal = face_normal(quad[0].face).as_vec3();
+            assert_eq!(size(quad), Vec3::ONE - normal.abs());
+            assert!(quad.iter().all(|vertex| vertex.ambient_occlusion == 3));
+        }
+    }
+
+    #[test]
+    fn flat_slab_merges_into_one_quad_per_face() {
+        let chunk = slab(|_| MaterialId::STONE);
+        let mesh = ChunkMesh::from_world(&world([(IVec3::ZERO, chunk)]), IVec3::ZERO);
+
+        assert_eq!(quads_per_face(&mesh), [1; 6]);
+        let top = quads(&mesh).find(|quad| quad[0].face == 2).unwrap();
+        assert_eq!(size(top), Vec3::new(32.0, 0.0, 32.0));
+    }
+
+    #[test]
+    fn different_materials_are_not_merged() {
+        let chunk = slab(|local| {
+            if local.x < 16 {
+                MaterialId::STONE
+            } else {
+                MaterialId::DIRT
+            }
+        });
+        let mesh = ChunkMesh::from_world(&world([(IVec3::ZERO, chunk)]), IVec3::ZERO);
+
+        let top: Vec<_> = quads(&mesh).filter(|quad| quad[0].face == 2).collect();
+        assert_eq!(top.len(), 2);
+        for quad in top {
+            assert_eq!(size(quad), Vec3::new(16.0, 0.0, 32.0));
+        }
+    }
+
+    #[test]
+    fn different_ambient_occlusion_is_not_merged() {
+        let mut chunk = slab(|_| MaterialId::STONE);
+        chunk.set(UVec3::new(16, 1, 16), MaterialId::STONE);
+        let mesh = ChunkMesh::from_world(&world([(IVec3::ZERO, chunk)]), IVec3::ZERO);
+
+        let floor_top = quads(&mesh).filter(|quad| quad[0].face == 2 && quad[0].position[1] == 1.0);
+        let (darkened, open): (Vec<_>, Vec<_>) =
+            floor_top.partition(|quad| quad.iter().any(|vertex| vertex.ambient_occlusion < 3));
+
+        // Each of the 8 faces around the bump is darkened differently.
+        assert_eq!(darkened.len(), 8);
+        for quad in darkened {
+            assert_eq!(size(quad), Vec3::new(1.0, 0.0, 1.0));
+        }
+        let open_area: f32 = open.iter().map(|quad| size(quad).x * size(quad).z).sum();
+        assert_eq!(open_area, (32 * 32 - 9) as f32);
+    }
+
+    #[test]
+    fn faces_against_a_loaded_neighbour_are_culled() {
+        let alone = world([(IVec3::ZERO, slab(|_| MaterialId::STONE))]);
+        assert_eq!(
+            quads_per_face(&ChunkMesh::from_world(&alone, IVec3::ZERO))[0],
+            1
+        );
+
+        let neighbours = world([
+            (IVec3::ZERO, slab(|_| MaterialId::STONE)),
+            (IVec3::X, slab(|_| MaterialId::STONE)),
+        ]);
+        let near = ChunkMesh::from_world(&neighbours, IVec3::ZERO);
+        let far = ChunkMesh::from_world(&neighbours, IVec3::X);
+        assert_eq!(quads_per_face(&near), [0, 1, 1, 1, 1, 1]);
+        assert_eq!(quads_per_face(&far), [1, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn triangles_are_counter_clockwise_from_outside() {
+        let mut chunk = slab(|_| MaterialId::STONE);
+        chunk.set(UVec3::new(16, 1, 16), MaterialId::DIRT);
+        chunk.set(UVec3::new(3, 9, 27), MaterialId::GRASS);
+        let mesh = ChunkMesh::from_world(&world([(IVec3::ZERO, chunk)]), IVec3::ZERO);
+
+        assert!(!mesh.is_empty());
+        for triangle in mesh.indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| mesh.vertices[triangle[corner] as usize]);
+            let normal = face_normal(a.face).as_vec3();
+            let [a, b, c] = [a, b, c].map(|vertex| Vec3::from(vertex.position));
+            let winding = (b - a).cross(c - a);
+            assert!(winding.dot(normal) > 0.0, "{triangle:?} winds clockwise");
+        }
+    }
+}
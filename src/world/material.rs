@@ -0,0 +1,82 @@
+use glam::Vec3;
+
+/// Index into the world's material table. `0` is air, every other value is solid.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaterialId(pub u16);
+
+impl MaterialId {
+    pub const AIR: Self = Self(0);
+
+    // Built-in materials placed by the terrain generator.
+    pub const STONE: Self = Self(1);
+    pub const DIRT: Self = Self(2);
+    pub const GRASS: Self = Self(3);
+    pub const SAND: Self = Self(4);
+    pub const SNOW: Self = Self(5);
+    pub const GRAVEL: Self = Self(6);
+    pub const COAL_ORE: Self = Self(7);
+    pub const IRON_ORE: Self = Self(8);
+    pub const GOLD_ORE: Self = Self(9);
+
+    pub fn is_air(self) -> bool {
+        self == Self::AIR
+    }
+
+    pub fn is_solid(self) -> bool {
+        !self.is_air()
+    }
+
+    /// Albedo of the built-in materials, magenta for unknown ones.
+    pub fn color(self) -> Vec3 {
+        MATERIAL_COLORS
+            .get(self.0 as usize)
+            .copied()
+            .unwrap_or(MATERIAL_COLORS[0])
+    }
+
+    /// Name of the built-in materials, stored in saves so ids can change between versions.
+    pub fn name(self) -> Option<&'static str> {
+        MATERIAL_NAMES.get(self.0 as usize).copied()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        MATERIAL_NAMES
+            .iter()
+            .position(|&built_in| built_in == name)
+            .map(|index| Self(index as u16))
+    }
+
+    /// Every built-in material, air included.
+    pub fn built_in() -> impl Iterator<Item = Self> {
+        (0..MATERIAL_NAMES.len() as u16).map(Self)
+    }
+
+    /// Solid built-in material with the closest albedo, for imported colors.
+    pub fn closest_built_in(color: Vec3) -> Self {
+        let index = (1..MATERIAL_COLORS.len())
+            .min_by(|&a, &b| {
+                let distance = |index: usize| MATERIAL_COLORS[index].distance_squared(color);
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap();
+        Self(index as u16)
+    }
+}
+
+const MATERIAL_NAMES: [&str; 10] = [
+    "air", "stone", "dirt", "grass", "sand", "snow", "gravel", "coal_ore", "iron_ore", "gold_ore",
+];
+
+/// Same table as `shaders/include/voxel_shading.glsl`, keep both in sync.
+const MATERIAL_COLORS: [Vec3; 10] = [
+    Vec3::new(1.0, 0.0, 1.0),
+    Vec3::new(0.5, 0.5, 0.5),
+    Vec3::new(0.45, 0.3, 0.2),
+    Vec3::new(0.3, 0.6, 0.2),
+    Vec3::new(0.85, 0.8, 0.55),
+    Vec3::new(0.95, 0.95, 1.0),
+    Vec3::new(0.55, 0.5, 0.5),
+    Vec3::new(0.15, 0.15, 0.15),
+    Vec3::new(0.7, 0.55, 0.45),
+    Vec3::new(0.9, 0.75, 0.2),
+];
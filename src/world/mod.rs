@@ -0,0 +1,14 @@
+pub mod camera;
+pub mod chunk;
+pub mod chunk_aabbs;
+pub mod chunk_streaming;
+pub mod cpu_tracer;
+pub mod greedy_mesh;
+pub mod material;
+pub mod palette;
+pub mod sparse_tree;
+pub mod terrain;
+pub mod vox;
+pub mod voxel_dda;
+pub mod voxel_world;
+pub mod world_save;
@@ -0,0 +1,436 @@
+//! Palette compression of the chunk voxels: each voxel stores an index into a small local
+//! palette of materials, with as few bits as the palette size allows.
+
+use std::{collections::HashMap, time::Instant};
+
+use glam::{IVec3, UVec3};
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE, CHUNK_VOLUME},
+    material::MaterialId,
+    terrain::{TerrainGenerator, TerrainSettings},
+};
+
+/// Smallest supported index width for a palette of `len` entries. Widths are powers of two
+/// so an index never straddles two words, which keeps the shader decoding simple.
+pub(super) fn bits_for(len: usize) -> u32 {
+    match len {
+        0..=1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+/// `CHUNK_VOLUME` palette indices of `bits_per_voxel` bits, with the palette.
+#[derive(Debug, Clone)]
+pub struct PaletteVoxels {
+    palette: Vec<MaterialId>,
+    /// Voxels using each palette entry. Entries at zero are reused by the next new material.
+    counts: Vec<u32>,
+    /// Number of entries with a non-zero count.
+    live_entries: usize,
+    bits_per_voxel: u32,
+    words: Vec<u32>,
+}
+
+impl PaletteVoxels {
+    /// Every voxel set to `material`, no index storage at all.
+    pub fn filled(material: MaterialId) -> Self {
+        Self {
+            palette: vec![material],
+            counts: vec![CHUNK_VOLUME as u32],
+            live_entries: 1,
+            bits_per_voxel: 0,
+            words: Vec::new(),
+        }
+    }
+
+    /// Voxels in chunk index order.
+    pub fn from_voxels(voxels: impl IntoIterator<Item = MaterialId>) -> Self {
+        let mut palette = Vec::new();
+        let mut counts: Vec<u32> = Vec::new();
+        let mut lookup = HashMap::new();
+
+        let indices: Vec<u16> = voxels
+            .into_iter()
+            .map(|material| {
+                let index = *lookup.entry(material).or_insert_with(|| {
+                    palette.push(material);
+                    counts.push(0);
+                    palette.len() - 1
+                });
+                counts[index] += 1;
+                index as u16
+            })
+            .collect();
+        assert_eq!(
+            indices.len(),
+            CHUNK_VOLUME,
+            "A chunk has {CHUNK_VOLUME} voxels"
+        );
+
+        let mut voxels = Self {
+            live_entries: palette.len(),
+            bits_per_voxel: bits_for(palette.len()),
+            palette,
+            counts,
+            words: Vec::new(),
+        };
+        voxels.pack(&indices);
+        voxels
+    }
+
+    pub fn bits_per_voxel(&self) -> u32 {
+        self.bits_per_voxel
+    }
+
+    /// Materials of the palette, unused entries included.
+    pub fn palette(&self) -> &[MaterialId] {
+        &self.palette
+    }
+
+    /// Number of voxels made of `material`.
+    pub fn count(&self, material: MaterialId) -> u32 {
+        self.palette
+            .iter()
+            .position(|&entry| entry == material)
+            .map_or(0, |index| self.counts[index])
+    }
+
+    fn palette_index(&self, index: usize) -> usize {
+        if self.bits_per_voxel == 0 {
+            return 0;
+        }
+
+        let bit = index * self.bits_per_voxel as usize;
+        let mask = (1u32 << self.bits_per_voxel) - 1;
+        ((self.words[bit / 32] >> (bit % 32)) & mask) as usize
+    }
+
+    fn set_palette_index(&mut self, index: usize, palette_index: usize) {
+        let bit = index * self.bits_per_voxel as usize;
+        let mask = (1u32 << self.bits_per_voxel) - 1;
+        let word = &mut self.words[bit / 32];
+        *word = (*word & !(mask << (bit % 32))) | ((palette_index as u32) << (bit % 32));
+    }
+
+    pub fn get(&self, index: usize) -> MaterialId {
+        self.palette[self.palette_index(index)]
+    }
+
+    /// Returns the previous material.
+    pub fn set(&mut self, index: usize, material: MaterialId) -> MaterialId {
+        let previous_index = self.palette_index(index);
+        let previous = self.palette[previous_index];
+        if previous == material {
+            return previous;
+        }
+
+        let palette_index = match self.palette.iter().position(|&entry| entry == material) {
+            Some(palette_index) => palette_index,
+            None => self.add_entry(material),
+        };
+
+        if self.counts[palette_index] == 0 {
+            self.live_entries += 1;
+        }
+        self.counts[palette_index] += 1;
+        self.counts[previous_index] -= 1;
+        if self.counts[previous_index] == 0 {
+            self.live_entries -= 1;
+        }
+
+        self.set_palette_index(index, palette_index);
+        self.shrink_if_sparse();
+        previous
+    }
+
+    /// New palette entry for `material`, reusing an unused one if any.
+    fn add_entry(&mut self, material: MaterialId) -> usize {
+        if let Some(free) = self.counts.iter().position(|&count| count == 0) {
+            self.palette[free] = material;
+            return free;
+        }
+
+        self.palette.push(material);
+        self.counts.push(0);
+
+        let bits = bits_for(self.palette.len());
+        if bits != self.bits_per_voxel {
+            let indices = self.indices();
+            self.bits_per_voxel = bits;
+            self.pack(&indices);
+        }
+        self.palette.len() - 1
+    }
+
+    /// Drops the unused entries once the live ones would fit twice in a narrower width,
+    /// so that an edit going back and forth over a width boundary does not repack every time.
+    fn shrink_if_sparse(&mut self) {
+        let target_bits = if self.live_entries == 1 {
+            0
+        } else {
+            bits_for(self.live_entries * 2)
+        };
+        if target_bits >= self.bits_per_voxel {
+            return;
+        }
+
+        let indices = self.indices();
+
+        let mut remap = vec![0u16; self.palette.len()];
+        let mut palette = Vec::with_capacity(self.live_entries);
+        let mut counts = Vec::with_capacity(self.live_entries);
+        for (old_index, (&material, &count)) in self.palette.iter().zip(&self.counts).enumerate() {
+            if count > 0 {
+                remap[old_index] = palette.len() as u16;
+                palette.push(material);
+                counts.push(count);
+            }
+        }
+
+        self.palette = palette;
+        self.counts = counts;
+        self.bits_per_voxel = target_bits;
+        let indices: Vec<u16> = indices.iter().map(|&index| remap[index as usize]).collect();
+        self.pack(&indices);
+    }
+
+    fn indices(&self) -> Vec<u16> {
+        (0..CHUNK_VOLUME)
+            .map(|index| self.palette_index(index) as u16)
+            .collect()
+    }
+
+    fn pack(&mut self, indices: &[u16]) {
+        let bits = self.bits_per_voxel as usize;
+        self.words = vec![0; (CHUNK_VOLUME * bits).div_ceil(32)];
+        if bits == 0 {
+            return;
+        }
+
+        for (index, &palette_index) in indices.iter().enumerate() {
+            let bit = index * bits;
+            self.words[bit / 32] |= (palette_index as u32) << (bit % 32);
+        }
+    }
+
+    /// Heap memory used by the voxels.
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of_val(self.palette.as_slice())
+            + std::mem::size_of_val(self.counts.as_slice())
+            + std::mem::size_of_val(self.words.as_slice())
+    }
+
+    /// Layout decoded by `paletteChunkMaterial` in `shaders/include/palette_chunk.glsl`:
+    /// the index width, the palette length, the palette two materials per word, lower half
+    /// first, then the packed indices.
+    pub fn gpu_data(&self) -> Vec<u32> {
+        let mut data = Vec::with_capacity(2 + self.palette.len().div_ceil(2) + self.words.len());
+        data.push(self.bits_per_voxel);
+        data.push(self.palette.len() as u32);
+        data.extend(self.palette.chunks(2).map(|pair| {
+            pair[0].0 as u32 | pair.get(1).map_or(0, |material| material.0 as u32) << 16
+        }));
+        data.extend_from_slice(&self.words);
+        data
+    }
+}
+
+/// CPU version of `paletteChunkMaterial`, decodes a voxel from [`PaletteVoxels::gpu_data`].
+pub fn decode_gpu_material(data: &[u32], index: usize) -> MaterialId {
+    let bits = data[0] as usize;
+    let palette_len = data[1] as usize;
+
+    let palette_index = if bits == 0 {
+        0
+    } else {
+        let bit = index * bits;
+        let word = data[2 + palette_len.div_ceil(2) + bit / 32];
+        ((word >> (bit % 32)) & ((1u32 << bits) - 1)) as usize
+    };
+
+    MaterialId((data[2 + palette_index / 2] >> (16 * (palette_index % 2))) as u16)
+}
+
+/// Memory of `chunks` stored densely, one material per voxel, and with palettes.
+fn memory_bytes(chunks: &[Chunk]) -> (usize, usize) {
+    let dense_bytes = chunks.len() * CHUNK_VOLUME * std::mem::size_of::<MaterialId>();
+    let palette_bytes = chunks.iter().map(Chunk::memory_bytes).sum();
+    (dense_bytes, palette_bytes)
+}
+
+/// Compares the palette storage with the dense one on generated terrain, and times the
+/// accesses. Run with `--palette-benchmark`.
+pub fn run_palette_benchmark() {
+    let generator = TerrainGenerator::new(TerrainSettings::default());
+    let positions: Vec<IVec3> = (-2..2)
+        .flat_map(|x| (-2..3).flat_map(move |y| (-2..2).map(move |z| IVec3::new(x, y, z))))
+        .collect();
+
+    let start = Instant::now();
+    let chunks = generator.generate_chunks(&positions, 8);
+    println!("Generated {} chunks in {:?}", chunks.len(), start.elapsed());
+
+    let (dense_bytes, palette_bytes) = memory_bytes(&chunks);
+    let mut chunks_per_width = [0; 17];
+    for chunk in &chunks {
+        chunks_per_width[chunk.voxels_storage().bits_per_voxel() as usize] += 1;
+    }
+
+    println!(
+        "Dense: {} KiB, palette: {} KiB ({:.1}x smaller)",
+        dense_bytes / 1024,
+        palette_bytes / 1024,
+        dense_bytes as f32 / palette_bytes as f32
+    );
+    for (bits, count) in chunks_per_width
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+    {
+        println!("  {count} chunks at {bits} bits per voxel");
+    }
+
+    let start = Instant::now();
+    let mut solid = 0u32;
+    for chunk in &chunks {
+        for index in 0..CHUNK_VOLUME {
+            solid += chunk.get(Chunk::position(index)).is_solid() as u32;
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "Get: {:.2} ns per voxel ({solid} solid)",
+        elapsed.as_nanos() as f64 / (chunks.len() * CHUNK_VOLUME) as f64
+    );
+
+    // Carve a tunnel through every chunk, then fill it back.
+    let mut edited = chunks.clone();
+    let start = Instant::now();
+    let mut edits = 0;
+    for chunk in &mut edited {
+        for material in [MaterialId::AIR, MaterialId::GOLD_ORE] {
+            for x in 0..CHUNK_SIZE {
+                for y in 12..20 {
+                    chunk.set(UVec3::new(x, y, 16), material);
+                    edits += 1;
+                }
+            }
+        }
+    }
+    println!(
+        "Set: {:.2} ns per edit",
+        start.elapsed().as_nanos() as f64 / edits as f64
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` materials repeating over the chunk.
+    fn striped(count: u16) -> PaletteVoxels {
+        PaletteVoxels::from_voxels((0..CHUNK_VOLUME).map(|index| MaterialId(index as u16 % count)))
+    }
+
+    #[test]
+    fn set_widens_the_indices_as_the_palette_grows() {
+        let mut voxels = PaletteVoxels::filled(MaterialId::STONE);
+        assert_eq!(voxels.bits_per_voxel(), 0);
+
+        // Palette length at which each width is reached. Voxel n gets material 101 + n.
+        let mut materials = 1;
+        for (palette_len, bits) in [(2, 1), (3, 2), (5, 4), (17, 8), (257, 16)] {
+            while materials < palette_len {
+                let previous = voxels.set(materials - 1, MaterialId(100 + materials as u16));
+                assert_eq!(previous, MaterialId::STONE);
+                materials += 1;
+            }
+            assert_eq!(voxels.bits_per_voxel(), bits, "{palette_len} materials");
+
+            for index in 0..materials - 1 {
+                assert_eq!(voxels.get(index), MaterialId(101 + index as u16));
+            }
+            assert_eq!(voxels.get(materials - 1), MaterialId::STONE);
+            assert_eq!(voxels.get(CHUNK_VOLUME - 1), MaterialId::STONE);
+        }
+        assert_eq!(
+            voxels.count(MaterialId::STONE),
+            (CHUNK_VOLUME - materials + 1) as u32
+        );
+    }
+
+    #[test]
+    fn shrink_if_sparse_drops_unused_entries() {
+        let mut voxels = PaletteVoxels::filled(MaterialId::STONE);
+        for index in 0..16 {
+            voxels.set(index, MaterialId(100 + index as u16));
+        }
+        assert_eq!(voxels.bits_per_voxel(), 8);
+        assert_eq!(voxels.palette().len(), 17);
+
+        // 9 live entries do not fit twice in 4 bits, the unused ones are kept.
+        for index in 8..16 {
+            voxels.set(index, MaterialId::STONE);
+        }
+        assert_eq!(voxels.bits_per_voxel(), 8);
+        assert_eq!(voxels.palette().len(), 17);
+
+        // 8 do.
+        voxels.set(7, MaterialId::STONE);
+        assert_eq!(voxels.bits_per_voxel(), 4);
+        assert_eq!(voxels.palette().len(), 8);
+
+        // 3 live entries would need 2 bits, but only shrink once they fit twice.
+        for index in 2..7 {
+            voxels.set(index, MaterialId::STONE);
+        }
+        assert_eq!(voxels.bits_per_voxel(), 4);
+        voxels.set(1, MaterialId::STONE);
+        assert_eq!(voxels.bits_per_voxel(), 2);
+        assert_eq!(voxels.palette(), [MaterialId::STONE, MaterialId(100)]);
+        assert_eq!(voxels.get(0), MaterialId(100));
+        assert_eq!(voxels.get(1), MaterialId::STONE);
+
+        // A single material needs no index at all.
+        voxels.set(0, MaterialId::STONE);
+        assert_eq!(voxels.bits_per_voxel(), 0);
+        assert_eq!(voxels.palette(), [MaterialId::STONE]);
+        assert_eq!(voxels.get(0), MaterialId::STONE);
+    }
+
+    #[test]
+    fn gpu_data_decodes_to_the_same_materials() {
+        for count in [1, 2, 3, 5, 17, 257] {
+            let voxels = striped(count);
+            let data = voxels.gpu_data();
+            for index in (0..CHUNK_VOLUME).step_by(7).chain([CHUNK_VOLUME - 1]) {
+                assert_eq!(
+                    decode_gpu_material(&data, index),
+                    voxels.get(index),
+                    "{count} materials, voxel {index}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn palette_is_smaller_than_dense_storage_on_terrain() {
+        let generator = TerrainGenerator::new(TerrainSettings::default());
+        let positions: Vec<IVec3> = (-1..1)
+            .flat_map(|x| (-1..2).flat_map(move |y| (-1..1).map(move |z| IVec3::new(x, y, z))))
+            .collect();
+        let chunks = generator.generate_chunks(&positions, 4);
+
+        let (dense_bytes, palette_bytes) = memory_bytes(&chunks);
+        assert_eq!(dense_bytes, chunks.len() * CHUNK_VOLUME * 2);
+        assert!(
+            palette_bytes * 4 <= dense_bytes,
+            "palette {palette_bytes} bytes, dense {dense_bytes} bytes"
+        );
+    }
+}
@@ -0,0 +1,702 @@
+//! Sparse 64-tree: every node splits its cube in 4³ cells and only stores the non-empty ones.
+//! An alternative to the flat chunk grid for large, mostly empty worlds.
+//!
+//! The traversal is mirrored by `shaders/include/sparse_tree.glsl`, keep both in sync.
+
+use std::collections::VecDeque;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{IVec3, UVec3, Vec3};
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE, CHUNK_VOLUME},
+    material::MaterialId,
+    voxel_dda::{self, Ray},
+    voxel_world::World,
+};
+
+/// Deepest tree the shader stack can hold, the root then covers 4^8 = 65536 voxels per axis.
+pub const SPARSE_TREE_MAX_DEPTH: u32 = 8;
+
+/// Cells per axis of a node.
+const NODE_WIDTH: i32 = 4;
+
+/// GPU node, 16 bytes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct TreeNode {
+    /// Bit `x + y * 4 + z * 16` is set for the non-empty cells, low word first.
+    pub child_mask: [u32; 2],
+    /// Index of the first child, in the node array or in the material array for leaves.
+    /// The other children follow in cell order.
+    pub first_child: u32,
+    /// [`TreeNode::LEAF`] when the cells are single voxels, [`TreeNode::UNIFORM`] when the
+    /// whole node is solid with a single material, stored in `first_child`.
+    pub flags: u32,
+}
+
+impl TreeNode {
+    pub const LEAF: u32 = 1;
+    pub const UNIFORM: u32 = 2;
+
+    fn uniform(material: MaterialId) -> Self {
+        Self {
+            child_mask: [u32::MAX; 2],
+            first_child: material.0 as u32,
+            flags: Self::UNIFORM,
+        }
+    }
+
+    pub fn mask(&self) -> u64 {
+        self.child_mask[0] as u64 | (self.child_mask[1] as u64) << 32
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.flags & Self::LEAF != 0
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.flags & Self::UNIFORM != 0
+    }
+
+    pub fn has_child(&self, cell: u32) -> bool {
+        self.mask() & (1 << cell) != 0
+    }
+
+    /// Index of the child of `cell` among the stored children.
+    pub fn child_index(&self, cell: u32) -> u32 {
+        self.first_child + (self.mask() & ((1 << cell) - 1)).count_ones()
+    }
+}
+
+fn cell_index(cell: IVec3) -> u32 {
+    (cell.x + cell.y * NODE_WIDTH + cell.z * NODE_WIDTH * NODE_WIDTH) as u32
+}
+
+fn cell_position(index: u32) -> IVec3 {
+    let index = index as i32;
+    IVec3::new(
+        index % NODE_WIDTH,
+        (index / NODE_WIDTH) % NODE_WIDTH,
+        index / (NODE_WIDTH * NODE_WIDTH),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeHit {
+    pub t: f32,
+    /// World position of the hit voxel.
+    pub voxel: IVec3,
+    /// Same encoding as [`voxel_dda::VoxelHit::face`].
+    pub face: u32,
+    pub material: MaterialId,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SparseTreeStats {
+    pub node_count: u32,
+    pub leaf_count: u32,
+    /// Nodes standing for a whole solid cube, without children.
+    pub uniform_count: u32,
+    pub voxel_count: u32,
+    /// Nodes and materials as uploaded.
+    pub tree_bytes: usize,
+    /// Same voxels in the chunk grid, one [`MaterialId`] per voxel of every stored chunk.
+    pub dense_bytes: usize,
+}
+
+impl SparseTreeStats {
+    /// How many times smaller the tree is than the chunk grid.
+    pub fn compression_ratio(&self) -> f32 {
+        if self.tree_bytes == 0 {
+            return 1.0;
+        }
+        self.dense_bytes as f32 / self.tree_bytes as f32
+    }
+}
+
+/// One level of the traversal stack.
+struct Frame {
+    node: u32,
+    /// World position of the cell grid.
+    origin: Vec3,
+    cell_size: f32,
+    cell: IVec3,
+    /// Distance at which the ray entered the current cell, and the face it crossed.
+    t: f32,
+    face: u32,
+    /// Distance at which the ray leaves the node.
+    t_exit: f32,
+}
+
+pub struct SparseVoxelTree {
+    /// World position of the root minimum corner, a multiple of 64.
+    pub origin: IVec3,
+    /// Levels of nodes, the root covers `4^depth` voxels per axis.
+    pub depth: u32,
+    /// Breadth first, the root is the first node.
+    pub nodes: Vec<TreeNode>,
+    /// Materials of the solid voxels, in leaf then cell order.
+    pub materials: Vec<MaterialId>,
+    dense_bytes: usize,
+}
+
+impl SparseVoxelTree {
+    pub fn new(world: &World) -> Self {
+        let (origin, depth) = Self::bounds(world);
+        assert!(
+            depth <= SPARSE_TREE_MAX_DEPTH,
+            "World too large for a sparse tree of depth {SPARSE_TREE_MAX_DEPTH}"
+        );
+
+        let mut tree = Self {
+            origin,
+            depth,
+            nodes: vec![TreeNode::default()],
+            materials: Vec::new(),
+            dense_bytes: world.chunk_count() * CHUNK_VOLUME * std::mem::size_of::<MaterialId>(),
+        };
+
+        // Breadth first so the children of a node end up next to each other.
+        let mut queue = VecDeque::from([(0, origin, NODE_WIDTH.pow(depth))]);
+        while let Some((node_index, node_origin, size)) = queue.pop_front() {
+            let cell_size = size / NODE_WIDTH;
+            let mut node = TreeNode::default();
+            let mut mask = 0u64;
+
+            if cell_size == 1 {
+                node.flags = TreeNode::LEAF;
+                node.first_child = tree.materials.len() as u32;
+                for cell in 0..64 {
+                    let material = world.get_voxel(node_origin + cell_position(cell));
+                    if material.is_solid() {
+                        mask |= 1 << cell;
+                        tree.materials.push(material);
+                    }
+                }
+            } else {
+                node.first_child = tree.nodes.len() as u32;
+                for cell in 0..64 {
+                    let child_origin = node_origin + cell_position(cell) * cell_size;
+                    match region_content(world, child_origin, cell_size) {
+                        RegionContent::Empty => continue,
+                        RegionContent::Uniform(material) => {
+                            tree.nodes.push(TreeNode::uniform(material));
+                        }
+                        RegionContent::Mixed => {
+                            tree.nodes.push(TreeNode::default());
+                            queue.push_back((tree.nodes.len() - 1, child_origin, cell_size));
+                        }
+                    }
+                    mask |= 1 << cell;
+                }
+            }
+
+            node.child_mask = [mask as u32, (mask >> 32) as u32];
+            tree.nodes[node_index] = node;
+        }
+
+        tree
+    }
+
+    /// Tree of the chunks crossed by `ray` before `t_max`, small enough to build on every pick.
+    pub fn along_ray(world: &World, ray: &Ray, t_max: f32) -> Self {
+        let chunk_size = CHUNK_SIZE as f32;
+        let center = World::chunk_position(ray.origin.floor().as_ivec3());
+        let reach = (t_max / chunk_size).ceil() as i32 + 1;
+
+        let mut crossed = World::new();
+        for z in -reach..=reach {
+            for y in -reach..=reach {
+                for x in -reach..=reach {
+                    let position = center + IVec3::new(x, y, z);
+                    let Some(chunk) = world.chunk(position) else {
+                        continue;
+                    };
+                    let min = Chunk::origin(position).as_vec3();
+                    if voxel_dda::intersect_box(ray, min, min + chunk_size, 0.0, t_max).is_some() {
+                        crossed.insert_chunk(position, chunk.clone());
+                    }
+                }
+            }
+        }
+        Self::new(&crossed)
+    }
+
+    /// Root corner aligned to 64 voxels, so that nodes of 16 voxels or less never straddle
+    /// two chunks, and the smallest depth covering every chunk.
+    fn bounds(world: &World) -> (IVec3, u32) {
+        let chunk_positions: Vec<IVec3> = world.chunks().map(|(position, _)| position).collect();
+        if chunk_positions.is_empty() {
+            return (IVec3::ZERO, 1);
+        }
+
+        let min = chunk_positions.iter().copied().reduce(IVec3::min).unwrap();
+        let max = chunk_positions.iter().copied().reduce(IVec3::max).unwrap() + IVec3::ONE;
+
+        let alignment = IVec3::splat(64);
+        let origin = Chunk::origin(min).div_euclid(alignment) * alignment;
+        let extent = (Chunk::origin(max) - origin).max_element();
+
+        let mut depth = 1;
+        while NODE_WIDTH.pow(depth) < extent {
+            depth += 1;
+        }
+        (origin, depth)
+    }
+
+    pub fn size(&self) -> i32 {
+        NODE_WIDTH.pow(self.depth)
+    }
+
+    /// First solid voxel along a world space ray, for picking.
+    pub fn raycast(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<TreeHit> {
+        let origin = self.origin.as_vec3();
+        let size = self.size() as f32;
+        let (t_enter, t_exit, axis) =
+            voxel_dda::intersect_box(ray, origin, origin + size, t_min, t_max)?;
+
+        let step = IVec3::new(
+            voxel_dda::step_of(ray.direction.x),
+            voxel_dda::step_of(ray.direction.y),
+            voxel_dda::step_of(ray.direction.z),
+        );
+
+        let mut stack = vec![Frame {
+            node: 0,
+            origin,
+            cell_size: size / NODE_WIDTH as f32,
+            cell: entry_cell(ray, origin, size / NODE_WIDTH as f32, t_enter),
+            t: t_enter,
+            face: voxel_dda::entry_face(axis, step[axis]),
+            t_exit,
+        }];
+
+        while let Some(frame) = stack.last_mut() {
+            // Past the node, also reached when a child is done and its parent was moved on.
+            if frame.t > frame.t_exit || !is_inside_node(frame.cell) {
+                stack.pop();
+                continue;
+            }
+
+            let node = self.nodes[frame.node as usize];
+            let cell = cell_index(frame.cell);
+
+            // Distance to the next cell boundary on each axis.
+            let mut t_next = Vec3::INFINITY;
+            for a in 0..3 {
+                if step[a] != 0 {
+                    let boundary = frame.origin[a]
+                        + (frame.cell[a] + (step[a] > 0) as i32) as f32 * frame.cell_size;
+                    t_next[a] = (boundary - ray.origin[a]) / ray.direction[a];
+                }
+            }
+            let next_axis = if t_next.x < t_next.y && t_next.x < t_next.z {
+                0
+            } else if t_next.y < t_next.z {
+                1
+            } else {
+                2
+            };
+
+            if node.has_child(cell) {
+                let child_origin = frame.origin + frame.cell.as_vec3() * frame.cell_size;
+
+                if node.is_leaf() {
+                    return Some(TreeHit {
+                        t: frame.t,
+                        voxel: child_origin.as_ivec3(),
+                        face: frame.face,
+                        material: self.materials[node.child_index(cell) as usize],
+                    });
+                }
+
+                let child_node = self.nodes[node.child_index(cell) as usize];
+                if child_node.is_uniform() {
+                    let local = (ray.at(frame.t) - child_origin)
+                        .floor()
+                        .clamp(Vec3::ZERO, Vec3::splat(frame.cell_size - 1.0));
+                    return Some(TreeHit {
+                        t: frame.t,
+                        voxel: (child_origin + local).as_ivec3(),
+                        face: frame.face,
+                        material: MaterialId(child_node.first_child as u16),
+                    });
+                }
+
+                let child_cell_size = frame.cell_size / NODE_WIDTH as f32;
+                let child = Frame {
+                    node: node.child_index(cell),
+                    origin: child_origin,
+                    cell_size: child_cell_size,
+                    cell: entry_cell(ray, child_origin, child_cell_size, frame.t),
+                    t: frame.t,
+                    face: frame.face,
+                    t_exit: t_next[next_axis].min(frame.t_exit),
+                };
+                // The frame resumes past this cell once the child is done.
+                advance(frame, &step, t_next[next_axis], next_axis);
+                stack.push(child);
+                continue;
+            }
+
+            advance(frame, &step, t_next[next_axis], next_axis);
+        }
+
+        None
+    }
+
+    pub fn stats(&self) -> SparseTreeStats {
+        let (nodes, materials) = self.gpu_data();
+        SparseTreeStats {
+            node_count: self.nodes.len() as u32,
+            leaf_count: self.nodes.iter().filter(|node| node.is_leaf()).count() as u32,
+            uniform_count: self.nodes.iter().filter(|node| node.is_uniform()).count() as u32,
+            voxel_count: self.materials.len() as u32,
+            tree_bytes: std::mem::size_of_val(nodes) + std::mem::size_of_val(materials.as_slice()),
+            dense_bytes: self.dense_bytes,
+        }
+    }
+
+    /// Buffers for `sparse_tree.glsl`: the nodes, and the materials packed two per `u32`.
+    pub fn gpu_data(&self) -> (&[TreeNode], Vec<u32>) {
+        let materials = self
+            .materials
+            .chunks(2)
+            .map(|pair| {
+                pair[0].0 as u32 | pair.get(1).map_or(0, |material| material.0 as u32) << 16
+            })
+            .collect();
+        (&self.nodes, materials)
+    }
+}
+
+/// Moves to the next cell along `axis`, entered at `t`.
+fn advance(frame: &mut Frame, step: &IVec3, t: f32, axis: usize) {
+    frame.t = t;
+    frame.cell[axis] += step[axis];
+    frame.face = voxel_dda::entry_face(axis, step[axis]);
+}
+
+fn is_inside_node(cell: IVec3) -> bool {
+    cell.cmpge(IVec3::ZERO).all() && cell.cmplt(IVec3::splat(NODE_WIDTH)).all()
+}
+
+/// Cell of the grid at `origin` containing the ray at `t`.
+fn entry_cell(ray: &Ray, origin: Vec3, cell_size: f32, t: f32) -> IVec3 {
+    ((ray.at(t) - origin) / cell_size)
+        .floor()
+        .as_ivec3()
+        .clamp(IVec3::ZERO, IVec3::splat(NODE_WIDTH - 1))
+}
+
+enum RegionContent {
+    Empty,
+    Uniform(MaterialId),
+    Mixed,
+}
+
+/// What the cube of `size` voxels at `origin` holds. The cube is either made of whole chunks
+/// or inside a single chunk.
+fn region_content(world: &World, origin: IVec3, size: i32) -> RegionContent {
+    let chunk_size = CHUNK_SIZE as i32;
+
+    if size < chunk_size {
+        let Some(chunk) = world.chunk(World::chunk_position(origin)) else {
+            return RegionContent::Empty;
+        };
+        let local = World::local_position(origin);
+        let size = size as u32;
+        let voxels = (0..size * size * size).map(|index| {
+            chunk
+                .get(local + UVec3::new(index % size, (index / size) % size, index / (size * size)))
+        });
+        return content_of(voxels);
+    }
+
+    let min = World::chunk_position(origin);
+    let max = min + IVec3::splat(size / chunk_size);
+    let chunks_in_cube = (size / chunk_size).pow(3) as usize;
+
+    // Whichever is cheaper: looking up every chunk of the cube, or going over the world.
+    let chunks: Vec<&Chunk> = if chunks_in_cube <= world.chunk_count() {
+        (min.z..max.z)
+            .flat_map(|z| {
+                (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| IVec3::new(x, y, z)))
+            })
+            .filter_map(|position| world.chunk(position))
+            .collect()
+    } else {
+        world
+            .chunks()
+            .filter(|(position, _)| position.cmpge(min).all() && position.cmplt(max).all())
+            .map(|(_, chunk)| chunk)
+            .collect()
+    };
+
+    // Missing chunks are empty.
+    match chunks.len() {
+        0 => RegionContent::Empty,
+        count if count < chunks_in_cube => RegionContent::Mixed,
+        _ => content_of(chunks.iter().flat_map(|chunk| chunk.voxels())),
+    }
+}
+
+fn content_of(mut voxels: impl Iterator<Item = MaterialId>) -> RegionContent {
+    let Some(first) = voxels.next() else {
+        return RegionContent::Empty;
+    };
+
+    // Any other material, solid or air, means the cube needs children.
+    if voxels.any(|material| material != first) {
+        RegionContent::Mixed
+    } else if first.is_air() {
+        RegionContent::Empty
+    } else {
+        RegionContent::Uniform(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::world::{
+        chunk_aabbs::AabbGranularity,
+        cpu_tracer::CpuTracer,
+        terrain::{TerrainGenerator, TerrainSettings},
+    };
+
+    /// Material at `world_position`, air outside of the tree.
+    fn get(tree: &SparseVoxelTree, world_position: IVec3) -> MaterialId {
+        let local = world_position - tree.origin;
+        if local.cmplt(IVec3::ZERO).any() || local.cmpge(IVec3::splat(tree.size())).any() {
+            return MaterialId::AIR;
+        }
+
+        let mut node = tree.nodes[0];
+        let mut cell_size = tree.size() / NODE_WIDTH;
+        loop {
+            let cell = cell_index(local / cell_size % NODE_WIDTH);
+            if !node.has_child(cell) {
+                return MaterialId::AIR;
+            }
+            if node.is_leaf() {
+                return tree.materials[node.child_index(cell) as usize];
+            }
+            node = tree.nodes[node.child_index(cell) as usize];
+            if node.is_uniform() {
+                return MaterialId(node.first_child as u16);
+            }
+            cell_size /= NODE_WIDTH;
+        }
+    }
+
+    /// Chunks -3..1 on x and z: generated terrain for y in 0..2, solid stone below down to
+    /// y = -2 so that whole nodes are uniform. Shared by the tests, generating it is slow.
+    fn world() -> &'static World {
+        static WORLD: OnceLock<World> = OnceLock::new();
+        WORLD.get_or_init(generate_world)
+    }
+
+    fn generate_world() -> World {
+        let generator = TerrainGenerator::new(TerrainSettings::default());
+        let mut world = World::new();
+        for z in -3..1 {
+            for y in -2..2 {
+                for x in -3..1 {
+                    let position = IVec3::new(x, y, z);
+                    let chunk = if y < 0 {
+                        Chunk::from_fn(|_| MaterialId::STONE)
+                    } else {
+                        generator.generate_chunk(position)
+                    };
+                    world.insert_chunk(position, chunk);
+                }
+            }
+        }
+        world
+    }
+
+    #[test]
+    fn root_is_aligned_below_negative_chunks() {
+        let tree = SparseVoxelTree::new(world());
+
+        // The lowest chunk corner is -96 on x and z and -64 on y.
+        assert_eq!(tree.origin, IVec3::new(-128, -64, -128));
+        assert_eq!(tree.depth, 4);
+        assert_eq!(tree.size(), 256);
+
+        let stats = tree.stats();
+        assert!(stats.uniform_count > 0);
+        assert!(stats.leaf_count > 0);
+        assert!(stats.compression_ratio() > 1.0);
+    }
+
+    #[test]
+    fn get_matches_the_world() {
+        let world = world();
+        let tree = SparseVoxelTree::new(world);
+
+        for (chunk_position, _) in world.chunks() {
+            let origin = Chunk::origin(chunk_position);
+            for index in 0..CHUNK_VOLUME {
+                let position = origin + Chunk::position(index).as_ivec3();
+                assert_eq!(
+                    get(&tree, position),
+                    world.get_voxel(position),
+                    "{position}"
+                );
+            }
+        }
+
+        // Inside the root but outside every chunk, and outside the root.
+        for position in [
+            IVec3::new(-128, -64, -128),
+            IVec3::new(40, 0, 0),
+            IVec3::new(127, 191, 127),
+            IVec3::new(-129, 0, -32),
+            IVec3::new(-32, 192, -32),
+            IVec3::new(-32, -65, -32),
+        ] {
+            assert_eq!(world.get_voxel(position), MaterialId::AIR);
+            assert_eq!(get(&tree, position), MaterialId::AIR, "{position}");
+        }
+    }
+
+    #[test]
+    fn raycast_matches_voxel_dda() {
+        let world = world();
+        let tree = SparseVoxelTree::new(world);
+        let tracer = CpuTracer::new(world, AabbGranularity::Brick);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(45);
+        let mut hits = 0;
+        for _ in 0..300 {
+            // From above the terrain, or from the side into the uniform stone.
+            let origin = if rng.random_bool(0.5) {
+                Vec3::new(
+                    rng.random_range(-140.0..20.0),
+                    rng.random_range(40.0..100.0),
+                    rng.random_range(-140.0..20.0),
+                )
+            } else {
+                Vec3::new(
+                    -120.0,
+                    rng.random_range(-60.0..-4.0),
+                    rng.random_range(-90.0..-6.0),
+                )
+            };
+            let target = Vec3::new(
+                rng.random_range(-96.0..0.0),
+                rng.random_range(-64.0..64.0),
+                rng.random_range(-96.0..0.0),
+            );
+            let ray = Ray::new(origin, (target - origin).normalize());
+
+            let expected = tracer
+                .trace(&ray, 0.0, 1000.0)
+                .map(|(chunk_position, hit)| {
+                    (
+                        Chunk::origin(chunk_position) + hit.voxel.as_ivec3(),
+                        hit.material,
+                        hit.face,
+                        hit.t,
+                    )
+                });
+            let actual = tree
+                .raycast(&ray, 0.0, 1000.0)
+                .map(|hit| (hit.voxel, hit.material, hit.face, hit.t));
+
+            match (expected, actual) {
+                (None, None) => {}
+                (Some(expected), Some(actual)) => {
+                    assert_eq!(
+                        (expected.0, expected.1, expected.2),
+                        (actual.0, actual.1, actual.2),
+                        "{ray:?}"
+                    );
+                    assert!((expected.3 - actual.3).abs() < 1e-3, "{ray:?}");
+                    hits += 1;
+                }
+                _ => panic!("{ray:?}: {expected:?} from voxel_dda, {actual:?} from the tree"),
+            }
+        }
+        assert!(hits > 200);
+
+        // Straight down onto the terrain surface.
+        for (x, z) in [(-90, -90), (-1, -1), (-64, -33), (-17, -80)] {
+            let ray = Ray::new(
+                Vec3::new(x as f32 + 0.5, 100.0, z as f32 + 0.5),
+                Vec3::NEG_Y,
+            );
+            let top = (-64..64)
+                .rev()
+                .find(|&y| world.get_voxel(IVec3::new(x, y, z)).is_solid())
+                .unwrap();
+
+            let hit = tree.raycast(&ray, 0.0, 1000.0).unwrap();
+            assert_eq!(hit.voxel, IVec3::new(x, top, z));
+            assert_eq!(hit.t, 100.0 - (top + 1) as f32);
+            assert_eq!(voxel_dda::face_normal(hit.face), IVec3::Y);
+        }
+
+        // Rays that start below the root or point away from it.
+        assert_eq!(
+            tree.raycast(
+                &Ray::new(Vec3::new(-32.0, 50.0, -32.0), Vec3::Y),
+                0.0,
+                1000.0
+            ),
+            None
+        );
+        assert_eq!(
+            tree.raycast(
+                &Ray::new(Vec3::new(-32.0, -80.0, -32.0), Vec3::NEG_Y),
+                0.0,
+                1000.0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn along_ray_hits_like_the_whole_tree() {
+        let world = world();
+        let tree = SparseVoxelTree::new(world);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(40);
+        let mut hits = 0;
+        for _ in 0..100 {
+            let origin = Vec3::new(
+                rng.random_range(-100.0..4.0),
+                rng.random_range(20.0..80.0),
+                rng.random_range(-100.0..4.0),
+            );
+            let direction = Vec3::new(
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..-0.2),
+                rng.random_range(-1.0..1.0),
+            );
+            // Rays starting in a solid voxel hit it through a face that depends on the root.
+            if world.get_voxel(origin.floor().as_ivec3()).is_solid() {
+                continue;
+            }
+            let ray = Ray::new(origin, direction.normalize());
+
+            let expected = tree.raycast(&ray, 0.0, 64.0);
+            let actual = SparseVoxelTree::along_ray(world, &ray, 64.0).raycast(&ray, 0.0, 64.0);
+            assert_eq!(
+                expected.map(|hit| (hit.voxel, hit.face, hit.material)),
+                actual.map(|hit| (hit.voxel, hit.face, hit.material)),
+                "{ray:?}"
+            );
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 20);
+    }
+}
@@ -0,0 +1,366 @@
+use glam::{IVec3, UVec3};
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE},
+    material::MaterialId,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainSettings {
+    pub seed: u32,
+    /// Average surface height, in voxels.
+    pub base_height: f64,
+    /// Surface variation of the plains, mountains go up to 3 times higher.
+    pub height_amplitude: f64,
+    pub height_frequency: f64,
+    pub height_octaves: usize,
+    pub biome_frequency: f64,
+    pub cave_frequency: f64,
+    /// Higher values carve fewer, thinner caves. In `[0, 1]`.
+    pub cave_threshold: f64,
+    /// Caves stay below the surface by this many voxels.
+    pub cave_surface_margin: i32,
+    pub ore_veins_per_chunk: u32,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            base_height: 32.0,
+            height_amplitude: 24.0,
+            height_frequency: 0.004,
+            height_octaves: 5,
+            biome_frequency: 0.0015,
+            cave_frequency: 0.02,
+            cave_threshold: 0.55,
+            cave_surface_margin: 4,
+            ore_veins_per_chunk: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Plains,
+    Desert,
+    Mountains,
+    Snow,
+}
+
+impl Biome {
+    /// Materials of the top voxel and of the few below it.
+    fn surface_materials(self) -> (MaterialId, MaterialId) {
+        match self {
+            Biome::Plains => (MaterialId::GRASS, MaterialId::DIRT),
+            Biome::Desert => (MaterialId::SAND, MaterialId::SAND),
+            Biome::Mountains => (MaterialId::STONE, MaterialId::GRAVEL),
+            Biome::Snow => (MaterialId::SNOW, MaterialId::DIRT),
+        }
+    }
+}
+
+/// Depth of the biome surface materials.
+const SURFACE_DEPTH: i32 = 4;
+
+/// Seeded terrain generator. The output only depends on the settings and the chunk position,
+/// so chunks can be generated in any order, from any thread.
+pub struct TerrainGenerator {
+    pub settings: TerrainSettings,
+    height: Fbm<Perlin>,
+    mountains: Perlin,
+    temperature: Perlin,
+    caves: Fbm<Perlin>,
+}
+
+impl TerrainGenerator {
+    pub fn new(settings: TerrainSettings) -> Self {
+        // Each layer gets its own seed so they are not correlated.
+        let seed = settings.seed;
+        Self {
+            height: Fbm::<Perlin>::new(seed)
+                .set_octaves(settings.height_octaves)
+                .set_frequency(settings.height_frequency),
+            mountains: Perlin::new(seed.wrapping_add(1)),
+            temperature: Perlin::new(seed.wrapping_add(2)),
+            caves: Fbm::<Perlin>::new(seed.wrapping_add(3))
+                .set_octaves(3)
+                .set_frequency(settings.cave_frequency),
+            settings,
+        }
+    }
+
+    /// How mountainous the terrain is at a column, in `[0, 1]`.
+    fn mountain_factor(&self, x: i32, z: i32) -> f64 {
+        let frequency = self.settings.biome_frequency;
+        let value = self
+            .mountains
+            .get([x as f64 * frequency, z as f64 * frequency]);
+        smoothstep(0.15, 0.45, value)
+    }
+
+    pub fn biome(&self, x: i32, z: i32) -> Biome {
+        if self.mountain_factor(x, z) > 0.5 {
+            return Biome::Mountains;
+        }
+
+        let frequency = self.settings.biome_frequency;
+        let temperature = self
+            .temperature
+            .get([x as f64 * frequency, z as f64 * frequency]);
+
+        if temperature > 0.3 {
+            Biome::Desert
+        } else if temperature < -0.3 {
+            Biome::Snow
+        } else {
+            Biome::Plains
+        }
+    }
+
+    /// World height of the top solid voxel of a column.
+    pub fn surface_height(&self, x: i32, z: i32) -> i32 {
+        let amplitude = self.settings.height_amplitude * (1.0 + 2.0 * self.mountain_factor(x, z));
+        let height = self.height.get([x as f64, z as f64]);
+        (self.settings.base_height + amplitude * height).floor() as i32
+    }
+
+    fn is_cave(&self, position: IVec3, surface_height: i32) -> bool {
+        if position.y > surface_height - self.settings.cave_surface_margin {
+            return false;
+        }
+
+        let density = self
+            .caves
+            .get([position.x as f64, position.y as f64, position.z as f64]);
+        density.abs() > self.settings.cave_threshold
+    }
+
+    /// Fills the chunk at `chunk_position`.
+    pub fn generate_chunk(&self, chunk_position: IVec3) -> Chunk {
+        let origin = Chunk::origin(chunk_position);
+
+        // Per column data, indexed by x + z * CHUNK_SIZE.
+        let columns: Vec<(i32, Biome)> = (0..CHUNK_SIZE * CHUNK_SIZE)
+            .map(|index| {
+                let x = origin.x + (index % CHUNK_SIZE) as i32;
+                let z = origin.z + (index / CHUNK_SIZE) as i32;
+                (self.surface_height(x, z), self.biome(x, z))
+            })
+            .collect();
+
+        // Entirely above the highest column: nothing to sample.
+        let highest = columns.iter().map(|&(height, _)| height).max().unwrap();
+        if origin.y > highest {
+            return Chunk::new();
+        }
+
+        let mut chunk = Chunk::from_fn(|local| {
+            let (surface_height, biome) = columns[(local.x + local.z * CHUNK_SIZE) as usize];
+            let position = origin + local.as_ivec3();
+            let depth = surface_height - position.y;
+
+            if depth < 0 || self.is_cave(position, surface_height) {
+                return MaterialId::AIR;
+            }
+
+            let (top, below) = biome.surface_materials();
+            match depth {
+                0 => top,
+                1..SURFACE_DEPTH => below,
+                _ => MaterialId::STONE,
+            }
+        });
+
+        self.place_ores(&mut chunk, chunk_position);
+        chunk
+    }
+
+    /// Random walks of ore replacing stone, kept inside the chunk so that chunks stay
+    /// independent of each other.
+    fn place_ores(&self, chunk: &mut Chunk, chunk_position: IVec3) {
+        if chunk.is_empty() {
+            return;
+        }
+
+        // Unlike `StdRng`, the ChaCha output is guaranteed across rand releases, which keeps
+        // the ores of regenerated chunks stable.
+        let mut rng = ChaCha8Rng::seed_from_u64(chunk_seed(self.settings.seed, chunk_position));
+        let origin = Chunk::origin(chunk_position);
+
+        for _ in 0..self.settings.ore_veins_per_chunk {
+            let mut position = UVec3::new(
+                rng.random_range(0..CHUNK_SIZE),
+                rng.random_range(0..CHUNK_SIZE),
+                rng.random_range(0..CHUNK_SIZE),
+            );
+
+            // Rarer ores deeper down.
+            let depth = self.settings.base_height as i32 - (origin.y + position.y as i32);
+            let (ore, vein_size) = match (depth, rng.random_range(0..100)) {
+                (48.., 0..15) => (MaterialId::GOLD_ORE, 4),
+                (16.., 0..40) => (MaterialId::IRON_ORE, 6),
+                _ => (MaterialId::COAL_ORE, 10),
+            };
+
+            for _ in 0..vein_size {
+                if chunk.get(position) == MaterialId::STONE {
+                    chunk.set(position, ore);
+                }
+
+                let axis = rng.random_range(0..3);
+                let step: i32 = if rng.random_bool(0.5) { 1 } else { -1 };
+                let mut next = position.as_ivec3();
+                next[axis] = (next[axis] + step).clamp(0, CHUNK_SIZE as i32 - 1);
+                position = next.as_uvec3();
+            }
+        }
+    }
+
+    /// Generates `chunk_positions` on up to `threads` worker threads.
+    /// The result is in the same order as the positions.
+    pub fn generate_chunks(&self, chunk_positions: &[IVec3], threads: usize) -> Vec<Chunk> {
+        let batch_size = chunk_positions.len().div_ceil(threads.max(1)).max(1);
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = chunk_positions
+                .chunks(batch_size)
+                .map(|batch| {
+                    scope.spawn(move || {
+                        batch
+                            .iter()
+                            .map(|&position| self.generate_chunk(position))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("Terrain worker panicked"))
+                .collect()
+        })
+    }
+}
+
+/// Mixes the world seed with the chunk position, the same chunk always gets the same ores.
+fn chunk_seed(seed: u32, chunk_position: IVec3) -> u64 {
+    // SplitMix64 finalizer over the packed inputs.
+    let mut value = (seed as u64) << 32
+        ^ (chunk_position.x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (chunk_position.y as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (chunk_position.z as u32 as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Surface and underground chunks, the underground ones hold the ores.
+    fn test_positions() -> Vec<IVec3> {
+        let mut positions = Vec::new();
+        for z in -1..1 {
+            for y in -1..2 {
+                for x in -1..1 {
+                    positions.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+        positions
+    }
+
+    fn is_ore(material: MaterialId) -> bool {
+        [
+            MaterialId::COAL_ORE,
+            MaterialId::IRON_ORE,
+            MaterialId::GOLD_ORE,
+        ]
+        .contains(&material)
+    }
+
+    #[test]
+    fn same_seed_generates_the_same_chunks() {
+        let settings = TerrainSettings {
+            seed: 42,
+            ..TerrainSettings::default()
+        };
+        let first = TerrainGenerator::new(settings);
+        let second = TerrainGenerator::new(settings);
+
+        let mut ores = 0;
+        for position in test_positions() {
+            let chunk = first.generate_chunk(position);
+            ores += chunk.voxels().filter(|&material| is_ore(material)).count();
+            assert!(
+                chunk.voxels().eq(second.generate_chunk(position).voxels()),
+                "chunk {position} differs between generators"
+            );
+        }
+        assert!(ores > 0, "no ore to compare");
+    }
+
+    #[test]
+    fn other_seed_generates_other_chunks() {
+        let first = TerrainGenerator::new(TerrainSettings {
+            seed: 1,
+            ..TerrainSettings::default()
+        });
+        let second = TerrainGenerator::new(TerrainSettings {
+            seed: 2,
+            ..TerrainSettings::default()
+        });
+
+        let positions = test_positions();
+        let differing = positions
+            .iter()
+            .filter(|&&position| {
+                !first
+                    .generate_chunk(position)
+                    .voxels()
+                    .eq(second.generate_chunk(position).voxels())
+            })
+            .count();
+        assert_eq!(differing, positions.len());
+    }
+
+    #[test]
+    fn generate_chunks_matches_single_threaded_generation() {
+        let generator = TerrainGenerator::new(TerrainSettings::default());
+        let mut positions = Vec::new();
+        for z in -1..1 {
+            for y in 0..3 {
+                for x in -1..1 {
+                    positions.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+
+        let single_threaded: Vec<Chunk> = positions
+            .iter()
+            .map(|&position| generator.generate_chunk(position))
+            .collect();
+        let multi_threaded = generator.generate_chunks(&positions, 5);
+
+        assert_eq!(single_threaded.len(), multi_threaded.len());
+        assert!(single_threaded.iter().any(|chunk| !chunk.is_empty()));
+        for ((position, expected), chunk) in
+            positions.iter().zip(&single_threaded).zip(&multi_threaded)
+        {
+            assert!(
+                expected.voxels().eq(chunk.voxels()),
+                "chunk {position} differs between threads"
+            );
+        }
+    }
+}
@@ -0,0 +1,802 @@
+//! MagicaVoxel `.vox` import: models (`SIZE`/`XYZI`), palette (`RGBA`), scene graph
+//! (`nTRN`/`nGRP`/`nSHP`) and materials (`MATL`), see
+//! <https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt>.
+//!
+//! MagicaVoxel is Z up, models are converted to the Y up world by [`VoxInstance::transform`].
+
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use glam::{Affine3A, IVec3, Mat3, UVec3, Vec3};
+
+use super::{material::MaterialId, voxel_world::World};
+
+/// Deeper scene graphs are assumed to be cyclic.
+const MAX_SCENE_DEPTH: u32 = 64;
+
+/// MagicaVoxel `(x, y, z)` to world `(x, z, -y)`, a rotation so models are not mirrored.
+const Z_UP_TO_Y_UP: Mat3 = Mat3::from_cols(Vec3::X, Vec3::NEG_Z, Vec3::Y);
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    NotAVoxFile,
+    Malformed(String),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::NotAVoxFile => write!(f, "not a MagicaVoxel file"),
+            Self::Malformed(reason) => write!(f, "malformed .vox file: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for VoxError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+fn malformed<T>(reason: impl Into<String>) -> Result<T, VoxError> {
+    Err(VoxError::Malformed(reason.into()))
+}
+
+/// Voxels of one model, positions in MagicaVoxel model space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxModel {
+    pub size: UVec3,
+    /// Position and palette index, never 0.
+    pub voxels: Vec<(UVec3, u8)>,
+}
+
+impl VoxModel {
+    /// The model in a world of its own, voxel `v` at `v`, e.g. to build one BLAS per chunk
+    /// and instance it with [`VoxInstance::transform`].
+    pub fn to_world(&self, materials: &[MaterialId; 256]) -> World {
+        let mut world = World::new();
+        for &(position, color) in &self.voxels {
+            world.set_voxel(position.as_ivec3(), materials[color as usize]);
+        }
+        world.take_dirty_chunks();
+        world
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoxMaterialKind {
+    Diffuse,
+    Metal,
+    Glass,
+    Emit,
+    Blend,
+    Media,
+    Cloud,
+    Other(String),
+}
+
+/// `MATL` chunk. Only the type is decoded, the other properties are kept as written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxMaterial {
+    pub kind: VoxMaterialKind,
+    pub properties: HashMap<String, String>,
+}
+
+impl VoxMaterial {
+    fn new(properties: HashMap<String, String>) -> Self {
+        let kind = match properties.get("_type").map(String::as_str) {
+            None | Some("_diffuse") => VoxMaterialKind::Diffuse,
+            Some("_metal") => VoxMaterialKind::Metal,
+            Some("_glass") => VoxMaterialKind::Glass,
+            Some("_emit") => VoxMaterialKind::Emit,
+            Some("_blend") => VoxMaterialKind::Blend,
+            Some("_media") => VoxMaterialKind::Media,
+            Some("_cloud") => VoxMaterialKind::Cloud,
+            Some(other) => VoxMaterialKind::Other(other.to_string()),
+        };
+        Self { kind, properties }
+    }
+}
+
+/// One placed shape of the scene graph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxInstance {
+    pub model: usize,
+    /// Signed permutation matrix, in MagicaVoxel space.
+    pub rotation: Mat3,
+    /// Position of the model center, in MagicaVoxel space.
+    pub translation: IVec3,
+}
+
+impl VoxInstance {
+    /// Model space voxel corners to world space, snapped so that every voxel lands exactly
+    /// on a world voxel. Also the transform of the model BLAS instances.
+    pub fn transform(&self, model_size: UVec3) -> Affine3A {
+        let linear = Z_UP_TO_Y_UP * self.rotation;
+        let half_size = model_size.as_vec3() * 0.5;
+        let translation = Z_UP_TO_Y_UP * self.translation.as_vec3() - linear * half_size;
+
+        // Rotations permute the axes, all voxel centers share the fractional part of this one.
+        let first_center = linear * Vec3::splat(0.5) + translation;
+        let snap = first_center.floor() + 0.5 - first_center;
+
+        Affine3A::from_mat3_translation(linear, translation + snap)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VoxFile {
+    pub version: u32,
+    pub models: Vec<VoxModel>,
+    /// RGBA colors by palette index, index 0 is unused.
+    pub palette: [[u8; 4]; 256],
+    pub materials: HashMap<u8, VoxMaterial>,
+    /// Visible shapes of the scene graph, one per model for files without one.
+    pub instances: Vec<VoxInstance>,
+}
+
+impl VoxFile {
+    pub fn load(path: &Path) -> Result<Self, VoxError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, VoxError> {
+        let mut reader = Reader::new(data);
+        if reader.bytes(4).ok() != Some(b"VOX ".as_slice()) {
+            return Err(VoxError::NotAVoxFile);
+        }
+        let version = reader.u32()?;
+
+        let main = reader.chunk()?;
+        if main.id != *b"MAIN" {
+            return malformed("the first chunk is not MAIN");
+        }
+
+        let mut file = Self {
+            version,
+            models: Vec::new(),
+            palette: default_palette(),
+            materials: HashMap::new(),
+            instances: Vec::new(),
+        };
+        let mut nodes = HashMap::new();
+        let mut pending_size = None;
+
+        let mut children = Reader::new(main.children);
+        while !children.is_at_end() {
+            let chunk = children.chunk()?;
+            let mut content = Reader::new(chunk.content);
+
+            match &chunk.id {
+                b"SIZE" => {
+                    let size = UVec3::new(content.u32()?, content.u32()?, content.u32()?);
+                    if size.min_element() == 0 || size.max_element() > 256 {
+                        return malformed(format!("invalid model size {size}"));
+                    }
+                    pending_size = Some(size);
+                }
+                b"XYZI" => {
+                    let Some(size) = pending_size.take() else {
+                        return malformed("XYZI chunk without a SIZE chunk");
+                    };
+                    let count = content.u32()? as usize;
+                    let packed = content.bytes(count.saturating_mul(4))?;
+
+                    let mut voxels = Vec::with_capacity(count);
+                    for voxel in packed.chunks_exact(4) {
+                        let position =
+                            UVec3::new(voxel[0] as u32, voxel[1] as u32, voxel[2] as u32);
+                        if position.cmpge(size).any() {
+                            return malformed(format!(
+                                "voxel {position} outside of a {size} model"
+                            ));
+                        }
+                        if voxel[3] != 0 {
+                            voxels.push((position, voxel[3]));
+                        }
+                    }
+                    file.models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    // Entry `i` is the color of palette index `i + 1`, the last one is unused.
+                    let colors = content.bytes(256 * 4)?;
+                    for (index, color) in colors.chunks_exact(4).take(255).enumerate() {
+                        file.palette[index + 1] = color.try_into().unwrap();
+                    }
+                }
+                b"MATL" => {
+                    let id = content.i32()?;
+                    let properties = content.dict()?;
+                    if let Ok(id @ 1..=255) = u8::try_from(id) {
+                        file.materials.insert(id, VoxMaterial::new(properties));
+                    }
+                }
+                b"nTRN" | b"nGRP" | b"nSHP" => {
+                    let id = content.i32()?;
+                    let attributes = content.dict()?;
+                    let node = SceneNode::read(&chunk.id, attributes, &mut content)?;
+                    if nodes.insert(id, node).is_some() {
+                        return malformed(format!("duplicate scene node {id}"));
+                    }
+                }
+                // PACK, LAYR, rOBJ, rCAM, NOTE, IMAP... are not needed.
+                _ => {}
+            }
+        }
+
+        if pending_size.is_some() {
+            return malformed("SIZE chunk without a XYZI chunk");
+        }
+
+        if nodes.is_empty() {
+            file.instances = (0..file.models.len())
+                .map(|model| VoxInstance {
+                    model,
+                    rotation: Mat3::IDENTITY,
+                    translation: IVec3::ZERO,
+                })
+                .collect();
+        } else {
+            // The root is always the first node written.
+            let root = *nodes.keys().min().unwrap();
+            let mut instances = Vec::new();
+            collect_instances(&nodes, root, Mat3::IDENTITY, IVec3::ZERO, 0, &mut instances)?;
+
+            if let Some(instance) = instances
+                .iter()
+                .find(|instance| instance.model >= file.models.len())
+            {
+                return malformed(format!("shape of the missing model {}", instance.model));
+            }
+            file.instances = instances;
+        }
+
+        Ok(file)
+    }
+
+    /// Closest built-in material of every palette color, air for index 0.
+    pub fn closest_materials(&self) -> [MaterialId; 256] {
+        std::array::from_fn(|index| {
+            if index == 0 {
+                return MaterialId::AIR;
+            }
+            let [r, g, b, _] = self.palette[index];
+            MaterialId::closest_built_in(Vec3::new(r as f32, g as f32, b as f32) / 255.0)
+        })
+    }
+
+    /// Places every instance with its model center at `origin`, overwriting the world voxels.
+    pub fn place_in_world(&self, world: &mut World, origin: IVec3, materials: &[MaterialId; 256]) {
+        for instance in &self.instances {
+            let model = &self.models[instance.model];
+            let transform = instance.transform(model.size);
+
+            for &(position, color) in &model.voxels {
+                let center = transform.transform_point3(position.as_vec3() + 0.5);
+                world.set_voxel(
+                    origin + center.floor().as_ivec3(),
+                    materials[color as usize],
+                );
+            }
+        }
+    }
+}
+
+enum SceneNode {
+    Transform {
+        child: i32,
+        hidden: bool,
+        rotation: Mat3,
+        translation: IVec3,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        models: Vec<usize>,
+    },
+}
+
+impl SceneNode {
+    fn read(
+        id: &[u8; 4],
+        attributes: HashMap<String, String>,
+        content: &mut Reader,
+    ) -> Result<Self, VoxError> {
+        match id {
+            b"nTRN" => {
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let _layer = content.i32()?;
+                let frame_count = content.u32()?;
+                if frame_count == 0 {
+                    return malformed("transform node without frames");
+                }
+                // Animations are not supported, only the first frame is used.
+                let frame = content.dict()?;
+
+                let rotation = match frame.get("_r") {
+                    Some(rotation) => match rotation.parse() {
+                        Ok(rotation) => decode_rotation(rotation)?,
+                        Err(_) => return malformed(format!("invalid rotation {rotation:?}")),
+                    },
+                    None => Mat3::IDENTITY,
+                };
+                let translation = match frame.get("_t") {
+                    Some(translation) => parse_translation(translation)?,
+                    None => IVec3::ZERO,
+                };
+
+                Ok(Self::Transform {
+                    child,
+                    hidden: attributes
+                        .get("_hidden")
+                        .is_some_and(|hidden| hidden == "1"),
+                    rotation,
+                    translation,
+                })
+            }
+            b"nGRP" => {
+                let count = content.u32()?;
+                let children = (0..count)
+                    .map(|_| content.i32())
+                    .collect::<Result<_, _>>()?;
+                Ok(Self::Group { children })
+            }
+            _ => {
+                let count = content.u32()?;
+                let mut models = Vec::new();
+                for _ in 0..count {
+                    let model = content.i32()?;
+                    content.dict()?;
+                    match usize::try_from(model) {
+                        Ok(model) => models.push(model),
+                        Err(_) => return malformed(format!("invalid model index {model}")),
+                    }
+                }
+                Ok(Self::Shape { models })
+            }
+        }
+    }
+}
+
+fn collect_instances(
+    nodes: &HashMap<i32, SceneNode>,
+    id: i32,
+    rotation: Mat3,
+    translation: IVec3,
+    depth: u32,
+    instances: &mut Vec<VoxInstance>,
+) -> Result<(), VoxError> {
+    if depth > MAX_SCENE_DEPTH {
+        return malformed("scene graph too deep or cyclic");
+    }
+    let Some(node) = nodes.get(&id) else {
+        return malformed(format!("missing scene node {id}"));
+    };
+
+    match node {
+        SceneNode::Transform { hidden: true, .. } => {}
+        SceneNode::Transform {
+            child,
+            rotation: local_rotation,
+            translation: local_translation,
+            ..
+        } => {
+            let child_translation =
+                translation + (rotation * local_translation.as_vec3()).round().as_ivec3();
+            collect_instances(
+                nodes,
+                *child,
+                rotation * *local_rotation,
+                child_translation,
+                depth + 1,
+                instances,
+            )?;
+        }
+        SceneNode::Group { children } => {
+            for &child in children {
+                collect_instances(nodes, child, rotation, translation, depth + 1, instances)?;
+            }
+        }
+        SceneNode::Shape { models } => {
+            instances.extend(models.iter().map(|&model| VoxInstance {
+                model,
+                rotation,
+                translation,
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// `_r` byte: bits 0-1 and 2-3 are the column of the non-zero entry of the first two rows,
+/// bits 4, 5 and 6 the signs of the three rows.
+fn decode_rotation(bits: u8) -> Result<Mat3, VoxError> {
+    let first = (bits & 3) as usize;
+    let second = ((bits >> 2) & 3) as usize;
+    if first > 2 || second > 2 || first == second {
+        return malformed(format!("invalid rotation {bits}"));
+    }
+    let third = 3 - first - second;
+
+    let mut rows = [Vec3::ZERO; 3];
+    for (row, column) in [first, second, third].into_iter().enumerate() {
+        let negative = bits & (1 << (4 + row)) != 0;
+        rows[row][column] = if negative { -1.0 } else { 1.0 };
+    }
+    Ok(Mat3::from_cols(rows[0], rows[1], rows[2]).transpose())
+}
+
+fn parse_translation(text: &str) -> Result<IVec3, VoxError> {
+    let components: Vec<i32> = text
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .or_else(|_| malformed(format!("invalid translation {text:?}")))?;
+
+    match components[..] {
+        [x, y, z] => Ok(IVec3::new(x, y, z)),
+        _ => malformed(format!("invalid translation {text:?}")),
+    }
+}
+
+/// Palette of files without a `RGBA` chunk: a 6 level color cube without black, then ramps
+/// of red, green, blue and gray.
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE_LEVELS: [u8; 6] = [0xFF, 0xCC, 0x99, 0x66, 0x33, 0x00];
+    const RAMP_LEVELS: [u8; 10] = [0xEE, 0xDD, 0xBB, 0xAA, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let cube = CUBE_LEVELS.iter().flat_map(|&r| {
+        CUBE_LEVELS
+            .iter()
+            .flat_map(move |&g| CUBE_LEVELS.iter().map(move |&b| [r, g, b, 0xFF]))
+    });
+    let ramps = [[1, 0, 0], [0, 1, 0], [0, 0, 1], [1, 1, 1]]
+        .into_iter()
+        .flat_map(|[r, g, b]| {
+            RAMP_LEVELS
+                .iter()
+                .map(move |&level| [level * r, level * g, level * b, 0xFF])
+        });
+
+    let mut palette = [[0; 4]; 256];
+    for (entry, color) in palette[1..]
+        .iter_mut()
+        .zip(cube.filter(|color| color[..3] != [0, 0, 0]).chain(ramps))
+    {
+        *entry = color;
+    }
+    palette
+}
+
+struct RiffChunk<'a> {
+    id: [u8; 4],
+    content: &'a [u8],
+    children: &'a [u8],
+}
+
+/// Little endian reads failing with [`VoxError::Malformed`] past the end.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        match self.data.get(self.offset..self.offset.saturating_add(len)) {
+            Some(bytes) => {
+                self.offset += len;
+                Ok(bytes)
+            }
+            None => malformed("unexpected end of data"),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, VoxError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let count = self.u32()?;
+        (0..count)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+
+    fn chunk(&mut self) -> Result<RiffChunk<'a>, VoxError> {
+        let id = self.bytes(4)?.try_into().unwrap();
+        let content_size = self.u32()? as usize;
+        let children_size = self.u32()? as usize;
+
+        Ok(RiffChunk {
+            id,
+            content: self.bytes(content_size)?,
+            children: self.bytes(children_size)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SINGLE_MODEL: &[u8] = include_bytes!("../../fixtures/vox/single_model.vox");
+    const SCENE: &[u8] = include_bytes!("../../fixtures/vox/scene.vox");
+    const MATERIALS: &[u8] = include_bytes!("../../fixtures/vox/materials.vox");
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend((content.len() as u32).to_le_bytes());
+        data.extend((children.len() as u32).to_le_bytes());
+        data.extend(content);
+        data.extend(children);
+        data
+    }
+
+    fn vox(children: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"VOX ".to_vec();
+        data.extend(200u32.to_le_bytes());
+        data.extend(chunk(b"MAIN", &[], &children.concat()));
+        data
+    }
+
+    fn words(values: &[i32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn size(x: i32, y: i32, z: i32) -> Vec<u8> {
+        chunk(b"SIZE", &words(&[x, y, z]), &[])
+    }
+
+    fn xyzi(voxels: &[[u8; 4]]) -> Vec<u8> {
+        let mut content = (voxels.len() as u32).to_le_bytes().to_vec();
+        content.extend(voxels.concat());
+        chunk(b"XYZI", &content, &[])
+    }
+
+    /// Transform without attributes or frame properties.
+    fn transform_node(id: i32, child: i32) -> Vec<u8> {
+        chunk(b"nTRN", &words(&[id, 0, child, -1, -1, 1, 0]), &[])
+    }
+
+    fn group_node(id: i32, children: &[i32]) -> Vec<u8> {
+        let content = [words(&[id, 0, children.len() as i32]), words(children)].concat();
+        chunk(b"nGRP", &content, &[])
+    }
+
+    fn shape_node(id: i32, model: i32) -> Vec<u8> {
+        chunk(b"nSHP", &words(&[id, 0, 1, model, 0]), &[])
+    }
+
+    fn assert_malformed(data: &[u8]) {
+        match VoxFile::parse(data) {
+            Err(VoxError::Malformed(_)) => {}
+            result => panic!("expected a malformed file, got {result:?}"),
+        }
+    }
+
+    fn stone_materials() -> [MaterialId; 256] {
+        let mut materials = [MaterialId::STONE; 256];
+        materials[0] = MaterialId::AIR;
+        materials
+    }
+
+    #[test]
+    fn parses_a_single_model() {
+        let file = VoxFile::parse(SINGLE_MODEL).unwrap();
+
+        assert_eq!(file.version, 200);
+        assert_eq!(
+            file.models,
+            [VoxModel {
+                size: UVec3::new(2, 3, 4),
+                // The voxel with palette index 0 is dropped.
+                voxels: vec![
+                    (UVec3::new(0, 0, 0), 1),
+                    (UVec3::new(1, 2, 3), 2),
+                    (UVec3::new(1, 0, 2), 3),
+                ],
+            }]
+        );
+        assert_eq!(file.palette[0], [0, 0, 0, 0]);
+        assert_eq!(file.palette[1], [0, 255, 0, 255]);
+        assert_eq!(file.palette[2], [1, 254, 2, 255]);
+        assert_eq!(
+            file.instances,
+            [VoxInstance {
+                model: 0,
+                rotation: Mat3::IDENTITY,
+                translation: IVec3::ZERO,
+            }]
+        );
+        assert!(file.materials.is_empty());
+    }
+
+    #[test]
+    fn places_a_model_y_up_around_its_center() {
+        let file = VoxFile::parse(SINGLE_MODEL).unwrap();
+        let mut world = World::new();
+        file.place_in_world(&mut world, IVec3::ZERO, &stone_materials());
+
+        // Model (x, y, z) is world (x, z, -y), centered on the origin and snapped to voxels.
+        for position in [
+            IVec3::new(-1, -2, 1),
+            IVec3::new(0, 1, -1),
+            IVec3::new(0, 0, 1),
+        ] {
+            assert_eq!(world.get_voxel(position), MaterialId::STONE, "{position}");
+        }
+        let solid: usize = world
+            .chunks()
+            .map(|(_, chunk)| chunk.voxels().filter(|voxel| voxel.is_solid()).count())
+            .sum();
+        assert_eq!(solid, 3);
+    }
+
+    #[test]
+    fn parses_the_scene_graph() {
+        let file = VoxFile::parse(SCENE).unwrap();
+
+        assert_eq!(file.models.len(), 2);
+        assert_eq!(file.models[1].size, UVec3::new(3, 1, 1));
+
+        // The root transform moves everything by (1, 2, 3), the third shape is hidden.
+        // `_r` 17 has the rows (0 -1 0), (1 0 0), (0 0 1) and 70 (0 0 1), (0 1 0), (-1 0 0).
+        assert_eq!(
+            file.instances,
+            [
+                VoxInstance {
+                    model: 0,
+                    rotation: Mat3::from_cols(Vec3::Y, Vec3::NEG_X, Vec3::Z),
+                    translation: IVec3::new(11, 2, 3),
+                },
+                VoxInstance {
+                    model: 1,
+                    rotation: Mat3::from_cols(Vec3::NEG_Z, Vec3::Y, Vec3::X),
+                    translation: IVec3::new(-4, 5, 5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rotated_instances_land_on_voxels() {
+        let file = VoxFile::parse(SCENE).unwrap();
+
+        for instance in &file.instances {
+            let model = &file.models[instance.model];
+            let transform = instance.transform(model.size);
+            for &(position, _) in &model.voxels {
+                let center = transform.transform_point3(position.as_vec3() + 0.5);
+                assert_eq!(center - center.floor(), Vec3::splat(0.5), "{center}");
+            }
+        }
+
+        let mut world = World::new();
+        file.place_in_world(&mut world, IVec3::ZERO, &stone_materials());
+        // Model voxel (x, y, z) of the first instance is world (-y, z, -x) around (11, 3, -2).
+        assert_eq!(world.get_voxel(IVec3::new(12, 1, -2)), MaterialId::STONE);
+        assert_eq!(world.get_voxel(IVec3::new(10, 4, -3)), MaterialId::STONE);
+
+        let solid: usize = world
+            .chunks()
+            .map(|(_, chunk)| chunk.voxels().filter(|voxel| voxel.is_solid()).count())
+            .sum();
+        assert_eq!(solid, 5);
+    }
+
+    /// Numeric property such as `_rough`, `_metal`, `_emit` or `_ior`.
+    fn float(material: &VoxMaterial, key: &str) -> Option<f32> {
+        material.properties.get(key)?.parse().ok()
+    }
+
+    #[test]
+    fn parses_materials() {
+        let file = VoxFile::parse(MATERIALS).unwrap();
+
+        // Material 0 has no palette index and is ignored.
+        let mut ids: Vec<u8> = file.materials.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3, 4]);
+
+        let metal = &file.materials[&1];
+        assert_eq!(metal.kind, VoxMaterialKind::Metal);
+        assert_eq!(float(metal, "_rough"), Some(0.25));
+        assert_eq!(float(metal, "_metal"), Some(1.0));
+        assert_eq!(file.materials[&2].kind, VoxMaterialKind::Emit);
+        assert_eq!(float(&file.materials[&2], "_emit"), Some(2.5));
+        assert_eq!(file.materials[&3].kind, VoxMaterialKind::Glass);
+        assert_eq!(float(&file.materials[&3], "_ior"), Some(1.5));
+        assert_eq!(file.materials[&4].kind, VoxMaterialKind::Diffuse);
+        assert_eq!(float(&file.materials[&4], "_ior"), None);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        for fixture in [SINGLE_MODEL, SCENE, MATERIALS] {
+            assert!(matches!(
+                VoxFile::parse(&fixture[..3]),
+                Err(VoxError::NotAVoxFile)
+            ));
+            for len in 4..fixture.len() {
+                assert_malformed(&fixture[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            VoxFile::parse(b"RIFF\x96\0\0\0"),
+            Err(VoxError::NotAVoxFile)
+        ));
+
+        let mut data = b"VOX ".to_vec();
+        data.extend(200u32.to_le_bytes());
+        data.extend(size(1, 1, 1));
+        assert_malformed(&data);
+    }
+
+    #[test]
+    fn rejects_invalid_models() {
+        assert_malformed(&vox(&[size(0, 1, 1), xyzi(&[])]));
+        assert_malformed(&vox(&[size(257, 1, 1), xyzi(&[])]));
+        assert_malformed(&vox(&[xyzi(&[[0, 0, 0, 1]])]));
+        assert_malformed(&vox(&[size(1, 1, 1)]));
+        assert_malformed(&vox(&[size(2, 2, 2), xyzi(&[[0, 2, 0, 1]])]));
+
+        // The voxel count must match the data.
+        let mut short = xyzi(&[[0, 0, 0, 1]]);
+        short[12] = 2;
+        assert_malformed(&vox(&[size(1, 1, 1), short]));
+    }
+
+    #[test]
+    fn rejects_invalid_scene_graphs() {
+        let model = [size(1, 1, 1), xyzi(&[[0, 0, 0, 1]])];
+        let with_nodes = |nodes: &[Vec<u8>]| vox(&[&model[..], nodes].concat());
+
+        // Cycle through the group.
+        assert_malformed(&with_nodes(&[
+            transform_node(0, 1),
+            group_node(1, &[2]),
+            transform_node(2, 1),
+        ]));
+        // Missing child node.
+        assert_malformed(&with_nodes(&[transform_node(0, 1)]));
+        // Shape of a model that does not exist.
+        assert_malformed(&with_nodes(&[transform_node(0, 1), shape_node(1, 1)]));
+        // Two nodes with the same id.
+        assert_malformed(&with_nodes(&[
+            transform_node(0, 1),
+            shape_node(1, 0),
+            shape_node(1, 0),
+        ]));
+
+        let valid = VoxFile::parse(&with_nodes(&[transform_node(0, 1), shape_node(1, 0)]));
+        assert_eq!(valid.unwrap().instances.len(), 1);
+    }
+}
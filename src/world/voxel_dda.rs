@@ -0,0 +1,588 @@
+//! CPU version of `shaders/intersection.rint`, step for step, so its hits can be compared
+//! with the GPU ones. Keep both in sync.
+
+use glam::{IVec3, UVec3, Vec3};
+
+use super::{
+    chunk::Chunk,
+    chunk_aabbs::{AabbGranularity, Brick, ChunkAabbs, BRICK_SIZE},
+    material::MaterialId,
+    palette,
+};
+
+/// Ray in chunk local voxel units, the object space of a chunk BLAS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Does not need to be normalized, distances are in multiples of it.
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+}
+
+/// What the intersection shader reports: the distance to `reportIntersectionEXT`,
+/// the face as hit kind and the voxel and material as hit attributes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxelHit {
+    pub t: f32,
+    /// Chunk local position of the hit voxel.
+    pub voxel: UVec3,
+    /// `axis * 2`, plus 1 when the normal points to the negative side. See [`face_normal`].
+    pub face: u32,
+    pub material: MaterialId,
+}
+
+impl VoxelHit {
+    pub fn normal(&self) -> IVec3 {
+        face_normal(self.face)
+    }
+}
+
+pub fn face_normal(face: u32) -> IVec3 {
+    let mut normal = IVec3::ZERO;
+    normal[(face / 2) as usize] = if face.is_multiple_of(2) { 1 } else { -1 };
+    normal
+}
+
+/// Face through which a ray going `step` along `axis` enters a voxel.
+pub fn entry_face(axis: usize, step: i32) -> u32 {
+    axis as u32 * 2 + (step > 0) as u32
+}
+
+pub fn step_of(direction: f32) -> i32 {
+    if direction > 0.0 {
+        1
+    } else if direction < 0.0 {
+        -1
+    } else {
+        0
+    }
+}
+
+/// Slab test, returns the entry and exit distances clamped to `[t_min, t_max]` and the axis
+/// of the entry face. When the ray starts inside the box the axis is the one the box is
+/// entered through behind the origin.
+pub fn intersect_box(
+    ray: &Ray,
+    min: Vec3,
+    max: Vec3,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, f32, usize)> {
+    let mut near = f32::NEG_INFINITY;
+    let mut far = f32::INFINITY;
+    let mut axis = 0;
+
+    for a in 0..3 {
+        if ray.direction[a] == 0.0 {
+            // Parallel to the slab, avoids 0 * inf.
+            if ray.origin[a] < min[a] || ray.origin[a] > max[a] {
+                return None;
+            }
+            continue;
+        }
+
+        let t0 = (min[a] - ray.origin[a]) / ray.direction[a];
+        let t1 = (max[a] - ray.origin[a]) / ray.direction[a];
+        let (slab_near, slab_far) = (t0.min(t1), t0.max(t1));
+
+        if slab_near > near {
+            near = slab_near;
+            axis = a;
+        }
+        far = far.min(slab_far);
+    }
+
+    let t_enter = near.max(t_min);
+    let t_exit = far.min(t_max);
+    (t_enter <= t_exit).then_some((t_enter, t_exit, axis))
+}
+
+/// First voxel of the box `[min, max)` along the ray, entered at `t_enter` through `axis`.
+fn entry_voxel(ray: &Ray, min: UVec3, max: UVec3, t_enter: f32, axis: usize) -> UVec3 {
+    let position = ray.at(t_enter).floor().as_ivec3();
+    let mut voxel = position.clamp(min.as_ivec3(), max.as_ivec3() - IVec3::ONE);
+
+    // Rounding can put the entry point one voxel off on the entry axis.
+    if ray.direction[axis] > 0.0 {
+        voxel[axis] = voxel[axis].max(min[axis] as i32);
+    } else if ray.direction[axis] < 0.0 {
+        voxel[axis] = voxel[axis].min(max[axis] as i32 - 1);
+    }
+
+    voxel.as_uvec3()
+}
+
+/// 3D-DDA over the solid voxels of `brick` inside the box `[min, max)`, in chunk local units.
+/// `materials` is the chunk as [`super::palette::PaletteVoxels::gpu_data`].
+#[allow(clippy::too_many_arguments)]
+pub fn march_brick(
+    ray: &Ray,
+    brick: &Brick,
+    materials: &[u32],
+    brick_origin: UVec3,
+    min: UVec3,
+    max: UVec3,
+    t_enter: f32,
+    t_exit: f32,
+    entry_axis: usize,
+) -> Option<VoxelHit> {
+    let step = IVec3::new(
+        step_of(ray.direction.x),
+        step_of(ray.direction.y),
+        step_of(ray.direction.z),
+    );
+
+    let mut voxel = entry_voxel(ray, min, max, t_enter, entry_axis).as_ivec3();
+    let mut t = t_enter;
+    let mut face = entry_face(entry_axis, step[entry_axis]);
+
+    // A ray crosses at most one voxel per axis and per step.
+    for _ in 0..3 * BRICK_SIZE {
+        let local = (voxel - brick_origin.as_ivec3()).as_uvec3();
+        if brick.is_solid(local) {
+            return Some(VoxelHit {
+                t,
+                voxel: voxel.as_uvec3(),
+                face,
+                material: palette::decode_gpu_material(materials, Chunk::index(voxel.as_uvec3())),
+            });
+        }
+
+        // Distance to the next voxel boundary on each axis, recomputed rather than
+        // accumulated so the CPU and the GPU round the same way.
+        let mut t_next = Vec3::INFINITY;
+        for a in 0..3 {
+            if step[a] != 0 {
+                let boundary = (voxel[a] + (step[a] > 0) as i32) as f32;
+                t_next[a] = (boundary - ray.origin[a]) / ray.direction[a];
+            }
+        }
+
+        let axis = if t_next.x < t_next.y && t_next.x < t_next.z {
+            0
+        } else if t_next.y < t_next.z {
+            1
+        } else {
+            2
+        };
+
+        t = t_next[axis];
+        if t > t_exit {
+            return None;
+        }
+
+        voxel[axis] += step[axis];
+        if voxel[axis] < min[axis] as i32 || voxel[axis] >= max[axis] as i32 {
+            return None;
+        }
+        face = entry_face(axis, step[axis]);
+    }
+
+    None
+}
+
+/// Intersection of the ray with primitive `primitive_index` of a chunk, as the intersection
+/// shader would report it for `gl_PrimitiveID == primitive_index`.
+pub fn intersect_primitive(
+    chunk_aabbs: &ChunkAabbs,
+    primitive_index: usize,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<VoxelHit> {
+    let aabb = &chunk_aabbs.aabbs[primitive_index];
+    let primitive = &chunk_aabbs.primitives[primitive_index];
+
+    let min = Vec3::new(aabb.min_x, aabb.min_y, aabb.min_z);
+    let max = Vec3::new(aabb.max_x, aabb.max_y, aabb.max_z);
+    let (t_enter, t_exit, axis) = intersect_box(ray, min, max, t_min, t_max)?;
+    let (min, max) = (min.as_uvec3(), max.as_uvec3());
+
+    match chunk_aabbs.granularity {
+        // The whole box is solid, the entry point is the hit.
+        AabbGranularity::Voxel | AabbGranularity::Greedy => Some(VoxelHit {
+            t: t_enter,
+            voxel: entry_voxel(ray, min, max, t_enter, axis),
+            face: entry_face(axis, step_of(ray.direction[axis])),
+            material: MaterialId(primitive.data as u16),
+        }),
+        AabbGranularity::Brick => march_brick(
+            ray,
+            &chunk_aabbs.bricks[primitive.data as usize],
+            &chunk_aabbs.materials,
+            UVec3::from_array(primitive.origin),
+            min,
+            max,
+            t_enter,
+            t_exit,
+            axis,
+        ),
+    }
+}
+
+/// Closest hit over all the primitives of a chunk, what the BLAS traversal would return.
+pub fn trace_chunk(
+    chunk_aabbs: &ChunkAabbs,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<VoxelHit> {
+    let mut closest: Option<VoxelHit> = None;
+
+    for primitive_index in 0..chunk_aabbs.aabbs.len() {
+        let t_max = closest.map_or(t_max, |hit| hit.t);
+        if let Some(hit) = intersect_primitive(chunk_aabbs, primitive_index, ray, t_min, t_max) {
+            if closest.is_none_or(|closest| hit.t < closest.t) {
+                closest = Some(hit);
+            }
+        }
+    }
+
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRANULARITIES: [AabbGranularity; 3] = [
+        AabbGranularity::Voxel,
+        AabbGranularity::Brick,
+        AabbGranularity::Greedy,
+    ];
+
+    fn chunk_with(voxels: &[(UVec3, MaterialId)]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &(local, material) in voxels {
+            chunk.set(local, material);
+        }
+        chunk
+    }
+
+    /// Stone floor at y = 0, a grass column at x = z = 10 up to y = 4 and a gold voxel
+    /// floating at (20, 3, 7).
+    fn scene() -> Chunk {
+        Chunk::from_fn(|local| match local.to_array() {
+            [_, 0, _] => MaterialId::STONE,
+            [10, 1..=4, 10] => MaterialId::GRASS,
+            [20, 3, 7] => MaterialId::GOLD_ORE,
+            _ => MaterialId::AIR,
+        })
+    }
+
+    fn hit(t: f32, voxel: [u32; 3], normal: IVec3, material: MaterialId) -> VoxelHit {
+        let face = (0..6).find(|&face| face_normal(face) == normal).unwrap();
+        VoxelHit {
+            t,
+            voxel: UVec3::from_array(voxel),
+            face,
+            material,
+        }
+    }
+
+    #[test]
+    fn faces_encode_the_axis_and_the_normal_sign() {
+        let normals: Vec<IVec3> = (0..6).map(face_normal).collect();
+        assert_eq!(
+            normals,
+            [
+                IVec3::X,
+                IVec3::NEG_X,
+                IVec3::Y,
+                IVec3::NEG_Y,
+                IVec3::Z,
+                IVec3::NEG_Z
+            ]
+        );
+
+        // The face a ray enters through faces the ray.
+        for axis in 0..3 {
+            for step in [-1, 1] {
+                let normal = face_normal(entry_face(axis, step));
+                assert_eq!(normal[axis], -step);
+                assert_eq!(normal.abs().element_sum(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn intersect_box_axis_aligned_rays() {
+        let (min, max) = (Vec3::ZERO, Vec3::ONE);
+
+        let ray = Ray::new(Vec3::new(-2.0, 0.5, 0.5), Vec3::X);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((2.0, 3.0, 0))
+        );
+
+        let ray = Ray::new(Vec3::new(0.5, 3.5, 0.5), Vec3::NEG_Y);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((2.5, 3.5, 1))
+        );
+
+        // Distances are in multiples of the direction.
+        let ray = Ray::new(Vec3::new(0.5, 0.5, -4.0), Vec3::new(0.0, 0.0, 2.0));
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((2.0, 2.5, 2))
+        );
+
+        // Clamped to the ray interval, and missed when the box is outside of it.
+        assert_eq!(
+            intersect_box(&ray, min, max, 2.25, 10.0),
+            Some((2.25, 2.5, 2))
+        );
+        assert_eq!(intersect_box(&ray, min, max, 0.0, 1.5), None);
+        assert_eq!(intersect_box(&ray, min, max, 3.0, 10.0), None);
+
+        // Pointing away from the box.
+        let ray = Ray::new(Vec3::new(-2.0, 0.5, 0.5), Vec3::NEG_X);
+        assert_eq!(intersect_box(&ray, min, max, 0.0, f32::INFINITY), None);
+    }
+
+    #[test]
+    fn intersect_box_from_inside() {
+        let (min, max) = (Vec3::ZERO, Vec3::ONE);
+
+        let ray = Ray::new(Vec3::new(0.5, 0.5, 0.5), Vec3::X);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((0.0, 0.5, 0))
+        );
+
+        // The entry axis is the last slab crossed behind the origin: x at -0.25,
+        // after y at -0.5.
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 0.5), Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((0.0, 0.5, 0))
+        );
+        let ray = Ray::new(Vec3::new(0.5, 0.75, 0.5), Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((0.0, 0.25, 0))
+        );
+        let ray = Ray::new(Vec3::new(0.5, 0.25, 0.5), Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((0.0, 0.5, 1))
+        );
+    }
+
+    #[test]
+    fn intersect_box_parallel_to_a_slab() {
+        let (min, max) = (Vec3::ZERO, Vec3::ONE);
+
+        // Outside the y slab, never entered.
+        let ray = Ray::new(Vec3::new(-1.0, 2.0, 0.5), Vec3::X);
+        assert_eq!(intersect_box(&ray, min, max, 0.0, f32::INFINITY), None);
+        let ray = Ray::new(Vec3::new(-1.0, -0.5, 0.5), Vec3::X);
+        assert_eq!(intersect_box(&ray, min, max, 0.0, f32::INFINITY), None);
+
+        // On the faces, which are part of the box.
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.5), Vec3::X);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((1.0, 2.0, 0))
+        );
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 0.0), Vec3::X);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((1.0, 2.0, 0))
+        );
+
+        // Parallel to two slabs, no 0 * inf turning into NaN.
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 1.0), Vec3::Z);
+        assert_eq!(
+            intersect_box(&ray, min, max, 0.0, f32::INFINITY),
+            Some((0.0, 0.0, 2))
+        );
+    }
+
+    /// Stone at (2, 1, 1) and grass at (5, 2, 1), one brick box from (2, 1, 1) to (6, 3, 2)
+    /// that is mostly empty.
+    fn brick_chunk() -> ChunkAabbs {
+        let chunk = chunk_with(&[
+            (UVec3::new(2, 1, 1), MaterialId::STONE),
+            (UVec3::new(5, 2, 1), MaterialId::GRASS),
+        ]);
+        let chunk_aabbs = ChunkAabbs::new(&chunk, AabbGranularity::Brick);
+        assert_eq!(chunk_aabbs.primitive_count(), 1);
+        chunk_aabbs
+    }
+
+    fn march(chunk_aabbs: &ChunkAabbs, ray: &Ray, t_min: f32) -> Option<VoxelHit> {
+        let aabb = &chunk_aabbs.aabbs[0];
+        let primitive = &chunk_aabbs.primitives[0];
+        let min = Vec3::new(aabb.min_x, aabb.min_y, aabb.min_z);
+        let max = Vec3::new(aabb.max_x, aabb.max_y, aabb.max_z);
+        assert_eq!(
+            (min, max),
+            (Vec3::new(2.0, 1.0, 1.0), Vec3::new(6.0, 3.0, 2.0))
+        );
+
+        let (t_enter, t_exit, axis) = intersect_box(ray, min, max, t_min, f32::INFINITY)?;
+        march_brick(
+            ray,
+            &chunk_aabbs.bricks[primitive.data as usize],
+            &chunk_aabbs.materials,
+            UVec3::from_array(primitive.origin),
+            min.as_uvec3(),
+            max.as_uvec3(),
+            t_enter,
+            t_exit,
+            axis,
+        )
+    }
+
+    #[test]
+    fn march_brick_axis_aligned_rays() {
+        let chunk_aabbs = brick_chunk();
+
+        // Solid at the entry voxel.
+        let ray = Ray::new(Vec3::new(0.0, 1.5, 1.5), Vec3::X);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(2.0, [2, 1, 1], IVec3::NEG_X, MaterialId::STONE))
+        );
+
+        // Steps over three empty voxels first.
+        let ray = Ray::new(Vec3::new(0.0, 2.5, 1.5), Vec3::X);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(5.0, [5, 2, 1], IVec3::NEG_X, MaterialId::GRASS))
+        );
+
+        let ray = Ray::new(Vec3::new(10.0, 1.5, 1.5), Vec3::NEG_X);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(7.0, [2, 1, 1], IVec3::X, MaterialId::STONE))
+        );
+
+        let ray = Ray::new(Vec3::new(5.5, 10.0, 1.5), Vec3::NEG_Y);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(7.0, [5, 2, 1], IVec3::Y, MaterialId::GRASS))
+        );
+
+        // Through the box between the two voxels.
+        let ray = Ray::new(Vec3::new(3.5, 10.0, 1.5), Vec3::NEG_Y);
+        assert_eq!(march(&chunk_aabbs, &ray, 0.0), None);
+    }
+
+    #[test]
+    fn march_brick_from_inside() {
+        let chunk_aabbs = brick_chunk();
+
+        // Starts in the empty voxel (3, 2, 1).
+        let ray = Ray::new(Vec3::new(3.5, 2.5, 1.5), Vec3::X);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(1.5, [5, 2, 1], IVec3::NEG_X, MaterialId::GRASS))
+        );
+
+        // Crosses y = 2 at t = 0.5, then x = 3 into the stone at t = 0.75.
+        let ray = Ray::new(Vec3::new(3.75, 2.25, 1.5), Vec3::new(-1.0, -0.5, 0.0));
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(0.75, [2, 1, 1], IVec3::X, MaterialId::STONE))
+        );
+
+        // Starts in the solid voxel, which is hit right away, through the face behind it.
+        let ray = Ray::new(Vec3::new(5.5, 2.5, 1.5), Vec3::Z);
+        assert_eq!(
+            march(&chunk_aabbs, &ray, 0.0),
+            Some(hit(0.0, [5, 2, 1], IVec3::NEG_Z, MaterialId::GRASS))
+        );
+    }
+
+    #[test]
+    fn trace_chunk_finds_the_closest_hit() {
+        let chunk = scene();
+        let rays = [
+            // Onto the top of the column rather than the floor behind it.
+            (
+                Ray::new(Vec3::new(10.5, 20.0, 10.5), Vec3::NEG_Y),
+                Some(hit(15.0, [10, 4, 10], IVec3::Y, MaterialId::GRASS)),
+            ),
+            (
+                Ray::new(Vec3::new(3.5, 20.0, 3.5), Vec3::NEG_Y),
+                Some(hit(19.0, [3, 0, 3], IVec3::Y, MaterialId::STONE)),
+            ),
+            (
+                Ray::new(Vec3::new(-5.0, 3.5, 7.5), Vec3::X),
+                Some(hit(25.0, [20, 3, 7], IVec3::NEG_X, MaterialId::GOLD_ORE)),
+            ),
+            (
+                Ray::new(Vec3::new(10.5, 2.5, 40.0), Vec3::NEG_Z),
+                Some(hit(29.0, [10, 2, 10], IVec3::Z, MaterialId::GRASS)),
+            ),
+            // Down onto the floor at (6.5, 1, 12.5), past the column.
+            (
+                Ray::new(Vec3::new(16.5, 6.0, 12.5), Vec3::new(-2.0, -1.0, 0.0)),
+                Some(hit(5.0, [6, 0, 12], IVec3::Y, MaterialId::STONE)),
+            ),
+            // Up, away from everything.
+            (Ray::new(Vec3::new(-5.0, 10.0, -5.0), Vec3::Y), None),
+            // Under the floor.
+            (Ray::new(Vec3::new(-5.0, -0.5, 3.5), Vec3::X), None),
+        ];
+
+        for granularity in GRANULARITIES {
+            let chunk_aabbs = ChunkAabbs::new(&chunk, granularity);
+            for (ray, expected) in &rays {
+                assert_eq!(
+                    trace_chunk(&chunk_aabbs, ray, 0.0, f32::INFINITY),
+                    *expected,
+                    "{granularity:?} {ray:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn granularities_report_the_same_closest_hit() {
+        let chunk = scene();
+        let chunk_aabbs = GRANULARITIES.map(|granularity| ChunkAabbs::new(&chunk, granularity));
+
+        let origins = [
+            Vec3::new(-3.3, 7.1, -2.9),
+            Vec3::new(15.3, 8.7, 12.1),
+            Vec3::new(35.2, 2.6, 30.4),
+            Vec3::new(24.6, 6.2, 3.3),
+        ];
+        let targets = [
+            Vec3::new(10.4, 2.7, 10.6),
+            Vec3::new(20.3, 3.4, 7.2),
+            Vec3::new(27.1, 0.3, 4.9),
+            Vec3::new(5.7, 1.2, 25.3),
+            Vec3::new(12.9, 0.9, 10.1),
+        ];
+
+        // Rays starting inside a solid voxel are left out, the face they report is the one
+        // of the box they start in, which differs between granularities.
+        let mut hits = 0;
+        for origin in origins {
+            for target in targets {
+                let ray = Ray::new(origin, target - origin);
+                let [voxel, brick, greedy] = chunk_aabbs
+                    .each_ref()
+                    .map(|chunk_aabbs| trace_chunk(chunk_aabbs, &ray, 0.0, f32::INFINITY));
+                assert_eq!(voxel, brick, "{ray:?}");
+                assert_eq!(voxel, greedy, "{ray:?}");
+                hits += voxel.is_some() as u32;
+            }
+        }
+        assert!(hits > origins.len() as u32 * targets.len() as u32 / 2);
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::{IVec3, UVec3};
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE},
+    material::MaterialId,
+};
+
+/// Voxel world made of chunks, only the chunks holding solid voxels are stored.
+///
+/// Edits mark their chunk dirty, and the stored neighbour chunks touching an edited border
+/// voxel since their meshes cull and shade against it. The renderer collects them with
+/// [`World::take_dirty_chunks`] to rebuild or refit the chunk BLASes.
+#[derive(Default)]
+pub struct World {
+    chunks: HashMap<IVec3, Chunk>,
+    dirty_chunks: HashSet<IVec3>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Position of the chunk containing the voxel at `world_position`.
+    pub fn chunk_position(world_position: IVec3) -> IVec3 {
+        world_position.div_euclid(IVec3::splat(CHUNK_SIZE as i32))
+    }
+
+    /// Position of the voxel at `world_position` inside its chunk.
+    pub fn local_position(world_position: IVec3) -> UVec3 {
+        world_position
+            .rem_euclid(IVec3::splat(CHUNK_SIZE as i32))
+            .as_uvec3()
+    }
+
+    pub fn get_voxel(&self, world_position: IVec3) -> MaterialId {
+        self.chunks
+            .get(&Self::chunk_position(world_position))
+            .map_or(MaterialId::AIR, |chunk| {
+                chunk.get(Self::local_position(world_position))
+            })
+    }
+
+    /// Returns the previous material. Chunks are created on the first solid voxel
+    /// and dropped with the last one.
+    pub fn set_voxel(&mut self, world_position: IVec3, material: MaterialId) -> MaterialId {
+        let chunk_position = Self::chunk_position(world_position);
+        let local_position = Self::local_position(world_position);
+
+        let chunk = match self.chunks.get_mut(&chunk_position) {
+            Some(chunk) => chunk,
+            None if material.is_air() => return MaterialId::AIR,
+            None => self.chunks.entry(chunk_position).or_default(),
+        };
+
+        let previous = chunk.set(local_position, material);
+        if previous == material {
+            return previous;
+        }
+
+        if chunk.is_empty() {
+            self.chunks.remove(&chunk_position);
+        }
+        self.dirty_chunks.insert(chunk_position);
+        self.mark_neighbours_dirty(chunk_position, local_position);
+
+        previous
+    }
+
+    /// Marks the stored chunks sharing a face, edge or corner with the voxel at `local_position`
+    /// of the chunk at `chunk_position`.
+    fn mark_neighbours_dirty(&mut self, chunk_position: IVec3, local_position: UVec3) {
+        let last = CHUNK_SIZE - 1;
+        let range = |local: u32| (-((local == 0) as i32))..=((local == last) as i32);
+
+        for z in range(local_position.z) {
+            for y in range(local_position.y) {
+                for x in range(local_position.x) {
+                    let neighbour = chunk_position + IVec3::new(x, y, z);
+                    if neighbour != chunk_position && self.chunks.contains_key(&neighbour) {
+                        self.dirty_chunks.insert(neighbour);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn chunk(&self, chunk_position: IVec3) -> Option<&Chunk> {
+        self.chunks.get(&chunk_position)
+    }
+
+    /// Replaces a whole chunk, e.g. freshly generated or loaded. Empty chunks are not stored.
+    pub fn insert_chunk(&mut self, chunk_position: IVec3, chunk: Chunk) -> Option<Chunk> {
+        self.dirty_chunks.insert(chunk_position);
+
+        if chunk.is_empty() {
+            self.chunks.remove(&chunk_position)
+        } else {
+            self.chunks.insert(chunk_position, chunk)
+        }
+    }
+
+    pub fn remove_chunk(&mut self, chunk_position: IVec3) -> Option<Chunk> {
+        let removed = self.chunks.remove(&chunk_position);
+        if removed.is_some() {
+            self.dirty_chunks.insert(chunk_position);
+        }
+        removed
+    }
+
+    /// Chunks holding at least one solid voxel, in no particular order.
+    pub fn chunks(&self) -> impl Iterator<Item = (IVec3, &Chunk)> {
+        self.chunks
+            .iter()
+            .map(|(&position, chunk)| (position, chunk))
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Chunks changed since the last call. A dirty chunk missing from the world was emptied
+    /// or removed, its BLAS should be dropped.
+    pub fn take_dirty_chunks(&mut self) -> Vec<IVec3> {
+        self.dirty_chunks.drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_dirty_chunks(world: &mut World) -> Vec<IVec3> {
+        let mut dirty = world.take_dirty_chunks();
+        dirty.sort_by_key(|position| position.to_array());
+        dirty
+    }
+
+    #[test]
+    fn negative_coordinates_wrap_into_the_chunk_below() {
+        let cases = [
+            (IVec3::new(-1, -1, -1), IVec3::splat(-1), UVec3::splat(31)),
+            (
+                IVec3::new(-32, 0, -33),
+                IVec3::new(-1, 0, -2),
+                UVec3::new(0, 0, 31),
+            ),
+            (
+                IVec3::new(-33, 5, 32),
+                IVec3::new(-2, 0, 1),
+                UVec3::new(31, 5, 0),
+            ),
+        ];
+
+        let mut world = World::new();
+        for (world_position, chunk_position, local_position) in cases {
+            assert_eq!(World::chunk_position(world_position), chunk_position);
+            assert_eq!(World::local_position(world_position), local_position);
+
+            assert_eq!(
+                world.set_voxel(world_position, MaterialId::STONE),
+                MaterialId::AIR
+            );
+            assert_eq!(world.get_voxel(world_position), MaterialId::STONE);
+            let chunk = world.chunk(chunk_position).unwrap();
+            assert_eq!(chunk.get(local_position), MaterialId::STONE);
+            assert_eq!(chunk.solid_count(), 1);
+        }
+
+        assert_eq!(world.chunk_count(), cases.len());
+        assert_eq!(world.get_voxel(IVec3::ZERO), MaterialId::AIR);
+        assert_eq!(world.get_voxel(IVec3::new(-2, -1, -1)), MaterialId::AIR);
+    }
+
+    #[test]
+    fn edits_mark_their_chunk_dirty_once() {
+        let mut world = World::new();
+        world.set_voxel(IVec3::new(5, 6, 7), MaterialId::STONE);
+        world.set_voxel(IVec3::new(8, 6, 7), MaterialId::DIRT);
+        assert_eq!(sorted_dirty_chunks(&mut world), [IVec3::ZERO]);
+        assert!(world.take_dirty_chunks().is_empty());
+
+        // Unchanged voxels and air outside of the stored chunks are no edit.
+        world.set_voxel(IVec3::new(5, 6, 7), MaterialId::STONE);
+        world.set_voxel(IVec3::new(100, 6, 7), MaterialId::AIR);
+        assert!(world.take_dirty_chunks().is_empty());
+        assert_eq!(world.chunk_count(), 1);
+    }
+
+    #[test]
+    fn border_edits_mark_the_stored_neighbours_dirty() {
+        let full = || Chunk::from_fn(|_| MaterialId::STONE);
+        let mut world = World::new();
+        for position in [IVec3::ZERO, IVec3::NEG_X, IVec3::NEG_ONE, IVec3::Y] {
+            world.insert_chunk(position, full());
+        }
+        world.take_dirty_chunks();
+
+        // Only the chunks sharing the face: the one above isn't stored next to this voxel.
+        world.set_voxel(IVec3::new(0, 5, 5), MaterialId::AIR);
+        assert_eq!(sorted_dirty_chunks(&mut world), [IVec3::NEG_X, IVec3::ZERO]);
+
+        // The corner touches the diagonal chunk too.
+        world.set_voxel(IVec3::ZERO, MaterialId::AIR);
+        assert_eq!(
+            sorted_dirty_chunks(&mut world),
+            [IVec3::NEG_ONE, IVec3::NEG_X, IVec3::ZERO]
+        );
+
+        // Missing neighbours have no mesh to update.
+        world.set_voxel(IVec3::new(31, 31, 5), MaterialId::AIR);
+        assert_eq!(sorted_dirty_chunks(&mut world), [IVec3::ZERO, IVec3::Y]);
+    }
+
+    #[test]
+    fn emptied_chunks_are_removed_and_dirty() {
+        let mut world = World::new();
+        let position = IVec3::new(-40, 3, 70);
+        let chunk_position = World::chunk_position(position);
+        world.set_voxel(position, MaterialId::STONE);
+        world.set_voxel(position + IVec3::X, MaterialId::STONE);
+        world.take_dirty_chunks();
+
+        world.set_voxel(position, MaterialId::AIR);
+        assert!(world.chunk(chunk_position).is_some());
+        assert_eq!(
+            world.set_voxel(position + IVec3::X, MaterialId::AIR),
+            MaterialId::STONE
+        );
+        assert!(world.chunk(chunk_position).is_none());
+        assert_eq!(world.chunk_count(), 0);
+        assert_eq!(world.take_dirty_chunks(), [chunk_position]);
+
+        world.insert_chunk(IVec3::ZERO, Chunk::from_fn(|_| MaterialId::DIRT));
+        world.insert_chunk(IVec3::ZERO, Chunk::new());
+        assert_eq!(world.chunk_count(), 0);
+        assert_eq!(world.take_dirty_chunks(), [IVec3::ZERO]);
+    }
+}
@@ -0,0 +1,908 @@
+//! On disk worlds. A save is a directory holding:
+//!
+//! - `world.dat`: format version, terrain settings (seed included) and the material table.
+//! - `regions/<x>.<y>.<z>.region`: the chunks of a `REGION_SIZE`³ region, each compressed
+//!   on its own and found through an offset table, so single chunks can be streamed in.
+//!   A zero offset is a chunk never saved, generated again from the settings, and a zero
+//!   length at a non-zero offset a chunk saved empty, e.g. dug out entirely.
+//!
+//! Everything is little endian. Chunks store file material ids, translated through the
+//! material table by name: built-in materials can be renumbered without breaking saves.
+//!
+//! Every file starts with its format version. A layout change bumps [`FORMAT_VERSION`] and
+//! keeps a reader for the previous layouts: older files still load and are rewritten in the
+//! current version when next saved, or all at once with [`WorldSave::upgrade`].
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use glam::IVec3;
+
+use super::{
+    chunk::{Chunk, CHUNK_VOLUME},
+    material::MaterialId,
+    palette::bits_for,
+    terrain::TerrainSettings,
+    voxel_world::World,
+};
+
+/// Version written by this build. Version 2 added the empty chunk entries.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Edge length of a region, in chunks.
+pub const REGION_SIZE: i32 = 8;
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+const HEADER_MAGIC: &[u8; 4] = b"VXWD";
+const REGION_MAGIC: &[u8; 4] = b"VXRG";
+/// Magic, version, then an offset and a length per chunk.
+const REGION_HEADER_SIZE: usize = 8 + REGION_VOLUME * 8;
+
+const COMPRESSION_PACKED: u8 = 0;
+const COMPRESSION_RUNS: u8 = 1;
+
+#[derive(Debug)]
+pub enum WorldSaveError {
+    Io(io::Error),
+    NotAWorldSave(PathBuf),
+    /// Written by a newer build.
+    UnsupportedVersion(u32),
+    Malformed(String),
+}
+
+impl fmt::Display for WorldSaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::NotAWorldSave(path) => write!(f, "{} is not a world save", path.display()),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "save format version {version} is newer than the supported {FORMAT_VERSION}"
+            ),
+            Self::Malformed(reason) => write!(f, "malformed world save: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for WorldSaveError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+fn malformed<T>(reason: impl Into<String>) -> Result<T, WorldSaveError> {
+    Err(WorldSaveError::Malformed(reason.into()))
+}
+
+fn check_version(version: u32) -> Result<u32, WorldSaveError> {
+    match version {
+        1..=FORMAT_VERSION => Ok(version),
+        _ => Err(WorldSaveError::UnsupportedVersion(version)),
+    }
+}
+
+/// Region of a chunk, and the index of the chunk in the region offset table.
+pub fn region_of(chunk_position: IVec3) -> (IVec3, usize) {
+    let region = chunk_position.div_euclid(IVec3::splat(REGION_SIZE));
+    let local = chunk_position.rem_euclid(IVec3::splat(REGION_SIZE));
+    let index = local.x + local.y * REGION_SIZE + local.z * REGION_SIZE * REGION_SIZE;
+    (region, index as usize)
+}
+
+fn chunk_in_region(region: IVec3, index: usize) -> IVec3 {
+    let index = index as i32;
+    let local = IVec3::new(
+        index % REGION_SIZE,
+        (index / REGION_SIZE) % REGION_SIZE,
+        index / (REGION_SIZE * REGION_SIZE),
+    );
+    region * REGION_SIZE + local
+}
+
+/// File material ids to materials and back. Ids are only ever added, so chunks written
+/// with an older table stay valid.
+#[derive(Debug, Default)]
+struct MaterialTable {
+    names: Vec<String>,
+    materials: Vec<MaterialId>,
+    file_ids: HashMap<MaterialId, u16>,
+}
+
+impl MaterialTable {
+    fn from_names(names: Vec<String>) -> Self {
+        let mut table = Self::default();
+        for name in names {
+            // Unnamed materials are saved as `#<id>`.
+            let material = MaterialId::from_name(&name)
+                .or_else(|| name.strip_prefix('#')?.parse().ok().map(MaterialId))
+                .unwrap_or(MaterialId::AIR);
+            table.push(name, material);
+        }
+        table
+    }
+
+    fn push(&mut self, name: String, material: MaterialId) -> u16 {
+        let file_id = self.names.len() as u16;
+        self.names.push(name);
+        self.materials.push(material);
+        self.file_ids.entry(material).or_insert(file_id);
+        file_id
+    }
+
+    fn material(&self, file_id: u16) -> Result<MaterialId, WorldSaveError> {
+        match self.materials.get(file_id as usize) {
+            Some(&material) => Ok(material),
+            None => malformed(format!("unknown material {file_id}")),
+        }
+    }
+
+    /// Adds the material to the table if needed, returns whether it was added.
+    fn register(&mut self, material: MaterialId) -> bool {
+        if self.file_ids.contains_key(&material) {
+            return false;
+        }
+        let name = match material.name() {
+            Some(name) => name.to_string(),
+            None => format!("#{}", material.0),
+        };
+        self.push(name, material);
+        true
+    }
+}
+
+/// Offset table of a region file.
+struct RegionIndex {
+    version: u32,
+    entries: Vec<(u32, u32)>,
+}
+
+pub struct WorldSave {
+    directory: PathBuf,
+    settings: TerrainSettings,
+    header_version: Mutex<u32>,
+    materials: Mutex<MaterialTable>,
+}
+
+impl WorldSave {
+    /// Starts an empty save in `directory`, deleting the chunks of an existing one.
+    pub fn create(directory: &Path, settings: TerrainSettings) -> Result<Self, WorldSaveError> {
+        let regions = directory.join("regions");
+        match fs::remove_dir_all(&regions) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        fs::create_dir_all(regions)?;
+
+        let mut materials = MaterialTable::default();
+        for material in MaterialId::built_in() {
+            materials.register(material);
+        }
+
+        let save = Self {
+            directory: directory.to_path_buf(),
+            settings,
+            header_version: Mutex::new(FORMAT_VERSION),
+            materials: Mutex::new(materials),
+        };
+        save.write_header(&save.materials.lock().unwrap())?;
+        Ok(save)
+    }
+
+    /// Reads the header only, chunks are loaded on demand.
+    pub fn open(directory: &Path) -> Result<Self, WorldSaveError> {
+        let data = match fs::read(directory.join("world.dat")) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(WorldSaveError::NotAWorldSave(directory.to_path_buf()));
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut reader = Reader::new(&data);
+        if reader.bytes(4).ok() != Some(HEADER_MAGIC.as_slice()) {
+            return Err(WorldSaveError::NotAWorldSave(directory.to_path_buf()));
+        }
+        let version = check_version(reader.u32()?)?;
+
+        let settings = TerrainSettings {
+            seed: reader.u32()?,
+            base_height: reader.f64()?,
+            height_amplitude: reader.f64()?,
+            height_frequency: reader.f64()?,
+            height_octaves: reader.u32()? as usize,
+            biome_frequency: reader.f64()?,
+            cave_frequency: reader.f64()?,
+            cave_threshold: reader.f64()?,
+            cave_surface_margin: reader.u32()? as i32,
+            ore_veins_per_chunk: reader.u32()?,
+        };
+
+        let material_count = reader.u32()?;
+        let names = (0..material_count)
+            .map(|_| reader.string())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            settings,
+            header_version: Mutex::new(version),
+            materials: Mutex::new(MaterialTable::from_names(names)),
+        })
+    }
+
+    pub fn settings(&self) -> &TerrainSettings {
+        &self.settings
+    }
+
+    fn write_header(&self, materials: &MaterialTable) -> Result<(), WorldSaveError> {
+        let settings = &self.settings;
+        let mut data = Vec::new();
+        data.extend_from_slice(HEADER_MAGIC);
+        data.extend(FORMAT_VERSION.to_le_bytes());
+        data.extend(settings.seed.to_le_bytes());
+        for value in [
+            settings.base_height,
+            settings.height_amplitude,
+            settings.height_frequency,
+        ] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend((settings.height_octaves as u32).to_le_bytes());
+        for value in [
+            settings.biome_frequency,
+            settings.cave_frequency,
+            settings.cave_threshold,
+        ] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(settings.cave_surface_margin.to_le_bytes());
+        data.extend(settings.ore_veins_per_chunk.to_le_bytes());
+
+        data.extend((materials.names.len() as u32).to_le_bytes());
+        for name in &materials.names {
+            data.extend((name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+        }
+
+        write_atomically(&self.directory.join("world.dat"), &data)?;
+        *self.header_version.lock().unwrap() = FORMAT_VERSION;
+        Ok(())
+    }
+
+    fn region_path(&self, region: IVec3) -> PathBuf {
+        self.directory
+            .join("regions")
+            .join(format!("{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    /// Loads a single chunk, reading only its bytes. `None` if it was never saved, the
+    /// caller then generates it from [`WorldSave::settings`], and an empty chunk if it was
+    /// saved empty. Can be called from several threads.
+    pub fn load_chunk(&self, chunk_position: IVec3) -> Result<Option<Chunk>, WorldSaveError> {
+        let (region, index) = region_of(chunk_position);
+
+        // Regions are replaced by renaming a new file over them, everything is read from
+        // this handle so the offsets and the payload come from the same file.
+        let mut file = match fs::File::open(self.region_path(region)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let header = read_at(&mut file, 0, 8)?;
+        let mut reader = Reader::new(&header);
+        if reader.bytes(4)? != REGION_MAGIC {
+            return malformed("invalid region file");
+        }
+        let version = check_version(reader.u32()?)?;
+
+        let entry = read_at(&mut file, 8 + index as u64 * 8, 8)?;
+        let mut reader = Reader::new(&entry);
+        let (offset, length) = (reader.u32()?, reader.u32()?);
+        if offset == 0 {
+            return Ok(None);
+        }
+        if length == 0 {
+            return Ok(Some(Chunk::new()));
+        }
+
+        let payload = read_at(&mut file, offset as u64, length as usize)?;
+        let materials = self.materials.lock().unwrap();
+        decode_chunk(version, &payload, &materials).map(Some)
+    }
+
+    /// Writes the given chunks of `world`. Chunks missing from the world are saved empty,
+    /// e.g. the dirty chunks emptied by edits, so they are not generated again.
+    pub fn save_chunks(
+        &self,
+        world: &World,
+        chunk_positions: &[IVec3],
+    ) -> Result<(), WorldSaveError> {
+        let mut by_region: HashMap<IVec3, Vec<IVec3>> = HashMap::new();
+        for &chunk_position in chunk_positions {
+            by_region
+                .entry(region_of(chunk_position).0)
+                .or_default()
+                .push(chunk_position);
+        }
+
+        for (region, chunk_positions) in by_region {
+            let (_, mut chunks) = self.read_region(region)?;
+            for chunk_position in chunk_positions {
+                let chunk = world.chunk(chunk_position).cloned().unwrap_or_default();
+                chunks.insert(chunk_position, chunk);
+            }
+            self.write_region(region, chunks.iter())?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites every file written by an older build in the current version.
+    pub fn upgrade(&self) -> Result<(), WorldSaveError> {
+        for region in self.region_files()? {
+            let (version, chunks) = self.read_region(region)?;
+            if version < FORMAT_VERSION {
+                self.write_region(region, chunks.iter())?;
+            }
+        }
+        if *self.header_version.lock().unwrap() < FORMAT_VERSION {
+            self.write_header(&self.materials.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Regions with a file, in no particular order.
+    fn region_files(&self) -> Result<Vec<IVec3>, WorldSaveError> {
+        let mut regions = Vec::new();
+        for entry in fs::read_dir(self.directory.join("regions"))? {
+            let name = entry?.file_name();
+            let Some(coordinates) = name.to_str().and_then(|name| name.strip_suffix(".region"))
+            else {
+                continue;
+            };
+            let coordinates: Vec<i32> = coordinates
+                .split('.')
+                .filter_map(|coordinate| coordinate.parse().ok())
+                .collect();
+            if let [x, y, z] = coordinates[..] {
+                regions.push(IVec3::new(x, y, z));
+            }
+        }
+        Ok(regions)
+    }
+
+    /// Version and chunks of a region, empty if it has no file. Chunks saved empty are
+    /// included.
+    fn read_region(&self, region: IVec3) -> Result<(u32, HashMap<IVec3, Chunk>), WorldSaveError> {
+        let data = match fs::read(self.region_path(region)) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok((FORMAT_VERSION, HashMap::new()))
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let index = parse_region_header(data.get(..REGION_HEADER_SIZE).unwrap_or(&data))?;
+
+        let materials = self.materials.lock().unwrap();
+        let mut chunks = HashMap::new();
+        for (chunk_index, &(offset, length)) in index.entries.iter().enumerate() {
+            if offset == 0 {
+                continue;
+            }
+            let chunk_position = chunk_in_region(region, chunk_index);
+            if length == 0 {
+                chunks.insert(chunk_position, Chunk::new());
+                continue;
+            }
+            let Some(payload) =
+                data.get(offset as usize..(offset as usize).saturating_add(length as usize))
+            else {
+                return malformed(format!("chunk {chunk_index} outside of region {region}"));
+            };
+            chunks.insert(
+                chunk_position,
+                decode_chunk(index.version, payload, &materials)?,
+            );
+        }
+        Ok((index.version, chunks))
+    }
+
+    /// Writes a region in the current version, deletes its file if there are no chunks.
+    /// Empty chunks only get an entry.
+    fn write_region<'a>(
+        &self,
+        region: IVec3,
+        chunks: impl Iterator<Item = (&'a IVec3, &'a Chunk)>,
+    ) -> Result<(), WorldSaveError> {
+        let mut materials = self.materials.lock().unwrap();
+        let mut table_grew = false;
+
+        let mut entries = vec![(0u32, 0u32); REGION_VOLUME];
+        let mut payloads = Vec::new();
+        let mut has_chunks = false;
+        for (&chunk_position, chunk) in chunks {
+            let (chunk_region, index) = region_of(chunk_position);
+            debug_assert_eq!(chunk_region, region);
+            has_chunks = true;
+
+            if chunk.is_empty() {
+                entries[index] = ((REGION_HEADER_SIZE + payloads.len()) as u32, 0);
+                continue;
+            }
+            for &material in chunk.voxels_storage().palette() {
+                table_grew |= materials.register(material);
+            }
+            let payload = encode_chunk(chunk, &materials);
+            entries[index] = (
+                (REGION_HEADER_SIZE + payloads.len()) as u32,
+                payload.len() as u32,
+            );
+            payloads.extend(payload);
+        }
+
+        // Chunks may use the new materials only once the header knows them.
+        if table_grew || *self.header_version.lock().unwrap() < FORMAT_VERSION {
+            self.write_header(&materials)?;
+        }
+
+        let path = self.region_path(region);
+        if !has_chunks {
+            match fs::remove_file(&path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        } else {
+            let mut data = Vec::with_capacity(REGION_HEADER_SIZE + payloads.len());
+            data.extend_from_slice(REGION_MAGIC);
+            data.extend(FORMAT_VERSION.to_le_bytes());
+            for (offset, length) in entries {
+                data.extend(offset.to_le_bytes());
+                data.extend(length.to_le_bytes());
+            }
+            data.extend(payloads);
+            write_atomically(&path, &data)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, data)?;
+    fs::rename(&temporary, path)
+}
+
+/// Reads `len` bytes at `offset`, a file shorter than that is malformed.
+fn read_at(file: &mut fs::File, offset: u64, len: usize) -> Result<Vec<u8>, WorldSaveError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0; len];
+    match file.read_exact(&mut data) {
+        Ok(()) => Ok(data),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            malformed("unexpected end of region file")
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn parse_region_header(header: &[u8]) -> Result<RegionIndex, WorldSaveError> {
+    let mut reader = Reader::new(header);
+    if reader.bytes(4)? != REGION_MAGIC {
+        return malformed("invalid region file");
+    }
+    let version = check_version(reader.u32()?)?;
+    let entries = (0..REGION_VOLUME)
+        .map(|_| Ok((reader.u32()?, reader.u32()?)))
+        .collect::<Result<_, WorldSaveError>>()?;
+    Ok(RegionIndex { version, entries })
+}
+
+/// Version 1 chunk: the compression, the palette of file material ids, then either the
+/// palette indices bit packed like [`super::palette::PaletteVoxels`], or runs of the same
+/// index. The smaller of the two is written.
+fn encode_chunk(chunk: &Chunk, materials: &MaterialTable) -> Vec<u8> {
+    let mut palette = Vec::new();
+    let mut palette_indices = HashMap::new();
+    let indices: Vec<u16> = chunk
+        .voxels()
+        .map(|material| {
+            *palette_indices.entry(material).or_insert_with(|| {
+                palette.push(materials.file_ids[&material]);
+                palette.len() as u16 - 1
+            })
+        })
+        .collect();
+
+    let mut runs: Vec<(u16, u16)> = Vec::new();
+    for &index in &indices {
+        match runs.last_mut() {
+            Some((length, run_index)) if *run_index == index => *length += 1,
+            _ => runs.push((1, index)),
+        }
+    }
+
+    let bits = bits_for(palette.len()) as usize;
+    let packed_size = (CHUNK_VOLUME * bits).div_ceil(32) * 4;
+    let runs_size = 4 + runs.len() * 4;
+
+    let mut data = Vec::new();
+    data.push(if packed_size <= runs_size {
+        COMPRESSION_PACKED
+    } else {
+        COMPRESSION_RUNS
+    });
+    data.extend((palette.len() as u16).to_le_bytes());
+    for file_id in palette {
+        data.extend(file_id.to_le_bytes());
+    }
+
+    if packed_size <= runs_size {
+        let mut words = vec![0u32; packed_size / 4];
+        for (voxel, &index) in indices.iter().enumerate() {
+            let bit = voxel * bits;
+            words[bit / 32] |= (index as u32) << (bit % 32);
+        }
+        data.extend(words.iter().flat_map(|word| word.to_le_bytes()));
+    } else {
+        data.extend((runs.len() as u32).to_le_bytes());
+        for (length, index) in runs {
+            data.extend(length.to_le_bytes());
+            data.extend(index.to_le_bytes());
+        }
+    }
+    data
+}
+
+fn decode_chunk(
+    version: u32,
+    payload: &[u8],
+    materials: &MaterialTable,
+) -> Result<Chunk, WorldSaveError> {
+    match version {
+        // Older layouts are decoded here, into the current `Chunk`. Version 2 only changed
+        // the region offset table.
+        1 | 2 => decode_chunk_v1(payload, materials),
+        _ => Err(WorldSaveError::UnsupportedVersion(version)),
+    }
+}
+
+fn decode_chunk_v1(payload: &[u8], materials: &MaterialTable) -> Result<Chunk, WorldSaveError> {
+    let mut reader = Reader::new(payload);
+    let compression = reader.u8()?;
+    let palette_len = reader.u16()? as usize;
+    let palette = (0..palette_len)
+        .map(|_| materials.material(reader.u16()?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if palette.is_empty() {
+        return malformed("empty chunk palette");
+    }
+
+    let mut indices = Vec::with_capacity(CHUNK_VOLUME);
+    match compression {
+        COMPRESSION_PACKED => {
+            let bits = bits_for(palette_len) as usize;
+            if bits == 0 {
+                indices.resize(CHUNK_VOLUME, 0);
+            } else {
+                let words = reader.bytes((CHUNK_VOLUME * bits).div_ceil(32) * 4)?;
+                let mask = (1u32 << bits) - 1;
+                for voxel in 0..CHUNK_VOLUME {
+                    let bit = voxel * bits;
+                    let word = u32::from_le_bytes(
+                        words[bit / 32 * 4..bit / 32 * 4 + 4].try_into().unwrap(),
+                    );
+                    indices.push(((word >> (bit % 32)) & mask) as usize);
+                }
+            }
+        }
+        COMPRESSION_RUNS => {
+            let run_count = reader.u32()?;
+            for _ in 0..run_count {
+                let length = reader.u16()? as usize;
+                let index = reader.u16()? as usize;
+                if indices.len() + length > CHUNK_VOLUME {
+                    return malformed("chunk runs longer than a chunk");
+                }
+                indices.resize(indices.len() + length, index);
+            }
+        }
+        _ => return malformed(format!("unknown chunk compression {compression}")),
+    }
+
+    if indices.len() != CHUNK_VOLUME {
+        return malformed("chunk runs shorter than a chunk");
+    }
+    if indices.iter().any(|&index| index >= palette_len) {
+        return malformed("palette index out of range");
+    }
+    Ok(Chunk::from_fn(|local| {
+        palette[indices[Chunk::index(local)]]
+    }))
+}
+
+/// Little endian reads failing with [`WorldSaveError::Malformed`] past the end.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], WorldSaveError> {
+        match self.data.get(self.offset..self.offset.saturating_add(len)) {
+            Some(bytes) => {
+                self.offset += len;
+                Ok(bytes)
+            }
+            None => malformed("unexpected end of data"),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, WorldSaveError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, WorldSaveError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, WorldSaveError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, WorldSaveError> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, WorldSaveError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Save in a fresh directory, deleted when dropped.
+    struct TestSave {
+        directory: PathBuf,
+        save: WorldSave,
+    }
+
+    impl TestSave {
+        fn new(name: &str) -> Self {
+            let directory = std::env::temp_dir()
+                .join(format!("voxrt-world-save-{name}-{}", std::process::id()));
+            let save = WorldSave::create(&directory, TerrainSettings::default()).unwrap();
+            Self { directory, save }
+        }
+
+        fn region_path(&self, chunk_position: IVec3) -> PathBuf {
+            self.save.region_path(region_of(chunk_position).0)
+        }
+
+        fn reopen(&self) -> WorldSave {
+            WorldSave::open(&self.directory).unwrap()
+        }
+    }
+
+    impl Drop for TestSave {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.directory).ok();
+        }
+    }
+
+    /// Many short runs, stored bit packed.
+    fn noisy_chunk() -> Chunk {
+        let materials = [
+            MaterialId::STONE,
+            MaterialId::DIRT,
+            MaterialId::COAL_ORE,
+            MaterialId::AIR,
+        ];
+        Chunk::from_fn(|local| {
+            let index = (local.x * 7 + local.y * 13 + local.z * 3) % 4;
+            materials[index as usize]
+        })
+    }
+
+    /// Stone below grass, stored as runs.
+    fn layered_chunk() -> Chunk {
+        Chunk::from_fn(|local| match local.y {
+            0..12 => MaterialId::STONE,
+            12 => MaterialId::GRASS,
+            _ => MaterialId::AIR,
+        })
+    }
+
+    fn compression(chunk: &Chunk) -> u8 {
+        let mut materials = MaterialTable::default();
+        for material in MaterialId::built_in() {
+            materials.register(material);
+        }
+        encode_chunk(chunk, &materials)[0]
+    }
+
+    fn assert_same_voxels(actual: &Chunk, expected: &Chunk) {
+        assert!(actual.voxels().eq(expected.voxels()));
+    }
+
+    /// Overwrites the bytes at `offset` of `path`.
+    fn patch(path: &Path, offset: usize, bytes: &[u8]) {
+        let mut data = fs::read(path).unwrap();
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let test = TestSave::new("round-trip");
+        let (packed, runs) = (IVec3::new(0, 0, 0), IVec3::new(-1, 2, 9));
+        let mut world = World::new();
+        world.insert_chunk(packed, noisy_chunk());
+        world.insert_chunk(runs, layered_chunk());
+        assert_eq!(compression(&noisy_chunk()), COMPRESSION_PACKED);
+        assert_eq!(compression(&layered_chunk()), COMPRESSION_RUNS);
+
+        test.save.save_chunks(&world, &[packed, runs]).unwrap();
+
+        for save in [&test.save, &test.reopen()] {
+            let loaded = save.load_chunk(packed).unwrap().unwrap();
+            assert_same_voxels(&loaded, &noisy_chunk());
+            let loaded = save.load_chunk(runs).unwrap().unwrap();
+            assert_same_voxels(&loaded, &layered_chunk());
+        }
+    }
+
+    #[test]
+    fn emptied_chunks_load_empty() {
+        let test = TestSave::new("emptied");
+        let position = IVec3::new(3, -1, 2);
+        let mut world = World::new();
+        world.insert_chunk(position, layered_chunk());
+        test.save.save_chunks(&world, &[position]).unwrap();
+
+        // Dug out entirely, the world drops the chunk.
+        world.remove_chunk(position);
+        test.save.save_chunks(&world, &[position]).unwrap();
+
+        let (region, index) = region_of(position);
+        let data = fs::read(test.save.region_path(region)).unwrap();
+        let entry = parse_region_header(&data).unwrap().entries[index];
+        assert_ne!(entry.0, 0);
+        assert_eq!(entry.1, 0);
+
+        let loaded = test.reopen().load_chunk(position).unwrap().unwrap();
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn chunks_never_saved_are_none() {
+        let test = TestSave::new("never-saved");
+        let mut world = World::new();
+        world.insert_chunk(IVec3::ZERO, layered_chunk());
+        test.save.save_chunks(&world, &[IVec3::ZERO]).unwrap();
+
+        // Zero offset in a region file, and a region without a file.
+        assert!(test.save.load_chunk(IVec3::new(1, 0, 0)).unwrap().is_none());
+        assert!(test
+            .save
+            .load_chunk(IVec3::splat(REGION_SIZE))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn truncated_or_corrupt_regions_are_malformed() {
+        let test = TestSave::new("malformed");
+        let mut world = World::new();
+        world.insert_chunk(IVec3::ZERO, noisy_chunk());
+        test.save.save_chunks(&world, &[IVec3::ZERO]).unwrap();
+        let path = test.region_path(IVec3::ZERO);
+        let data = fs::read(&path).unwrap();
+
+        let assert_malformed = |what: &str| {
+            let result = test.save.load_chunk(IVec3::ZERO);
+            assert!(
+                matches!(result, Err(WorldSaveError::Malformed(_))),
+                "{what}: {:?}",
+                result.err()
+            );
+        };
+
+        // The payload is cut.
+        fs::write(&path, &data[..data.len() - 10]).unwrap();
+        assert_malformed("truncated payload");
+
+        // Within the header.
+        fs::write(&path, &data[..6]).unwrap();
+        assert_malformed("truncated header");
+
+        // Unknown compression.
+        fs::write(&path, &data).unwrap();
+        patch(&path, REGION_HEADER_SIZE, &[7]);
+        assert_malformed("unknown compression");
+
+        fs::write(&path, &data).unwrap();
+        patch(&path, 0, b"NOPE");
+        assert_malformed("invalid magic");
+    }
+
+    #[test]
+    fn newer_versions_are_unsupported() {
+        let test = TestSave::new("newer");
+        let mut world = World::new();
+        world.insert_chunk(IVec3::ZERO, layered_chunk());
+        test.save.save_chunks(&world, &[IVec3::ZERO]).unwrap();
+        let newer = (FORMAT_VERSION + 1).to_le_bytes();
+
+        patch(&test.region_path(IVec3::ZERO), 4, &newer);
+        match test.save.load_chunk(IVec3::ZERO) {
+            Err(WorldSaveError::UnsupportedVersion(version)) => {
+                assert_eq!(version, FORMAT_VERSION + 1)
+            }
+            result => panic!("{:?}", result.err()),
+        }
+
+        patch(&test.directory.join("world.dat"), 4, &newer);
+        let result = WorldSave::open(&test.directory);
+        assert!(
+            matches!(result, Err(WorldSaveError::UnsupportedVersion(_))),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn upgrade_rewrites_version_1_regions() {
+        let test = TestSave::new("upgrade");
+        let position = IVec3::new(2, 1, 0);
+        let (region, index) = region_of(position);
+
+        // Version 1 region holding a single chunk, written by hand.
+        let payload = {
+            let materials = test.save.materials.lock().unwrap();
+            encode_chunk(&noisy_chunk(), &materials)
+        };
+        let mut data = Vec::new();
+        data.extend_from_slice(REGION_MAGIC);
+        data.extend(1u32.to_le_bytes());
+        for entry in 0..REGION_VOLUME {
+            let (offset, length) = if entry == index {
+                (REGION_HEADER_SIZE as u32, payload.len() as u32)
+            } else {
+                (0, 0)
+            };
+            data.extend(offset.to_le_bytes());
+            data.extend(length.to_le_bytes());
+        }
+        data.extend(&payload);
+        let path = test.save.region_path(region);
+        fs::write(&path, &data).unwrap();
+        patch(&test.directory.join("world.dat"), 4, &1u32.to_le_bytes());
+
+        let save = test.reopen();
+        assert_same_voxels(&save.load_chunk(position).unwrap().unwrap(), &noisy_chunk());
+
+        save.upgrade().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(parse_region_header(&data).unwrap().version, FORMAT_VERSION);
+        let header = fs::read(test.directory.join("world.dat")).unwrap();
+        assert_eq!(header[4..8], FORMAT_VERSION.to_le_bytes());
+
+        let save = test.reopen();
+        assert_same_voxels(&save.load_chunk(position).unwrap().unwrap(), &noisy_chunk());
+        assert!(save.load_chunk(position + IVec3::X).unwrap().is_none());
+    }
+}